@@ -1,5 +1,22 @@
+use std::{collections::BTreeMap, env, fmt::Write as _, fs, io::Write as _, path::Path};
+
+use flate2::{Compression, GzBuilder};
+
+/// font compiled in when `PRINTER_FONT` is not set, a subset of GNU Unifont
+const DEFAULT_FONT: &str = "fonts/unifont.hex";
+/// unicode ranges compiled in when `PRINTER_FONT_RANGES` is not set: latin supplements, greek,
+/// cyrillic, punctuation, currency, arrows, maths, box drawing, common symbols, cjk punctuation,
+/// kana, hangul, cjk ideographs, fullwidth forms and emoji
+const DEFAULT_FONT_RANGES: &str = "00A0-024F,0370-03FF,0400-04FF,2000-20CF,2190-22FF,2500-259F,\
+    25A0-25FF,2600-26FF,2700-27BF,3000-30FF,3130-318F,4E00-9FFF,AC00-D7A3,FF00-FFEF,\
+    1F300-1F64F,1F680-1F6FF,1F900-1F9FF";
+/// flash the compiled font may take, the firmware and its ota twin have to fit alongside
+const MAX_FONT_BYTES: usize = 512 * 1024;
+
 fn main() {
     linker_be_nice();
+    compile_font();
+    compress_web_page();
     println!("cargo:rustc-link-arg=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
@@ -57,3 +74,241 @@ fn linker_be_nice() {
         std::env::current_exe().unwrap().display()
     );
 }
+
+/// Compiles the subset of the BDF or Unifont hex font named by `PRINTER_FONT` into a glyph table
+/// for the firmware, characters the printer's rom lacks are drawn from it. With `PRINTER_FONT`
+/// set empty the table is empty and those characters print as `?`.
+fn compile_font() {
+    println!("cargo:rerun-if-env-changed=PRINTER_FONT");
+    println!("cargo:rerun-if-env-changed=PRINTER_FONT_RANGES");
+
+    let ranges = env::var("PRINTER_FONT_RANGES").unwrap_or_else(|_| DEFAULT_FONT_RANGES.into());
+    let ranges = parse_ranges(&ranges);
+
+    let path = env::var("PRINTER_FONT").unwrap_or_else(|_| DEFAULT_FONT.into());
+    let font = (!path.is_empty()).then(|| {
+        let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join(path);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let source = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("failed to read font {}: {e}", path.display()));
+        if path.extension().is_some_and(|extension| extension == "hex") {
+            parse_hex(&source, &ranges)
+        } else {
+            parse_bdf(&source, &ranges)
+        }
+    });
+
+    let mut code = String::new();
+    match font {
+        Some(font) => {
+            let glyphs: usize = font.glyphs.len();
+            let bytes: usize = font.glyphs.values().map(|glyph| glyph.bitmap.len()).sum();
+            let flash = bytes + glyphs * 8;
+            println!("cargo:warning=bitmap font: {glyphs} glyphs, {flash} bytes");
+            if flash > MAX_FONT_BYTES {
+                panic!(
+                    "the bitmap font takes {flash} bytes of flash, more than {MAX_FONT_BYTES}, \
+                     narrow PRINTER_FONT_RANGES"
+                );
+            }
+
+            writeln!(code, "pub const FONT_HEIGHT: usize = {};", font.height).unwrap();
+            code.push_str("pub static GLYPHS: &[Glyph] = &[\n");
+            let mut column = 0;
+            for (encoding, glyph) in &font.glyphs {
+                if column > u16::MAX as usize {
+                    panic!("the bitmap font has too many glyphs, narrow PRINTER_FONT_RANGES");
+                }
+                writeln!(
+                    code,
+                    "    Glyph {{ c: '\\u{{{encoding:x}}}', width: {}, column: {column} }},",
+                    glyph.width
+                )
+                .unwrap();
+                column += glyph.width.div_ceil(8);
+            }
+            code.push_str("];\npub static BITMAPS: &[u8] = &[");
+            for glyph in font.glyphs.values() {
+                for byte in &glyph.bitmap {
+                    write!(code, "{byte},").unwrap();
+                }
+            }
+            code.push_str("];\n");
+        }
+        None => {
+            code.push_str("pub const FONT_HEIGHT: usize = 0;\n");
+            code.push_str("pub static GLYPHS: &[Glyph] = &[];\n");
+            code.push_str("pub static BITMAPS: &[u8] = &[];\n");
+        }
+    }
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("bitmap_font.rs");
+    fs::write(out, code).unwrap();
+}
+
+struct Font {
+    height: usize,
+    /// sorted by codepoint, which the firmware relies on to binary search
+    glyphs: BTreeMap<u32, Glyph>,
+}
+
+struct Glyph {
+    /// advance in dots
+    width: usize,
+    /// `height` rows of `width.div_ceil(8)` bytes, msb on the left
+    bitmap: Vec<u8>,
+}
+
+/// Parses `0370-03FF,20AC` style lists of hex codepoints, printable ascii is always included
+fn parse_ranges(ranges: &str) -> Vec<(u32, u32)> {
+    let hex = |s: &str| {
+        let s = s.trim();
+        let s = s.strip_prefix("U+").or(s.strip_prefix("0x")).unwrap_or(s);
+        u32::from_str_radix(s, 16)
+            .unwrap_or_else(|_| panic!("invalid codepoint in PRINTER_FONT_RANGES: {s}"))
+    };
+
+    let mut parsed = vec![(0x20, 0x7E)];
+    for range in ranges.split(',').filter(|range| !range.trim().is_empty()) {
+        parsed.push(match range.split_once('-') {
+            Some((start, end)) => (hex(start), hex(end)),
+            None => (hex(range), hex(range)),
+        });
+    }
+    parsed
+}
+
+/// Reads the glyphs within `ranges`, each positioned in a cell of the font's bounding box height
+fn parse_bdf(bdf: &str, ranges: &[(u32, u32)]) -> Font {
+    let numbers = |line: &str| -> Vec<i32> {
+        line.split_whitespace()
+            .skip(1)
+            .map(|n| n.parse().expect("invalid number in font"))
+            .collect()
+    };
+
+    let mut height = 0;
+    let mut ascent = 0;
+    let mut glyphs = BTreeMap::new();
+
+    let mut lines = bdf.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with("FONTBOUNDINGBOX ") {
+            let bounds = numbers(line);
+            height = bounds[1] as usize;
+            ascent = bounds[1] + bounds[3];
+            continue;
+        }
+        if !line.starts_with("STARTCHAR") {
+            continue;
+        }
+
+        let mut encoding = None;
+        let mut advance = 0;
+        let mut bounds = vec![0; 4];
+        for line in lines.by_ref() {
+            match line.split_whitespace().next() {
+                Some("ENCODING") => encoding = u32::try_from(numbers(line)[0]).ok(),
+                Some("DWIDTH") => advance = numbers(line)[0] as usize,
+                Some("BBX") => bounds = numbers(line),
+                Some("BITMAP") => break,
+                _ => {}
+            }
+        }
+        let rows: Vec<&str> = lines
+            .by_ref()
+            .take_while(|line| !line.starts_with("ENDCHAR"))
+            .collect();
+
+        let Some(encoding) = encoding else { continue };
+        if !ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&encoding))
+        {
+            continue;
+        }
+
+        let [width, rows_high, x_offset, y_offset] = [bounds[0], bounds[1], bounds[2], bounds[3]];
+        let row_bytes = advance.div_ceil(8);
+        let mut bitmap = vec![0u8; row_bytes * height];
+        let top = ascent - (rows_high + y_offset);
+        for (y, row) in rows.iter().enumerate() {
+            let bits = (0..row.len() / 2)
+                .map(|i| u8::from_str_radix(&row[i * 2..i * 2 + 2], 16).expect("invalid bitmap"))
+                .collect::<Vec<_>>();
+            for x in 0..width {
+                if bits.get(x as usize / 8).is_none_or(|byte| byte & (0x80 >> (x % 8)) == 0) {
+                    continue;
+                }
+                let (cell_x, cell_y) = (x + x_offset, top + y as i32);
+                if (0..advance as i32).contains(&cell_x) && (0..height as i32).contains(&cell_y) {
+                    let (cell_x, cell_y) = (cell_x as usize, cell_y as usize);
+                    bitmap[cell_y * row_bytes + cell_x / 8] |= 0x80 >> (cell_x % 8);
+                }
+            }
+        }
+
+        glyphs.insert(
+            encoding,
+            Glyph {
+                width: advance,
+                bitmap,
+            },
+        );
+    }
+
+    Font { height, glyphs }
+}
+
+/// Reads the glyphs within `ranges` from GNU Unifont's `codepoint:bitmap` hex lines, each 16
+/// rows of 8 or 16 dots
+fn parse_hex(hex: &str, ranges: &[(u32, u32)]) -> Font {
+    const HEIGHT: usize = 16;
+
+    let mut glyphs = BTreeMap::new();
+    for line in hex.lines().filter(|line| !line.trim().is_empty()) {
+        let (encoding, bitmap) = line
+            .trim()
+            .split_once(':')
+            .unwrap_or_else(|| panic!("invalid line in font: {line}"));
+        let encoding = u32::from_str_radix(encoding, 16).expect("invalid codepoint in font");
+        if !ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&encoding))
+        {
+            continue;
+        }
+
+        let bitmap = (0..bitmap.len() / 2)
+            .map(|i| u8::from_str_radix(&bitmap[i * 2..i * 2 + 2], 16).expect("invalid bitmap"))
+            .collect::<Vec<_>>();
+        if bitmap.len() != HEIGHT && bitmap.len() != HEIGHT * 2 {
+            panic!("glyph {encoding:x} is neither 8 nor 16 dots wide");
+        }
+        glyphs.insert(
+            encoding,
+            Glyph {
+                width: bitmap.len() / HEIGHT * 8,
+                bitmap,
+            },
+        );
+    }
+
+    Font {
+        height: HEIGHT,
+        glyphs,
+    }
+}
+
+/// Gzips the web page into `OUT_DIR`, so it takes less flash and loads faster over wifi
+fn compress_web_page() {
+    let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/net/index.html");
+    println!("cargo:rerun-if-changed={}", path.display());
+    let page = fs::read(&path).unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()));
+
+    // no file name or time, so the output only depends on the page
+    let mut encoder = GzBuilder::new().write(Vec::new(), Compression::best());
+    encoder.write_all(&page).unwrap();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("index.html.gz");
+    fs::write(out, encoder.finish().unwrap()).unwrap();
+}
@@ -0,0 +1,63 @@
+//! Tests of the firmware's modules that don't touch the hardware, run on the machine building
+//! them with `cargo test` from this directory. The modules are compiled from the firmware's own
+//! sources, so they can only use `core`, `alloc` and the crates listed in `Cargo.toml`, and are
+//! laid out in the same module tree where they refer to each other.
+// only the parts each module's tests use are called
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../src/time/calendar.rs"]
+mod calendar;
+#[path = "../../src/scheduler/cron.rs"]
+mod cron;
+#[path = "../../src/printer/escpos.rs"]
+mod escpos;
+#[path = "../../src/printer/feed.rs"]
+mod feed;
+#[path = "../../src/net/web/multipart.rs"]
+mod multipart;
+#[path = "../../src/net/mdns/name.rs"]
+mod name;
+#[path = "../../src/dedup/recent.rs"]
+mod recent;
+#[path = "../../src/printer/sanitize.rs"]
+mod sanitize;
+#[path = "../../src/sha256.rs"]
+mod sha256;
+#[path = "../../src/limits/usage.rs"]
+mod usage;
+#[path = "../../src/printer/utf8.rs"]
+mod utf8;
+
+/// Stands in for `printer`, which drives the printer. `image` is declared in here rather than
+/// with a `#[path]` of its own so its submodules are found in `src/printer/image/`.
+#[path = "../../src/printer"]
+mod printer {
+    pub use super::feed::DOTS_PER_MM;
+
+    pub mod image;
+
+    /// Stands in for `layout`, which lays out text in the printer's fonts
+    pub mod layout {
+        pub const PAPER_WIDTH: usize = 384;
+    }
+}
+
+/// Stands in for `time`, which keeps the clock
+mod time {
+    pub use super::calendar::DateTime;
+}
+
+/// Log messages are dropped, the firmware sends them over the serial port
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
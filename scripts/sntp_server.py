@@ -0,0 +1,55 @@
+#!/usr/bin/env python3
+"""Minimal SNTP server for testing the device's time sync on a local network.
+
+Answers every request with this machine's clock, shifted by --offset seconds.
+
+    python3 scripts/sntp_server.py --port 1123 --offset 3600
+"""
+
+import argparse
+import socket
+import struct
+import time
+
+NTP_UNIX_OFFSET = 2_208_988_800
+
+
+def ntp_timestamp(unix_time: float) -> bytes:
+    seconds = int(unix_time) + NTP_UNIX_OFFSET
+    fraction = int((unix_time % 1) * 2**32)
+    return struct.pack("!II", seconds & 0xFFFFFFFF, fraction)
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--port", type=int, default=123)
+    parser.add_argument("--offset", type=float, default=0.0, help="seconds added to the time")
+    args = parser.parse_args()
+
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    sock.bind(("0.0.0.0", args.port))
+    print(f"listening on udp port {args.port}")
+
+    while True:
+        request, address = sock.recvfrom(1024)
+        received = time.time() + args.offset
+        if len(request) < 48:
+            continue
+
+        # LI = 0, VN = 4, Mode = 4 (server), stratum 1
+        header = struct.pack("!BBbb", 0b00_100_100, 1, 6, -20)
+        response = (
+            header
+            + bytes(8)  # root delay and dispersion
+            + b"LOCL"  # reference id
+            + ntp_timestamp(received)  # reference
+            + request[40:48]  # originate, the client's transmit timestamp
+            + ntp_timestamp(received)
+            + ntp_timestamp(time.time() + args.offset)
+        )
+        sock.sendto(response, address)
+        print(f"answered {address[0]}:{address[1]}")
+
+
+if __name__ == "__main__":
+    main()
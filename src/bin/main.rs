@@ -13,12 +13,14 @@ use esp_hal::{
     gpio::{Input, InputConfig},
     interrupt::software::SoftwareInterruptControl,
     rng::Rng,
+    rtc_cntl::Rtc,
     system::Stack,
     timer::timg::TimerGroup,
     uart::AtCmdConfig,
 };
 use esp_hal::{clock::CpuClock, uart::Uart};
 use esp_rtos::embassy::Executor;
+use esp_storage::FlashStorage;
 use static_cell::StaticCell;
 use webserver_html::prelude::*;
 
@@ -39,6 +41,15 @@ async fn main(spawner: Spawner) {
 
     info!("Embassy initialized!");
 
+    // the power monitor core is parked while the flash is written
+    let mut flash = FlashStorage::new(peripherals.FLASH).multicore_auto_park();
+    // a new firmware runs on trial, watched from the start so it rolls back if it hangs
+    let mut watchdog = Watchdog::new(Rtc::new(peripherals.LPWR));
+    watch_new_firmware(&mut flash, &mut watchdog);
+    init_storage(flash).await;
+    load_settings().await;
+    start_ota(watchdog, &spawner).await;
+
     // init second core
 
     // init power monitor peripherials
@@ -81,16 +92,19 @@ async fn main(spawner: Spawner) {
 
     let (stack, mac_address) = start_wifi(wifi, &spawner).await;
     info!("MAC Address: {:#x}", mac_address);
+    start_sntp(stack, &spawner);
+    start_mdns(stack, &spawner);
 
     // init printer peripherials
-    let config = esp_hal::uart::Config::default()
-        .with_baudrate(9600)
+    let baud_rate = webserver_html::config::config().printer.baud_rate;
+    let uart_config = esp_hal::uart::Config::default()
+        .with_baudrate(baud_rate)
         .with_parity(esp_hal::uart::Parity::None)
         .with_data_bits(esp_hal::uart::DataBits::_8)
         .with_stop_bits(esp_hal::uart::StopBits::_1);
     // .with_rx(RxConfig::default().with_fifo_full_threshold(1024));
 
-    let mut uart = match Uart::new(peripherals.UART2, config) {
+    let mut uart = match Uart::new(peripherals.UART2, uart_config) {
         Ok(uart) => uart
             .with_rx(peripherals.GPIO17)
             .with_tx(peripherals.GPIO16)
@@ -107,11 +121,16 @@ async fn main(spawner: Spawner) {
         peripherals.GPIO14,
         InputConfig::default().with_pull(esp_hal::gpio::Pull::Down),
     );
-    let printer = ThermalPrinter::new(uart, input);
+    let printer = ThermalPrinter::new(uart, uart_config, input);
 
+    start_events(&spawner);
     start_printer(printer, &spawner).await;
+    start_restarter(&spawner);
+
+    start_scheduler(&spawner).await;
 
-    start_mqtt_client(mac_address, stack, rng.into(), &spawner);
+    start_mqtt_client(mac_address, stack, &spawner);
+    start_raw_server(stack, &spawner);
 
-    start_web_host(stack, &spawner);
+    start_web_host(stack, &spawner).await;
 }
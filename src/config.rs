@@ -0,0 +1,453 @@
+use core::{cell::RefCell, str::FromStr};
+
+use defmt::info;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    limits::LimitConfig,
+    printer::{ControlPolicy, CutMode, Feed},
+    storage::{self, Record},
+    time::DstRule,
+};
+
+/// Filled with the defaults on first use
+static DEVICE_CONFIG: Mutex<CriticalSectionRawMutex, RefCell<Option<DeviceConfig>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Returns a snapshot of the current device configuration
+pub fn config() -> DeviceConfig {
+    DEVICE_CONFIG.lock(|config| {
+        config
+            .borrow_mut()
+            .get_or_insert_with(DeviceConfig::new)
+            .clone()
+    })
+}
+
+pub fn update_config(update: impl FnOnce(&mut DeviceConfig)) {
+    DEVICE_CONFIG.lock(|config| update(config.borrow_mut().get_or_insert_with(DeviceConfig::new)))
+}
+
+#[derive(Clone, Debug, defmt::Format)]
+pub struct DeviceConfig {
+    pub network: NetworkConfig,
+    pub mqtt: MqttConfig,
+    pub printer: PrinterConfig,
+    pub power: PowerConfig,
+    /// printed above every job
+    pub header: BlockConfig,
+    /// printed below every job
+    pub footer: BlockConfig,
+    pub controls: ControlConfig,
+    pub limits: LimitConfig,
+    pub dedup: DedupConfig,
+    pub time: TimeConfig,
+    pub auth: AuthConfig,
+}
+
+impl DeviceConfig {
+    fn new() -> Self {
+        Self {
+            network: NetworkConfig::new(),
+            mqtt: MqttConfig::new(),
+            printer: PrinterConfig::new(),
+            power: PowerConfig::new(),
+            header: BlockConfig {
+                web: true,
+                mqtt: true,
+                schedule: true,
+                ..BlockConfig::new()
+            },
+            footer: BlockConfig::new(),
+            controls: ControlConfig::new(),
+            limits: LimitConfig::new(),
+            dedup: DedupConfig::new(),
+            time: TimeConfig::new(),
+            auth: AuthConfig::new(),
+        }
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The sections edited through `/api/v1/config` and the mqtt admin commands, saved together as
+/// one record
+#[derive(Serialize, Deserialize)]
+struct Settings {
+    network: NetworkConfig,
+    mqtt: MqttConfig,
+    printer: PrinterConfig,
+    power: PowerConfig,
+    time: TimeConfig,
+    header: BlockConfig,
+    footer: BlockConfig,
+    controls: ControlConfig,
+    limits: LimitConfig,
+    dedup: DedupConfig,
+}
+
+/// Restores the settings saved before the last reboot, before anything that uses them starts
+pub async fn load_settings() {
+    if let Some(settings) = storage::load::<Settings>(Record::Config).await {
+        info!("Loaded settings for network {}", settings.network.ssid);
+        update_config(|config| {
+            config.network = settings.network;
+            config.mqtt = settings.mqtt;
+            config.printer = settings.printer;
+            config.power = settings.power;
+            config.time = settings.time;
+            config.header = settings.header;
+            config.footer = settings.footer;
+            config.controls = settings.controls;
+            config.limits = settings.limits;
+            config.dedup = settings.dedup;
+        });
+    }
+}
+
+/// Saves the settings of `config`, which are then applied with [`update_config`] so that a change
+/// is only made once it will survive a restart
+pub async fn save_settings(config: &DeviceConfig) -> Result<(), ()> {
+    let config = config.clone();
+    let settings = Settings {
+        network: config.network,
+        mqtt: config.mqtt,
+        printer: config.printer,
+        power: config.power,
+        time: config.time,
+        header: config.header,
+        footer: config.footer,
+        controls: config.controls,
+        limits: config.limits,
+        dedup: config.dedup,
+    };
+    storage::save(Record::Config, &settings).await
+}
+
+/// Saves the settings with `change` made to them, then makes it to the running configuration.
+/// Nothing changes if they can't be saved.
+pub async fn change_settings(change: impl Fn(&mut DeviceConfig)) -> Result<(), ()> {
+    let mut updated = config();
+    change(&mut updated);
+    save_settings(&updated).await?;
+    update_config(change);
+    Ok(())
+}
+
+pub type Ssid = heapless::String<32>;
+/// a single dns label
+pub type Hostname = heapless::String<32>;
+pub type Credential = heapless::String<64>;
+
+/// A default set from the environment at build time, empty if it doesn't fit
+fn build_env<const N: usize>(value: &str) -> heapless::String<N> {
+    heapless::String::try_from(value).unwrap_or_default()
+}
+
+/// The network set at build time, joined when the saved one can't be
+pub fn build_network() -> NetworkConfig {
+    NetworkConfig::new()
+}
+
+/// The wifi network joined at boot, changes take a restart
+#[derive(Clone, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub ssid: Ssid,
+    /// empty for an open network
+    pub password: Credential,
+    /// answered over mdns as `<hostname>.local`
+    pub hostname: Hostname,
+}
+
+impl NetworkConfig {
+    fn new() -> Self {
+        Self {
+            ssid: build_env(option_env!("WIFI_SSID").unwrap_or_default()),
+            password: build_env(option_env!("WIFI_PASSWORD").unwrap_or_default()),
+            hostname: build_env("scribe"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub struct MqttConfig {
+    /// hostname or ip address, empty turns the client off
+    pub broker: ServerName,
+    pub port: u16,
+    pub username: Credential,
+    pub password: Credential,
+}
+
+impl MqttConfig {
+    fn new() -> Self {
+        Self {
+            broker: build_env(option_env!("MQTT_BROKER").unwrap_or("192.168.1.33")),
+            port: 1883,
+            username: build_env(option_env!("MQTT_USER").unwrap_or_default()),
+            password: build_env(option_env!("MQTT_PASSWORD").unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, defmt::Format, Serialize, Deserialize)]
+pub struct PrinterConfig {
+    pub baud_rate: u32,
+    /// probe the common baud rates at boot if the printer does not answer at `baud_rate`
+    pub auto_detect_baud: bool,
+    /// print rotated 180° with the lines in reverse, so the note reads top to bottom as it
+    /// hangs out of the printer
+    pub upside_down: bool,
+    /// longest job in bytes accepted from any producer
+    pub max_job_length: usize,
+    /// accept jobs on the raw print port, which has no authentication, so it stays open to
+    /// anyone on the network even with a web password
+    pub raw_port: bool,
+    /// paper fed before every job
+    pub leading_feed: Feed,
+    /// paper fed after every job, enough to tear the note off past the print head
+    pub trailing_feed: Feed,
+    /// cut after every job, leave as `None` unless the printer has a cutter
+    pub cut: CutMode,
+}
+
+impl PrinterConfig {
+    const fn new() -> Self {
+        Self {
+            baud_rate: 9600,
+            auto_detect_baud: false,
+            upside_down: true,
+            max_job_length: 16 * 1024,
+            raw_port: true,
+            leading_feed: Feed::NONE,
+            trailing_feed: Feed::Lines(3),
+            cut: CutMode::None,
+        }
+    }
+}
+
+/// Power monitor adc readings the power state changes at
+#[derive(Clone, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub struct PowerConfig {
+    /// power is back once the reading drops to this
+    pub normal_max: u16,
+    /// power is being lost from this reading
+    pub loss_min: u16,
+    /// readings above this are usb power rather than a failing supply
+    pub loss_max: u16,
+}
+
+impl PowerConfig {
+    // based on quick manual calibration of the adc
+    const fn new() -> Self {
+        Self {
+            normal_max: 700,
+            loss_min: 1_000,
+            loss_max: 2_200,
+        }
+    }
+}
+
+/// Which details a header or footer block shows, and for which sources
+#[derive(Clone, Debug, defmt::Format, Serialize, Deserialize)]
+pub struct BlockConfig {
+    /// added to jobs from the web interface
+    pub web: bool,
+    /// added to jobs from the mqtt producer topics
+    pub mqtt: bool,
+    /// added to scheduled jobs
+    pub schedule: bool,
+    /// added to jobs from the raw print port
+    pub socket: bool,
+    pub time: bool,
+    pub source: bool,
+    pub job_id: bool,
+    /// separates the block from the job's text
+    pub divider: Divider,
+}
+
+impl BlockConfig {
+    const fn new() -> Self {
+        Self {
+            web: false,
+            mqtt: false,
+            schedule: false,
+            socket: false,
+            time: true,
+            source: true,
+            job_id: true,
+            divider: Divider::Dashes,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Divider {
+    None,
+    Dashes,
+    Equals,
+    Dots,
+}
+
+impl Divider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Divider::None => "none",
+            Divider::Dashes => "dashes",
+            Divider::Equals => "equals",
+            Divider::Dots => "dots",
+        }
+    }
+}
+
+impl FromStr for Divider {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(Divider::None),
+            "dashes" => Ok(Divider::Dashes),
+            "equals" => Ok(Divider::Equals),
+            "dots" => Ok(Divider::Dots),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How control characters in the text of each source's jobs are handled
+#[derive(Clone, Debug, defmt::Format, Serialize, Deserialize)]
+pub struct ControlConfig {
+    pub web: ControlPolicy,
+    pub mqtt: ControlPolicy,
+    pub schedule: ControlPolicy,
+    /// jobs from the raw print port are text unless set to `Passthrough`
+    pub socket: ControlPolicy,
+}
+
+impl ControlConfig {
+    const fn new() -> Self {
+        Self {
+            web: ControlPolicy::Strip,
+            mqtt: ControlPolicy::Strip,
+            schedule: ControlPolicy::Strip,
+            socket: ControlPolicy::Strip,
+        }
+    }
+}
+
+#[derive(Clone, Debug, defmt::Format, Serialize, Deserialize)]
+pub struct DedupConfig {
+    /// seconds the same content from the same sender is ignored for, 0 disables it
+    pub window_secs: u32,
+    /// print the retained messages the broker replays on every reconnect
+    pub accept_retained: bool,
+}
+
+impl DedupConfig {
+    const fn new() -> Self {
+        Self {
+            window_secs: 5 * 60,
+            accept_retained: false,
+        }
+    }
+}
+
+pub type ServerName = heapless::String<64>;
+
+#[derive(Clone, Debug, defmt::Format, Serialize, Deserialize)]
+pub struct TimeConfig {
+    /// hostname or ip address of the ntp server
+    pub server: ServerName,
+    pub port: u16,
+    /// local standard time offset from UTC
+    pub utc_offset_minutes: i16,
+    pub dst: DstRule,
+    /// seconds between syncs once the clock is set
+    pub sync_interval: u32,
+}
+
+impl TimeConfig {
+    fn new() -> Self {
+        Self {
+            server: ServerName::try_from("pool.ntp.org").unwrap_or_default(),
+            port: 123,
+            utc_offset_minutes: 0,
+            dst: DstRule::None,
+            sync_interval: 60 * 60,
+        }
+    }
+}
+
+pub const MAX_TOKENS: usize = 8;
+pub const SALT_SIZE: usize = 16;
+pub const HASH_SIZE: usize = 32;
+
+pub type Username = heapless::String<32>;
+pub type TokenName = heapless::String<16>;
+
+/// Credentials for the web interface and api, only their hashes are kept
+#[derive(Clone, Debug, Default, defmt::Format, serde::Serialize, serde::Deserialize)]
+pub struct AuthConfig {
+    /// anyone on the network is an admin until a password is set
+    pub password: Option<PasswordHash>,
+    pub tokens: heapless::Vec<ApiToken, MAX_TOKENS>,
+}
+
+impl AuthConfig {
+    const fn new() -> Self {
+        Self {
+            password: None,
+            tokens: heapless::Vec::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.password.is_some()
+    }
+}
+
+/// The admin login for HTTP Basic authentication
+#[derive(Clone, Debug, defmt::Format, serde::Serialize, serde::Deserialize)]
+pub struct PasswordHash {
+    pub username: Username,
+    pub salt: [u8; SALT_SIZE],
+    /// PBKDF2 rounds the hash was made with
+    pub iterations: u32,
+    /// PBKDF2-HMAC-SHA256 of the password
+    pub hash: [u8; HASH_SIZE],
+}
+
+/// A bearer token for the api
+#[derive(Clone, Debug, defmt::Format, serde::Serialize, serde::Deserialize)]
+pub struct ApiToken {
+    pub name: TokenName,
+    pub scope: Scope,
+    /// SHA-256 of the token, which is random so needs no salt
+    pub hash: [u8; HASH_SIZE],
+}
+
+/// What a set of credentials is allowed to do
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    defmt::Format,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// print and read the device's state
+    Print,
+    /// anything, including changing settings and credentials
+    Admin,
+}
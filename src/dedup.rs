@@ -0,0 +1,41 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+
+use crate::config::config;
+
+mod recent;
+
+use recent::RecentJobs;
+pub use recent::{Duplicate, Submission};
+
+pub const MAX_KEY_LENGTH: usize = 64;
+
+static RECENT: Mutex<CriticalSectionRawMutex, RefCell<RecentJobs>> =
+    Mutex::new(RefCell::new(RecentJobs::new()));
+
+/// Whether the submission repeats a recent job, without remembering it
+pub fn check(submission: &Submission) -> Result<(), Duplicate> {
+    let window = Duration::from_secs(config().dedup.window_secs as u64);
+    let now = Instant::now();
+    RECENT.lock(|recent| recent.borrow().check(submission, window, now))
+}
+
+/// Remembers an accepted job, call once it has passed [`check`] and any other admission. A job
+/// is remembered while it prints so a retry can't print it twice, so one that fails must be
+/// forgotten with [`forget`] or [`forget_job`].
+pub fn remember(submission: &Submission, job: Option<u32>) {
+    let now = Instant::now();
+    RECENT.lock(|recent| recent.borrow_mut().remember(submission, job, now));
+}
+
+/// Forgets a job that failed to print, so the sender can retry it with the same key or content
+pub fn forget(submission: &Submission) {
+    RECENT.lock(|recent| recent.borrow_mut().forget(submission));
+}
+
+/// Forgets the job remembered under `id`, once it failed to print or was cancelled
+pub fn forget_job(id: u32) {
+    RECENT.lock(|recent| recent.borrow_mut().forget_job(id));
+}
@@ -0,0 +1,233 @@
+use alloc::{string::String, vec::Vec};
+use defmt::info;
+use embassy_time::{Duration, Instant};
+
+/// jobs remembered at once, the oldest is forgotten to make room
+const MAX_RECENT: usize = 32;
+/// how long an idempotency key is remembered, independent of the content window
+const KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Identifies a job to recognise it if it is submitted again
+pub struct Submission<'a> {
+    /// idempotency key chosen by the sender
+    pub key: Option<&'a str>,
+    /// who sent it, the same content from different senders is not a duplicate
+    pub sender: &'a str,
+    /// `None` for streamed jobs, which can only be recognised by their key
+    pub content: Option<&'a [u8]>,
+}
+
+impl Submission<'_> {
+    fn hash(&self) -> Option<u32> {
+        let content = self.content?;
+        Some(crc32(crc32(0, self.sender.as_bytes()), content))
+    }
+}
+
+/// Which recent job a submission repeats, and its id when it was given one up front
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Duplicate {
+    /// a job with the same idempotency key was already printed
+    Key(Option<u32>),
+    /// the same content from the same sender was printed within the window
+    Content(Option<u32>),
+}
+
+impl Duplicate {
+    pub fn job(&self) -> Option<u32> {
+        match self {
+            Duplicate::Key(job) | Duplicate::Content(job) => *job,
+        }
+    }
+}
+
+struct Recent {
+    key: Option<String>,
+    hash: Option<u32>,
+    job: Option<u32>,
+    at: Instant,
+}
+
+/// The jobs accepted most recently, oldest first
+pub struct RecentJobs(Vec<Recent>);
+
+impl RecentJobs {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Whether the submission repeats a job remembered less than `window` before `now`, or one
+    /// with the same idempotency key
+    pub fn check(
+        &self,
+        submission: &Submission,
+        window: Duration,
+        now: Instant,
+    ) -> Result<(), Duplicate> {
+        let hash = submission.hash();
+        for job in self.0.iter() {
+            let age = now.saturating_duration_since(job.at);
+            if submission.key.is_some()
+                && job.key.as_deref() == submission.key
+                && age < KEY_LIFETIME
+            {
+                info!("Ignoring job with a repeated idempotency key");
+                return Err(Duplicate::Key(job.job));
+            }
+            if hash.is_some() && job.hash == hash && age < window {
+                info!("Ignoring job repeating one from {} s ago", age.as_secs());
+                return Err(Duplicate::Content(job.job));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remember(&mut self, submission: &Submission, job: Option<u32>, now: Instant) {
+        if self.0.len() >= MAX_RECENT {
+            self.0.remove(0);
+        }
+        self.0.push(Recent {
+            key: submission.key.map(String::from),
+            hash: submission.hash(),
+            job,
+            at: now,
+        });
+    }
+
+    /// Forgets the latest job remembered for the submission
+    pub fn forget(&mut self, submission: &Submission) {
+        let hash = submission.hash();
+        let position = self
+            .0
+            .iter()
+            .rposition(|job| job.key.as_deref() == submission.key && job.hash == hash);
+        if let Some(position) = position {
+            self.0.remove(position);
+        }
+    }
+
+    pub fn forget_job(&mut self, id: u32) {
+        self.0.retain(|job| job.job != Some(id));
+    }
+}
+
+/// The CRC-32 of zip and ethernet, as the rom's `crc32_le` works it out, continuing from the `crc`
+/// of the data before
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(300);
+
+    fn text(sender: &'static str, content: &'static str) -> Submission<'static> {
+        Submission {
+            key: None,
+            sender,
+            content: Some(content.as_bytes()),
+        }
+    }
+
+    fn keyed<'a>(key: &'a str, content: Option<&'a [u8]>) -> Submission<'a> {
+        Submission {
+            key: Some(key),
+            sender: "web",
+            content,
+        }
+    }
+
+    fn at(seconds: u64) -> Instant {
+        Instant::from_secs(seconds)
+    }
+
+    #[test]
+    fn crc_matches_the_standard() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"12345"), b"6789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn hashes_the_sender_with_the_content() {
+        assert_eq!(text("a", "note").hash(), text("a", "note").hash());
+        assert_ne!(text("a", "note").hash(), text("b", "note").hash());
+        assert_ne!(text("a", "note").hash(), text("a", "notes").hash());
+        assert_eq!(keyed("k", None).hash(), None);
+    }
+
+    #[test]
+    fn repeated_content_within_the_window() {
+        let mut recent = RecentJobs::new();
+        recent.remember(&text("kitchen", "milk"), Some(7), at(100));
+
+        let repeat = recent.check(&text("kitchen", "milk"), WINDOW, at(399));
+        assert_eq!(repeat, Err(Duplicate::Content(Some(7))));
+        assert_eq!(
+            recent.check(&text("kitchen", "milk"), WINDOW, at(400)),
+            Ok(())
+        );
+        assert_eq!(
+            recent.check(&text("garage", "milk"), WINDOW, at(101)),
+            Ok(())
+        );
+        assert_eq!(
+            recent.check(&text("kitchen", "eggs"), WINDOW, at(101)),
+            Ok(())
+        );
+        // a window of 0 turns content matching off
+        let off = recent.check(&text("kitchen", "milk"), Duration::from_secs(0), at(100));
+        assert_eq!(off, Ok(()));
+    }
+
+    #[test]
+    fn repeated_keys_for_a_day() {
+        let mut recent = RecentJobs::new();
+        recent.remember(&keyed("order-1", None), Some(3), at(0));
+
+        // a streamed job is only known by its key, whatever it sends
+        let repeat = recent.check(&keyed("order-1", Some(b"other")), WINDOW, at(3_600));
+        assert_eq!(repeat, Err(Duplicate::Key(Some(3))));
+        let expired = recent.check(&keyed("order-1", None), WINDOW, at(24 * 3_600));
+        assert_eq!(expired, Ok(()));
+        assert_eq!(recent.check(&keyed("order-2", None), WINDOW, at(1)), Ok(()));
+    }
+
+    #[test]
+    fn forgets_failed_jobs() {
+        let mut recent = RecentJobs::new();
+        recent.remember(&text("web", "one"), Some(1), at(0));
+        recent.remember(&keyed("two", Some(b"two")), Some(2), at(0));
+        recent.remember(&text("web", "three"), None, at(0));
+
+        recent.forget_job(1);
+        assert_eq!(recent.check(&text("web", "one"), WINDOW, at(1)), Ok(()));
+        recent.forget(&keyed("two", Some(b"two")));
+        assert_eq!(recent.check(&keyed("two", None), WINDOW, at(1)), Ok(()));
+        recent.forget(&text("web", "three"));
+        assert_eq!(recent.check(&text("web", "three"), WINDOW, at(1)), Ok(()));
+    }
+
+    #[test]
+    fn forgets_the_oldest_when_full() {
+        let mut recent = RecentJobs::new();
+        let keys: Vec<String> = (0..=MAX_RECENT).map(|id| format!("key-{id}")).collect();
+        for (id, key) in keys.iter().enumerate() {
+            recent.remember(&keyed(key, None), Some(id as u32), at(0));
+        }
+        assert_eq!(recent.check(&keyed("key-0", None), WINDOW, at(1)), Ok(()));
+        let kept = recent.check(&keyed("key-1", None), WINDOW, at(1));
+        assert_eq!(kept, Err(Duplicate::Key(Some(1))));
+    }
+}
@@ -0,0 +1,87 @@
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    pubsub::{PubSubChannel, Subscriber},
+};
+use serde::Serialize;
+
+use crate::{
+    power::{self, SHUTDOWN_WATCHER},
+    printer::{JobState, PaperStatus},
+};
+
+/// events kept for the slowest listener, older ones are dropped and it is told how many it
+/// missed
+const EVENT_CAPACITY: usize = 8;
+/// listeners at once, each web event stream is one
+pub const MAX_LISTENERS: usize = 2;
+
+type EventChannel = PubSubChannel<CriticalSectionRawMutex, Event, EVENT_CAPACITY, MAX_LISTENERS, 0>;
+pub type EventListener =
+    Subscriber<'static, CriticalSectionRawMutex, Event, EVENT_CAPACITY, MAX_LISTENERS, 0>;
+
+static EVENTS: EventChannel = PubSubChannel::new();
+
+/// Something that changed on the device, serialized as just its fields
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    Job {
+        id: u32,
+        state: JobState,
+        error: Option<&'static str>,
+    },
+    Printer {
+        online: bool,
+    },
+    /// the paper ran low, or the roll was changed or reset
+    Paper(PaperStatus),
+    Power {
+        state: &'static str,
+        adc: Option<u16>,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Job { .. } => "job",
+            Event::Printer { .. } => "printer",
+            Event::Paper(_) => "paper",
+            Event::Power { .. } => "power",
+        }
+    }
+}
+
+/// Sends the event to every listener, without waiting for slow ones
+pub fn publish(event: Event) {
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// `None` while there are already [`MAX_LISTENERS`]
+pub fn listen() -> Option<EventListener> {
+    EVENTS.subscriber().ok()
+}
+
+/// Republishes power transitions from the power monitor
+pub fn start_events(spawner: &Spawner) {
+    spawner.must_spawn(power_events_task());
+    info!("Events initialized...");
+}
+
+#[embassy_executor::task]
+async fn power_events_task() {
+    let Some(mut shutdown_recv) = SHUTDOWN_WATCHER.receiver() else {
+        warn!("No shutdown receiver left, power events are disabled");
+        return;
+    };
+
+    loop {
+        let status = shutdown_recv.changed().await;
+        publish(Event::Power {
+            state: status.as_str(),
+            adc: power::power_level(),
+        });
+    }
+}
@@ -1,9 +1,14 @@
 mod power;
 mod printer;
 mod rng;
+mod watchdog;
 mod wifi;
 
 pub use power::*;
 pub use printer::*;
 pub use rng::*;
+pub use watchdog::*;
 pub use wifi::*;
+
+/// Chip id in the header of app images built for this chip
+pub const IMAGE_CHIP_ID: u16 = 0;
@@ -1,14 +1,26 @@
 use defmt::{debug, warn};
-use esp_hal::{Async, gpio::Input, uart::Uart};
+use embassy_time::{Duration, with_timeout};
+use esp_hal::{
+    Async,
+    gpio::Input,
+    uart::{Config, Uart},
+};
+
+const STATUS_TIMEOUT: Duration = Duration::from_millis(250);
 
 pub struct ThermalPrinter {
     uart: Uart<'static, Async>,
+    uart_config: Config,
     dtr_pin: Input<'static>,
 }
 
 impl ThermalPrinter {
-    pub fn new(uart: Uart<'static, Async>, dtr_pin: Input<'static>) -> Self {
-        Self { uart, dtr_pin }
+    pub fn new(uart: Uart<'static, Async>, uart_config: Config, dtr_pin: Input<'static>) -> Self {
+        Self {
+            uart,
+            uart_config,
+            dtr_pin,
+        }
     }
 
     pub async fn send_data(&mut self, data: &[u8]) {
@@ -21,4 +33,69 @@ impl ThermalPrinter {
             Err(e) => warn!("Thermal printer write failed with: {:?}", e),
         }
     }
+
+    /// Waits for everything sent to have left the uart
+    pub async fn flush(&mut self) {
+        if let Err(e) = self.uart.flush_async().await {
+            warn!("Thermal printer flush failed with: {:?}", e);
+        }
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        self.uart_config.baudrate()
+    }
+
+    pub async fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), ()> {
+        // anything still in the tx fifo would be garbled by the switch
+        if let Err(e) = self.uart.flush_async().await {
+            warn!("Thermal printer flush failed with: {:?}", e);
+        }
+
+        let config = self.uart_config.with_baudrate(baud_rate);
+        match self.uart.apply_config(&config) {
+            Ok(()) => {
+                self.uart_config = config;
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Failed to set printer baud rate to {}: {:?}", baud_rate, e);
+                Err(())
+            }
+        }
+    }
+
+    /// Sends a `DLE EOT 1` real-time status request, returning the status byte if the printer
+    /// answered with something that looks like a valid response.
+    pub async fn query_status(&mut self) -> Option<u8> {
+        self.transmit_status(1).await
+    }
+
+    /// Sends a `DLE EOT 4` request for the paper roll sensor status, bits 2 and 3 are set when
+    /// the roll is near its end and bits 5 and 6 when it has run out.
+    pub async fn query_paper_sensor(&mut self) -> Option<u8> {
+        self.transmit_status(4).await
+    }
+
+    async fn transmit_status(&mut self, n: u8) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        while self.uart.read_ready() {
+            let _ = self.uart.read_buffered(&mut buf);
+        }
+
+        self.send_data(&[0x10, 0x04, n]).await; // DLE EOT n
+
+        match with_timeout(STATUS_TIMEOUT, self.uart.read_async(&mut buf)).await {
+            // bits 1 and 4 are always set, bits 0 and 7 are always cleared
+            Ok(Ok(1)) if buf[0] & 0b1001_0011 == 0b0001_0010 => Some(buf[0]),
+            Ok(Ok(_)) => {
+                debug!("Unexpected printer status response: {:#x}", buf[0]);
+                None
+            }
+            Ok(Err(e)) => {
+                debug!("Printer status read failed with: {:?}", e);
+                None
+            }
+            Err(_) => None,
+        }
+    }
 }
@@ -0,0 +1,29 @@
+use esp_hal::rtc_cntl::{Rtc, RwdtStage};
+
+/// The rtc watchdog, which resets the whole chip when it isn't fed in time. Unlike the tasks
+/// feeding it, it keeps running through a panic.
+pub struct Watchdog {
+    rtc: Rtc<'static>,
+}
+
+impl Watchdog {
+    pub fn new(rtc: Rtc<'static>) -> Self {
+        Self { rtc }
+    }
+
+    pub fn start(&mut self, timeout: embassy_time::Duration) {
+        self.rtc.rwdt.set_timeout(
+            RwdtStage::Stage0,
+            esp_hal::time::Duration::from_millis(timeout.as_millis()),
+        );
+        self.rtc.rwdt.enable();
+    }
+
+    pub fn feed(&mut self) {
+        self.rtc.rwdt.feed();
+    }
+
+    pub fn stop(&mut self) {
+        self.rtc.rwdt.disable();
+    }
+}
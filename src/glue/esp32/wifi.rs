@@ -1,7 +1,9 @@
+use core::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
 use alloc::vec::Vec;
-use defmt::{error, info};
+use defmt::{error, info, warn};
 use embassy_net::driver::Driver;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Timer, with_timeout};
 use esp_hal::rng::Rng;
 use esp_radio::wifi::{
     ClientConfig, ModeConfig, ScanConfig, WifiController as EspWifiController, WifiDevice,
@@ -10,6 +12,29 @@ use esp_radio::wifi::{
 
 use crate::glue::shared::Capabilities;
 
+const RSSI_INTERVAL: Duration = Duration::from_secs(10);
+/// failed attempts in a row before moving on to the next network
+const MAX_FAILED_CONNECTS: u32 = 5;
+
+/// signal strength of the access point in dBm, 0 while not connected
+static RSSI: AtomicI32 = AtomicI32::new(0);
+
+/// index of the network being joined
+static NETWORK: AtomicUsize = AtomicUsize::new(0);
+
+/// Which of the networks given to [`WifiController::connection_loop`] is being joined
+pub fn active_network() -> usize {
+    NETWORK.load(Ordering::Relaxed)
+}
+
+/// Signal strength of the access point in dBm, as of the last beacon sampled
+pub fn wifi_rssi() -> Option<i32> {
+    match RSSI.load(Ordering::Relaxed) {
+        0 => None,
+        rssi => Some(rssi),
+    }
+}
+
 pub struct Wifi {
     wifi_controller: EspWifiController<'static>,
     wifi_device: WifiDevice<'static>,
@@ -108,15 +133,21 @@ impl WifiController {
             .build()
     }
 
-    pub async fn connection_loop(&mut self, ssid: &str, password: &str) {
+    /// Keeps the device connected to the first of `networks`, each an ssid and password. After
+    /// a few failed attempts in a row the next one is tried, so a mistyped network can't leave
+    /// the device unreachable.
+    pub async fn connection_loop(&mut self, networks: &[(&str, &str)]) {
+        let mut network = 0;
+        let mut failures = 0;
         loop {
             if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-                // wait until we're no longer connected
-                self.0.wait_for_event(WifiEvent::StaDisconnected).await;
+                self.wait_for_disconnect().await;
                 Timer::after(Duration::from_millis(5000)).await
             }
 
             if !matches!(self.0.is_started(), Ok(true)) {
+                let (ssid, password) = networks[network];
+                NETWORK.store(network, Ordering::Relaxed);
                 let client_config = ModeConfig::Client(
                     ClientConfig::default()
                         .with_ssid(ssid.into())
@@ -149,12 +180,37 @@ impl WifiController {
             info!("About to connect...");
 
             match self.0.connect_async().await {
-                Ok(_) => info!("Wifi connected!"),
+                Ok(_) => {
+                    info!("Wifi connected!");
+                    failures = 0;
+                }
                 Err(e) => {
                     info!("Failed to connect to wifi: {:?}", e);
+                    failures += 1;
+                    if failures >= MAX_FAILED_CONNECTS && networks.len() > 1 {
+                        failures = 0;
+                        network = (network + 1) % networks.len();
+                        warn!("Giving up on the network, trying {}", networks[network].0);
+                        // stopped, the new network is set when it starts again
+                        if let Err(e) = self.0.stop_async().await {
+                            error!("wifi failed to stop: {:?}", e);
+                        }
+                    }
                     Timer::after(Duration::from_millis(5000)).await
                 }
             }
         }
     }
+
+    /// Waits until we're no longer connected, sampling the signal strength meanwhile
+    async fn wait_for_disconnect(&mut self) {
+        while esp_radio::wifi::sta_state() == WifiStaState::Connected {
+            if let Ok(rssi) = self.0.rssi() {
+                RSSI.store(rssi, Ordering::Relaxed);
+            }
+            let disconnected = self.0.wait_for_event(WifiEvent::StaDisconnected);
+            let _ = with_timeout(RSSI_INTERVAL, disconnected).await;
+        }
+        RSSI.store(0, Ordering::Relaxed);
+    }
 }
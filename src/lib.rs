@@ -1,19 +1,40 @@
 #![no_std]
 #![feature(impl_trait_in_assoc_type)]
+// the web router type nests deeper than the default limit
+#![recursion_limit = "256"]
 
 pub extern crate alloc;
 
+pub mod config;
+mod dedup;
+mod events;
 pub mod glue;
+mod limits;
 mod net;
+mod ota;
 mod power;
 mod printer;
+mod restart;
+mod scheduler;
+mod sha256;
+pub mod storage;
+pub mod time;
 
 pub mod prelude;
+pub use crate::config::load_settings;
+pub use crate::events::start_events;
+pub use crate::net::mdns::start_mdns;
 pub use crate::net::mqtt::start_mqtt_client;
+pub use crate::net::raw::start_raw_server;
+pub use crate::net::sntp::start_sntp;
 pub use crate::net::web::start_web_host;
 pub use crate::net::wifi::start_wifi;
+pub use crate::ota::{start_ota, watch_new_firmware};
 pub use crate::power::start_power_monitor;
 pub use crate::printer::start_printer;
+pub use crate::restart::start_restarter;
+pub use crate::scheduler::start_scheduler;
+pub use crate::storage::init_storage;
 
 #[macro_export]
 macro_rules! mk_static {
@@ -0,0 +1,166 @@
+use core::cell::RefCell;
+
+use alloc::vec::Vec;
+use defmt::{debug, info, warn};
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    config::{TokenName, config},
+    time,
+};
+
+mod usage;
+
+use usage::Usage;
+pub use usage::{JobUsage, LimitConfig, LimitError};
+
+/// requesters tracked at once, the one seen least recently is forgotten to make room for a new one
+const MAX_REQUESTERS: usize = 16;
+/// bytes of a producer topic its limits are kept under, longer topics starting the same share them
+const MAX_TOPIC_KEY: usize = 32;
+/// longest a queued api job over the rate limit is held back, rather than refused
+pub const MAX_DEFERRAL: Duration = Duration::from_secs(10 * 60);
+
+static USAGE: Mutex<CriticalSectionRawMutex, RefCell<Vec<Entry>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Who a job is charged to, only identities a sender can't make up
+#[derive(Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Requester {
+    /// web or raw socket client address
+    Client(IpAddress),
+    /// the part of the producer topic after the prefix
+    Mqtt(heapless::String<MAX_TOPIC_KEY>),
+    /// name of the api token the request was authenticated with
+    Token(TokenName),
+}
+
+impl Requester {
+    /// The producer publishing to `topic`, the part of the topic after the producer prefix
+    pub fn mqtt(topic: &str) -> Self {
+        let mut key = heapless::String::new();
+        for c in topic.chars() {
+            if key.push(c).is_err() {
+                break;
+            }
+        }
+        Requester::Mqtt(key)
+    }
+}
+
+/// Everything a job is charged to, a web job with a token counts against both
+pub type Requesters = heapless::Vec<Requester, 2>;
+
+struct Entry {
+    requester: Requester,
+    usage: Usage,
+    /// when a job was last admitted or recorded for the requester
+    seen: Instant,
+}
+
+/// Takes a job from every requester's bucket, failing if any of them is out of jobs or over a
+/// daily quota, in which case nothing is taken
+pub fn admit(requesters: &[Requester]) -> Result<(), LimitError> {
+    schedule(requesters, Duration::MIN).map(|_| ())
+}
+
+/// Like [`admit`], but a job the rate limit holds back for no more than `max_delay` is admitted
+/// to start once the buckets have refilled, returning when that is
+pub fn schedule(requesters: &[Requester], max_delay: Duration) -> Result<Instant, LimitError> {
+    let now = Instant::now();
+    let (limits, day) = (config().limits, today());
+    USAGE.lock(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut at = now;
+        for requester in requesters {
+            let entry = &mut entry(&mut usage, requester, now).usage;
+            entry.refresh(&limits, now, day);
+            if let Err(e) = entry.check(&limits) {
+                warn!("Rejecting job from {}: {}", requester, e);
+                return Err(e);
+            }
+            at = at.max(entry.available_at(&limits, now));
+        }
+        if at > now + max_delay {
+            warn!("Rejecting job, rate limited for {} s", (at - now).as_secs());
+            return Err(LimitError::RateLimited);
+        }
+
+        for requester in requesters {
+            entry(&mut usage, requester, now).usage.take(&limits, now);
+        }
+        Ok(at)
+    })
+}
+
+/// Hands back the job [`schedule`] took from every requester's bucket, for a job that was
+/// cancelled or couldn't be queued before it printed
+pub fn refund(requesters: &[Requester]) {
+    let limits = config().limits;
+    if limits.jobs_per_hour == 0 {
+        return;
+    }
+    USAGE.lock(|usage| {
+        let mut usage = usage.borrow_mut();
+        for entry in usage
+            .iter_mut()
+            .filter(|entry| requesters.contains(&entry.requester))
+        {
+            entry.usage.refund(&limits);
+        }
+    });
+}
+
+/// Adds a finished job to its requesters' daily totals
+pub fn record(requesters: &[Requester], job: JobUsage) {
+    let now = Instant::now();
+    let (limits, day) = (config().limits, today());
+    USAGE.lock(|usage| {
+        let mut usage = usage.borrow_mut();
+        for requester in requesters {
+            let entry = &mut entry(&mut usage, requester, now).usage;
+            entry.refresh(&limits, now, day);
+            entry.add(job);
+        }
+    });
+}
+
+/// The requester's usage, tracking it in place of the requester seen least recently once there
+/// are too many
+fn entry<'a>(usage: &'a mut Vec<Entry>, requester: &Requester, now: Instant) -> &'a mut Entry {
+    let index = match usage.iter().position(|entry| entry.requester == *requester) {
+        Some(index) => index,
+        None => {
+            if usage.len() >= MAX_REQUESTERS
+                && let Some(oldest) = usage
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, entry)| entry.seen)
+                    .map(|(index, _)| index)
+            {
+                debug!("Forgetting the limits of {}", usage[oldest].requester);
+                usage.swap_remove(oldest);
+            }
+            info!("Tracking limits for {}", requester);
+            usage.push(Entry {
+                requester: requester.clone(),
+                usage: Usage::new(&config().limits, now, today()),
+                seen: now,
+            });
+            usage.len() - 1
+        }
+    };
+    let entry = &mut usage[index];
+    entry.seen = now;
+    entry
+}
+
+/// Quotas reset at local midnight, or every 24 hours of uptime until the clock is synchronized
+fn today() -> i64 {
+    match time::now() {
+        Some(now) => now.to_unix().div_euclid(86_400),
+        None => (Instant::now().as_secs() / 86_400) as i64,
+    }
+}
@@ -0,0 +1,334 @@
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::printer::DOTS_PER_MM;
+
+/// token bucket levels are kept in thousandths of a job
+const JOB: u32 = 1_000;
+
+/// Limits applied to each web client, mqtt topic and api token separately, 0 disables a limit
+#[derive(Clone, Debug, defmt::Format, Serialize, Deserialize)]
+pub struct LimitConfig {
+    /// rate the token bucket refills at
+    pub jobs_per_hour: u16,
+    /// jobs that can be sent back to back
+    pub burst: u16,
+    pub daily_bytes: u32,
+    pub daily_lines: u32,
+    pub daily_paper_mm: u32,
+}
+
+impl LimitConfig {
+    pub const fn new() -> Self {
+        Self {
+            jobs_per_hour: 60,
+            burst: 10,
+            daily_bytes: 0,
+            daily_lines: 0,
+            daily_paper_mm: 5_000,
+        }
+    }
+
+    fn bucket_size(&self) -> u32 {
+        self.burst.max(1) as u32 * JOB
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LimitError {
+    /// the requester's token bucket is empty
+    RateLimited,
+    DailyBytes,
+    DailyLines,
+    DailyPaper,
+}
+
+impl LimitError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitError::RateLimited => "too many print jobs, try again later",
+            LimitError::DailyBytes => "daily byte quota reached",
+            LimitError::DailyLines => "daily line quota reached",
+            LimitError::DailyPaper => "daily paper quota reached",
+        }
+    }
+}
+
+/// What a finished job used
+#[derive(Clone, Copy, Debug, Default, defmt::Format)]
+pub struct JobUsage {
+    pub bytes: u32,
+    pub lines: u32,
+    pub paper_dots: u32,
+}
+
+/// A requester's token bucket and what it has used today
+pub struct Usage {
+    /// token bucket level and when it was last topped up
+    tokens: u32,
+    refilled: Instant,
+    /// local day the totals are for
+    day: i64,
+    today: JobUsage,
+}
+
+impl Usage {
+    pub fn new(limits: &LimitConfig, now: Instant, day: i64) -> Self {
+        Self {
+            tokens: limits.bucket_size(),
+            refilled: now,
+            day,
+            today: JobUsage::default(),
+        }
+    }
+
+    /// Tops the bucket up for the time since it last was, and starts the totals over on a new day
+    pub fn refresh(&mut self, limits: &LimitConfig, now: Instant, day: i64) {
+        let rate = limits.jobs_per_hour as u64;
+        let elapsed = now.saturating_duration_since(self.refilled).as_millis();
+        // jobs_per_hour * JOB per 3,600,000 ms
+        let refill = elapsed * rate / 3_600;
+        let size = limits.bucket_size() as u64;
+        if self.tokens as u64 + refill >= size {
+            self.tokens = size as u32;
+            self.refilled = now;
+        } else if refill > 0 {
+            self.tokens += refill as u32;
+            // only the time the whole tokens took is used up, or the fractions lost on every
+            // refresh would slow the rate down for frequent senders
+            self.refilled += Duration::from_millis(refill * 3_600 / rate);
+        }
+
+        if day != self.day {
+            self.day = day;
+            self.today = JobUsage::default();
+        }
+    }
+
+    /// Checks the daily quotas, the rate is checked by when the next job can start
+    pub fn check(&self, limits: &LimitConfig) -> Result<(), LimitError> {
+        let over = |limit: u32, used: u32| limit > 0 && used >= limit;
+
+        if over(limits.daily_bytes, self.today.bytes) {
+            return Err(LimitError::DailyBytes);
+        }
+        if over(limits.daily_lines, self.today.lines) {
+            return Err(LimitError::DailyLines);
+        }
+        if over(limits.daily_paper_mm, self.today.paper_dots / DOTS_PER_MM) {
+            return Err(LimitError::DailyPaper);
+        }
+        Ok(())
+    }
+
+    /// When the bucket will hold a whole job, after `refilled` once a deferred job has taken
+    /// the tokens up to then
+    pub fn available_at(&self, limits: &LimitConfig, now: Instant) -> Instant {
+        let rate = limits.jobs_per_hour as u64;
+        if rate == 0 || self.tokens >= JOB {
+            return now;
+        }
+        let missing = (JOB - self.tokens) as u64;
+        self.refilled + Duration::from_millis(missing * 3_600 / rate)
+    }
+
+    /// Takes a job from the bucket, ahead of time for a deferred job
+    pub fn take(&mut self, limits: &LimitConfig, now: Instant) {
+        if limits.jobs_per_hour == 0 {
+            return;
+        }
+        if self.tokens >= JOB {
+            self.tokens -= JOB;
+        } else {
+            // the bucket is empty once the job starts and fills again from then
+            self.tokens = 0;
+            self.refilled = self.available_at(limits, now);
+        }
+    }
+
+    /// Hands back a job taken from the bucket
+    pub fn refund(&mut self, limits: &LimitConfig) {
+        self.tokens = (self.tokens + JOB).min(limits.bucket_size());
+    }
+
+    /// Adds a finished job to today's totals
+    pub fn add(&mut self, job: JobUsage) {
+        self.today.bytes = self.today.bytes.saturating_add(job.bytes);
+        self.today.lines = self.today.lines.saturating_add(job.lines);
+        self.today.paper_dots = self.today.paper_dots.saturating_add(job.paper_dots);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// one job a minute in bursts of 3
+    const LIMITS: LimitConfig = LimitConfig {
+        jobs_per_hour: 60,
+        burst: 3,
+        daily_bytes: 1_000,
+        daily_lines: 0,
+        daily_paper_mm: 100,
+    };
+    const DAY: i64 = 20_000;
+
+    fn at(seconds: u64) -> Instant {
+        Instant::from_secs(seconds)
+    }
+
+    /// Takes a job as the limits would, if it can start at `now`
+    fn admit(usage: &mut Usage, now: Instant) -> bool {
+        usage.refresh(&LIMITS, now, DAY);
+        if usage.check(&LIMITS).is_err() || usage.available_at(&LIMITS, now) > now {
+            return false;
+        }
+        usage.take(&LIMITS, now);
+        true
+    }
+
+    #[test]
+    fn allows_a_burst_then_the_rate() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        for _ in 0..3 {
+            assert!(admit(&mut usage, at(0)));
+        }
+        assert!(!admit(&mut usage, at(0)));
+        assert_eq!(usage.available_at(&LIMITS, at(0)), at(60));
+        assert!(!admit(&mut usage, at(59)));
+        assert!(admit(&mut usage, at(60)));
+        assert!(!admit(&mut usage, at(61)));
+    }
+
+    #[test]
+    fn keeps_the_fractions_between_refreshes() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        for _ in 0..3 {
+            assert!(admit(&mut usage, at(0)));
+        }
+        let mut millis = 0;
+        while millis < 60_000 {
+            assert!(!admit(&mut usage, Instant::from_millis(millis)));
+            millis += 70;
+        }
+        assert!(admit(&mut usage, at(60)));
+    }
+
+    #[test]
+    fn refills_no_further_than_the_burst() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        assert!(admit(&mut usage, at(0)));
+        let later = at(24 * 3_600);
+        for _ in 0..3 {
+            assert!(admit(&mut usage, later));
+        }
+        assert!(!admit(&mut usage, later));
+    }
+
+    #[test]
+    fn deferred_jobs_queue_behind_each_other() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        for _ in 0..3 {
+            usage.take(&LIMITS, at(0));
+        }
+        // each job taken ahead of time pushes the next one a minute further out
+        for minute in 1..=3 {
+            assert_eq!(usage.available_at(&LIMITS, at(0)), at(minute * 60));
+            usage.take(&LIMITS, at(0));
+        }
+    }
+
+    #[test]
+    fn refunds_a_job() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        for _ in 0..3 {
+            assert!(admit(&mut usage, at(0)));
+        }
+        usage.refund(&LIMITS);
+        assert!(admit(&mut usage, at(0)));
+        // never past a full bucket
+        for _ in 0..10 {
+            usage.refund(&LIMITS);
+        }
+        for _ in 0..3 {
+            assert!(admit(&mut usage, at(0)));
+        }
+        assert!(!admit(&mut usage, at(0)));
+    }
+
+    #[test]
+    fn no_rate_limit_at_0_jobs_per_hour() {
+        let limits = LimitConfig {
+            jobs_per_hour: 0,
+            burst: 1,
+            ..LIMITS
+        };
+        let mut usage = Usage::new(&limits, at(0), DAY);
+        for _ in 0..100 {
+            usage.take(&limits, at(0));
+            assert_eq!(usage.available_at(&limits, at(0)), at(0));
+        }
+    }
+
+    #[test]
+    fn stops_at_the_daily_quotas() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        usage.add(JobUsage {
+            bytes: 999,
+            lines: 5_000,
+            paper_dots: 0,
+        });
+        // lines are unlimited, and the job that goes over is still printed
+        assert_eq!(usage.check(&LIMITS), Ok(()));
+        usage.add(JobUsage {
+            bytes: 1,
+            ..JobUsage::default()
+        });
+        assert_eq!(usage.check(&LIMITS), Err(LimitError::DailyBytes));
+
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        usage.add(JobUsage {
+            paper_dots: 100 * DOTS_PER_MM - 1,
+            ..JobUsage::default()
+        });
+        assert_eq!(usage.check(&LIMITS), Ok(()));
+        usage.add(JobUsage {
+            paper_dots: 1,
+            ..JobUsage::default()
+        });
+        assert_eq!(usage.check(&LIMITS), Err(LimitError::DailyPaper));
+
+        let limits = LimitConfig {
+            daily_lines: 10,
+            ..LIMITS
+        };
+        let mut usage = Usage::new(&limits, at(0), DAY);
+        usage.add(JobUsage {
+            lines: 9,
+            ..JobUsage::default()
+        });
+        assert_eq!(usage.check(&limits), Ok(()));
+        usage.add(JobUsage {
+            lines: 1,
+            ..JobUsage::default()
+        });
+        assert_eq!(usage.check(&limits), Err(LimitError::DailyLines));
+    }
+
+    #[test]
+    fn quotas_start_over_on_a_new_day() {
+        let mut usage = Usage::new(&LIMITS, at(0), DAY);
+        usage.add(JobUsage {
+            bytes: u32::MAX,
+            ..JobUsage::default()
+        });
+        usage.add(JobUsage {
+            bytes: 1,
+            ..JobUsage::default()
+        });
+        usage.refresh(&LIMITS, at(10), DAY);
+        assert_eq!(usage.check(&LIMITS), Err(LimitError::DailyBytes));
+        usage.refresh(&LIMITS, at(20), DAY + 1);
+        assert_eq!(usage.check(&LIMITS), Ok(()));
+    }
+}
@@ -1,4 +1,7 @@
-// pub mod dns;
+pub mod dns;
+pub mod mdns;
 pub mod mqtt;
+pub mod raw;
+pub mod sntp;
 pub mod web;
 pub mod wifi;
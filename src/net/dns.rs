@@ -1,7 +1,7 @@
 use defmt::error;
 use embassy_net::{
-    dns::{self, DnsQueryType},
     IpAddress, Stack,
+    dns::{self, DnsQueryType},
 };
 
 pub async fn lookup(url: &str, stack: Stack<'static>) -> Result<IpAddress, dns::Error> {
@@ -0,0 +1,417 @@
+use alloc::vec::Vec;
+use defmt::{debug, error, info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    IpEndpoint, Ipv4Address, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use crate::config::config;
+
+mod name;
+
+use name::{instance_name, read_name, write_name};
+
+const PORT: u16 = 5353;
+const GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+/// the largest packet sent without fragmenting on ethernet
+const PACKET_SIZE: usize = 1500;
+const HEADER_SIZE: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// set on records only this device answers, so caches replace what they held for the name
+const CACHE_FLUSH: u16 = 0x8000;
+/// ttl of records naming this device, recommended for those holding a hostname
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// announcements are repeated as a packet may be lost
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// Announces the device again, after its hostname changed
+pub static MDNS_ANNOUNCE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// A service advertised over dns-sd, under the device's hostname
+struct Service {
+    kind: &'static str,
+    port: u16,
+    txt: &'static [&'static str],
+    /// printed to through the raw print port, which can be turned off in the settings
+    printing: bool,
+}
+
+impl Service {
+    fn is_offered(&self) -> bool {
+        !self.printing || config().printer.raw_port
+    }
+}
+
+const SERVICES: [Service; 3] = [
+    Service {
+        kind: "_http._tcp.local",
+        port: 80,
+        txt: &["path=/"],
+        printing: false,
+    },
+    // port 0 says there's no lpd, it only names the printer for the other printing services
+    Service {
+        kind: "_printer._tcp.local",
+        port: 0,
+        txt: &["txtvers=1", "qtotal=1", "ty=Scribe thermal printer"],
+        printing: true,
+    },
+    Service {
+        kind: "_pdl-datastream._tcp.local",
+        port: super::raw::PORT,
+        txt: &[
+            "txtvers=1",
+            "qtotal=1",
+            "ty=Scribe thermal printer",
+            "pdl=text/plain",
+        ],
+        printing: true,
+    },
+];
+
+/// The records the device answers for
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Record {
+    /// `<hostname>.local` to the device's address
+    Host,
+    /// each service type, to browsers asking what types there are
+    ServiceType(usize),
+    /// the service type to the device's instance of it
+    Instance(usize),
+    /// the instance's port and host
+    Srv(usize),
+    Txt(usize),
+}
+
+pub fn start_mdns(stack: Stack<'static>, spawner: &Spawner) {
+    spawner.must_spawn(mdns_task(stack));
+    info!("mDNS initialized...");
+}
+
+#[embassy_executor::task]
+async fn mdns_task(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; PACKET_SIZE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        error!("Failed to bind mDNS socket: {:?}", e);
+        panic!("Failed to bind mDNS socket")
+    }
+    if let Err(e) = stack.join_multicast_group(GROUP) {
+        error!("Failed to join the mDNS group: {:?}", e);
+    }
+
+    announce(&socket, stack).await;
+    let mut packet = [0; PACKET_SIZE];
+    loop {
+        let received = match select(socket.recv_from(&mut packet), MDNS_ANNOUNCE.wait()).await {
+            Either::First(received) => received,
+            Either::Second(()) => {
+                announce(&socket, stack).await;
+                continue;
+            }
+        };
+        let (length, meta) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Dropped an mDNS packet: {:?}", e);
+                continue;
+            }
+        };
+        let Some(address) = stack.config_v4().map(|config| config.address.address()) else {
+            continue;
+        };
+
+        // queries from a port other than mdns' are from plain resolvers, which expect a
+        // normal dns answer sent back to them
+        let legacy = meta.endpoint.port != PORT;
+        let hostname = config().network.hostname;
+        let Some(response) = answer(&packet[..length], &hostname, address, legacy) else {
+            continue;
+        };
+        let destination = match legacy {
+            true => meta.endpoint,
+            false => IpEndpoint::new(GROUP.into(), PORT),
+        };
+        if let Err(e) = socket.send_to(&response, destination).await {
+            warn!("Failed to send an mDNS response: {:?}", e);
+        }
+    }
+}
+
+/// Tells the network about the device's name and services, so caches holding an old address
+/// or hostname are brought up to date
+async fn announce(socket: &UdpSocket<'_>, stack: Stack<'static>) {
+    let Some(address) = stack.config_v4().map(|config| config.address.address()) else {
+        return;
+    };
+    let hostname = config().network.hostname;
+    info!("Announcing {}.local over mDNS", hostname.as_str());
+
+    let mut records = heapless::Vec::<Record, 16>::new();
+    let mut withdrawn = heapless::Vec::<Record, 4>::new();
+    let _ = records.push(Record::Host);
+    for (i, service) in SERVICES.iter().enumerate() {
+        match service.is_offered() {
+            true => {
+                let service = [Record::Instance(i), Record::Srv(i), Record::Txt(i)];
+                let _ = records.extend_from_slice(&service);
+            }
+            false => {
+                let _ = withdrawn.push(Record::Instance(i));
+            }
+        }
+    }
+    let mut response = Response::new(0, false);
+    for record in records {
+        response.record(record, &hostname, address);
+    }
+    // browsers drop services that are said goodbye to, rather than waiting for them to expire
+    response.goodbye();
+    for record in withdrawn {
+        response.record(record, &hostname, address);
+    }
+    let response = response.finish(0, &[]);
+
+    for _ in 0..ANNOUNCEMENTS {
+        if let Err(e) = socket
+            .send_to(&response, IpEndpoint::new(GROUP.into(), PORT))
+            .await
+        {
+            warn!("Failed to announce over mDNS: {:?}", e);
+        }
+        Timer::after(ANNOUNCE_INTERVAL).await;
+    }
+}
+
+/// The response to a query, `None` for responses and queries about other names
+fn answer(packet: &[u8], hostname: &str, address: Ipv4Address, legacy: bool) -> Option<Vec<u8>> {
+    let header = packet.get(..HEADER_SIZE)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    // responses, and queries with an opcode other than a standard query
+    if flags & 0xF800 != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+
+    let host = instance_name(hostname, "local");
+    let mut answers = heapless::Vec::<Record, 16>::new();
+    let mut additional = heapless::Vec::<Record, 16>::new();
+    let mut offset = HEADER_SIZE;
+    for _ in 0..questions {
+        let (name, end) = read_name(packet, offset)?;
+        let question = packet.get(end..end + 4)?;
+        let kind = u16::from_be_bytes([question[0], question[1]]);
+        let class = u16::from_be_bytes([question[2], question[3]]) & !CACHE_FLUSH;
+        offset = end + 4;
+        if class != CLASS_IN && class != CLASS_ANY {
+            continue;
+        }
+        let wants = |wanted: u16| kind == wanted || kind == TYPE_ANY;
+
+        if name.eq_ignore_ascii_case(&host) && wants(TYPE_A) {
+            add(&mut answers, Record::Host);
+        }
+        let offered = SERVICES
+            .iter()
+            .enumerate()
+            .filter(|(_, service)| service.is_offered());
+        for (i, service) in offered {
+            if name.eq_ignore_ascii_case(SERVICE_TYPES) && wants(TYPE_PTR) {
+                add(&mut answers, Record::ServiceType(i));
+            }
+            if name.eq_ignore_ascii_case(service.kind) && wants(TYPE_PTR) {
+                add(&mut answers, Record::Instance(i));
+                for record in [Record::Srv(i), Record::Txt(i), Record::Host] {
+                    add(&mut additional, record);
+                }
+            }
+            if name.eq_ignore_ascii_case(&instance_name(hostname, service.kind)) {
+                if wants(TYPE_SRV) {
+                    add(&mut answers, Record::Srv(i));
+                    add(&mut additional, Record::Host);
+                }
+                if wants(TYPE_TXT) {
+                    add(&mut answers, Record::Txt(i));
+                }
+            }
+        }
+    }
+    if answers.is_empty() {
+        return None;
+    }
+
+    // legacy resolvers match the answer to their query by its id and questions
+    let (id, questions, question) = match legacy {
+        true => (id, questions, packet.get(HEADER_SIZE..offset)?),
+        false => (0, 0, &[][..]),
+    };
+    let mut response = Response::new(id, legacy);
+    for record in &answers {
+        response.record(*record, hostname, address);
+    }
+    response.additional();
+    for record in additional.iter().filter(|record| !answers.contains(record)) {
+        response.record(*record, hostname, address);
+    }
+    Some(response.finish(questions, question))
+}
+
+fn add(records: &mut heapless::Vec<Record, 16>, record: Record) {
+    if !records.contains(&record) {
+        let _ = records.push(record);
+    }
+}
+
+/// A response being written, answers first and then the additional records
+struct Response {
+    header: [u8; HEADER_SIZE],
+    records: Vec<u8>,
+    answers: u16,
+    additional: u16,
+    in_additional: bool,
+    /// records are written with a ttl of 0, withdrawing them
+    goodbye: bool,
+    /// legacy answers aren't cached by multicast rules, so they don't flush caches
+    legacy: bool,
+}
+
+impl Response {
+    fn new(id: u16, legacy: bool) -> Self {
+        let mut header = [0; HEADER_SIZE];
+        header[..2].copy_from_slice(&id.to_be_bytes());
+        // a response with authoritative answers
+        header[2] = 0x84;
+        Self {
+            header,
+            records: Vec::new(),
+            answers: 0,
+            additional: 0,
+            in_additional: false,
+            goodbye: false,
+            legacy,
+        }
+    }
+
+    /// Records written after this are additional
+    fn additional(&mut self) {
+        self.in_additional = true;
+    }
+
+    /// Records written after this are withdrawn
+    fn goodbye(&mut self) {
+        self.goodbye = true;
+    }
+
+    fn record(&mut self, record: Record, hostname: &str, address: Ipv4Address) {
+        let host = instance_name(hostname, "local");
+        match record {
+            Record::Host => self.write(&host, TYPE_A, true, HOST_TTL, |data| {
+                data.extend_from_slice(&address.octets())
+            }),
+            Record::ServiceType(i) => {
+                self.write(SERVICE_TYPES, TYPE_PTR, false, SERVICE_TTL, |data| {
+                    write_name(data, SERVICES[i].kind)
+                })
+            }
+            Record::Instance(i) => {
+                let service = &SERVICES[i];
+                self.write(service.kind, TYPE_PTR, false, SERVICE_TTL, |data| {
+                    write_name(data, &instance_name(hostname, service.kind))
+                })
+            }
+            Record::Srv(i) => {
+                let service = &SERVICES[i];
+                let name = instance_name(hostname, service.kind);
+                self.write(&name, TYPE_SRV, true, HOST_TTL, |data| {
+                    // priority and weight
+                    data.extend_from_slice(&[0; 4]);
+                    data.extend_from_slice(&service.port.to_be_bytes());
+                    write_name(data, &host);
+                })
+            }
+            Record::Txt(i) => {
+                let service = &SERVICES[i];
+                let name = instance_name(hostname, service.kind);
+                self.write(&name, TYPE_TXT, true, SERVICE_TTL, |data| {
+                    for entry in service.txt {
+                        data.push(entry.len() as u8);
+                        data.extend_from_slice(entry.as_bytes());
+                    }
+                })
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        name: &str,
+        kind: u16,
+        unique: bool,
+        ttl: u32,
+        rdata: impl FnOnce(&mut Vec<u8>),
+    ) {
+        let class = match unique && !self.legacy {
+            true => CLASS_IN | CACHE_FLUSH,
+            false => CLASS_IN,
+        };
+        // legacy resolvers cache for at most 10 seconds
+        let ttl = match (self.goodbye, self.legacy) {
+            (true, _) => 0,
+            (false, true) => ttl.min(10),
+            (false, false) => ttl,
+        };
+
+        write_name(&mut self.records, name);
+        self.records.extend_from_slice(&kind.to_be_bytes());
+        self.records.extend_from_slice(&class.to_be_bytes());
+        self.records.extend_from_slice(&ttl.to_be_bytes());
+        let length_at = self.records.len();
+        self.records.extend_from_slice(&[0; 2]);
+        rdata(&mut self.records);
+        let length = (self.records.len() - length_at - 2) as u16;
+        self.records[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+
+        match self.in_additional {
+            true => self.additional += 1,
+            false => self.answers += 1,
+        }
+    }
+
+    /// The whole packet, repeating the questions for legacy resolvers
+    fn finish(mut self, questions: u16, question: &[u8]) -> Vec<u8> {
+        self.header[4..6].copy_from_slice(&questions.to_be_bytes());
+        self.header[6..8].copy_from_slice(&self.answers.to_be_bytes());
+        self.header[10..12].copy_from_slice(&self.additional.to_be_bytes());
+
+        let mut packet = Vec::with_capacity(HEADER_SIZE + question.len() + self.records.len());
+        packet.extend_from_slice(&self.header);
+        packet.extend_from_slice(question);
+        packet.extend_from_slice(&self.records);
+        packet
+    }
+}
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+
+/// longest name that is looked at, anything longer can't be one of ours
+const MAX_NAME_LENGTH: usize = 128;
+/// pointers followed in a name before it is taken to be a loop
+const MAX_POINTERS: usize = 8;
+
+pub type Name = heapless::String<MAX_NAME_LENGTH>;
+
+pub fn instance_name(hostname: &str, kind: &str) -> Name {
+    let mut name = Name::new();
+    let _ = name.push_str(hostname);
+    let _ = name.push('.');
+    let _ = name.push_str(kind);
+    name
+}
+
+/// Reads the name at `offset`, following compression pointers, and returns it with the offset
+/// after it
+pub fn read_name(packet: &[u8], mut offset: usize) -> Option<(Name, usize)> {
+    let mut name = Name::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *packet.get(offset)? as usize;
+        match length {
+            0 => break,
+            // a pointer to the rest of the name earlier in the packet
+            0xC0.. => {
+                let target = u16::from_be_bytes([length as u8 & 0x3F, *packet.get(offset + 1)?]);
+                end.get_or_insert(offset + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                offset = target as usize;
+            }
+            1..=63 => {
+                let label = packet.get(offset + 1..offset + 1 + length)?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+                offset += 1 + length;
+            }
+            _ => return None,
+        }
+    }
+    Some((name, end.unwrap_or(offset + 1)))
+}
+
+/// Writes a dotted name as dns labels, without compression
+pub fn write_name(data: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packet with `printer.local` at 12, then at 27 `_http._tcp` and a pointer back to that
+    /// `local`
+    fn packet() -> Vec<u8> {
+        let mut packet = Vec::from([0; 12]);
+        write_name(&mut packet, "printer.local");
+        packet.extend_from_slice(b"\x05_http\x04_tcp\xC0\x14");
+        packet
+    }
+
+    #[test]
+    fn reads_names() {
+        let packet = packet();
+        let (name, end) = read_name(&packet, 12).unwrap();
+        assert_eq!((name.as_str(), end), ("printer.local", 27));
+        // the name continues inside the one before, and ends after its pointer
+        let (name, end) = read_name(&packet, 27).unwrap();
+        assert_eq!((name.as_str(), end), ("_http._tcp.local", packet.len()));
+        // the root name
+        assert_eq!(read_name(&[0], 0).unwrap().0.as_str(), "");
+    }
+
+    #[test]
+    fn follows_chained_pointers() {
+        let mut packet = packet();
+        let start = packet.len();
+        packet.extend_from_slice(b"\x02my\xC0\x1B");
+        let (name, end) = read_name(&packet, start).unwrap();
+        assert_eq!((name.as_str(), end), ("my._http._tcp.local", packet.len()));
+
+        // the root name behind as many pointers as are followed, each to the one before
+        let mut packet = Vec::from([0]);
+        for i in 0..MAX_POINTERS {
+            packet.extend_from_slice(&[0xC0, (i * 2).saturating_sub(1) as u8]);
+        }
+        let last = packet.len() - 2;
+        assert_eq!(read_name(&packet, last).unwrap().0.as_str(), "");
+        packet.extend_from_slice(&[0xC0, last as u8]);
+        assert_eq!(read_name(&packet, last + 2), None);
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        // pointing at itself
+        assert_eq!(read_name(b"\xC0\x00", 0), None);
+        // two pointers at each other, and a label leading back round to its own start
+        assert_eq!(read_name(b"\xC0\x02\xC0\x00", 0), None);
+        assert_eq!(read_name(b"\x01a\xC0\x00", 0), None);
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        // labels and pointers running off the end
+        assert_eq!(read_name(b"\x05abc", 0), None);
+        assert_eq!(read_name(b"\x03abc", 0), None);
+        assert_eq!(read_name(b"\xC0", 0), None);
+        assert_eq!(read_name(b"\xC0\x10", 0), None);
+        assert_eq!(read_name(&[], 0), None);
+        // the reserved label types
+        assert_eq!(read_name(b"\x40abc\x00", 0), None);
+        assert_eq!(read_name(b"\x80abc\x00", 0), None);
+        // not utf-8
+        assert_eq!(read_name(b"\x02\xFF\xFE\x00", 0), None);
+        // longer than any of ours
+        let mut packet = Vec::new();
+        for _ in 0..3 {
+            packet.push(63);
+            packet.extend_from_slice(&[b'a'; 63]);
+        }
+        packet.push(0);
+        assert_eq!(read_name(&packet, 0), None);
+    }
+
+    #[test]
+    fn writes_names_read_back() {
+        let name = instance_name("Kitchen", "_pdl-datastream._tcp.local");
+        let mut packet = Vec::new();
+        write_name(&mut packet, &name);
+        assert_eq!(&packet[..9], b"\x07Kitchen\x0F");
+        let (read, end) = read_name(&packet, 0).unwrap();
+        assert_eq!((read, end), (name, packet.len()));
+    }
+}
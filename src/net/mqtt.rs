@@ -1,28 +1,76 @@
-use alloc::{format, string::String};
+use core::{
+    fmt::Write as _,
+    num::NonZero,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{format, string::String, vec::Vec};
 use defmt::{debug, error, info};
 use embassy_executor::Spawner;
-use embassy_futures::select::{Either, select};
-use embassy_net::{IpAddress, Stack, tcp::TcpSocket};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_net::{IpAddress, Ipv4Address, Stack, tcp::TcpSocket};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, TrySendError},
+    signal::Signal,
+};
 use embassy_time::{Duration, Ticker, Timer};
+use embedded_io_async::ErrorKind;
 use rust_mqtt::{
+    Bytes,
+    buffer::AllocBuffer,
     client::{
-        client,
-        client_config::{ClientConfig, MqttVersion},
+        Client,
+        event::{Event, Publish},
+        options::{
+            ConnectOptions, DisconnectOptions, PublicationOptions, SubscriptionOptions,
+            TopicReference,
+        },
     },
-    packet::v5::publish_packet::QualityOfService,
+    config::KeepAlive,
+    types::{MqttBinary, MqttString, QoS, TopicFilter, TopicName},
 };
 
 use crate::{
-    glue::Rng,
+    config::{BlockConfig, DeviceConfig, MqttConfig, ServerName, change_settings, config},
+    dedup::{self, MAX_KEY_LENGTH, Submission},
+    limits::{self, LimitConfig, Requester, Requesters},
+    net::{dns, sntp::SNTP_RESYNC, web},
     power::{POWER_MONITOR_WATCHER, PowerMonitorData, SHUTDOWN_WATCHER, ShutdownStatus},
-    printer::PrinterWriter,
+    printer::{
+        self, ControlPolicy, Feed, JobOptions, JobSource, LOW_PAPER_SIGNAL, PaperSensor,
+        PaperStatus, PaperWarning, PrinterWriter,
+    },
+    scheduler::{self, Trigger},
+    time::DstRule,
 };
 
-const MQTT_USER: &str = env!("MQTT_USER");
-const MQTT_PASSWORD: &str = env!("MQTT_PASSWORD");
+const BUFFER_SIZE: usize = 1024;
+const PRODUCER_TOPIC_PREFIX: &str = "embedded/scribe/producer/";
+const ADMIN_TOPIC_PREFIX: &str = "embedded/scribe/admin/";
+/// user property a producer can set so a message is printed once however often it is sent
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const KEEP_ALIVE: NonZero<u16> = NonZero::new(10).unwrap();
+/// received messages are held whole while they print, the broker drops larger ones
+const MAX_PACKET_SIZE: NonZero<u32> = NonZero::new(8 * 1024).unwrap();
+/// messages and commands waiting for the printer, further ones are rejected until it catches up
+const MAX_WAITING_JOBS: usize = 4;
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
 
-pub fn start_mqtt_client(mac_address: [u8; 6], stack: Stack<'static>, rng: Rng, spawner: &Spawner) {
+/// Producer messages and printer commands handed from the mqtt loop to [`print_task`], so the loop
+/// keeps answering the broker while they wait for the printer
+static PRINT_JOBS: Channel<CriticalSectionRawMutex, MqttJob, MAX_WAITING_JOBS> = Channel::new();
+
+/// Drops the connection to reconnect with the current settings, e.g. after the broker changed
+pub static MQTT_RECONNECT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the client is connected and subscribed to the broker
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+pub fn start_mqtt_client(mac_address: [u8; 6], stack: Stack<'static>, spawner: &Spawner) {
     let client_id = format!(
         "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
         mac_address[0],
@@ -32,8 +80,9 @@ pub fn start_mqtt_client(mac_address: [u8; 6], stack: Stack<'static>, rng: Rng,
         mac_address[4],
         mac_address[5]
     );
-    let mqtt = MQTTService::new(stack, rng, client_id);
+    let mqtt = MQTTService::new(stack, client_id);
     spawner.must_spawn(mqtt_task(mqtt));
+    spawner.must_spawn(print_task(PrinterWriter::new()));
     spawner.must_spawn(status_task());
     info!("MQTT initialized...");
 }
@@ -47,6 +96,37 @@ async fn status_task() {
     status_runner().await
 }
 
+enum MqttJob {
+    Message {
+        /// reserved with [`printer::reserve_job_id`], the message is remembered under it
+        id: u32,
+        payload: Vec<u8>,
+        options: JobOptions,
+    },
+    Feed(Feed),
+    BaudRate(u32),
+}
+
+#[embassy_executor::task]
+async fn print_task(printer: PrinterWriter) {
+    loop {
+        match PRINT_JOBS.receive().await {
+            MqttJob::Message {
+                id,
+                payload,
+                options,
+            } => {
+                if let Err(e) = printer.print_as(id, &payload, options).await {
+                    error!("Failed to print message: {:?}", e);
+                    dedup::forget_job(id);
+                }
+            }
+            MqttJob::Feed(feed) => printer.feed(feed).await,
+            MqttJob::BaudRate(baud_rate) => printer.set_baud_rate(baud_rate).await,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 enum StatusState {
     Up,
@@ -60,6 +140,7 @@ enum StatusState {
 struct Status {
     state: StatusState,
     power_level: PowerMonitorData,
+    paper: PaperStatus,
 }
 
 static STATUS_SIGNAL: Signal<CriticalSectionRawMutex, Status> = Signal::new();
@@ -85,23 +166,32 @@ async fn status_runner() {
         match select(ticker.next(), shutdown_recv.changed()).await {
             Either::First(_) => {
                 let power_level = power_recv.get().await;
-                STATUS_SIGNAL.signal(Status { state, power_level });
+                let paper = printer::paper_status().await;
+                STATUS_SIGNAL.signal(Status {
+                    state,
+                    power_level,
+                    paper,
+                });
             }
             Either::Second(shutdown_status) => match shutdown_status {
                 ShutdownStatus::LowPower => {
                     state = StatusState::Down;
                     let power_level = power_recv.get().await;
+                    let paper = printer::paper_status().await;
                     STATUS_SIGNAL.signal(Status {
                         state: StatusState::ShuttingDown,
                         power_level,
+                        paper,
                     });
                 }
                 ShutdownStatus::NormalPower => {
                     state = StatusState::Up;
                     let power_level = power_recv.get().await;
+                    let paper = printer::paper_status().await;
                     STATUS_SIGNAL.signal(Status {
                         state: StatusState::RegainedPower,
                         power_level,
+                        paper,
                     });
                 }
             },
@@ -111,54 +201,51 @@ async fn status_runner() {
 
 struct MQTTService {
     stack: Stack<'static>,
-    rng: Rng,
     client_id: String,
-    printer: PrinterWriter,
 }
 
 impl MQTTService {
-    fn new(stack: Stack<'static>, rng: Rng, client_id: String) -> Self {
-        MQTTService {
-            stack,
-            rng,
-            client_id,
-            printer: PrinterWriter::new(),
-        }
+    fn new(stack: Stack<'static>, client_id: String) -> Self {
+        MQTTService { stack, client_id }
     }
 
     async fn run(&self) {
-        mqtt_runner(self.stack, self.rng, &self.client_id, &self.printer).await;
+        mqtt_runner(self.stack, &self.client_id).await;
     }
 }
 
-async fn mqtt_runner(stack: Stack<'static>, rng: Rng, client_id: &str, printer: &PrinterWriter) {
-    let mut rx_buffer = [0; 1024];
-    let mut tx_buffer = [0; 1024];
-    let mut recv_buffer = [0; 1024];
-    let mut write_buffer = [0; 1024];
+async fn mqtt_runner(stack: Stack<'static>, client_id: &str) {
+    let mut buffers = Buffers {
+        rx: [0; BUFFER_SIZE],
+        tx: [0; BUFFER_SIZE],
+        packets: AllocBuffer,
+    };
 
     'outer: loop {
-        let mut client = loop {
-            if let Ok(client) = init_mqtt_client(
-                stack,
-                rng,
-                client_id,
-                &mut rx_buffer,
-                &mut tx_buffer,
-                &mut write_buffer,
-                &mut recv_buffer,
-            )
-            .await
-            {
-                break client;
-            }
+        CONNECTED.store(false, Ordering::Relaxed);
+        let settings = config().mqtt;
+        let Ok(mut client) = init_mqtt_client(stack, client_id, &settings, &mut buffers).await
+        else {
+            continue;
         };
 
         info!("Starting mqtt loop");
+        CONNECTED.store(true, Ordering::Relaxed);
         let client_queue = format!("embedded/scribe/client/{client_id}");
+        let admin_reply_queue = format!("{client_queue}/admin");
+        let paper_queue = format!("{client_queue}/paper");
+        let rejected_queue = format!("{client_queue}/rejected");
         loop {
-            match select(STATUS_SIGNAL.wait(), client.receive_message()).await {
-                embassy_futures::select::Either::First(res) => {
+            // only the fixed header is read while waiting, the rest can't be cancelled halfway
+            let header = match select4(
+                STATUS_SIGNAL.wait(),
+                LOW_PAPER_SIGNAL.wait(),
+                client.poll_header(),
+                MQTT_RECONNECT.wait(),
+            )
+            .await
+            {
+                Either4::First(res) => {
                     if handle_status(&mut client, &client_queue, res)
                         .await
                         .is_err()
@@ -166,157 +253,579 @@ async fn mqtt_runner(stack: Stack<'static>, rng: Rng, client_id: &str, printer:
                         error!("Failed to handle Status");
                         continue 'outer;
                     }
+                    continue;
                 }
-                embassy_futures::select::Either::Second(res) => match res {
-                    Ok(msg) => handle_recieve(printer, msg.0, msg.1).await,
-                    Err(e) => {
-                        error!("MQTT Error in receive: {:?}", e);
+                Either4::Second(warning) => {
+                    let warning = match warning {
+                        PaperWarning::Low(remaining) => {
+                            format!("low paper, about {remaining} mm left")
+                        }
+                        PaperWarning::NearEnd => String::from("paper roll near its end"),
+                        PaperWarning::Out => String::from("out of paper"),
+                    };
+                    if send_message(
+                        &mut client,
+                        &paper_queue,
+                        warning.as_bytes(),
+                        QoS::AtLeastOnce,
+                    )
+                    .await
+                    .is_err()
+                    {
+                        error!("Failed to send the low paper warning");
                         continue 'outer;
                     }
-                },
+                    continue;
+                }
+                Either4::Third(Ok(header)) => header,
+                Either4::Third(Err(e)) => {
+                    error!("MQTT Error in receive: {:?}", e);
+                    continue 'outer;
+                }
+                Either4::Fourth(()) => {
+                    info!("MQTT settings changed, reconnecting");
+                    let _ = client.disconnect(&DisconnectOptions::new()).await;
+                    continue 'outer;
+                }
+            };
+
+            let publish = match client.poll_body(header).await {
+                Ok(Event::Publish(publish)) => publish,
+                Ok(Event::Suback(suback)) if suback.reason_code.is_erroneous() => {
+                    error!("MQTT subscription refused: {:?}", suback.reason_code);
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("MQTT Error in receive: {:?}", e);
+                    continue 'outer;
+                }
+            };
+            let Some(topic) = publish.topic.name() else {
+                continue;
+            };
+            let topic = topic.as_ref().as_str();
+
+            let (queue, reply) = if topic.starts_with(ADMIN_TOPIC_PREFIX) {
+                let reply = handle_admin(topic, &publish.message).await;
+                (&admin_reply_queue, reply)
+            } else {
+                let reason = handle_recieve(topic, &publish);
+                (&rejected_queue, reason)
+            };
+            let Some(reply) = reply else {
+                continue;
+            };
+            if send_message(&mut client, queue, reply.as_bytes(), QoS::AtMostOnce)
+                .await
+                .is_err()
+            {
+                error!("Failed to send a reply");
+                continue 'outer;
             }
         }
     }
 }
 
-async fn handle_status<'a>(
-    client: &mut MqttClient<'a>,
-    topic: &str,
-    status: Status,
-) -> Result<(), ()> {
-    if client.send_ping().await.is_err() {
+async fn handle_status(client: &mut MqttClient<'_>, topic: &str, status: Status) -> Result<(), ()> {
+    if let Err(e) = client.ping().await {
+        error!("MQTT Error in ping: {:?}", e);
         return Err(());
     }
 
-    if send_message(
+    send_message(
         client,
         topic,
         format!("{status:?}").as_bytes(),
-        QualityOfService::QoS0,
-        false,
+        QoS::AtMostOnce,
     )
     .await
-    .is_err()
-    {
-        Err(())
-    } else {
-        Ok(())
-    }
 }
 
-async fn handle_recieve(printer: &PrinterWriter, topic: &str, payload: &[u8]) {
+/// Hands a producer message to [`print_task`], returning the reason if it was rejected. A
+/// producer can name the message with an `idempotency-key` user property, it is then printed once
+/// however many times it is delivered.
+fn handle_recieve(topic: &str, publish: &Publish<'_, 0, MAX_USER_PROPERTIES>) -> Option<String> {
+    let payload = publish.message.as_bytes();
     info!("Received message on: {}", topic);
     debug!("Payload: {}", payload);
-    let payload = match str::from_utf8(payload) {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Utf8Error; vut: {} el: {}", e.valid_up_to(), e.error_len());
-            return;
+
+    let dedup = config().dedup;
+    if publish.retain && !dedup.accept_retained {
+        info!("Ignoring a retained message");
+        return None;
+    }
+
+    let sender = topic.strip_prefix(PRODUCER_TOPIC_PREFIX).unwrap_or(topic);
+    let key = publish
+        .user_properties
+        .iter()
+        .find(|property| property.name.as_str() == IDEMPOTENCY_KEY)
+        .map(|property| property.value.as_str());
+    if key.is_some_and(|key| key.len() > MAX_KEY_LENGTH) {
+        return Some(format!("{sender}: idempotency key is too long"));
+    }
+    let submission = Submission {
+        key,
+        sender: topic,
+        content: Some(payload),
+    };
+    if dedup::check(&submission).is_err() {
+        return None;
+    }
+
+    let mut requesters = Requesters::new();
+    let _ = requesters.push(Requester::mqtt(sender));
+    if let Err(e) = limits::admit(&requesters) {
+        return Some(format!("{sender}: {}", e.as_str()));
+    }
+    let id = printer::reserve_job_id();
+    dedup::remember(&submission, Some(id));
+
+    let job = MqttJob::Message {
+        id,
+        payload: Vec::from(payload),
+        options: JobOptions {
+            requesters: requesters.clone(),
+            ..JobOptions::new(JobSource::Mqtt(String::from(sender)))
+        },
+    };
+    if let Err(TrySendError::Full(_)) = PRINT_JOBS.try_send(job) {
+        info!("Too many messages waiting to print, rejecting job {}", id);
+        dedup::forget_job(id);
+        limits::refund(&requesters);
+        return Some(format!("{sender}: too many messages waiting to print"));
+    }
+    None
+}
+
+/// Queues a printer command behind the messages waiting to print, replying if there's no room
+fn send_to_printer(job: MqttJob) -> Option<String> {
+    match PRINT_JOBS.try_send(job) {
+        Ok(()) => None,
+        Err(TrySendError::Full(_)) => {
+            info!("Too many messages waiting to print, rejecting a printer command");
+            Some(String::from("error: too many messages waiting to print"))
         }
+    }
+}
+
+/// Runs the admin command named by the last topic segment, returning a reply for commands that
+/// have one. Commands for the printer are handed to [`print_task`], as they wait for the job
+/// printing to finish.
+async fn handle_admin(topic: &str, payload: &[u8]) -> Option<String> {
+    info!("Received admin command on: {}", topic);
+    let command = topic.rsplit('/').next()?;
+    let Ok(payload) = str::from_utf8(payload) else {
+        error!("Admin payload is not valid utf8");
+        return None;
     };
 
-    printer.chunk_print(payload).await;
+    match command {
+        "baud" => match payload.trim().parse::<u32>() {
+            Ok(baud_rate) => return send_to_printer(MqttJob::BaudRate(baud_rate)),
+            Err(_) => error!("Invalid baud rate: {}", payload),
+        },
+        "feed" => match payload.parse::<Feed>() {
+            Ok(feed) => return send_to_printer(MqttJob::Feed(feed)),
+            Err(()) => error!("Invalid feed amount: {}", payload),
+        },
+        "ntp_server" => match parse_server(payload) {
+            Some((server, port)) => {
+                let saved = save_setting(command, |config| {
+                    config.time.server = server.clone();
+                    config.time.port = port;
+                })
+                .await;
+                if saved {
+                    SNTP_RESYNC.signal(());
+                }
+            }
+            None => error!("Invalid ntp server: {}", payload),
+        },
+        "utc_offset" => match payload.trim().parse::<i16>() {
+            Ok(minutes) if minutes.abs() <= 14 * 60 => {
+                save_setting(command, |config| config.time.utc_offset_minutes = minutes).await;
+            }
+            _ => error!("Invalid utc offset in minutes: {}", payload),
+        },
+        "dst" => match payload.parse::<DstRule>() {
+            Ok(rule) => {
+                save_setting(command, |config| config.time.dst = rule).await;
+            }
+            Err(()) => error!("Invalid dst rule: {}", payload),
+        },
+        "schedule_add" => {
+            // the first line is the trigger, the rest is printed
+            let (trigger, message) = payload.split_once('\n').unwrap_or((payload, ""));
+            let result = match trigger.parse::<Trigger>() {
+                Ok(trigger) => scheduler::add_schedule(trigger, String::from(message)).await,
+                Err(e) => Err(e),
+            };
+            return Some(match result {
+                Ok(id) => format!("added schedule {id}"),
+                Err(e) => format!("error: {}", e.as_str()),
+            });
+        }
+        "schedule_delete" => {
+            let result = match payload.trim().parse::<u32>() {
+                Ok(id) => scheduler::remove_schedule(id).await,
+                Err(_) => Err(scheduler::ScheduleError::NotFound),
+            };
+            return Some(match result {
+                Ok(()) => String::from("deleted schedule"),
+                Err(e) => format!("error: {}", e.as_str()),
+            });
+        }
+        "schedule_list" => {
+            let mut reply = String::new();
+            for schedule in scheduler::schedules().await {
+                let first_line = schedule.message.lines().next().unwrap_or_default();
+                let _ = writeln!(
+                    reply,
+                    "{} {}: {}",
+                    schedule.id, schedule.trigger, first_line
+                );
+            }
+            if reply.is_empty() {
+                reply.push_str("no schedules");
+            }
+            return Some(reply);
+        }
+        "controls" => match parse_controls(payload) {
+            Some((source, policy)) => {
+                save_setting(command, |config| match source {
+                    "web" => config.controls.web = policy,
+                    "mqtt" => config.controls.mqtt = policy,
+                    "socket" => config.controls.socket = policy,
+                    _ => config.controls.schedule = policy,
+                })
+                .await;
+            }
+            None => error!("Invalid control policy: {}", payload),
+        },
+        "limits" => {
+            let mut limits = config().limits;
+            for setting in payload.split_whitespace() {
+                if parse_limit(&mut limits, setting).is_none() {
+                    return Some(format!("error: invalid limit {setting}"));
+                }
+            }
+            if !save_setting(command, |config| config.limits = limits.clone()).await {
+                return Some(String::from("error: failed to save the limits"));
+            }
+            return Some(format!(
+                "{} jobs per hour, bursts of {}, daily {} bytes, {} lines, {} mm (0 is unlimited)",
+                limits.jobs_per_hour,
+                limits.burst,
+                limits.daily_bytes,
+                limits.daily_lines,
+                limits.daily_paper_mm
+            ));
+        }
+        "header" | "footer" => {
+            let current = config();
+            let mut block = match command {
+                "header" => current.header,
+                _ => current.footer,
+            };
+            for setting in payload.split_whitespace() {
+                if parse_block(&mut block, setting).is_none() {
+                    return Some(format!("error: invalid {command} setting {setting}"));
+                }
+            }
+            let saved = save_setting(command, |config| match command {
+                "header" => config.header = block.clone(),
+                _ => config.footer = block.clone(),
+            })
+            .await;
+            if !saved {
+                return Some(format!("error: failed to save the {command}"));
+            }
+            return Some(format!(
+                "web={} mqtt={} schedule={} socket={} time={} source={} job_id={} divider={}",
+                block.web,
+                block.mqtt,
+                block.schedule,
+                block.socket,
+                block.time,
+                block.source,
+                block.job_id,
+                block.divider.as_str()
+            ));
+        }
+        "dedup_window" => match payload.trim().parse::<u32>() {
+            Ok(seconds) => {
+                save_setting(command, |config| config.dedup.window_secs = seconds).await;
+            }
+            Err(_) => error!("Invalid dedup window in seconds: {}", payload),
+        },
+        "accept_retained" => match payload.trim().parse::<bool>() {
+            Ok(accept) => {
+                save_setting(command, |config| config.dedup.accept_retained = accept).await;
+            }
+            Err(_) => error!("Invalid value, expected true or false: {}", payload),
+        },
+        "roll_length" => {
+            let result = match payload.trim().parse::<u32>() {
+                Ok(mm) => printer::set_roll_length(mm).await,
+                Err(_) => return Some(String::from("error: expected the roll length in mm")),
+            };
+            return Some(paper_reply(result));
+        }
+        "low_paper" => {
+            let result = match payload.trim().parse::<u32>() {
+                Ok(mm) => printer::set_low_paper_threshold(mm).await,
+                Err(_) => return Some(String::from("error: expected the threshold in mm")),
+            };
+            return Some(paper_reply(result));
+        }
+        "paper_reset" => return Some(paper_reply(printer::reset_roll().await)),
+        "paper" => return Some(paper_reply(Ok(printer::paper_status().await))),
+        "auth_reset" => {
+            return Some(String::from(match web::clear_credentials().await {
+                Ok(()) => "web authentication is off",
+                Err(()) => "error: credentials cleared but not saved",
+            }));
+        }
+        _ => error!("Unknown admin command: {}", command),
+    }
+
+    None
+}
+
+/// Saves a setting changed by an admin command before it applies, so it survives a restart.
+/// Returns whether it was saved, it is left unchanged otherwise.
+async fn save_setting(command: &str, change: impl Fn(&mut DeviceConfig)) -> bool {
+    let saved = change_settings(change).await.is_ok();
+    if !saved {
+        error!("Failed to save the {} setting, it is unchanged", command);
+    }
+    saved
+}
+
+fn paper_reply(result: Result<PaperStatus, ()>) -> String {
+    let Ok(status) = result else {
+        return String::from("error: failed to save paper usage");
+    };
+
+    let mut reply = format!(
+        "{} mm used, {} mm last job, {} mm in total",
+        status.used_mm, status.last_job_mm, status.total_mm
+    );
+    if let (Some(length), Some(remaining)) = (status.roll_length_mm, status.remaining_mm) {
+        let _ = write!(reply, "\n{remaining} mm of {length} mm left");
+    }
+    if status.low {
+        reply.push_str("\nlow paper");
+    }
+    match status.sensor {
+        PaperSensor::NearEnd => reply.push_str("\npaper roll near its end"),
+        PaperSensor::Out => reply.push_str("\nout of paper"),
+        PaperSensor::Unknown | PaperSensor::Present => {}
+    }
+    reply
+}
+
+/// Applies a `name=value` limit setting
+fn parse_limit(limits: &mut LimitConfig, setting: &str) -> Option<()> {
+    let (name, value) = setting.split_once('=')?;
+    match name {
+        "jobs_per_hour" => limits.jobs_per_hour = value.parse().ok()?,
+        "burst" => limits.burst = value.parse().ok()?,
+        "daily_bytes" => limits.daily_bytes = value.parse().ok()?,
+        "daily_lines" => limits.daily_lines = value.parse().ok()?,
+        "daily_paper_mm" => limits.daily_paper_mm = value.parse().ok()?,
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Applies a `name=value` header or footer setting
+fn parse_block(block: &mut BlockConfig, setting: &str) -> Option<()> {
+    let (name, value) = setting.split_once('=')?;
+    match name {
+        "web" => block.web = value.parse().ok()?,
+        "mqtt" => block.mqtt = value.parse().ok()?,
+        "schedule" => block.schedule = value.parse().ok()?,
+        "socket" => block.socket = value.parse().ok()?,
+        "time" => block.time = value.parse().ok()?,
+        "source" => block.source = value.parse().ok()?,
+        "job_id" => block.job_id = value.parse().ok()?,
+        "divider" => block.divider = value.parse().ok()?,
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Parses `<source> <policy>`, e.g. `mqtt raw`
+fn parse_controls(payload: &str) -> Option<(&str, ControlPolicy)> {
+    let (source, policy) = payload.trim().split_once(' ')?;
+    if !matches!(source, "web" | "mqtt" | "schedule" | "socket") {
+        return None;
+    }
+
+    Some((source, policy.parse().ok()?))
+}
+
+/// Parses `host` or `host:port`, defaulting to the ntp port
+fn parse_server(payload: &str) -> Option<(ServerName, u16)> {
+    let (host, port) = match payload.trim().rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (payload.trim(), 123),
+    };
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((ServerName::try_from(host).ok()?, port))
+}
+
+/// user properties kept of each received message, enough for the idempotency key among others
+const MAX_USER_PROPERTIES: usize = 4;
+
+/// at most two subscriptions waiting for their acknowledgement, four messages in flight either
+/// way, no subscription identifiers or topic aliases
+type MqttClient<'a> =
+    Client<'a, 'a, Connection<'a>, AllocBuffer, 2, 4, 4, 0, MAX_USER_PROPERTIES, 0, 0>;
+
+/// The socket with the version of embedded-io rust-mqtt is written against, embassy-net still
+/// implements the previous one
+struct Connection<'a>(TcpSocket<'a>);
+
+impl embedded_io_async::ErrorType for Connection<'_> {
+    type Error = ErrorKind;
+}
+
+impl embedded_io_async::Read for Connection<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0
+            .read(buf)
+            .await
+            .map_err(|_| ErrorKind::ConnectionReset)
+    }
+}
+
+impl embedded_io_async::Write for Connection<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0
+            .write(buf)
+            .await
+            .map_err(|_| ErrorKind::ConnectionReset)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush().await.map_err(|_| ErrorKind::ConnectionReset)
+    }
 }
 
-type MqttClient<'a> = client::MqttClient<'a, TcpSocket<'a>, 5, Rng>;
+/// Socket buffers and the allocator for received packets, reused by every connection
+struct Buffers {
+    rx: [u8; BUFFER_SIZE],
+    tx: [u8; BUFFER_SIZE],
+    packets: AllocBuffer,
+}
 
+/// Connects and subscribes, an error once the broker couldn't be reached so the caller tries
+/// again with the settings as they are then
 async fn init_mqtt_client<'a>(
     stack: Stack<'static>,
-    rng: Rng,
     client_id: &'a str,
-    rx_buffer: &'a mut [u8],
-    tx_buffer: &'a mut [u8],
-    write_buffer: &'a mut [u8],
-    recv_buffer: &'a mut [u8],
+    settings: &'a MqttConfig,
+    buffers: &'a mut Buffers,
 ) -> Result<MqttClient<'a>, ()> {
+    // these settings are the ones being connected with
+    MQTT_RECONNECT.reset();
+    if settings.broker.is_empty() {
+        info!("No MQTT broker is configured");
+        MQTT_RECONNECT.wait().await;
+        return Err(());
+    }
+
     info!("initializing mqtt client");
-    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    let Buffers { rx, tx, packets } = buffers;
+    let mut socket = TcpSocket::new(stack, rx, tx);
     socket.set_timeout(Some(Duration::from_secs(30)));
-    let ip = IpAddress::v4(192, 168, 1, 33);
-
-    loop {
-        match socket.connect((ip, 1883)).await {
-            Ok(connection) => break connection,
-            Err(_) => error!("Failed to connect to MQTT broker..."),
-        }
-        Timer::after(Duration::from_secs(5)).await;
+    let address = match settings.broker.parse::<Ipv4Address>() {
+        Ok(address) => Ok(IpAddress::Ipv4(address)),
+        Err(_) => dns::lookup(&settings.broker, stack).await,
+    };
+    let connected = match address {
+        Ok(address) => socket.connect((address, settings.port)).await.is_ok(),
+        Err(_) => false,
+    };
+    if !connected {
+        error!("Failed to connect to MQTT broker {}", settings.broker);
+        select(Timer::after(Duration::from_secs(5)), MQTT_RECONNECT.wait()).await;
+        return Err(());
     }
 
-    let mut config = ClientConfig::new(MqttVersion::MQTTv5, rng);
-    config.add_max_subscribe_qos(QualityOfService::QoS2);
-    config.add_client_id(client_id);
-    config.add_username(MQTT_USER);
-    config.add_password(MQTT_PASSWORD);
-    config.max_packet_size = recv_buffer.len() as u32;
-    config.keep_alive = 10;
-
-    let mut client = MqttClient::<'a>::new(
-        socket,
-        write_buffer,
-        write_buffer.len(),
-        recv_buffer,
-        recv_buffer.len(),
-        config,
-    );
-
-    if connect_to_broker(&mut client).await.is_err() {
-        return Err(());
+    // a clean session, messages sent while the printer was away are not queued for it
+    let mut options = ConnectOptions::new()
+        .clean_start()
+        .keep_alive(KeepAlive::Seconds(KEEP_ALIVE))
+        .maximum_packet_size(MAX_PACKET_SIZE);
+    if let Ok(user_name) = MqttString::try_from(settings.username.as_str())
+        && !user_name.is_empty()
+    {
+        options = options.user_name(user_name);
+    }
+    if let Ok(password) = MqttBinary::try_from(settings.password.as_bytes())
+        && !password.as_ref().is_empty()
+    {
+        options = options.password(password);
     }
 
-    let producer_queue = "embedded/scribe/producer/#";
-    info!("MQTT subscribing to: {}", producer_queue);
-    if subscribe_to_topic(&mut client, producer_queue)
+    let mut client = MqttClient::<'a>::new(packets);
+    let identifier = MqttString::try_from(client_id).ok();
+    if let Err(e) = client
+        .connect(Connection(socket), &options, identifier)
         .await
-        .is_err()
     {
+        error!("MQTT Error in connect to broker: {:?}", e);
+        select(Timer::after(Duration::from_secs(5)), MQTT_RECONNECT.wait()).await;
         return Err(());
     }
-    Ok(client)
-}
 
-async fn subscribe_to_topic<'a>(client: &mut MqttClient<'a>, topic: &str) -> Result<(), ()> {
-    let mut failure_count = 0;
-    loop {
-        match client.subscribe_to_topic(topic).await {
-            Ok(()) => return Ok(()),
-            Err(mqtt_error) => {
-                error!("MQTT Error in subscribe: {:?}", mqtt_error);
-                failure_count += 1;
-            }
-        }
-        if failure_count > 5 {
-            return Err(());
-        }
-        Timer::after(Duration::from_secs(5)).await;
-    }
+    let producer_queue = format!("{PRODUCER_TOPIC_PREFIX}#");
+    subscribe(&mut client, &producer_queue).await?;
+    let admin_queue = format!("{ADMIN_TOPIC_PREFIX}{client_id}/#");
+    subscribe(&mut client, &admin_queue).await?;
+    Ok(client)
 }
 
-async fn connect_to_broker<'a>(client: &mut MqttClient<'a>) -> Result<(), ()> {
-    let mut failure_count = 0;
-    loop {
-        match client.connect_to_broker().await {
-            Ok(()) => return Ok(()),
-            Err(mqtt_error) => {
-                error!("MQTT Error in connect to broker: {:?}", mqtt_error);
-                failure_count += 1;
-            }
-        }
-        if failure_count > 5 {
-            return Err(());
+/// Asks for the topic's messages, the broker's answer is read with the messages
+async fn subscribe(client: &mut MqttClient<'_>, topic: &str) -> Result<(), ()> {
+    info!("MQTT subscribing to: {}", topic);
+    let Some(filter) = MqttString::try_from(topic).ok().and_then(TopicFilter::new) else {
+        error!("Invalid MQTT topic filter: {}", topic);
+        return Err(());
+    };
+    match client
+        .subscribe(filter, &SubscriptionOptions::new().exactly_once())
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("MQTT Error in subscribe: {:?}", e);
+            Err(())
         }
-        Timer::after(Duration::from_secs(5)).await;
     }
 }
 
-async fn send_message<'a>(
-    client: &mut MqttClient<'a>,
+async fn send_message(
+    client: &mut MqttClient<'_>,
     topic: &str,
     message: &[u8],
-    qos: QualityOfService,
-    retain: bool,
+    qos: QoS,
 ) -> Result<(), ()> {
-    match client.send_message(topic, message, qos, retain).await {
-        Ok(()) => {
+    let Some(topic) = MqttString::try_from(topic).ok().and_then(TopicName::new) else {
+        error!("Invalid MQTT topic: {}", topic);
+        return Err(());
+    };
+    let options = PublicationOptions::new(TopicReference::Name(topic)).qos(qos);
+    match client.publish(&options, Bytes::from(message)).await {
+        Ok(_) => {
             debug!("sent message");
             Ok(())
         }
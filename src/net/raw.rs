@@ -0,0 +1,149 @@
+use defmt::{debug, error, info, warn};
+use embassy_executor::Spawner;
+use embassy_net::{IpAddress, Stack, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, with_deadline};
+
+use crate::{
+    config::config,
+    limits::{self, Requester},
+    printer::{
+        CHUNK_SIZE, JobError, JobOptions, JobSource, MAX_JOB_TIME, PrinterWriter, is_too_slow,
+    },
+};
+
+/// the port raw printing, also called AppSocket or JetDirect, is known by
+pub const PORT: u16 = 9100;
+/// a client that sends nothing for this long is taken to have sent its whole job, some keep
+/// the connection open after the last byte
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const BUFFER_SIZE: usize = 1024;
+/// jobs from drivers carry their own raster images, so they may be longer than notes when the
+/// socket's control policy passes them through
+const MAX_JOB_LENGTH: usize = 256 * 1024;
+
+pub fn start_raw_server(stack: Stack<'static>, spawner: &Spawner) {
+    spawner.must_spawn(raw_task(stack));
+    info!("Raw print server initialized...");
+}
+
+/// Prints everything sent to the port as one job per connection. There's a single socket, so
+/// only one connection is accepted at a time and the printer is held for it until it closes.
+#[embassy_executor::task]
+async fn raw_task(stack: Stack<'static>) -> ! {
+    let mut rx_buffer = [0; BUFFER_SIZE];
+    let mut tx_buffer = [0; 64];
+    let printer = PrinterWriter::new();
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        if let Err(e) = socket.accept(PORT).await {
+            error!("Failed to accept a raw print connection: {:?}", e);
+            continue;
+        }
+        let Some(endpoint) = socket.remote_endpoint() else {
+            continue;
+        };
+        info!("Raw print connection from {}", endpoint);
+
+        // the port has no way to log in, so it only prints while turned on in the settings
+        if !config().printer.raw_port {
+            warn!(
+                "Refused raw print connection from {}, raw printing is turned off",
+                endpoint
+            );
+            socket.abort();
+        } else if let Err(()) = receive(&mut socket, &printer, endpoint.addr).await {
+            socket.abort();
+        } else {
+            socket.close();
+        }
+        let _ = socket.flush().await;
+    }
+}
+
+/// Streams the connection into a print job, begun when the first byte arrives
+async fn receive(
+    socket: &mut TcpSocket<'_>,
+    printer: &PrinterWriter,
+    address: IpAddress,
+) -> Result<(), ()> {
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut length = match read_idle(socket, &mut buffer, Instant::now() + IDLE_TIMEOUT).await {
+        Ok(0) => return Ok(()),
+        Ok(length) => length,
+        Err(_) => return Err(()),
+    };
+
+    let mut requesters = limits::Requesters::new();
+    let _ = requesters.push(Requester::Client(address));
+    if let Err(e) = limits::admit(&requesters) {
+        warn!("Refused raw print job: {}", e.as_str());
+        return Err(());
+    }
+    let mut options = JobOptions {
+        requesters,
+        ..JobOptions::new(JobSource::Socket)
+    };
+    // text is laid out in memory, so only jobs sent on to the printer as they are may be longer
+    // than notes
+    if options.control_policy().bypasses_layout() {
+        options.max_length = Some(MAX_JOB_LENGTH.max(config().printer.max_job_length));
+    }
+
+    // every other job waits while this one holds the printer, so it can't take forever
+    let mut job = printer.begin_job(options).await;
+    let started = Instant::now();
+    let deadline = started + MAX_JOB_TIME;
+    let mut received = 0;
+    info!("Receiving raw print job {}", job.id());
+    let result = loop {
+        if let Err(e) = job.write(&buffer[..length]).await {
+            break Err(e);
+        }
+        received += length as u64;
+        if is_too_slow(started, received) {
+            break Err(JobError::TooSlow);
+        }
+
+        length = match read_idle(socket, &mut buffer, deadline).await {
+            Ok(0) => break Ok(()),
+            Ok(length) => length,
+            Err(e) => break Err(e),
+        };
+    };
+
+    match result {
+        Ok(()) => {
+            job.finish().await;
+            info!("Raw print job complete");
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Raw print job failed: {}", e.as_str());
+            job.abort(e).await;
+            Err(())
+        }
+    }
+}
+
+/// Reads what has arrived, a connection idle for long counts as closed. Nothing is read past
+/// `deadline`.
+async fn read_idle(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+    deadline: Instant,
+) -> Result<usize, JobError> {
+    let idle = Instant::now() + IDLE_TIMEOUT;
+    match with_deadline(idle.min(deadline), socket.read(buffer)).await {
+        Ok(Ok(read)) => Ok(read),
+        Ok(Err(e)) => {
+            debug!("Raw print connection failed: {:?}", e);
+            Err(JobError::Disconnected)
+        }
+        Err(_) if idle < deadline => {
+            debug!("Raw print connection idle, ending the job");
+            Ok(0)
+        }
+        Err(_) => Err(JobError::TooSlow),
+    }
+}
@@ -0,0 +1,142 @@
+use defmt::{debug, error, info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_net::{
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+
+use crate::{
+    config::{TimeConfig, config},
+    net::dns,
+    time,
+};
+
+const LOCAL_PORT: u16 = 50123;
+const PACKET_SIZE: usize = 48;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// wait before retrying a failed sync
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// seconds between the ntp era (1900) and the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Wakes the client to sync immediately, e.g. after the server was changed
+pub static SNTP_RESYNC: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn start_sntp(stack: Stack<'static>, spawner: &Spawner) {
+    spawner.must_spawn(sntp_task(stack));
+    info!("SNTP initialized...");
+}
+
+#[embassy_executor::task]
+async fn sntp_task(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_SIZE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        error!("Failed to bind SNTP socket: {:?}", e);
+        panic!("Failed to bind SNTP socket")
+    }
+
+    loop {
+        let config = config().time;
+        let wait = match sync(&socket, stack, &config).await {
+            Ok(()) => Duration::from_secs(config.sync_interval as u64),
+            Err(()) => RETRY_INTERVAL,
+        };
+        select(Timer::after(wait), SNTP_RESYNC.wait()).await;
+    }
+}
+
+async fn sync(
+    socket: &UdpSocket<'_>,
+    stack: Stack<'static>,
+    config: &TimeConfig,
+) -> Result<(), ()> {
+    let address = match config.server.parse::<Ipv4Address>() {
+        Ok(address) => IpAddress::Ipv4(address),
+        Err(_) => dns::lookup(&config.server, stack).await.map_err(|_| ())?,
+    };
+    let server = IpEndpoint::new(address, config.port);
+    debug!("Requesting time from {}", server);
+
+    // LI = 0, VN = 4, Mode = 3 (client)
+    let mut request = [0u8; PACKET_SIZE];
+    request[0] = 0b00_100_011;
+    // the server echoes the transmit timestamp back, which identifies the response
+    let sent = Instant::now();
+    let nonce = sent.as_ticks().to_be_bytes();
+    request[40..48].copy_from_slice(&nonce);
+
+    if let Err(e) = socket.send_to(&request, server).await {
+        warn!("Failed to send SNTP request: {:?}", e);
+        return Err(());
+    }
+
+    let mut response = [0u8; PACKET_SIZE];
+    let received = with_timeout(RESPONSE_TIMEOUT, async {
+        loop {
+            match socket.recv_from(&mut response).await {
+                Ok((PACKET_SIZE, meta)) if meta.endpoint == server => {
+                    if response[24..32] == nonce {
+                        break;
+                    }
+                }
+                Ok(_) => debug!("Ignoring unexpected SNTP packet"),
+                Err(e) => warn!("Failed to receive SNTP response: {:?}", e),
+            }
+        }
+    })
+    .await;
+    let now = Instant::now();
+    if received.is_err() {
+        warn!("SNTP server {} did not respond", server);
+        return Err(());
+    }
+
+    let mode = response[0] & 0b111;
+    let stratum = response[1];
+    let transmit_set = response[40..48].iter().any(|b| *b != 0);
+    if mode != 4 || !(1..16).contains(&stratum) || !transmit_set {
+        warn!("Invalid SNTP response, mode {} stratum {}", mode, stratum);
+        return Err(());
+    }
+
+    // T2 and T3, the server's receive and transmit times
+    let server_received = ntp_to_unix_micros(&response[32..40]);
+    let server_sent = ntp_to_unix_micros(&response[40..48]);
+    let round_trip = now.saturating_duration_since(sent).as_micros();
+    let server_delay = server_sent.saturating_sub(server_received);
+    let unix_micros = server_sent + round_trip.saturating_sub(server_delay) / 2;
+
+    time::set_unix_time(now, unix_micros);
+    if let Some(now) = time::now() {
+        info!("Clock synchronized, local time is {}", now);
+    }
+
+    Ok(())
+}
+
+fn ntp_to_unix_micros(timestamp: &[u8]) -> u64 {
+    let seconds = u32::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]);
+    let fraction = u32::from_be_bytes([timestamp[4], timestamp[5], timestamp[6], timestamp[7]]);
+
+    // timestamps with the top bit clear are past the 2036 era rollover
+    let seconds = if seconds & 0x8000_0000 == 0 {
+        seconds as u64 + (1 << 32)
+    } else {
+        seconds as u64
+    };
+
+    seconds.saturating_sub(NTP_UNIX_OFFSET) * 1_000_000 + ((fraction as u64 * 1_000_000) >> 32)
+}
@@ -1,20 +1,54 @@
-use defmt::info;
+use core::{fmt::Write as _, str::FromStr};
+
+use alloc::string::String;
+use defmt::{Debug2Format, debug, info, warn};
 use embassy_executor::Spawner;
-use embassy_net::Stack;
-use embassy_time::Duration;
+use embassy_net::{IpAddress, Stack, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, with_deadline};
 use picoserve::{
-    AppRouter, AppWithStateBuilder,
-    extract::State,
-    response::{File, IntoResponse},
-    routing,
+    AppRouter, AppWithStateBuilder, ResponseSent,
+    extract::{FromRequestParts, State},
+    io::{Read, Write},
+    request::{Request, RequestParts},
+    response::{Content, File, IntoResponse, ResponseWriter, StatusCode},
+    routing::{self, RequestHandlerService},
+};
+
+use crate::{
+    config::{Scope, TokenName},
+    dedup::{self, Duplicate, MAX_KEY_LENGTH, Submission},
+    limits::{self, JobUsage, LimitError, Requester, Requesters},
+    printer::{
+        Banner, BannerOptions, BannerText, CHUNK_SIZE, Feed, JobError, JobOptions, JobSource,
+        LINE_HEIGHT, MAX_JOB_TIME, PrinterWriter, is_too_slow, render_preview,
+    },
 };
 
-use crate::printer::{DATA_SIZE, PrinterWriter};
+mod api;
+mod auth;
+mod events;
+mod firmware;
+mod images;
+mod jobs;
+mod multipart;
+mod settings;
+mod status;
 
 const BUFFER_SIZE: usize = 1024;
-const WEB_TASK_POOL_SIZE: usize = 2;
+/// form posts are decoded whole, longer documents have to be streamed
+const FORM_DATA_SIZE: usize = 2048;
+/// enough that every event stream can be open with requests still being served
+const WEB_TASK_POOL_SIZE: usize = 2 + crate::events::MAX_LISTENERS;
+/// long enough for any feed amount or cut mode
+const OPTION_SIZE: usize = 8;
 
-pub fn start_web_host(stack: Stack<'static>, spawner: &Spawner) {
+type OptionText = heapless::String<OPTION_SIZE>;
+type Rejection = (StatusCode, &'static str);
+
+pub use auth::clear_credentials;
+
+pub async fn start_web_host(stack: Stack<'static>, spawner: &Spawner) {
+    auth::load_credentials().await;
     let web = &*crate::mk_static!(WebService, WebService::new(stack));
     for id in 0..WEB_TASK_POOL_SIZE {
         spawner.must_spawn(web_task(id, web));
@@ -60,6 +94,8 @@ impl WebService {
             config,
             state: AppState {
                 printer: PrinterWriter::new(),
+                stack,
+                client: None,
             },
         }
     }
@@ -73,47 +109,537 @@ impl WebService {
     ) -> ! {
         let port = 80;
 
-        picoserve::Server::new(
-            &self.router.shared().with_state(&self.state),
-            self.config,
-            http_buffer,
-        )
-        .listen_and_serve(id, self.stack, port, rx_buffer, tx_buffer)
-        .await
-        .into_never()
+        // connections are accepted here rather than by picoserve so handlers know the client
+        loop {
+            let mut socket = TcpSocket::new(self.stack, rx_buffer, tx_buffer);
+            debug!("{}: Listening on TCP:{}...", id, port);
+            if let Err(e) = socket.accept(port).await {
+                warn!("{}: accept error: {:?}", id, e);
+                continue;
+            }
+            let client = socket.remote_endpoint().map(|endpoint| endpoint.addr);
+            debug!("{}: Received connection from {:?}", id, client);
+            socket.set_keep_alive(Some(Duration::from_secs(30)));
+            socket.set_timeout(Some(Duration::from_secs(45)));
+
+            let state = AppState {
+                client,
+                ..self.state.clone()
+            };
+            let app = self.router.shared().with_state(&state);
+            let server = picoserve::Server::new(&app, self.config, http_buffer);
+            if let Err(e) = server.serve(socket).await {
+                warn!("{}: connection error: {}", id, Debug2Format(&e));
+            }
+        }
     }
 }
 
 struct Application;
 
-static INDEX_PAGE: &str = include_str!("index.html");
+/// gzipped by the build script, browsers revalidate their copy against its etag on every load
+static INDEX_PAGE: File = File::with_content_type_and_headers(
+    File::MIME_HTML,
+    include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz")),
+    &[("Content-Encoding", "gzip"), ("Cache-Control", "no-cache")],
+);
 impl AppWithStateBuilder for Application {
     type PathRouter = impl routing::PathRouter<AppState>;
     type State = AppState;
 
     fn build_app(self) -> picoserve::Router<Self::PathRouter, Self::State> {
-        picoserve::Router::new().route(
-            "/",
-            routing::get_service(File::html(INDEX_PAGE)).post(post_handler),
-        )
+        // each route carries the scope it needs, so a request is checked against the route it
+        // was matched to however its path is spelled
+        let print = auth::RequireAuth::scope(Scope::Print);
+        let admin = auth::RequireAuth::scope(Scope::Admin);
+        let admin_to_change = auth::RequireAuth::admin_to_change();
+
+        picoserve::Router::new()
+            .route(
+                "/",
+                routing::get_service(INDEX_PAGE.clone())
+                    .post(post_handler)
+                    .layer(print),
+            )
+            .route("/preview", routing::post(preview_handler).layer(print))
+            .route("/banner", routing::post(banner_handler).layer(print))
+            .route("/feed", routing::post(feed_handler).layer(print))
+            .route("/print", routing::post_service(StreamPrint).layer(print))
+            .route(
+                "/api/v1/preview",
+                routing::post(jobs::preview_job).layer(print),
+            )
+            .route(
+                "/api/v1/images",
+                routing::post_service(images::ImageUpload).layer(print),
+            )
+            .route(
+                "/api/v1/firmware",
+                routing::post_service(firmware::FirmwareUpload).layer(admin),
+            )
+            .route(
+                "/api/v1/jobs",
+                routing::get(jobs::list_jobs)
+                    .post(jobs::create_job)
+                    .layer(print),
+            )
+            .route(
+                ("/api/v1/jobs", routing::parse_path_segment::<u32>()),
+                routing::get(jobs::get_job)
+                    .delete(jobs::delete_job)
+                    .layer(admin_to_change),
+            )
+            .route(
+                (
+                    "/api/v1/jobs",
+                    routing::parse_path_segment::<u32>(),
+                    "/reprint",
+                ),
+                routing::post(jobs::reprint_job).layer(print),
+            )
+            .route(
+                "/api/v1/schedules",
+                routing::get(api::list_schedules)
+                    .post(api::create_schedule)
+                    .layer(admin_to_change),
+            )
+            .route(
+                ("/api/v1/schedules", routing::parse_path_segment::<u32>()),
+                routing::delete(api::delete_schedule).layer(admin),
+            )
+            .route(
+                "/api/v1/status",
+                routing::get(status::get_status).layer(print),
+            )
+            .route(
+                "/api/v1/events",
+                routing::get(events::get_events).layer(print),
+            )
+            .route(
+                "/api/v1/paper",
+                routing::get(api::get_paper)
+                    .post(api::update_paper)
+                    .layer(admin_to_change),
+            )
+            .route(
+                "/api/v1/paper/reset",
+                routing::post(api::reset_paper).layer(admin),
+            )
+            .route(
+                "/api/v1/limits",
+                routing::get(api::get_limits)
+                    .post(api::update_limits)
+                    .layer(admin_to_change),
+            )
+            // credentials and the network's settings are only for admins, even to read
+            .route(
+                "/api/v1/config",
+                routing::get(settings::get_settings)
+                    .put(settings::update_settings)
+                    .layer(admin),
+            )
+            .route(
+                "/api/v1/auth/password",
+                routing::put(auth::set_password)
+                    .delete(auth::remove_password)
+                    .layer(admin),
+            )
+            .route(
+                "/api/v1/auth/tokens",
+                routing::get(auth::list_tokens)
+                    .post(auth::create_token)
+                    .layer(admin),
+            )
+            .route(
+                (
+                    "/api/v1/auth/tokens",
+                    routing::parse_path_segment::<TokenName>(),
+                ),
+                routing::delete(auth::revoke_token).layer(admin),
+            )
     }
 }
 
 #[derive(Clone)]
 struct AppState {
     printer: PrinterWriter,
+    stack: Stack<'static>,
+    /// address of the connected client
+    client: Option<IpAddress>,
+}
+
+/// Who sent the request, and the `Idempotency-Key` header if there is one
+struct Submitter {
+    requesters: Requesters,
+    /// tells senders apart when looking for repeated content
+    sender: String,
+    key: Option<String>,
+}
+
+impl<'r> FromRequestParts<'r, AppState> for Submitter {
+    type Rejection = Rejection;
+
+    async fn from_request_parts(
+        state: &'r AppState,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name| {
+            request_parts
+                .headers()
+                .get(name)
+                .and_then(|value| value.as_str().ok())
+                .map(str::trim)
+        };
+
+        let mut requesters = Requesters::new();
+        let mut sender = String::from("web");
+        if let Some(client) = state.client {
+            let _ = requesters.push(Requester::Client(client));
+            let _ = write!(sender, " {client}");
+        }
+        // a made up token would be a fresh set of limits, so only a stored one counts
+        if let Some(name) = header("Authorization").and_then(auth::token_name) {
+            let _ = requesters.push(Requester::Token(name));
+        }
+
+        let key = header("Idempotency-Key");
+        if key.is_some_and(|key| key.len() > MAX_KEY_LENGTH) {
+            return Err((StatusCode::BAD_REQUEST, "Idempotency key is too long\n"));
+        }
+
+        Ok(Self {
+            requesters,
+            sender,
+            key: key.map(String::from),
+        })
+    }
+}
+
+/// Why a submission was not accepted
+enum Refusal {
+    Duplicate(Duplicate),
+    Limited(LimitError),
+}
+
+impl Submitter {
+    /// Checks a job against the recently printed ones and the sender's limits. `Ok(false)` is a
+    /// repeat of a printed job, which is answered as though it was printed again.
+    fn admit(&self, content: Option<&[u8]>) -> Result<bool, Rejection> {
+        match self.check(content, Duration::MIN) {
+            Ok(_) => {
+                self.remember(content, None);
+                Ok(true)
+            }
+            Err(Refusal::Duplicate(_)) => Ok(false),
+            Err(Refusal::Limited(e)) => Err((StatusCode::TOO_MANY_REQUESTS, e.as_str())),
+        }
+    }
+
+    /// Admits the job, taking it from the sender's rate limit, which it must then be
+    /// remembered with [`Self::remember`] or handed back with [`limits::refund`]. A job the rate
+    /// limit holds back for no more than `max_delay` is admitted to start at the returned time.
+    fn check(&self, content: Option<&[u8]>, max_delay: Duration) -> Result<Instant, Refusal> {
+        dedup::check(&self.submission(content)).map_err(Refusal::Duplicate)?;
+        limits::schedule(&self.requesters, max_delay).map_err(Refusal::Limited)
+    }
+
+    /// Remembers an admitted job, as `job` once it has an id, so a repeat isn't printed again
+    fn remember(&self, content: Option<&[u8]>, job: Option<u32>) {
+        dedup::remember(&self.submission(content), job);
+    }
+
+    /// Forgets an admitted job that failed to print, so it can be retried
+    fn forget(&self, content: Option<&[u8]>) {
+        dedup::forget(&self.submission(content));
+    }
+
+    fn submission<'a>(&'a self, content: Option<&'a [u8]>) -> Submission<'a> {
+        Submission {
+            key: self.key.as_deref(),
+            sender: &self.sender,
+            content,
+        }
+    }
+}
+
+/// Per job settings, sent as form fields or in the `/print` query string. Empty fields use the
+/// configured defaults.
+#[derive(serde::Deserialize)]
+struct JobOptionsData {
+    leading_feed: Option<OptionText>,
+    trailing_feed: Option<OptionText>,
+    cut: Option<OptionText>,
+    header: Option<OptionText>,
+    footer: Option<OptionText>,
+}
+
+impl JobOptionsData {
+    fn options(&self) -> Result<JobOptions, Rejection> {
+        let invalid = |()| (StatusCode::BAD_REQUEST, "Invalid job option\n");
+
+        Ok(JobOptions {
+            leading_feed: parse_option(&self.leading_feed).map_err(invalid)?,
+            trailing_feed: parse_option(&self.trailing_feed).map_err(invalid)?,
+            cut: parse_option(&self.cut).map_err(invalid)?,
+            header: parse_option(&self.header).map_err(invalid)?,
+            footer: parse_option(&self.footer).map_err(invalid)?,
+            ..JobOptions::new(JobSource::Web)
+        })
+    }
+}
+
+fn parse_option<T: FromStr>(value: &Option<OptionText>) -> Result<Option<T>, ()> {
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|_| ()),
+    }
 }
 
 #[derive(serde::Deserialize)]
 struct SubmitData {
-    message: heapless::String<DATA_SIZE>,
+    message: heapless::String<FORM_DATA_SIZE>,
+    leading_feed: Option<OptionText>,
+    trailing_feed: Option<OptionText>,
+    cut: Option<OptionText>,
+    header: Option<OptionText>,
+    footer: Option<OptionText>,
+}
+
+impl SubmitData {
+    fn options(&self) -> Result<JobOptions, Rejection> {
+        JobOptionsData {
+            leading_feed: self.leading_feed.clone(),
+            trailing_feed: self.trailing_feed.clone(),
+            cut: self.cut.clone(),
+            header: self.header.clone(),
+            footer: self.footer.clone(),
+        }
+        .options()
+    }
 }
 
 async fn post_handler(
     State(state): picoserve::extract::State<AppState>,
+    submitter: Submitter,
     data: picoserve::extract::Form<SubmitData>,
-) -> impl IntoResponse {
+) -> Result<(), Rejection> {
     info!("Received message: {}", data.message);
+    let options = data.options()?;
+    if !submitter.admit(Some(data.message.as_bytes()))? {
+        return Ok(());
+    }
+    let options = JobOptions {
+        requesters: submitter.requesters.clone(),
+        ..options
+    };
+
+    if let Err(e) = state.printer.print(data.message.as_bytes(), options).await {
+        warn!("Failed to print message: {:?}", e);
+        submitter.forget(Some(data.message.as_bytes()));
+    }
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct FeedData {
+    amount: OptionText,
+}
+
+async fn feed_handler(
+    State(state): picoserve::extract::State<AppState>,
+    submitter: Submitter,
+    data: picoserve::extract::Form<FeedData>,
+) -> Result<(), Rejection> {
+    let feed = data
+        .amount
+        .parse::<Feed>()
+        .map_err(|()| (StatusCode::BAD_REQUEST, "Invalid feed amount\n"))?;
+    if !submitter.admit(None)? {
+        return Ok(());
+    }
+
+    state.printer.feed(feed).await;
+    let usage = JobUsage {
+        paper_dots: feed.dots(LINE_HEIGHT as u32),
+        ..JobUsage::default()
+    };
+    limits::record(&submitter.requesters, usage);
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct BannerData {
+    message: BannerText,
+    size: Option<u8>,
+    #[serde(default)]
+    inverse: bool,
+    border: Option<u8>,
+}
+
+async fn banner_handler(
+    State(state): picoserve::extract::State<AppState>,
+    submitter: Submitter,
+    data: picoserve::extract::Form<BannerData>,
+) -> Result<(), Rejection> {
+    info!("Received banner: {}", data.message);
+    if !submitter.admit(None)? {
+        return Ok(());
+    }
+
+    let defaults = BannerOptions::default();
+    let options = BannerOptions {
+        size: data.size.unwrap_or(defaults.size),
+        inverse: data.inverse,
+        border: data.border.unwrap_or(defaults.border),
+    };
+    let banner = Banner::new(data.message.clone(), options);
+    let usage = JobUsage {
+        bytes: data.message.len() as u32,
+        lines: 0,
+        paper_dots: banner.raster_rows() as u32,
+    };
+    let options = JobOptions {
+        requesters: submitter.requesters.clone(),
+        ..JobOptions::new(JobSource::Web)
+    };
+    state.printer.print_banner(banner, options).await;
+    limits::record(&submitter.requesters, usage);
+    Ok(())
+}
+
+/// Prints a raw `text/plain` or `application/octet-stream` body as it arrives, so documents
+/// larger than the http buffer can be printed. Reading stalls while the print queue is full.
+struct StreamPrint;
+
+impl RequestHandlerService<AppState> for StreamPrint {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &AppState,
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let is_supported = request
+            .parts
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.split(b';').next())
+            .is_some_and(|media_type| {
+                media_type == "text/plain" || media_type == "application/octet-stream"
+            });
+        if !is_supported {
+            let connection = request.body_connection.finalize().await?;
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected text/plain or application/octet-stream\n",
+            )
+                .write_to(connection, response_writer)
+                .await;
+        }
+
+        let submitter = Submitter::from_request_parts(state, &request.parts).await;
+        let options = picoserve::url_encoded::deserialize_form::<JobOptionsData>(
+            request.parts.query().unwrap_or_default(),
+        )
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid query string\n"))
+        .and_then(|data| data.options())
+        .and_then(|options| {
+            // only the key can identify a streamed job
+            let submitter = submitter?;
+            let admitted = submitter.admit(None)?;
+            let options = JobOptions {
+                requesters: submitter.requesters.clone(),
+                ..options
+            };
+            Ok(admitted.then_some((options, submitter)))
+        });
+        let (options, submitter) = match options {
+            Ok(Some(admitted)) => admitted,
+            Ok(None) => {
+                let connection = request.body_connection.finalize().await?;
+                return (StatusCode::OK, "Already printed\n")
+                    .write_to(connection, response_writer)
+                    .await;
+            }
+            Err(rejection) => {
+                let connection = request.body_connection.finalize().await?;
+                return rejection.write_to(connection, response_writer).await;
+            }
+        };
+
+        let mut reader = request.body_connection.body().reader();
+        let mut job = state.printer.begin_job(options).await;
+        info!(
+            "Streaming {} bytes to the printer as job {}",
+            reader.content_length(),
+            job.id()
+        );
+        let mut buffer = [0u8; CHUNK_SIZE];
+        // every other job waits while this one holds the printer, so it can't take forever
+        let started = Instant::now();
+        let deadline = started + MAX_JOB_TIME;
+        let mut received = 0;
+        let result = loop {
+            let read = match with_deadline(deadline, reader.read(&mut buffer)).await {
+                Ok(Ok(0)) => break Ok(()),
+                Ok(Ok(read)) => read,
+                Ok(Err(e)) => {
+                    job.abort(JobError::Disconnected).await;
+                    submitter.forget(None);
+                    return Err(e);
+                }
+                Err(_) => break Err(JobError::TooSlow),
+            };
+            if let Err(e) = job.write(&buffer[..read]).await {
+                break Err(e);
+            }
+            received += read as u64;
+            if is_too_slow(started, received) {
+                break Err(JobError::TooSlow);
+            }
+        };
+
+        let response = match result {
+            Ok(()) => {
+                job.finish().await;
+                (StatusCode::OK, "Printed\n")
+            }
+            Err(e) => {
+                warn!("Streamed print job failed: {}", e.as_str());
+                job.abort(e).await;
+                submitter.forget(None);
+                match e {
+                    JobError::TooSlow => (
+                        StatusCode::REQUEST_TIMEOUT,
+                        "Print job was sent too slowly\n",
+                    ),
+                    _ => (StatusCode::PAYLOAD_TOO_LARGE, "Print job is too long\n"),
+                }
+            }
+        };
 
-    state.printer.print(data.message.clone()).await;
+        let connection = request.body_connection.finalize().await?;
+        response.write_to(connection, response_writer).await
+    }
+}
+
+async fn preview_handler(data: picoserve::extract::Form<SubmitData>) -> Result<Svg, Rejection> {
+    let options = data.options()?;
+    Ok(Svg(render_preview(&data.message, &options)))
+}
+
+struct Svg(String);
+
+impl Content for Svg {
+    fn content_type(&self) -> &'static str {
+        "image/svg+xml"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: Write>(self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(self.0.as_bytes()).await
+    }
 }
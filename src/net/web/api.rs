@@ -0,0 +1,177 @@
+use alloc::{format, string::String, vec::Vec};
+use picoserve::{
+    extract::JsonWithUnescapeBufferSize,
+    response::{IntoResponse, Json, StatusCode},
+};
+use serde::Deserialize;
+
+use super::Rejection;
+use crate::{
+    config::{change_settings, config},
+    limits::LimitConfig,
+    printer::{self, PaperStatus},
+    scheduler::{self, MAX_MESSAGE_LENGTH, Schedule, ScheduleError, Trigger},
+    time,
+};
+
+#[derive(serde::Serialize)]
+struct ScheduleInfo {
+    id: u32,
+    cron: Option<String>,
+    /// local time
+    at: Option<String>,
+    message: String,
+}
+
+impl From<Schedule> for ScheduleInfo {
+    fn from(schedule: Schedule) -> Self {
+        let (cron, at) = match schedule.trigger {
+            Trigger::Cron(expression) => (Some(expression), None),
+            Trigger::At(at) => (None, Some(format!("{}", time::to_local(at)))),
+        };
+
+        Self {
+            id: schedule.id,
+            cron,
+            at,
+            message: schedule.message,
+        }
+    }
+}
+
+/// Either `cron` or `at` must be given
+#[derive(serde::Deserialize)]
+pub struct NewSchedule {
+    cron: Option<String>,
+    at: Option<String>,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+struct Created {
+    id: u32,
+}
+
+pub async fn list_schedules() -> impl IntoResponse {
+    let schedules = scheduler::schedules().await;
+    Json(
+        schedules
+            .into_iter()
+            .map(ScheduleInfo::from)
+            .collect::<Vec<_>>(),
+    )
+}
+
+pub async fn create_schedule(
+    JsonWithUnescapeBufferSize(data): JsonWithUnescapeBufferSize<NewSchedule, MAX_MESSAGE_LENGTH>,
+) -> Result<impl IntoResponse, Rejection> {
+    let trigger = match (&data.cron, &data.at) {
+        (Some(expression), None) => Trigger::cron(expression),
+        (None, Some(local_time)) => Trigger::at(local_time),
+        _ => return Err((StatusCode::BAD_REQUEST, "Expected one of cron or at\n")),
+    }
+    .map_err(schedule_rejection)?;
+
+    let id = scheduler::add_schedule(trigger, data.message)
+        .await
+        .map_err(schedule_rejection)?;
+
+    Ok(Json(Created { id })
+        .into_response()
+        .with_status_code(StatusCode::CREATED))
+}
+
+pub async fn delete_schedule(id: u32) -> Result<StatusCode, Rejection> {
+    scheduler::remove_schedule(id)
+        .await
+        .map_err(schedule_rejection)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn schedule_rejection(error: ScheduleError) -> Rejection {
+    let status = match error {
+        ScheduleError::NotFound => StatusCode::NOT_FOUND,
+        ScheduleError::NoClock => StatusCode::SERVICE_UNAVAILABLE,
+        ScheduleError::Storage => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, error.as_str())
+}
+
+/// Fields left out are unchanged
+#[derive(Deserialize)]
+pub struct PaperSettings {
+    roll_length_mm: Option<u32>,
+    low_paper_mm: Option<u32>,
+}
+
+pub async fn get_paper() -> Json<PaperStatus> {
+    Json(printer::paper_status().await)
+}
+
+pub async fn update_paper(
+    picoserve::extract::Json(settings): picoserve::extract::Json<PaperSettings>,
+) -> Result<Json<PaperStatus>, Rejection> {
+    let mut status = printer::paper_status().await;
+    if let Some(mm) = settings.roll_length_mm {
+        status = printer::set_roll_length(mm)
+            .await
+            .map_err(paper_rejection)?;
+    }
+    if let Some(mm) = settings.low_paper_mm {
+        status = printer::set_low_paper_threshold(mm)
+            .await
+            .map_err(paper_rejection)?;
+    }
+
+    Ok(Json(status))
+}
+
+pub async fn reset_paper() -> Result<Json<PaperStatus>, Rejection> {
+    let status = printer::reset_roll().await.map_err(paper_rejection)?;
+    Ok(Json(status))
+}
+
+fn paper_rejection((): ()) -> Rejection {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to save paper usage\n",
+    )
+}
+
+/// Fields left out are unchanged, 0 disables a limit
+#[derive(Deserialize)]
+pub struct LimitSettings {
+    jobs_per_hour: Option<u16>,
+    burst: Option<u16>,
+    daily_bytes: Option<u32>,
+    daily_lines: Option<u32>,
+    daily_paper_mm: Option<u32>,
+}
+
+pub async fn get_limits() -> Json<LimitConfig> {
+    Json(config().limits)
+}
+
+/// Saves the new limits, which only apply once they are saved
+pub async fn update_limits(
+    picoserve::extract::Json(settings): picoserve::extract::Json<LimitSettings>,
+) -> Result<Json<LimitConfig>, Rejection> {
+    change_settings(|config| {
+        let limits = &mut config.limits;
+        limits.jobs_per_hour = settings.jobs_per_hour.unwrap_or(limits.jobs_per_hour);
+        limits.burst = settings.burst.unwrap_or(limits.burst);
+        limits.daily_bytes = settings.daily_bytes.unwrap_or(limits.daily_bytes);
+        limits.daily_lines = settings.daily_lines.unwrap_or(limits.daily_lines);
+        limits.daily_paper_mm = settings.daily_paper_mm.unwrap_or(limits.daily_paper_mm);
+    })
+    .await
+    .map_err(|()| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the limits\n",
+        )
+    })?;
+    Ok(Json(config().limits))
+}
@@ -0,0 +1,573 @@
+use core::{cell::RefCell, fmt::Write as _};
+
+use alloc::{format, string::String, vec::Vec};
+use defmt::{info, warn};
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use picoserve::{
+    ResponseSent,
+    extract::JsonWithUnescapeBufferSize,
+    io::Read,
+    request::RequestParts,
+    response::{IntoResponse, Json, ResponseWriter, StatusCode},
+    routing::{Layer, Next},
+};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    AppState,
+    jobs::{ApiError, ErrorBody},
+};
+use crate::{
+    config::{
+        ApiToken, AuthConfig, MAX_TOKENS, PasswordHash, SALT_SIZE, Scope, TokenName, Username,
+        config, update_config,
+    },
+    glue::Rng,
+    sha256::{Digest, digests_match, pbkdf2, sha256},
+    storage::{self, Record},
+};
+
+/// PBKDF2 rounds new passwords are hashed with. The hash is only worked out on a login, as the
+/// Basic credentials are remembered once they match, so it can be made slow to guess from a
+/// copy of the flash. Each hash keeps its own count, raising it doesn't lock anyone out.
+const PASSWORD_ITERATIONS: u32 = 10_000;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 64;
+/// random bytes in a token, sent as twice as many hex digits
+const TOKEN_SIZE: usize = 16;
+/// longest decoded `user:password` accepted from a Basic header
+const MAX_BASIC_LENGTH: usize = 128;
+
+/// failed attempts a client is allowed before it has to wait
+const ALLOWED_FAILURES: u8 = 5;
+/// doubled with every further failure
+const LOCKOUT: Duration = Duration::from_secs(30);
+/// also how long failures are remembered for
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// clients whose failures are tracked at once, the one that failed longest ago is forgotten
+const MAX_TRACKED_CLIENTS: usize = 8;
+
+static FAILURES: Mutex<CriticalSectionRawMutex, RefCell<Vec<Failures>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+/// hash of the last Basic credentials that matched, so the browser sending them with every
+/// request doesn't cost a full password hash each time
+static VERIFIED_LOGIN: Mutex<CriticalSectionRawMutex, RefCell<Option<Digest>>> =
+    Mutex::new(RefCell::new(None));
+
+struct Failures {
+    client: IpAddress,
+    count: u8,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Restores the credentials saved before the last reboot
+pub async fn load_credentials() {
+    if let Some(auth) = storage::load::<AuthConfig>(Record::Auth).await {
+        info!("Loaded web credentials, {} api tokens", auth.tokens.len());
+        update_config(|config| config.auth = auth);
+    }
+}
+
+async fn save_credentials() -> Result<(), ()> {
+    VERIFIED_LOGIN.lock(|verified| verified.take());
+    storage::save(Record::Auth, &config().auth).await
+}
+
+/// Saves the credentials with the change made, and only then applies it, so a login or token
+/// is never handed out that would be lost on reboot
+async fn change_credentials(change: impl Fn(&mut AuthConfig)) -> Result<(), ()> {
+    let mut auth = config().auth;
+    change(&mut auth);
+    storage::save(Record::Auth, &auth).await?;
+    VERIFIED_LOGIN.lock(|verified| verified.take());
+    update_config(|config| change(&mut config.auth));
+    Ok(())
+}
+
+/// Removes the password and every token, leaving the web interface open to anyone
+pub async fn clear_credentials() -> Result<(), ()> {
+    warn!("Clearing web credentials");
+    update_config(|config| config.auth = AuthConfig::default());
+    save_credentials().await
+}
+
+/// Checks the credentials of every request to the route once a password is set, and the scope
+/// reading or changing it needs
+#[derive(Clone, Copy)]
+pub struct RequireAuth {
+    read: Scope,
+    change: Scope,
+}
+
+impl RequireAuth {
+    /// Every method of the route needs the scope
+    pub const fn scope(scope: Scope) -> Self {
+        Self {
+            read: scope,
+            change: scope,
+        }
+    }
+
+    /// Print tokens can read the route, only admins can change it
+    pub const fn admin_to_change() -> Self {
+        Self {
+            read: Scope::Print,
+            change: Scope::Admin,
+        }
+    }
+}
+
+impl<PathParameters> Layer<AppState, PathParameters> for RequireAuth {
+    type NextState = AppState;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &AppState,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let required = match request_parts.method() {
+            "GET" | "HEAD" => self.read,
+            _ => self.change,
+        };
+        let header = request_parts
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.as_str().ok());
+
+        let Err(denial) = authorize(state.client, header, required).await else {
+            return next.run(state, path_parameters, response_writer).await;
+        };
+
+        let connection = next.into_connection().await?;
+        if request_parts.path().encoded().starts_with("/api/") {
+            let body = ErrorBody {
+                error: denial.code(),
+                message: denial.as_str(),
+            };
+            Json(body)
+                .into_response()
+                .with_status_code(denial.status())
+                .with_headers(denial.headers(true))
+                .write_to(connection, response_writer)
+                .await
+        } else {
+            (denial.status(), denial.headers(false), denial.as_str())
+                .write_to(connection, response_writer)
+                .await
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+enum Denial {
+    Unauthorized,
+    Forbidden,
+    /// seconds until the client may try again
+    Throttled(u64),
+}
+
+impl Denial {
+    fn status(&self) -> StatusCode {
+        match self {
+            Denial::Unauthorized => StatusCode::UNAUTHORIZED,
+            Denial::Forbidden => StatusCode::FORBIDDEN,
+            Denial::Throttled(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Denial::Unauthorized => "unauthorized",
+            Denial::Forbidden => "forbidden",
+            Denial::Throttled(_) => "too_many_attempts",
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Denial::Unauthorized => "Missing or invalid credentials\n",
+            Denial::Forbidden => "Token is not allowed to do this\n",
+            Denial::Throttled(_) => "Too many failed attempts, try again later\n",
+        }
+    }
+
+    /// The challenge asking for credentials, api clients are asked for a token and browsers
+    /// for a password
+    fn headers(&self, api: bool) -> [Option<(&'static str, String)>; 2] {
+        let challenge = (*self == Denial::Unauthorized).then(|| {
+            let scheme = if api { "Bearer" } else { "Basic" };
+            ("WWW-Authenticate", format!("{scheme} realm=\"scribe\""))
+        });
+        let retry_after = match self {
+            Denial::Throttled(seconds) => Some(("Retry-After", format!("{seconds}"))),
+            _ => None,
+        };
+        [challenge, retry_after]
+    }
+}
+
+async fn authorize(
+    client: Option<IpAddress>,
+    header: Option<&str>,
+    required: Scope,
+) -> Result<(), Denial> {
+    let auth = config().auth;
+    if !auth.enabled() {
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    if let Some(wait) = client.and_then(|client| lockout(client, now)) {
+        return Err(Denial::Throttled(wait.as_secs().max(1)));
+    }
+    // a browser's first request has no credentials, which isn't counted as a failure
+    let header = header.ok_or(Denial::Unauthorized)?;
+    let Some(scope) = verify(&auth, header.trim()).await else {
+        if let Some(client) = client {
+            record_failure(client, now);
+        }
+        return Err(Denial::Unauthorized);
+    };
+    if let Some(client) = client {
+        FAILURES.lock(|failures| failures.borrow_mut().retain(|f| f.client != client));
+    }
+
+    if scope >= required {
+        Ok(())
+    } else {
+        Err(Denial::Forbidden)
+    }
+}
+
+/// The scope of the credentials in an `Authorization` header, `None` if they don't match
+async fn verify(auth: &AuthConfig, header: &str) -> Option<Scope> {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        let hash = sha256(token.trim().as_bytes());
+        // every token is compared so the time taken doesn't tell which one nearly matched
+        return auth
+            .tokens
+            .iter()
+            .filter(|stored| digests_match(&stored.hash, &hash))
+            .map(|stored| stored.scope)
+            .fold(None, |found, scope| found.or(Some(scope)));
+    }
+
+    let login = decode_base64(header.strip_prefix("Basic ")?.trim())?;
+    let password = auth.password.as_ref()?;
+    let login_hash = sha256(&login);
+    let cached = VERIFIED_LOGIN.lock(|verified| {
+        verified
+            .borrow()
+            .is_some_and(|v| digests_match(&v, &login_hash))
+    });
+    if cached {
+        return Some(Scope::Admin);
+    }
+
+    let split = login.iter().position(|byte| *byte == b':')?;
+    let (username, secret) = (&login[..split], &login[split + 1..]);
+    let hash = pbkdf2(secret, &password.salt, password.iterations).await;
+    if username == password.username.as_bytes() && digests_match(&hash, &password.hash) {
+        VERIFIED_LOGIN.lock(|verified| verified.replace(Some(login_hash)));
+        Some(Scope::Admin)
+    } else {
+        None
+    }
+}
+
+/// The name of the stored api token in an `Authorization` header
+pub fn token_name(header: &str) -> Option<TokenName> {
+    let token = header.strip_prefix("Bearer ")?;
+    let hash = sha256(token.trim().as_bytes());
+    config()
+        .auth
+        .tokens
+        .iter()
+        .filter(|stored| digests_match(&stored.hash, &hash))
+        .map(|stored| stored.name.clone())
+        .fold(None, |found, name| found.or(Some(name)))
+}
+
+/// How long the client has to wait before trying again, if it is locked out
+fn lockout(client: IpAddress, now: Instant) -> Option<Duration> {
+    FAILURES.lock(|failures| {
+        let failures = failures.borrow();
+        let until = failures
+            .iter()
+            .find(|failure| failure.client == client)?
+            .locked_until?;
+        (until > now).then(|| until - now)
+    })
+}
+
+fn record_failure(client: IpAddress, now: Instant) {
+    FAILURES.lock(|failures| {
+        let mut failures = failures.borrow_mut();
+        let index = match failures.iter().position(|failure| failure.client == client) {
+            Some(index) => index,
+            None => {
+                if failures.len() >= MAX_TRACKED_CLIENTS {
+                    let oldest = failures
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, failure)| failure.last)
+                        .map_or(0, |(index, _)| index);
+                    failures.remove(oldest);
+                }
+                failures.push(Failures {
+                    client,
+                    count: 0,
+                    last: now,
+                    locked_until: None,
+                });
+                failures.len() - 1
+            }
+        };
+
+        let failure = &mut failures[index];
+        if now - failure.last > MAX_LOCKOUT {
+            failure.count = 0;
+        }
+        failure.count = failure.count.saturating_add(1);
+        failure.last = now;
+        if failure.count >= ALLOWED_FAILURES {
+            let doublings = u32::from(failure.count - ALLOWED_FAILURES).min(5);
+            let wait = Duration::from_ticks(LOCKOUT.as_ticks() << doublings).min(MAX_LOCKOUT);
+            failure.locked_until = Some(now + wait);
+            warn!(
+                "{} failed logins from {}, locked out for {}s",
+                failure.count,
+                client,
+                wait.as_secs()
+            );
+        }
+    });
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let text = text.trim_end_matches('=').as_bytes();
+    if text.len() * 3 / 4 > MAX_BASIC_LENGTH {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        let bits = chunk
+            .iter()
+            .try_fold(0u32, |bits, c| Some(bits << 6 | value(*c)?))?
+            << (6 * (4 - chunk.len()));
+        let decoded = bits.to_be_bytes();
+        // a lone sixth of a byte can't be valid
+        let length = chunk.len().checked_sub(1).filter(|length| *length > 0)?;
+        bytes.extend_from_slice(&decoded[1..1 + length]);
+    }
+    Some(bytes)
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    username: String,
+    password: String,
+}
+
+/// Sets the admin login, turning authentication on
+pub async fn set_password(
+    JsonWithUnescapeBufferSize(data): JsonWithUnescapeBufferSize<NewPassword, 256>,
+) -> Result<StatusCode, ApiError> {
+    let username = Username::try_from(data.username.as_str())
+        .ok()
+        .filter(|username| !username.is_empty() && !username.contains(':'))
+        .ok_or(ApiError::bad_request(
+            "invalid_username",
+            "Username must be 1 to 32 characters without a colon",
+        ))?;
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&data.password.len()) {
+        return Err(ApiError::bad_request(
+            "invalid_password",
+            "Password must be 8 to 64 bytes",
+        ));
+    }
+
+    let mut salt = [0u8; SALT_SIZE];
+    random().fill_bytes(&mut salt);
+    let password = PasswordHash {
+        username,
+        salt,
+        iterations: PASSWORD_ITERATIONS,
+        hash: pbkdf2(data.password.as_bytes(), &salt, PASSWORD_ITERATIONS).await,
+    };
+    info!("Setting the web login for {}", password.username);
+    change_credentials(|auth| auth.password = Some(password.clone()))
+        .await
+        .map_err(|()| NOT_CHANGED)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Turns authentication off, revoking every token with the password
+pub async fn remove_password() -> Result<StatusCode, ApiError> {
+    clear_credentials().await.map_err(|()| NOT_SAVED)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct TokenInfo {
+    name: TokenName,
+    scope: Scope,
+}
+
+pub async fn list_tokens() -> impl IntoResponse {
+    Json(
+        config()
+            .auth
+            .tokens
+            .into_iter()
+            .map(|token| TokenInfo {
+                name: token.name,
+                scope: token.scope,
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+    scope: Scope,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    name: TokenName,
+    scope: Scope,
+    /// only ever shown here, the device keeps just its hash
+    token: String,
+}
+
+pub async fn create_token(
+    JsonWithUnescapeBufferSize(data): JsonWithUnescapeBufferSize<NewToken, 64>,
+) -> Result<impl IntoResponse, ApiError> {
+    let name = TokenName::try_from(data.name.as_str())
+        .ok()
+        .filter(|name| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        })
+        .ok_or(ApiError::bad_request(
+            "invalid_name",
+            "Token name must be 1 to 16 letters, digits, - or _",
+        ))?;
+
+    let auth = config().auth;
+    if !auth.enabled() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "no_password",
+            "Set a password before creating tokens",
+        ));
+    }
+    if auth.tokens.iter().any(|token| token.name == name) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "exists",
+            "A token with this name already exists",
+        ));
+    }
+    if auth.tokens.len() >= MAX_TOKENS {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "too_many_tokens",
+            "Revoke a token before creating another",
+        ));
+    }
+
+    let mut secret = [0u8; TOKEN_SIZE];
+    random().fill_bytes(&mut secret);
+    let mut token = String::with_capacity(TOKEN_SIZE * 2);
+    for byte in secret {
+        let _ = write!(token, "{byte:02x}");
+    }
+    let stored = ApiToken {
+        name: name.clone(),
+        scope: data.scope,
+        hash: sha256(token.as_bytes()),
+    };
+    info!("Creating {} api token {}", stored.scope, stored.name);
+    // also applied to the live credentials, where a token of the name may have been made since
+    change_credentials(|auth| {
+        if !auth.tokens.iter().any(|token| token.name == stored.name) {
+            let _ = auth.tokens.push(stored.clone());
+        }
+    })
+    .await
+    .map_err(|()| NOT_CHANGED)?;
+
+    let created = CreatedToken {
+        name,
+        scope: data.scope,
+        token,
+    };
+    Ok(Json(created)
+        .into_response()
+        .with_status_code(StatusCode::CREATED))
+}
+
+pub async fn revoke_token(name: TokenName) -> Result<StatusCode, ApiError> {
+    let mut found = false;
+    update_config(|config| {
+        let tokens = &mut config.auth.tokens;
+        let before = tokens.len();
+        tokens.retain(|token| token.name != name);
+        found = tokens.len() != before;
+    });
+    if !found {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "No such token",
+        ));
+    }
+    info!("Revoked api token {}", name);
+    save_credentials().await.map_err(|()| NOT_SAVED)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+const NOT_SAVED: ApiError = ApiError::new(
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "not_saved",
+    "Credentials changed but could not be saved, they will be lost on reboot",
+);
+/// logins and tokens are only handed out once they are saved
+const NOT_CHANGED: ApiError = ApiError::new(
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "not_saved",
+    "Credentials could not be saved, nothing was changed",
+);
+
+fn random() -> Rng {
+    Rng::from(esp_hal::rng::Rng::new())
+}
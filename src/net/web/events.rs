@@ -0,0 +1,66 @@
+use embassy_futures::select::{Either, select};
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Timer};
+use picoserve::{
+    io::Write,
+    response::{
+        IntoResponse, Json, StatusCode,
+        sse::{EventSource, EventStream, EventWriter},
+    },
+};
+
+use super::jobs::ApiError;
+use crate::{
+    events::{self, Event, EventListener},
+    power, printer,
+};
+
+/// a comment is sent this often while nothing happens, so proxies and the tcp keep alive don't
+/// drop the stream
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Streams device events as server-sent events, starting with the printer and power state
+pub async fn get_events() -> Result<impl IntoResponse, ApiError> {
+    let listener = events::listen().ok_or(ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "too_many_streams",
+        "Too many event streams are open",
+    ))?;
+    Ok(EventStream(Events(listener)))
+}
+
+struct Events(EventListener);
+
+impl EventSource for Events {
+    async fn write_events<W: Write>(self, mut writer: EventWriter<'_, W>) -> Result<(), W::Error> {
+        let Self(mut listener) = self;
+
+        let current = [
+            Event::Printer {
+                online: printer::printer_online(),
+            },
+            Event::Power {
+                state: power::shutdown_status().as_str(),
+                adc: power::power_level(),
+            },
+        ];
+        for event in current {
+            writer.write_event(event.name(), Json(event)).await?;
+        }
+
+        loop {
+            match select(listener.next_message(), Timer::after(KEEPALIVE_INTERVAL)).await {
+                Either::First(WaitResult::Message(event)) => {
+                    writer.write_event(event.name(), Json(event)).await?
+                }
+                // the client should fetch the state again rather than trust its own
+                Either::First(WaitResult::Lagged(missed)) => {
+                    writer
+                        .write_event("lagged", format_args!("{missed}"))
+                        .await?
+                }
+                Either::Second(()) => writer.write_keepalive().await?,
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use defmt::{info, warn};
+use picoserve::{
+    ResponseSent,
+    io::Read,
+    request::Request,
+    response::{IntoResponse, Json, ResponseWriter, StatusCode},
+    routing::RequestHandlerService,
+    url_encoded::UrlEncodedString,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{AppState, jobs::ApiError};
+use crate::{
+    ota::{OtaError, Update, Version},
+    sha256::{DIGEST_SIZE, Digest},
+};
+
+/// two hex digits for every byte of a sha-256
+const HASH_HEX_SIZE: usize = DIGEST_SIZE * 2;
+
+#[derive(Deserialize)]
+struct FirmwareQuery {
+    sha256: Option<heapless::String<HASH_HEX_SIZE>>,
+}
+
+#[derive(Serialize)]
+struct FirmwareUpdated {
+    version: Version,
+    size: usize,
+    /// the device restarts into the new firmware once the print queue is empty
+    restarting: bool,
+}
+
+/// Writes a firmware image sent as `application/octet-stream` to the ota partition that isn't
+/// running and restarts into it. The image is checked as it arrives, and against the
+/// `sha256` query parameter when one is given.
+pub struct FirmwareUpload;
+
+impl RequestHandlerService<AppState> for FirmwareUpload {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        _state: &AppState,
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let is_supported = request
+            .parts
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.split(b';').next())
+            .is_some_and(|media_type| media_type == "application/octet-stream");
+        let expected = match is_supported {
+            true => expected_digest(request.parts.query().unwrap_or_default()),
+            false => Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                "Expected application/octet-stream",
+            )),
+        };
+        let length = request.body_connection.content_length();
+        let started = match expected {
+            Ok(expected) => Update::begin(length)
+                .await
+                .map(|update| (update, expected))
+                .map_err(ota_error),
+            Err(error) => Err(error),
+        };
+        let (mut update, expected) = match started {
+            Ok(started) => started,
+            Err(error) => {
+                let connection = request.body_connection.finalize().await?;
+                return error.write_to(connection, response_writer).await;
+            }
+        };
+
+        info!("Receiving a {} byte firmware update", length);
+        let mut reader = request.body_connection.body().reader();
+        let mut buffer = [0u8; 512];
+        let written = loop {
+            let read = match reader.read(&mut buffer).await {
+                Ok(0) => break Ok(()),
+                Ok(read) => read,
+                // dropping the update leaves the running firmware selected
+                Err(e) => return Err(e),
+            };
+            if let Err(e) = update.write(&buffer[..read]).await {
+                break Err(e);
+            }
+        };
+        let result = match written {
+            Ok(()) => update.finish(expected).await,
+            Err(e) => Err(e),
+        };
+
+        let connection = request.body_connection.finalize().await?;
+        match result {
+            Ok(version) => {
+                let updated = FirmwareUpdated {
+                    version,
+                    size: length,
+                    restarting: true,
+                };
+                Json(updated).write_to(connection, response_writer).await
+            }
+            Err(e) => {
+                warn!("Firmware update failed: {}", e.as_str());
+                ota_error(e).write_to(connection, response_writer).await
+            }
+        }
+    }
+}
+
+/// The hash the image must have, from the `sha256` query parameter
+fn expected_digest(query: UrlEncodedString<'_>) -> Result<Option<Digest>, ApiError> {
+    let invalid = ApiError::bad_request("invalid_sha256", "sha256 must be 64 hex digits");
+    let query = picoserve::url_encoded::deserialize_form::<FirmwareQuery>(query)
+        .map_err(|_| ApiError::bad_request("invalid_query", "Invalid query string"))?;
+    match query.sha256 {
+        Some(hex) => parse_digest(&hex).map(Some).ok_or(invalid),
+        None => Ok(None),
+    }
+}
+
+fn parse_digest(hex: &str) -> Option<Digest> {
+    let mut digest = [0u8; DIGEST_SIZE];
+    if hex.len() != HASH_HEX_SIZE || !hex.is_ascii() {
+        return None;
+    }
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn ota_error(error: OtaError) -> ApiError {
+    let (status, code) = match error {
+        OtaError::NoOtaPartitions => (StatusCode::NOT_IMPLEMENTED, "no_ota_partitions"),
+        OtaError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "too_large"),
+        OtaError::InvalidImage => (StatusCode::BAD_REQUEST, "invalid_image"),
+        OtaError::WrongChip => (StatusCode::BAD_REQUEST, "wrong_chip"),
+        OtaError::NotAnApp => (StatusCode::BAD_REQUEST, "not_an_app"),
+        OtaError::HashMismatch => (StatusCode::BAD_REQUEST, "hash_mismatch"),
+        OtaError::Truncated => (StatusCode::BAD_REQUEST, "truncated"),
+        OtaError::Flash => (StatusCode::INTERNAL_SERVER_ERROR, "flash_error"),
+        OtaError::InProgress => (StatusCode::CONFLICT, "update_in_progress"),
+        OtaError::Unconfirmed => (StatusCode::CONFLICT, "unconfirmed"),
+    };
+    ApiError::new(status, code, error.as_str())
+}
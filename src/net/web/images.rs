@@ -0,0 +1,328 @@
+use defmt::{info, warn};
+use picoserve::{
+    ResponseSent,
+    extract::FromRequestParts,
+    io::Read,
+    request::Request,
+    response::{IntoResponse, Json, ResponseWriter, StatusCode},
+    routing::RequestHandlerService,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    AppState, Refusal, Submitter,
+    jobs::{ApiError, deferral, limit_error},
+    multipart::{self, Multipart, MultipartError},
+};
+use crate::{
+    dedup::{self, Duplicate},
+    limits::{self, MAX_DEFERRAL},
+    printer::{
+        self, ImageError, ImageFormat, ImageOptions, ImageSource, JobOptions, JobPayload,
+        JobSource, MAX_QUEUED, MAX_QUEUED_IMAGES, Priority, QueuedJob, decode_image,
+    },
+};
+
+/// longest option value, enough for `floyd-steinberg`
+const VALUE_SIZE: usize = 16;
+
+type OptionValue = heapless::String<VALUE_SIZE>;
+
+/// The same options as the form fields
+#[derive(Deserialize)]
+struct ImageQuery {
+    width: Option<OptionValue>,
+    dither: Option<OptionValue>,
+    brightness: Option<OptionValue>,
+    rotate: Option<OptionValue>,
+}
+
+#[derive(Serialize)]
+struct ImageAccepted {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<ImageFormat>,
+    /// printed size in dots
+    width: usize,
+    rows: usize,
+    /// `None` for a repeat of an image that was not given an id
+    id: Option<u32>,
+    /// jobs that will print before this one, `None` once it has left the queue
+    position: Option<usize>,
+    /// the idempotency key was used for an image that has already been queued
+    duplicate: bool,
+    /// seconds the sender's rate limit holds the image back for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deferred: Option<u64>,
+}
+
+impl ImageAccepted {
+    fn duplicate(duplicate: Duplicate) -> Self {
+        let original = duplicate.job();
+        info!("Image repeats job {}", original);
+        Self {
+            format: None,
+            width: 0,
+            rows: 0,
+            id: original,
+            position: original.and_then(printer::queue_position),
+            duplicate: true,
+            deferred: None,
+        }
+    }
+}
+
+/// Queues the file of a `multipart/form-data` upload. Options come from the query string or
+/// from fields sent before the file, the image is decoded as it arrives so only the dithered
+/// result has to fit in memory.
+pub struct ImageUpload;
+
+impl RequestHandlerService<AppState> for ImageUpload {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &AppState,
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let boundary = request
+            .parts
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.as_str().ok())
+            .and_then(multipart::boundary);
+        let admitted = match boundary {
+            Some(boundary) => submitter(state, &request)
+                .await
+                .map(|submitter| (boundary, submitter)),
+            None => Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                "Expected multipart/form-data",
+            )),
+        };
+        let mut options = ImageOptions::default();
+        let admitted = admitted.and_then(|admitted| {
+            let query = picoserve::url_encoded::deserialize_form::<ImageQuery>(
+                request.parts.query().unwrap_or_default(),
+            )
+            .map_err(|_| ApiError::bad_request("invalid_query", "Invalid query string"))?;
+            let fields = [
+                ("width", query.width),
+                ("dither", query.dither),
+                ("brightness", query.brightness),
+                ("rotate", query.rotate),
+            ];
+            for (name, value) in fields {
+                apply_option(&mut options, name, value.as_deref().unwrap_or_default())?;
+            }
+            Ok(admitted)
+        });
+        let (boundary, submitter) = match admitted {
+            Ok((boundary, Ok(submitter))) => (boundary, submitter),
+            Ok((_, Err(duplicate))) => {
+                let connection = request.body_connection.finalize().await?;
+                let duplicate = ImageAccepted::duplicate(duplicate);
+                return Json(duplicate).write_to(connection, response_writer).await;
+            }
+            Err(error) => {
+                let connection = request.body_connection.finalize().await?;
+                return error.write_to(connection, response_writer).await;
+            }
+        };
+
+        let reader = request.body_connection.body().reader();
+        let mut form = Multipart::new(reader, &boundary);
+        let decoded = loop {
+            let part = match form.next_part().await {
+                Ok(Some(part)) => part,
+                Ok(None) => break Err(ApiError::bad_request("no_image", "No image file was sent")),
+                Err(MultipartError::Read(e)) => return Err(e),
+                Err(MultipartError::Malformed) => {
+                    break Err(ApiError::bad_request(
+                        "invalid_form",
+                        "Malformed multipart form",
+                    ));
+                }
+            };
+
+            if !part.is_file {
+                match read_field(&mut form).await {
+                    Ok(Some(value)) => match apply_option(&mut options, &part.name, &value) {
+                        Ok(()) => continue,
+                        Err(error) => break Err(error),
+                    },
+                    Ok(None) => break Err(invalid_option()),
+                    Err(MultipartError::Read(e)) => return Err(e),
+                    Err(MultipartError::Malformed) => {
+                        break Err(ApiError::bad_request(
+                            "invalid_form",
+                            "Malformed multipart form",
+                        ));
+                    }
+                }
+            }
+
+            info!("Receiving image upload: {}", options);
+            let mut file = FilePart {
+                form: &mut form,
+                error: None,
+            };
+            let result = decode_image(&mut file, &options).await;
+            if let Some(e) = file.error {
+                return Err(e);
+            }
+            break result.map_err(image_error);
+        };
+        drop(form);
+
+        let connection = request.body_connection.finalize().await?;
+        let (format, image) = match decoded {
+            Ok(decoded) => decoded,
+            Err(error) => return error.write_to(connection, response_writer).await,
+        };
+
+        // only an image that decoded is taken from the rate limit and given an id
+        let not_before = match submitter.check(None, MAX_DEFERRAL) {
+            Ok(not_before) => not_before,
+            Err(Refusal::Duplicate(duplicate)) => {
+                let duplicate = ImageAccepted::duplicate(duplicate);
+                return Json(duplicate).write_to(connection, response_writer).await;
+            }
+            Err(Refusal::Limited(e)) => {
+                return limit_error(e).write_to(connection, response_writer).await;
+            }
+        };
+        let id = printer::reserve_job_id();
+        submitter.remember(None, Some(id));
+        let (width, rows) = (image.width(), image.rows());
+        let queued = QueuedJob {
+            id,
+            payload: JobPayload::Image(image),
+            options: JobOptions {
+                requesters: submitter.requesters.clone(),
+                ..JobOptions::new(JobSource::Web)
+            },
+            priority: Priority::Normal,
+            copies: 1,
+            not_before,
+        };
+        // the queue may have filled while the image was uploading
+        let Ok(position) = printer::enqueue(queued) else {
+            dedup::forget_job(id);
+            limits::refund(&submitter.requesters);
+            return ApiError::QUEUE_FULL
+                .write_to(connection, response_writer)
+                .await;
+        };
+
+        let accepted = ImageAccepted {
+            format: Some(format),
+            width,
+            rows,
+            id: Some(id),
+            position: Some(position),
+            duplicate: false,
+            deferred: deferral(not_before),
+        };
+        Json(accepted)
+            .into_response()
+            .with_status_code(StatusCode::ACCEPTED)
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// Who sent the upload, `Err` if it repeats an idempotency key already used for an image. The
+/// queue is checked here so a full one refuses the image before it is uploaded.
+async fn submitter<R: Read>(
+    state: &AppState,
+    request: &Request<'_, R>,
+) -> Result<Result<Submitter, Duplicate>, ApiError> {
+    let submitter = Submitter::from_request_parts(state, &request.parts)
+        .await
+        .map_err(|_| ApiError::bad_request("invalid_key", "Idempotency key is too long"))?;
+    if let Err(duplicate) = dedup::check(&submitter.submission(None)) {
+        return Ok(Err(duplicate));
+    }
+    if printer::queue_length() >= MAX_QUEUED || printer::queued_images() >= MAX_QUEUED_IMAGES {
+        return Err(ApiError::QUEUE_FULL);
+    }
+    Ok(Ok(submitter))
+}
+
+fn apply_option(options: &mut ImageOptions, name: &str, value: &str) -> Result<(), ApiError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    match name {
+        "width" => options.width = value.parse().map_err(|_| invalid_option())?,
+        "dither" => options.dither = value.parse().map_err(|()| invalid_option())?,
+        "brightness" => {
+            options.brightness = value
+                .parse()
+                .ok()
+                .filter(|brightness| (-100..=100).contains(brightness))
+                .ok_or_else(invalid_option)?;
+        }
+        "rotate" => options.rotation = value.parse().map_err(|()| invalid_option())?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn invalid_option() -> ApiError {
+    ApiError::bad_request("invalid_option", "Invalid image option")
+}
+
+/// The value of a form field, `None` if it is longer than any option
+async fn read_field<R: Read>(
+    form: &mut Multipart<R>,
+) -> Result<Option<OptionValue>, MultipartError<R::Error>> {
+    let mut value = heapless::Vec::<u8, VALUE_SIZE>::new();
+    let mut chunk = [0; VALUE_SIZE];
+    loop {
+        let read = form.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(OptionValue::from_utf8(value).ok());
+        }
+        if value.extend_from_slice(&chunk[..read]).is_err() {
+            return Ok(None);
+        }
+    }
+}
+
+fn image_error(error: ImageError) -> ApiError {
+    warn!("Image upload failed: {}", error);
+    let (status, code) = match error {
+        ImageError::UnknownFormat => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unknown_format"),
+        ImageError::Unsupported => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_image"),
+        ImageError::Invalid => (StatusCode::BAD_REQUEST, "invalid_image"),
+        ImageError::Truncated | ImageError::Disconnected => {
+            (StatusCode::BAD_REQUEST, "truncated_image")
+        }
+        ImageError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "image_too_large"),
+        ImageError::OutOfMemory => (StatusCode::SERVICE_UNAVAILABLE, "out_of_memory"),
+    };
+    ApiError::new(status, code, error.as_str())
+}
+
+/// The uploaded file, keeping any connection error to hand back to picoserve
+struct FilePart<'a, R: Read> {
+    form: &'a mut Multipart<R>,
+    error: Option<R::Error>,
+}
+
+impl<R: Read> ImageSource for FilePart<'_, R> {
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ImageError> {
+        match self.form.read(buffer).await {
+            Ok(read) => Ok(read),
+            Err(MultipartError::Read(e)) => {
+                self.error = Some(e);
+                Err(ImageError::Disconnected)
+            }
+            Err(MultipartError::Malformed) => Err(ImageError::Truncated),
+        }
+    }
+}
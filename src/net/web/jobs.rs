@@ -0,0 +1,435 @@
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use defmt::info;
+use embassy_time::Instant;
+use picoserve::{
+    ResponseSent,
+    extract::{FromRequest, FromRequestParts, JsonWithUnescapeBufferSize},
+    io::Read,
+    request::{RequestBody, RequestParts},
+    response::{Connection, IntoResponse, Json, ResponseWriter, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{AppState, FORM_DATA_SIZE, OptionText, Refusal, Submitter, Svg, parse_option};
+use crate::{
+    dedup::{self, MAX_KEY_LENGTH},
+    limits::{self, LimitError, MAX_DEFERRAL},
+    printer::{
+        self, JobOptions, JobPayload, JobRecord, JobSource, JobState, MAX_COPIES, MAX_QUEUED,
+        Priority, QueuedJob, Style, TemplateError, TextFormat,
+    },
+};
+
+/// An error answered as `{"error": code, "message": text}`, the code is meant for programs to
+/// match on and the message for people
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: &'static str,
+}
+
+impl ApiError {
+    pub(super) const fn new(status: StatusCode, code: &'static str, message: &'static str) -> Self {
+        Self {
+            status,
+            code,
+            message,
+        }
+    }
+
+    pub(super) const fn bad_request(code: &'static str, message: &'static str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    const NOT_FOUND: Self = Self::new(StatusCode::NOT_FOUND, "not_found", "No such job");
+    pub(super) const QUEUE_FULL: Self = Self::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "queue_full",
+        "Print queue is full, try again later",
+    );
+}
+
+#[derive(Serialize)]
+pub(super) struct ErrorBody {
+    pub(super) error: &'static str,
+    pub(super) message: &'static str,
+}
+
+impl IntoResponse for ApiError {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let body = ErrorBody {
+            error: self.code,
+            message: self.message,
+        };
+        Json(body)
+            .into_response()
+            .with_status_code(self.status)
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// `template` is printed as plain text once its placeholders are filled in
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Plain,
+    Markdown,
+    Template,
+}
+
+/// Paper handling, left out fields use the configured defaults
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Layout {
+    leading_feed: Option<OptionText>,
+    trailing_feed: Option<OptionText>,
+    cut: Option<OptionText>,
+    header: Option<bool>,
+    footer: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct NewJob {
+    text: String,
+    #[serde(default)]
+    format: Format,
+    /// values for the placeholders of a template
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    #[serde(default)]
+    style: Style,
+    #[serde(default)]
+    layout: Layout,
+    #[serde(default)]
+    priority: Priority,
+    copies: Option<u8>,
+    /// same as the `Idempotency-Key` header
+    idempotency_key: Option<String>,
+}
+
+impl NewJob {
+    fn options(&self) -> Result<JobOptions, ApiError> {
+        let invalid = |()| ApiError::bad_request("invalid_layout", "Invalid layout option");
+        let layout = &self.layout;
+
+        Ok(JobOptions {
+            leading_feed: parse_option(&layout.leading_feed).map_err(invalid)?,
+            trailing_feed: parse_option(&layout.trailing_feed).map_err(invalid)?,
+            cut: parse_option(&layout.cut).map_err(invalid)?,
+            header: layout.header,
+            footer: layout.footer,
+            format: match self.format {
+                Format::Markdown => TextFormat::Markdown,
+                Format::Plain | Format::Template => TextFormat::Plain,
+            },
+            style: self.style,
+            ..JobOptions::new(JobSource::Web)
+        })
+    }
+}
+
+/// The sender and body of a job submission, with both rejected as json errors
+pub struct JobRequest {
+    submitter: Submitter,
+    job: NewJob,
+}
+
+impl<'r> FromRequest<'r, AppState> for JobRequest {
+    type Rejection = ApiError;
+
+    async fn from_request<R: Read>(
+        state: &'r AppState,
+        request_parts: RequestParts<'r>,
+        request_body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let mut submitter = Submitter::from_request_parts(state, &request_parts)
+            .await
+            .map_err(|_| ApiError::bad_request("invalid_key", "Idempotency key is too long"))?;
+
+        if !request_body.entire_body_fits_into_buffer() {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too_large",
+                "Request body is too large, stream long documents to /print",
+            ));
+        }
+        let JsonWithUnescapeBufferSize(job) =
+            JsonWithUnescapeBufferSize::<NewJob, FORM_DATA_SIZE>::from_request(
+                state,
+                request_parts,
+                request_body,
+            )
+            .await
+            .map_err(|_| ApiError::bad_request("invalid_json", "Invalid job json"))?;
+
+        if let Some(key) = &job.idempotency_key {
+            if key.len() > MAX_KEY_LENGTH {
+                return Err(ApiError::bad_request(
+                    "invalid_key",
+                    "Idempotency key is too long",
+                ));
+            }
+            submitter.key = Some(key.clone());
+        }
+
+        Ok(Self { submitter, job })
+    }
+}
+
+#[derive(Serialize)]
+struct JobAccepted {
+    /// `None` for a repeat of a job that was not given an id up front
+    id: Option<u32>,
+    /// jobs that will print before this one, `None` once it has left the queue
+    position: Option<usize>,
+    duplicate: bool,
+    /// seconds the sender's rate limit holds the job back for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deferred: Option<u64>,
+}
+
+/// Seconds until a job admitted to start at `at` may print, `None` if it may now
+pub(super) fn deferral(at: Instant) -> Option<u64> {
+    let now = Instant::now();
+    (at > now).then(|| (at - now).as_secs().max(1))
+}
+
+pub async fn create_job(request: JobRequest) -> Result<impl IntoResponse, ApiError> {
+    let JobRequest { submitter, mut job } = request;
+    let options = job.options()?;
+    let copies = job.copies.unwrap_or(1);
+    if !(1..=MAX_COPIES).contains(&copies) {
+        return Err(ApiError::bad_request(
+            "invalid_copies",
+            "Copies must be between 1 and 5",
+        ));
+    }
+
+    // rendered with the id the job will take, so what is checked is what prints
+    let next_id = printer::next_job_id();
+    let text = match job.format {
+        Format::Template => {
+            printer::render_template(&job.text, &job.fields, next_id).map_err(template_error)?
+        }
+        Format::Plain | Format::Markdown => core::mem::take(&mut job.text),
+    };
+    if text.trim().is_empty() {
+        return Err(ApiError::bad_request(
+            "empty_text",
+            "Job has no text to print",
+        ));
+    }
+    if text.len() > options.max_length() {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too_long",
+            "Print job is too long",
+        ));
+    }
+    if printer::queue_length() >= MAX_QUEUED {
+        return Err(ApiError::QUEUE_FULL);
+    }
+
+    let not_before = match submitter.check(Some(text.as_bytes()), MAX_DEFERRAL) {
+        Ok(not_before) => not_before,
+        Err(Refusal::Duplicate(duplicate)) => {
+            let original = duplicate.job();
+            info!("Job repeats job {}", original);
+            let accepted = JobAccepted {
+                id: original,
+                position: original.and_then(printer::queue_position),
+                duplicate: true,
+                deferred: None,
+            };
+            return Ok(Json(accepted)
+                .into_response()
+                .with_status_code(StatusCode::OK));
+        }
+        Err(Refusal::Limited(e)) => return Err(limit_error(e)),
+    };
+
+    // only a job that will be queued takes an id, the one rendered into the text as nothing
+    // was awaited since
+    let id = printer::reserve_job_id();
+    submitter.remember(Some(text.as_bytes()), Some(id));
+    let queued = QueuedJob {
+        id,
+        payload: JobPayload::Text(text),
+        options: JobOptions {
+            requesters: submitter.requesters.clone(),
+            ..options
+        },
+        priority: job.priority,
+        copies,
+        not_before,
+    };
+    // nothing was awaited since the queue length was checked, this only fails if that changes
+    let Ok(position) = printer::enqueue(queued) else {
+        dedup::forget_job(id);
+        limits::refund(&submitter.requesters);
+        return Err(ApiError::QUEUE_FULL);
+    };
+    let accepted = JobAccepted {
+        id: Some(id),
+        position: Some(position),
+        duplicate: false,
+        deferred: deferral(not_before),
+    };
+
+    Ok(Json(accepted)
+        .into_response()
+        .with_status_code(StatusCode::ACCEPTED))
+}
+
+/// Renders the job as it would print, without queueing it
+pub async fn preview_job(
+    JsonWithUnescapeBufferSize(job): JsonWithUnescapeBufferSize<NewJob, FORM_DATA_SIZE>,
+) -> Result<Svg, ApiError> {
+    let options = job.options()?;
+    let text = match job.format {
+        // the id isn't known until the job is queued
+        Format::Template => {
+            printer::render_template(&job.text, &job.fields, 0).map_err(template_error)?
+        }
+        Format::Plain | Format::Markdown => job.text,
+    };
+    Ok(Svg(printer::render_preview(&text, &options)))
+}
+
+#[derive(Serialize)]
+struct JobInfo {
+    id: u32,
+    state: JobState,
+    /// jobs that will print before it, while it is queued
+    position: Option<usize>,
+    source: String,
+    /// unix times, `None` while the clock was not synced
+    submitted: Option<u64>,
+    started: Option<u64>,
+    finished: Option<u64>,
+    bytes: usize,
+    lines: u32,
+    error: Option<&'static str>,
+    reprintable: bool,
+}
+
+impl From<JobRecord> for JobInfo {
+    fn from(record: JobRecord) -> Self {
+        Self {
+            id: record.id,
+            state: record.state,
+            position: printer::queue_position(record.id),
+            source: format!("{}", record.source),
+            submitted: record.submitted,
+            started: record.started,
+            finished: record.finished,
+            bytes: record.bytes,
+            lines: record.lines,
+            error: record.error.map(|error| error.as_str()),
+            reprintable: record.can_reprint(),
+        }
+    }
+}
+
+pub async fn list_jobs() -> impl IntoResponse {
+    Json(
+        printer::recent_jobs()
+            .into_iter()
+            .map(JobInfo::from)
+            .collect::<Vec<_>>(),
+    )
+}
+
+pub async fn get_job(id: u32) -> Result<impl IntoResponse, ApiError> {
+    let record = printer::job_record(id).ok_or(ApiError::NOT_FOUND)?;
+    Ok(Json(JobInfo::from(record)))
+}
+
+/// Cancels a queued job, or forgets a finished one
+pub async fn delete_job(id: u32) -> Result<StatusCode, ApiError> {
+    if printer::cancel(id) {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let record = printer::job_record(id).ok_or(ApiError::NOT_FOUND)?;
+    // a queued job that is no longer in the queue is just about to print
+    if matches!(record.state, JobState::Queued | JobState::Printing) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "printing",
+            "Job is already printing",
+        ));
+    }
+    printer::forget_job(id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Queues a recent job again under a new id, charged to whoever asked for the reprint
+pub async fn reprint_job(id: u32, submitter: Submitter) -> Result<impl IntoResponse, ApiError> {
+    let (text, options) = printer::reprint_of(id).ok_or_else(|| match printer::job_record(id) {
+        Some(_) => ApiError::new(
+            StatusCode::CONFLICT,
+            "not_reprintable",
+            "Job was too long to keep for reprinting",
+        ),
+        None => ApiError::NOT_FOUND,
+    })?;
+    if printer::queue_length() >= MAX_QUEUED {
+        return Err(ApiError::QUEUE_FULL);
+    }
+    let not_before = limits::schedule(&submitter.requesters, MAX_DEFERRAL).map_err(limit_error)?;
+
+    let id = printer::reserve_job_id();
+    let reprint = QueuedJob {
+        id,
+        payload: JobPayload::Text(text),
+        options: JobOptions {
+            requesters: submitter.requesters.clone(),
+            ..options
+        },
+        priority: Priority::Normal,
+        copies: 1,
+        not_before,
+    };
+    let Ok(position) = printer::enqueue(reprint) else {
+        limits::refund(&submitter.requesters);
+        return Err(ApiError::QUEUE_FULL);
+    };
+    let accepted = JobAccepted {
+        id: Some(id),
+        position: Some(position),
+        duplicate: false,
+        deferred: deferral(not_before),
+    };
+    Ok(Json(accepted)
+        .into_response()
+        .with_status_code(StatusCode::ACCEPTED))
+}
+
+fn template_error(error: TemplateError) -> ApiError {
+    let code = match error {
+        TemplateError::UnknownField => "unknown_field",
+        TemplateError::Unterminated => "unterminated_placeholder",
+    };
+    ApiError::bad_request(code, error.as_str().trim_end())
+}
+
+pub(super) fn limit_error(error: LimitError) -> ApiError {
+    let code = match error {
+        LimitError::RateLimited => "rate_limited",
+        LimitError::DailyBytes => "daily_bytes",
+        LimitError::DailyLines => "daily_lines",
+        LimitError::DailyPaper => "daily_paper",
+    };
+    ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        code,
+        error.as_str().trim_end(),
+    )
+}
@@ -0,0 +1,365 @@
+use picoserve::io::Read;
+
+/// longest boundary rfc 2046 allows
+const MAX_BOUNDARY_SIZE: usize = 70;
+/// `CRLF--` before the boundary
+const DELIMITER_SIZE: usize = MAX_BOUNDARY_SIZE + 4;
+const BUFFER_SIZE: usize = 512;
+/// longest part header line kept, longer ones are rejected
+const HEADER_LINE_SIZE: usize = 256;
+const NAME_SIZE: usize = 32;
+
+pub type FieldName = heapless::String<NAME_SIZE>;
+pub type Boundary = heapless::String<MAX_BOUNDARY_SIZE>;
+
+#[derive(Debug)]
+pub enum MultipartError<E> {
+    Read(E),
+    Malformed,
+}
+
+impl<E> From<E> for MultipartError<E> {
+    fn from(error: E) -> Self {
+        MultipartError::Read(error)
+    }
+}
+
+/// The headers of a part that matter here
+pub struct Part {
+    pub name: FieldName,
+    /// whether the part is an uploaded file rather than a field
+    pub is_file: bool,
+}
+
+/// The boundary parameter of a `multipart/form-data` content type
+pub fn boundary(content_type: &str) -> Option<Boundary> {
+    let mut parameters = content_type.split(';');
+    let media_type = parameters.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = parameters.find_map(|parameter| {
+        let (name, value) = parameter.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("boundary").then_some(value)
+    })?;
+    let boundary = boundary.trim_matches('"');
+    if boundary.is_empty() {
+        return None;
+    }
+    Boundary::try_from(boundary).ok()
+}
+
+/// Splits a `multipart/form-data` body into its parts as it is read, only a small window of the
+/// body is buffered however large the parts are.
+pub struct Multipart<R> {
+    reader: R,
+    /// `CRLF--boundary`, the body is read as though it started with a CRLF so the first
+    /// boundary looks like every other one
+    delimiter: heapless::Vec<u8, DELIMITER_SIZE>,
+    buffer: [u8; BUFFER_SIZE],
+    start: usize,
+    end: usize,
+    /// the body has been read to the end
+    ended: bool,
+    /// the delimiter ending the current part has been consumed
+    at_delimiter: bool,
+    finished: bool,
+}
+
+impl<R: Read> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        let mut delimiter = heapless::Vec::new();
+        let _ = delimiter.extend_from_slice(b"\r\n--");
+        let _ = delimiter.extend_from_slice(boundary.as_bytes());
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer[..2].copy_from_slice(b"\r\n");
+
+        Self {
+            reader,
+            delimiter,
+            buffer,
+            start: 0,
+            end: 2,
+            ended: false,
+            at_delimiter: false,
+            finished: false,
+        }
+    }
+
+    /// Skips whatever is left of the current part and reads the headers of the next one,
+    /// `None` after the last part
+    pub async fn next_part(&mut self) -> Result<Option<Part>, MultipartError<R::Error>> {
+        let mut discard = [0; 64];
+        while !self.finished && !self.at_delimiter {
+            self.read(&mut discard).await?;
+        }
+        if self.finished {
+            return Ok(None);
+        }
+        self.at_delimiter = false;
+
+        // `--` straight after the boundary closes the body
+        if self.byte().await? == b'-' && self.byte().await? == b'-' {
+            self.finished = true;
+            return Ok(None);
+        }
+        // the rest of the boundary line is only padding
+        while self.byte().await? != b'\n' {}
+
+        let mut part = Part {
+            name: FieldName::new(),
+            is_file: false,
+        };
+        let mut line = heapless::Vec::<u8, HEADER_LINE_SIZE>::new();
+        loop {
+            self.read_line(&mut line).await?;
+            if line.is_empty() {
+                return Ok(Some(part));
+            }
+            let line = core::str::from_utf8(&line).map_err(|_| MultipartError::Malformed)?;
+            let Some((header, value)) = line.split_once(':') else {
+                return Err(MultipartError::Malformed);
+            };
+            if header.trim().eq_ignore_ascii_case("Content-Disposition") {
+                for parameter in value.split(';').skip(1) {
+                    match parameter.trim().split_once('=') {
+                        Some(("name", name)) => {
+                            part.name = FieldName::try_from(name.trim_matches('"'))
+                                .map_err(|_| MultipartError::Malformed)?;
+                        }
+                        Some(("filename", _)) => part.is_file = true,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the body of the current part, 0 once it ends
+    pub async fn read(&mut self, out: &mut [u8]) -> Result<usize, MultipartError<R::Error>> {
+        if self.at_delimiter || self.finished || out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let available = &self.buffer[self.start..self.end];
+            let found = available
+                .windows(self.delimiter.len())
+                .position(|window| window == self.delimiter.as_slice());
+            let safe = match found {
+                Some(0) => {
+                    self.start += self.delimiter.len();
+                    self.at_delimiter = true;
+                    return Ok(0);
+                }
+                Some(position) => position,
+                // the tail may be the start of a delimiter cut off by the end of the buffer
+                None => available.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if safe > 0 {
+                let len = safe.min(out.len());
+                out[..len].copy_from_slice(&available[..len]);
+                self.start += len;
+                return Ok(len);
+            }
+            if !self.fill().await? {
+                // the closing boundary never came
+                return Err(MultipartError::Malformed);
+            }
+        }
+    }
+
+    /// Reads more of the body into the buffer, `false` once it has all been read
+    async fn fill(&mut self) -> Result<bool, MultipartError<R::Error>> {
+        if self.ended {
+            return Ok(false);
+        }
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        let read = self.reader.read(&mut self.buffer[self.end..]).await?;
+        self.end += read;
+        self.ended = read == 0;
+        Ok(read > 0)
+    }
+
+    /// The next byte outside of part bodies
+    async fn byte(&mut self) -> Result<u8, MultipartError<R::Error>> {
+        if self.start == self.end && !self.fill().await? {
+            return Err(MultipartError::Malformed);
+        }
+        let byte = self.buffer[self.start];
+        self.start += 1;
+        Ok(byte)
+    }
+
+    /// A header line without its line ending
+    async fn read_line(
+        &mut self,
+        line: &mut heapless::Vec<u8, HEADER_LINE_SIZE>,
+    ) -> Result<(), MultipartError<R::Error>> {
+        line.clear();
+        loop {
+            match self.byte().await? {
+                b'\n' => break,
+                byte => line.push(byte).map_err(|_| MultipartError::Malformed)?,
+            }
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use alloc::{format, string::String, vec::Vec};
+    use picoserve::io::ErrorType;
+
+    use super::*;
+
+    /// A body handed over a few bytes at a time, so delimiters get split between reads
+    struct Chunks<'a> {
+        data: &'a [u8],
+        size: usize,
+    }
+
+    impl ErrorType for Chunks<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Chunks<'_> {
+        async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Infallible> {
+            let len = self.data.len().min(self.size).min(buffer.len());
+            buffer[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn multipart(data: &[u8], size: usize) -> Multipart<Chunks<'_>> {
+        Multipart::new(Chunks { data, size }, "XyZ")
+    }
+
+    /// The rest of the current part, read a few bytes at a time
+    async fn body(multipart: &mut Multipart<Chunks<'_>>) -> Result<Vec<u8>, ()> {
+        let mut body = Vec::new();
+        let mut buffer = [0; 5];
+        loop {
+            match multipart.read(&mut buffer).await.map_err(|_| ())? {
+                0 => return Ok(body),
+                len => body.extend_from_slice(&buffer[..len]),
+            }
+        }
+    }
+
+    /// A field and a file, the file holding lines that nearly match the delimiter
+    const FORM: &[u8] = b"--XyZ\r\n\
+        Content-Disposition: form-data; name=\"dither\"\r\n\
+        \r\n\
+        atkinson\r\n\
+        --XyZ  \r\n\
+        content-disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+        Content-Type: image/png\r\n\
+        \r\n\
+        x--XyZ\r\n--XyY\r\n--Xy\r\n\
+        --XyZ--\r\n";
+
+    #[test]
+    fn parses_the_boundary() {
+        let parse = |content_type| boundary(content_type).map(|b| String::from(b.as_str()));
+        assert_eq!(
+            parse("multipart/form-data; boundary=XyZ").as_deref(),
+            Some("XyZ")
+        );
+        assert_eq!(
+            parse("Multipart/Form-Data;charset=utf-8; BOUNDARY=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(parse("multipart/form-data"), None);
+        assert_eq!(parse("multipart/form-data; boundary=\"\""), None);
+        assert_eq!(parse("multipart/mixed; boundary=XyZ"), None);
+        let long = format!("multipart/form-data; boundary={}", "x".repeat(71));
+        assert_eq!(parse(&long), None);
+    }
+
+    #[test]
+    fn splits_parts_across_reads() {
+        for size in 1..=FORM.len() {
+            embassy_futures::block_on(async {
+                let mut form = multipart(FORM, size);
+                let part = form.next_part().await.unwrap().unwrap();
+                assert_eq!((part.name.as_str(), part.is_file), ("dither", false));
+                assert_eq!(body(&mut form).await.unwrap(), b"atkinson");
+
+                let part = form.next_part().await.unwrap().unwrap();
+                assert_eq!((part.name.as_str(), part.is_file), ("file", true));
+                assert_eq!(body(&mut form).await.unwrap(), b"x--XyZ\r\n--XyY\r\n--Xy");
+                assert!(form.next_part().await.unwrap().is_none());
+                assert!(form.next_part().await.unwrap().is_none());
+            });
+        }
+    }
+
+    #[test]
+    fn skips_unread_parts() {
+        embassy_futures::block_on(async {
+            let mut form = multipart(FORM, 3);
+            form.next_part().await.unwrap().unwrap();
+            let part = form.next_part().await.unwrap().unwrap();
+            assert_eq!(part.name.as_str(), "file");
+            assert!(form.next_part().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        embassy_futures::block_on(async {
+            // the closing boundary never comes
+            let mut form = multipart(b"--XyZ\r\n\r\nno end", 4);
+            form.next_part().await.unwrap().unwrap();
+            assert!(body(&mut form).await.is_err());
+
+            // the body ends inside the headers, or before any boundary
+            let mut form = multipart(b"--XyZ\r\nContent-Disp", 4);
+            assert!(matches!(
+                form.next_part().await,
+                Err(MultipartError::Malformed)
+            ));
+            let mut form = multipart(b"", 4);
+            assert!(matches!(
+                form.next_part().await,
+                Err(MultipartError::Malformed)
+            ));
+
+            // a header without a colon, and one too long to keep
+            let mut form = multipart(b"--XyZ\r\nno colon\r\n\r\n", 4);
+            assert!(matches!(
+                form.next_part().await,
+                Err(MultipartError::Malformed)
+            ));
+            let mut data = b"--XyZ\r\nX-Long: ".to_vec();
+            data.extend_from_slice(&[b'a'; HEADER_LINE_SIZE]);
+            let mut form = multipart(&data, 64);
+            assert!(matches!(
+                form.next_part().await,
+                Err(MultipartError::Malformed)
+            ));
+
+            // a field name too long to keep
+            let mut data = b"--XyZ\r\nContent-Disposition: form-data; name=\"".to_vec();
+            data.extend_from_slice(&[b'n'; NAME_SIZE + 1]);
+            data.extend_from_slice(b"\"\r\n\r\n");
+            let mut form = multipart(&data, 64);
+            assert!(matches!(
+                form.next_part().await,
+                Err(MultipartError::Malformed)
+            ));
+        });
+    }
+}
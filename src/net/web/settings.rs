@@ -0,0 +1,398 @@
+use alloc::string::String;
+use core::fmt::Write as _;
+
+use defmt::{info, warn};
+use picoserve::{
+    extract::JsonWithUnescapeBufferSize,
+    response::{Json, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{OptionText, jobs::ApiError};
+use crate::{
+    config::{
+        BlockConfig, Credential, DeviceConfig, Divider, Hostname, MqttConfig, NetworkConfig,
+        PowerConfig, PrinterConfig, ServerName, Ssid, config, save_settings, update_config,
+    },
+    net::{mdns::MDNS_ANNOUNCE, mqtt::MQTT_RECONNECT},
+    printer::COMMON_BAUD_RATES,
+    restart,
+};
+
+/// WPA2 passphrases are 8 to 63 characters
+const MIN_WIFI_PASSWORD_LENGTH: usize = 8;
+const MAX_WIFI_PASSWORD_LENGTH: usize = 63;
+const MIN_JOB_LENGTH: usize = 256;
+const MAX_JOB_LENGTH: usize = 1024 * 1024;
+/// full scale of the 12 bit adc
+const MAX_ADC_READING: u16 = 4095;
+
+/// The settings, with passwords replaced by whether they are set
+#[derive(Serialize)]
+pub struct SettingsView {
+    network: NetworkView,
+    mqtt: MqttView,
+    printer: PrinterView,
+    power: PowerConfig,
+    header: BlockConfig,
+    footer: BlockConfig,
+    /// the device restarts to apply the change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restarting: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct NetworkView {
+    ssid: Ssid,
+    password_set: bool,
+    hostname: Hostname,
+}
+
+#[derive(Serialize)]
+struct MqttView {
+    broker: ServerName,
+    port: u16,
+    username: Credential,
+    password_set: bool,
+}
+
+#[derive(Serialize)]
+struct PrinterView {
+    baud_rate: u32,
+    auto_detect_baud: bool,
+    upside_down: bool,
+    max_job_length: usize,
+    raw_port: bool,
+    leading_feed: OptionText,
+    trailing_feed: OptionText,
+    cut: OptionText,
+}
+
+impl SettingsView {
+    fn new(restarting: Option<bool>) -> Self {
+        let config = config();
+        let printer = config.printer;
+        let text = |value: &dyn core::fmt::Display| {
+            let mut text = OptionText::new();
+            let _ = write!(text, "{value}");
+            text
+        };
+
+        Self {
+            network: NetworkView {
+                password_set: !config.network.password.is_empty(),
+                ssid: config.network.ssid,
+                hostname: config.network.hostname,
+            },
+            mqtt: MqttView {
+                password_set: !config.mqtt.password.is_empty(),
+                broker: config.mqtt.broker,
+                port: config.mqtt.port,
+                username: config.mqtt.username,
+            },
+            printer: PrinterView {
+                baud_rate: printer.baud_rate,
+                auto_detect_baud: printer.auto_detect_baud,
+                upside_down: printer.upside_down,
+                max_job_length: printer.max_job_length,
+                raw_port: printer.raw_port,
+                leading_feed: text(&printer.leading_feed),
+                trailing_feed: text(&printer.trailing_feed),
+                cut: text(&printer.cut),
+            },
+            power: config.power,
+            header: config.header,
+            footer: config.footer,
+            restarting,
+        }
+    }
+}
+
+/// Sections and fields left out are unchanged, so passwords only need sending to change them
+#[derive(Deserialize)]
+pub struct SettingsUpdate {
+    network: Option<NetworkUpdate>,
+    mqtt: Option<MqttUpdate>,
+    printer: Option<PrinterUpdate>,
+    power: Option<PowerUpdate>,
+    header: Option<BlockUpdate>,
+    footer: Option<BlockUpdate>,
+}
+
+#[derive(Deserialize)]
+struct NetworkUpdate {
+    ssid: Option<String>,
+    password: Option<String>,
+    hostname: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MqttUpdate {
+    broker: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PrinterUpdate {
+    baud_rate: Option<u32>,
+    auto_detect_baud: Option<bool>,
+    upside_down: Option<bool>,
+    max_job_length: Option<usize>,
+    raw_port: Option<bool>,
+    leading_feed: Option<OptionText>,
+    trailing_feed: Option<OptionText>,
+    cut: Option<OptionText>,
+}
+
+#[derive(Deserialize)]
+struct PowerUpdate {
+    normal_max: Option<u16>,
+    loss_min: Option<u16>,
+    loss_max: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct BlockUpdate {
+    web: Option<bool>,
+    mqtt: Option<bool>,
+    schedule: Option<bool>,
+    socket: Option<bool>,
+    time: Option<bool>,
+    source: Option<bool>,
+    job_id: Option<bool>,
+    divider: Option<Divider>,
+}
+
+pub async fn get_settings() -> Json<SettingsView> {
+    Json(SettingsView::new(None))
+}
+
+/// Validates and saves the settings, then applies them. Mqtt reconnects with its new settings and
+/// the printer and power settings apply straight away, a new network or baud rate takes a restart.
+pub async fn update_settings(
+    JsonWithUnescapeBufferSize(update): JsonWithUnescapeBufferSize<SettingsUpdate, 256>,
+) -> Result<Json<SettingsView>, ApiError> {
+    let current = config();
+    let network = match update.network {
+        Some(update) => network_settings(current.network.clone(), update)?,
+        None => current.network.clone(),
+    };
+    let mqtt = match update.mqtt {
+        Some(update) => mqtt_settings(current.mqtt.clone(), update)?,
+        None => current.mqtt.clone(),
+    };
+    let printer = match update.printer {
+        Some(update) => printer_settings(current.printer.clone(), update)?,
+        None => current.printer.clone(),
+    };
+    let power = match update.power {
+        Some(update) => power_settings(current.power.clone(), update)?,
+        None => current.power.clone(),
+    };
+    let header = match update.header {
+        Some(update) => block_settings(current.header.clone(), update),
+        None => current.header.clone(),
+    };
+    let footer = match update.footer {
+        Some(update) => block_settings(current.footer.clone(), update),
+        None => current.footer.clone(),
+    };
+
+    // a new hostname, or the printer services coming and going with the raw port, is announced
+    // straight away
+    let restarting = network.ssid != current.network.ssid
+        || network.password != current.network.password
+        || printer.baud_rate != current.printer.baud_rate;
+    let announce = network.hostname != current.network.hostname
+        || printer.raw_port != current.printer.raw_port;
+    let reconnect = mqtt != current.mqtt;
+    info!(
+        "Updating settings, reconnect mqtt: {}, restart: {}",
+        reconnect, restarting
+    );
+    // without them saved, a restart would only undo the change
+    let updated = DeviceConfig {
+        network,
+        mqtt,
+        printer,
+        power,
+        header,
+        footer,
+        ..current
+    };
+    save_settings(&updated).await.map_err(|()| NOT_SAVED)?;
+    update_config(|config| {
+        config.network = updated.network;
+        config.mqtt = updated.mqtt;
+        config.printer = updated.printer;
+        config.power = updated.power;
+        config.header = updated.header;
+        config.footer = updated.footer;
+    });
+    if reconnect {
+        MQTT_RECONNECT.signal(());
+    }
+    if announce {
+        MDNS_ANNOUNCE.signal(());
+    }
+    if restarting {
+        restart::request_restart();
+    }
+
+    Ok(Json(SettingsView::new(Some(restarting))))
+}
+
+fn network_settings(
+    mut network: NetworkConfig,
+    update: NetworkUpdate,
+) -> Result<NetworkConfig, ApiError> {
+    if let Some(ssid) = update.ssid {
+        network.ssid = Ssid::try_from(ssid.as_str())
+            .ok()
+            .filter(|ssid| !ssid.is_empty())
+            .ok_or(ApiError::bad_request(
+                "invalid_ssid",
+                "Network name must be 1 to 32 bytes",
+            ))?;
+    }
+    if let Some(password) = update.password {
+        let length = MIN_WIFI_PASSWORD_LENGTH..=MAX_WIFI_PASSWORD_LENGTH;
+        if !password.is_empty() && !length.contains(&password.len()) {
+            return Err(ApiError::bad_request(
+                "invalid_wifi_password",
+                "Wi-Fi password must be 8 to 63 bytes, or empty for an open network",
+            ));
+        }
+        network.password = Credential::try_from(password.as_str()).unwrap_or_default();
+    }
+    if let Some(hostname) = update.hostname {
+        network.hostname = Hostname::try_from(hostname.as_str())
+            .ok()
+            .filter(|hostname| is_hostname(hostname))
+            .ok_or(ApiError::bad_request(
+                "invalid_hostname",
+                "Hostname must be 1 to 32 letters, digits and hyphens, not starting or ending with a hyphen",
+            ))?;
+    }
+    Ok(network)
+}
+
+/// A single dns label, which every name resolver accepts
+fn is_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn mqtt_settings(mut mqtt: MqttConfig, update: MqttUpdate) -> Result<MqttConfig, ApiError> {
+    if let Some(broker) = update.broker {
+        mqtt.broker = ServerName::try_from(broker.trim())
+            .ok()
+            .filter(|broker| !broker.contains(char::is_whitespace))
+            .ok_or(ApiError::bad_request(
+                "invalid_broker",
+                "Broker must be a hostname or address of up to 64 characters, or empty to turn mqtt off",
+            ))?;
+    }
+    if let Some(port) = update.port {
+        if port == 0 {
+            return Err(ApiError::bad_request("invalid_port", "Port must not be 0"));
+        }
+        mqtt.port = port;
+    }
+
+    let invalid_login = || {
+        ApiError::bad_request(
+            "invalid_mqtt_login",
+            "MQTT username and password must be up to 64 bytes",
+        )
+    };
+    if let Some(username) = update.username {
+        mqtt.username = Credential::try_from(username.as_str()).map_err(|_| invalid_login())?;
+    }
+    if let Some(password) = update.password {
+        mqtt.password = Credential::try_from(password.as_str()).map_err(|_| invalid_login())?;
+    }
+    Ok(mqtt)
+}
+
+fn printer_settings(
+    mut printer: PrinterConfig,
+    update: PrinterUpdate,
+) -> Result<PrinterConfig, ApiError> {
+    if let Some(baud_rate) = update.baud_rate {
+        if !COMMON_BAUD_RATES.contains(&baud_rate) {
+            return Err(ApiError::bad_request(
+                "invalid_baud_rate",
+                "Baud rate must be 9600, 19200, 38400, 57600 or 115200",
+            ));
+        }
+        printer.baud_rate = baud_rate;
+    }
+    if let Some(length) = update.max_job_length {
+        if !(MIN_JOB_LENGTH..=MAX_JOB_LENGTH).contains(&length) {
+            return Err(ApiError::bad_request(
+                "invalid_job_length",
+                "Longest job must be 256 bytes to 1 MiB",
+            ));
+        }
+        printer.max_job_length = length;
+    }
+
+    let invalid_feed = |()| ApiError::bad_request("invalid_feed", "Invalid feed amount");
+    if let Some(feed) = update.leading_feed {
+        printer.leading_feed = feed.parse().map_err(invalid_feed)?;
+    }
+    if let Some(feed) = update.trailing_feed {
+        printer.trailing_feed = feed.parse().map_err(invalid_feed)?;
+    }
+    if let Some(cut) = update.cut {
+        printer.cut = cut
+            .parse()
+            .map_err(|()| ApiError::bad_request("invalid_cut", "Invalid cut mode"))?;
+    }
+    printer.auto_detect_baud = update.auto_detect_baud.unwrap_or(printer.auto_detect_baud);
+    printer.upside_down = update.upside_down.unwrap_or(printer.upside_down);
+    printer.raw_port = update.raw_port.unwrap_or(printer.raw_port);
+    Ok(printer)
+}
+
+fn power_settings(mut power: PowerConfig, update: PowerUpdate) -> Result<PowerConfig, ApiError> {
+    power.normal_max = update.normal_max.unwrap_or(power.normal_max);
+    power.loss_min = update.loss_min.unwrap_or(power.loss_min);
+    power.loss_max = update.loss_max.unwrap_or(power.loss_max);
+    if power.normal_max >= power.loss_min
+        || power.loss_min > power.loss_max
+        || power.loss_max > MAX_ADC_READING
+    {
+        warn!("Refusing power thresholds {}", power);
+        return Err(ApiError::bad_request(
+            "invalid_power_thresholds",
+            "Power thresholds must rise from normal_max to loss_min to loss_max, up to 4095",
+        ));
+    }
+    Ok(power)
+}
+
+fn block_settings(mut block: BlockConfig, update: BlockUpdate) -> BlockConfig {
+    block.web = update.web.unwrap_or(block.web);
+    block.mqtt = update.mqtt.unwrap_or(block.mqtt);
+    block.schedule = update.schedule.unwrap_or(block.schedule);
+    block.socket = update.socket.unwrap_or(block.socket);
+    block.time = update.time.unwrap_or(block.time);
+    block.source = update.source.unwrap_or(block.source);
+    block.job_id = update.job_id.unwrap_or(block.job_id);
+    block.divider = update.divider.unwrap_or(block.divider);
+    block
+}
+
+const NOT_SAVED: ApiError = ApiError::new(
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "not_saved",
+    "Settings changed but could not be saved, they will be lost on reboot",
+);
@@ -0,0 +1,110 @@
+use alloc::{format, string::String};
+use embassy_net::Stack;
+use embassy_time::Instant;
+use picoserve::{
+    extract::State,
+    response::{IntoResponse, Json},
+};
+use serde::Serialize;
+
+use super::AppState;
+use crate::{
+    config::Ssid,
+    glue,
+    net::{mqtt, wifi},
+    power::{self, PowerMonitorData},
+    printer::{self, MAX_QUEUED, PaperStatus},
+    time,
+};
+
+#[derive(Serialize)]
+pub struct DeviceStatus {
+    uptime_secs: u64,
+    firmware: &'static str,
+    /// local time, `None` until the clock has synced
+    time: Option<String>,
+    heap: HeapStatus,
+    wifi: WifiStatus,
+    mqtt: MqttStatus,
+    power: PowerStatus,
+    printer: PrinterStatus,
+    queue: QueueStatus,
+}
+
+#[derive(Serialize)]
+struct HeapStatus {
+    used: usize,
+    free: usize,
+}
+
+#[derive(Serialize)]
+struct WifiStatus {
+    ssid: Ssid,
+    /// dBm
+    rssi: Option<i32>,
+    ip: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MqttStatus {
+    connected: bool,
+}
+
+#[derive(Serialize)]
+struct PowerStatus {
+    /// raw reading of the power monitor
+    adc: Option<PowerMonitorData>,
+    /// `normal`, or `low` while the supply is failing
+    state: &'static str,
+}
+
+#[derive(Serialize)]
+struct PrinterStatus {
+    online: bool,
+    paper: PaperStatus,
+}
+
+#[derive(Serialize)]
+struct QueueStatus {
+    depth: usize,
+    capacity: usize,
+}
+
+/// Health of everything the device depends on
+pub async fn device_status(stack: Stack<'static>) -> DeviceStatus {
+    DeviceStatus {
+        uptime_secs: Instant::now().as_secs(),
+        firmware: env!("CARGO_PKG_VERSION"),
+        time: time::now().map(|now| format!("{now}")),
+        heap: HeapStatus {
+            used: esp_alloc::HEAP.used(),
+            free: esp_alloc::HEAP.free(),
+        },
+        wifi: WifiStatus {
+            ssid: wifi::ssid(),
+            rssi: glue::wifi_rssi(),
+            ip: stack
+                .config_v4()
+                .map(|config| format!("{}", config.address.address())),
+        },
+        mqtt: MqttStatus {
+            connected: mqtt::is_connected(),
+        },
+        power: PowerStatus {
+            adc: power::power_level(),
+            state: power::shutdown_status().as_str(),
+        },
+        printer: PrinterStatus {
+            online: printer::printer_online(),
+            paper: printer::paper_status().await,
+        },
+        queue: QueueStatus {
+            depth: printer::queue_length(),
+            capacity: MAX_QUEUED,
+        },
+    }
+}
+
+pub async fn get_status(State(state): State<AppState>) -> impl IntoResponse {
+    Json(device_status(state.stack).await)
+}
@@ -1,15 +1,31 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
 use defmt::info;
 use embassy_executor::Spawner;
 use embassy_net::{DhcpConfig, Runner, Stack, StackResources};
 use embassy_time::{Duration, Timer};
 
 use crate::{
-    glue::{Wifi, WifiController, WifiInterface},
+    config::{Ssid, build_network, config},
+    glue::{Wifi, WifiController, WifiInterface, active_network},
     mk_static,
 };
 
-const SSID: &str = env!("WIFI_SSID");
-const PASSWORD: &str = env!("WIFI_PASSWORD");
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// The network the device connects to
+pub fn ssid() -> Ssid {
+    match active_network() {
+        0 => config().network.ssid,
+        _ => build_network().ssid,
+    }
+}
+
+/// Whether the device has got an address since it started
+pub fn has_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
 
 pub async fn start_wifi(wifi: Wifi, spawner: &Spawner) -> (Stack<'static>, [u8; 6]) {
     let dhcp_config = DhcpConfig::default();
@@ -22,7 +38,7 @@ pub async fn start_wifi(wifi: Wifi, spawner: &Spawner) -> (Stack<'static>, [u8;
     let (stack, runner) = embassy_net::new(
         interface,
         net_config,
-        mk_static!(StackResources<5>, StackResources::<5>::new()),
+        mk_static!(StackResources<10>, StackResources::<10>::new()),
         seed,
     );
 
@@ -39,7 +55,17 @@ async fn connection(mut controller: WifiController) {
     info!("start connection task");
     info!("Device capabilities: {:?}", controller.capabilities());
 
-    controller.connection_loop(SSID, PASSWORD).await;
+    // the network is only read here, changing it takes a restart. A saved network that can't be
+    // joined falls back to the one set at build time, so the settings can be put right.
+    let network = config().network;
+    let fallback = build_network();
+    let mut networks = Vec::new();
+    networks.push((network.ssid.as_str(), network.password.as_str()));
+    let same = fallback.ssid == network.ssid && fallback.password == network.password;
+    if !fallback.ssid.is_empty() && !same {
+        networks.push((fallback.ssid.as_str(), fallback.password.as_str()));
+    }
+    controller.connection_loop(&networks).await;
 }
 
 #[embassy_executor::task]
@@ -55,6 +81,7 @@ async fn wait_for_connection(stack: Stack<'_>) {
     loop {
         if let Some(config) = stack.config_v4() {
             info!("Got IP: {}", config.address);
+            CONNECTED.store(true, Ordering::Relaxed);
             break;
         }
         Timer::after(Duration::from_millis(500)).await;
@@ -0,0 +1,470 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+use defmt::{error, info, warn};
+use embassy_executor::Spawner;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::{Mutex, MutexGuard},
+};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use embedded_storage::{ReadStorage, nor_flash::NorFlash};
+use esp_bootloader_esp_idf::{
+    ota::{Ota, OtaImageState},
+    partitions::{
+        self, AppPartitionSubType, DataPartitionSubType, Error, PARTITION_TABLE_MAX_LEN,
+        PartitionTable, PartitionType,
+    },
+};
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    glue::{IMAGE_CHIP_ID, Watchdog},
+    net::wifi,
+    restart,
+    sha256::{Digest, Sha256, digests_match},
+    storage::{self, Record},
+};
+
+mod image;
+
+use image::ImageCheck;
+pub use image::Version;
+
+/// flash is erased a sector at a time
+const SECTOR_SIZE: usize = FlashStorage::SECTOR_SIZE as usize;
+/// a new firmware that keeps resetting before it is confirmed is abandoned after this many
+/// starts, a second one allows for the power going out during the first
+const MAX_TRIAL_BOOTS: u8 = 2;
+/// the watchdog resets the device when a new firmware hangs or panics for this long
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(60);
+const FEED_INTERVAL: Duration = Duration::from_secs(5);
+/// how long a new firmware has to run, connected to the network, before it is kept
+const TRIAL_UPTIME: Duration = Duration::from_secs(120);
+/// a new firmware that hasn't connected to the network by then is rolled back
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Held for the whole of an upload, only one can be written at a time
+static UPDATE_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+/// Set while a new firmware is running on trial, until it is confirmed
+static ON_TRIAL: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum OtaError {
+    /// the partition table has no ota data or fewer than two ota app partitions
+    NoOtaPartitions,
+    /// the image is larger than the partition it would be written to
+    TooLarge,
+    InvalidImage,
+    /// the image was built for another chip
+    WrongChip,
+    /// the image is not an application, such as a bootloader
+    NotAnApp,
+    /// the image doesn't match its hash or the one it was uploaded with
+    HashMismatch,
+    Flash,
+    /// another update is being written
+    InProgress,
+    /// the running firmware is new and has not been confirmed yet
+    Unconfirmed,
+    /// the upload ended before the image was complete
+    Truncated,
+}
+
+impl OtaError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OtaError::NoOtaPartitions => "Partition table has no OTA partitions",
+            OtaError::TooLarge => "Firmware is too large for the OTA partition",
+            OtaError::InvalidImage => "Not a valid firmware image",
+            OtaError::WrongChip => "Firmware is for a different chip",
+            OtaError::NotAnApp => "Image is not an application",
+            OtaError::HashMismatch => "Firmware does not match its SHA-256 hash",
+            OtaError::Flash => "Failed to write the firmware to flash",
+            OtaError::InProgress => "Another update is in progress",
+            OtaError::Unconfirmed => "The running firmware has not been confirmed yet",
+            OtaError::Truncated => "Firmware upload ended early",
+        }
+    }
+}
+
+/// An app partition
+#[derive(Clone, Copy, Debug, defmt::Format)]
+struct App {
+    kind: AppPartitionSubType,
+    offset: u32,
+    size: u32,
+}
+
+/// The app partitions and which of them is running
+struct Apps {
+    booted: Option<App>,
+    all: heapless::Vec<App, 18>,
+}
+
+impl Apps {
+    fn new(table: &PartitionTable<'_>) -> Result<Self, Error> {
+        let app = |partition: partitions::PartitionEntry<'_>| match partition.partition_type() {
+            PartitionType::App(kind) if kind != AppPartitionSubType::Test => Some(App {
+                kind,
+                offset: partition.offset(),
+                size: partition.len(),
+            }),
+            _ => None,
+        };
+        Ok(Self {
+            booted: table.booted_partition()?.and_then(app),
+            all: table.iter().filter_map(app).collect(),
+        })
+    }
+
+    fn ota(&self) -> impl Iterator<Item = &App> {
+        self.all
+            .iter()
+            .filter(|app| app.kind != AppPartitionSubType::Factory)
+    }
+
+    /// The ota partition after the running one, where an update is written
+    fn next(&self) -> Option<App> {
+        let ota: heapless::Vec<App, 18> = self.ota().copied().collect();
+        let booted = self
+            .booted
+            .and_then(|booted| ota.iter().position(|app| app.offset == booted.offset));
+        match booted {
+            Some(i) => ota.get((i + 1) % ota.len()).copied(),
+            None => ota.first().copied(),
+        }
+    }
+
+    fn find(&self, offset: u32) -> Option<App> {
+        self.all.iter().find(|app| app.offset == offset).copied()
+    }
+}
+
+/// Runs `f` with the app partitions and the ota data, which selects the app the bootloader
+/// starts
+fn with_ota<R>(
+    flash: &mut FlashStorage<'static>,
+    f: impl FnOnce(&Apps, &mut Ota<'_, FlashStorage<'static>>) -> Result<R, Error>,
+) -> Result<R, Error> {
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let table = partitions::read_partition_table(flash, &mut buffer)?;
+    let apps = Apps::new(&table)?;
+    let count = apps.ota().count();
+    if count < 2 {
+        return Err(Error::Invalid);
+    }
+
+    let mut region = table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Ota))?
+        .ok_or(Error::Invalid)?
+        .as_embedded_storage(flash);
+    let mut ota = Ota::new(&mut region, count)?;
+    f(&apps, &mut ota)
+}
+
+async fn ota<R>(
+    f: impl FnOnce(&Apps, &mut Ota<'_, FlashStorage<'static>>) -> Result<R, Error>,
+) -> Result<R, Error> {
+    storage::with_flash(|flash| with_ota(flash, f))
+        .await
+        .unwrap_or(Err(Error::Invalid))
+}
+
+/// Kept in storage while a new firmware is on trial
+#[derive(Clone, Copy, Serialize, Deserialize, defmt::Format)]
+struct Trial {
+    /// partition of the new firmware
+    offset: u32,
+    /// partition of the firmware it replaced, started again if the new one fails
+    previous: u32,
+    boots: u8,
+}
+
+async fn load_trial() -> Option<Trial> {
+    storage::load::<Option<Trial>>(Record::Update)
+        .await
+        .flatten()
+}
+
+async fn save_trial(trial: Option<Trial>) -> Result<(), ()> {
+    storage::save(Record::Update, &trial).await
+}
+
+/// Starts the watchdog before anything else when the firmware was just installed, so it is also
+/// rolled back if it hangs loading its settings. [`start_ota`] stops it again unless the update
+/// is on trial.
+pub fn watch_new_firmware(flash: &mut FlashStorage<'static>, watchdog: &mut Watchdog) {
+    let state = with_ota(flash, |_, ota| ota.current_ota_state());
+    if let Ok(OtaImageState::New | OtaImageState::PendingVerify) = state {
+        info!("Firmware is new, watchdog on");
+        watchdog.start(WATCHDOG_TIMEOUT);
+    }
+}
+
+/// Checks on a firmware that was just updated. It runs on trial with the watchdog on until it
+/// has connected to the network and stayed up for a while, then it is kept. If it resets too
+/// often or never gets there, the previous firmware is started again.
+pub async fn start_ota(mut watchdog: Watchdog, spawner: &Spawner) {
+    let Some(mut trial) = load_trial().await else {
+        watchdog.stop();
+        return;
+    };
+
+    let booted = ota(|apps, _| Ok(apps.booted)).await.ok().flatten();
+    if booted.is_none_or(|booted| booted.offset != trial.offset) {
+        // the bootloader went back to the previous firmware itself
+        warn!(
+            "Update at {:#x} did not start, running the previous firmware",
+            trial.offset
+        );
+        let selected = ota(|_, ota| {
+            let booted = booted.ok_or(Error::Invalid)?;
+            ota.set_current_app_partition(booted.kind)
+        })
+        .await;
+        if let Err(e) = selected {
+            error!("Failed to select the running firmware: {:?}", e);
+        }
+        let _ = save_trial(None).await;
+        watchdog.stop();
+        return;
+    }
+
+    if trial.boots >= MAX_TRIAL_BOOTS {
+        error!(
+            "Update reset {} times before it was confirmed, rolling back",
+            trial.boots
+        );
+        roll_back(trial).await;
+    }
+
+    trial.boots += 1;
+    if save_trial(Some(trial)).await.is_err() {
+        warn!("Failed to count the start of the new firmware");
+    }
+    info!("Firmware is new, start {} on trial", trial.boots);
+    ON_TRIAL.store(true, Ordering::Relaxed);
+    watchdog.start(WATCHDOG_TIMEOUT);
+    spawner.must_spawn(trial_task(watchdog, trial));
+}
+
+#[embassy_executor::task]
+async fn trial_task(mut watchdog: Watchdog, trial: Trial) {
+    let healthy = with_timeout(TRIAL_TIMEOUT, async {
+        loop {
+            watchdog.feed();
+            if wifi::has_connected() && Instant::now().as_millis() >= TRIAL_UPTIME.as_millis() {
+                break;
+            }
+            Timer::after(FEED_INTERVAL).await;
+        }
+    })
+    .await;
+    if healthy.is_err() {
+        error!("Update did not connect to the network in time, rolling back");
+        roll_back(trial).await;
+    }
+
+    if let Err(e) = ota(|_, ota| ota.set_current_ota_state(OtaImageState::Valid)).await {
+        // the bootloader only rolls back states it was told about, so it keeps this firmware
+        error!("Failed to mark the firmware valid: {:?}", e);
+    }
+    if save_trial(None).await.is_err() {
+        warn!("Failed to clear the update trial, it will be confirmed again");
+    }
+    watchdog.stop();
+    ON_TRIAL.store(false, Ordering::Relaxed);
+    info!("Update confirmed");
+}
+
+/// Marks the new firmware invalid and restarts into the previous one
+async fn roll_back(trial: Trial) -> ! {
+    let rolled_back = ota(|apps, ota| {
+        let previous = apps.find(trial.previous).ok_or(Error::Invalid)?;
+        ota.set_current_ota_state(OtaImageState::Invalid)?;
+        ota.set_current_app_partition(previous.kind)?;
+        // it ran before the update, and its entry may still hold an old state
+        if previous.kind != AppPartitionSubType::Factory {
+            ota.set_current_ota_state(OtaImageState::Valid)?;
+        }
+        Ok(())
+    })
+    .await;
+    match rolled_back {
+        Ok(()) => {
+            let _ = save_trial(None).await;
+        }
+        // the trial is kept so the next start tries again
+        Err(e) => error!("Failed to roll back: {:?}", e),
+    }
+    esp_hal::system::software_reset()
+}
+
+/// A firmware image being written to the ota partition that isn't running. Nothing changes
+/// until [`Update::finish`] has checked the whole image, dropping it leaves the running firmware
+/// selected.
+pub struct Update {
+    target: App,
+    previous: u32,
+    length: usize,
+    written: usize,
+    /// data for the sector being filled
+    sector: Vec<u8>,
+    check: ImageCheck,
+    hasher: Sha256,
+    _lock: MutexGuard<'static, CriticalSectionRawMutex, ()>,
+}
+
+impl Update {
+    /// Starts writing an image of `length` bytes
+    pub async fn begin(length: usize) -> Result<Self, OtaError> {
+        let lock = UPDATE_LOCK.try_lock().map_err(|_| OtaError::InProgress)?;
+        if ON_TRIAL.load(Ordering::Relaxed) {
+            return Err(OtaError::Unconfirmed);
+        }
+
+        let partitions = ota(|apps, _| {
+            let target = apps.next().ok_or(Error::Invalid)?;
+            Ok((target, apps.booted.map(|app| app.offset)))
+        })
+        .await;
+        let (target, previous) = match partitions {
+            Ok((target, Some(previous))) => (target, previous),
+            Ok((_, None)) => {
+                warn!("Running firmware is not in an app partition, can't update");
+                return Err(OtaError::NoOtaPartitions);
+            }
+            Err(e) => {
+                warn!("No ota partitions, can't update: {:?}", e);
+                return Err(OtaError::NoOtaPartitions);
+            }
+        };
+        if length > target.size as usize {
+            return Err(OtaError::TooLarge);
+        }
+
+        info!(
+            "Writing a {} byte update to the partition at {:#x}",
+            length, target.offset
+        );
+        Ok(Self {
+            target,
+            previous,
+            length,
+            written: 0,
+            sector: Vec::with_capacity(SECTOR_SIZE),
+            check: ImageCheck::new(IMAGE_CHIP_ID),
+            hasher: Sha256::new(),
+            _lock: lock,
+        })
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), OtaError> {
+        if self.written + data.len() > self.length {
+            return Err(OtaError::TooLarge);
+        }
+        self.check.update(data)?;
+        self.hasher.update(data);
+        self.written += data.len();
+
+        while !data.is_empty() {
+            let len = (SECTOR_SIZE - self.sector.len()).min(data.len());
+            self.sector.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.sector.len() == SECTOR_SIZE {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the image and what was written, then selects it to start after a restart. With
+    /// `expected` the image must also have that sha-256.
+    pub async fn finish(mut self, expected: Option<Digest>) -> Result<Version, OtaError> {
+        if !self.check.is_complete() {
+            return Err(OtaError::Truncated);
+        }
+        let digest = self.hasher.clone().finish();
+        if expected.is_some_and(|expected| !digests_match(&expected, &digest)) {
+            return Err(OtaError::HashMismatch);
+        }
+        if !self.sector.is_empty() {
+            self.flush().await?;
+        }
+        self.verify(&digest).await?;
+
+        let trial = Trial {
+            offset: self.target.offset,
+            previous: self.previous,
+            boots: 0,
+        };
+        // saved first, so the new firmware never starts without a way back
+        save_trial(Some(trial))
+            .await
+            .map_err(|()| OtaError::Flash)?;
+        let target = self.target.kind;
+        let selected = ota(|_, ota| {
+            ota.set_current_app_partition(target)?;
+            ota.set_current_ota_state(OtaImageState::New)
+        })
+        .await;
+        if let Err(e) = selected {
+            error!("Failed to select the update: {:?}", e);
+            let _ = save_trial(None).await;
+            return Err(OtaError::Flash);
+        }
+
+        let version = self.check.version();
+        info!("Update {} written, restarting into it", version.as_str());
+        restart::request_restart();
+        Ok(version)
+    }
+
+    /// Erases the next sector and writes the data for it, padding the last one
+    async fn flush(&mut self) -> Result<(), OtaError> {
+        let address = self.target.offset + (self.written - self.sector.len()) as u32;
+        let address = address - address % SECTOR_SIZE as u32;
+        self.sector.resize(SECTOR_SIZE, 0xFF);
+        let sector = &self.sector;
+        let written = storage::with_flash(|flash| {
+            flash.erase(address, address + SECTOR_SIZE as u32)?;
+            flash.write(address, sector)
+        })
+        .await;
+        self.sector.clear();
+        match written {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) => {
+                error!("Failed to write the update at {:#x}: {:?}", address, e);
+                Err(OtaError::Flash)
+            }
+            None => Err(OtaError::Flash),
+        }
+    }
+
+    /// Reads the image back to make sure it reached the flash intact
+    async fn verify(&mut self, digest: &Digest) -> Result<(), OtaError> {
+        let mut hasher = Sha256::new();
+        self.sector.resize(SECTOR_SIZE, 0);
+        let mut position = 0;
+        while position < self.written {
+            let len = SECTOR_SIZE.min(self.written - position);
+            let address = self.target.offset + position as u32;
+            let buffer = &mut self.sector[..len];
+            let read = storage::with_flash(|flash| flash.read(address, &mut *buffer)).await;
+            if !matches!(read, Some(Ok(()))) {
+                return Err(OtaError::Flash);
+            }
+            hasher.update(buffer);
+            position += len;
+        }
+
+        if !digests_match(&hasher.finish(), digest) {
+            error!("Update doesn't match what was written");
+            return Err(OtaError::Flash);
+        }
+        Ok(())
+    }
+}
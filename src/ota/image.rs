@@ -0,0 +1,223 @@
+use super::OtaError;
+use crate::sha256::{DIGEST_SIZE, Digest, Sha256};
+
+const MAGIC: u8 = 0xE9;
+const HEADER_SIZE: usize = 24;
+const SEGMENT_HEADER_SIZE: usize = 8;
+/// images have at most 16 segments
+const MAX_SEGMENTS: u8 = 16;
+const CHECKSUM_SEED: u8 = 0xEF;
+/// `esp_app_desc_t` starts the first segment of every app, the bootloader has none
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+/// magic, secure version and reserved words before the version string
+const VERSION_OFFSET: usize = 16;
+const VERSION_SIZE: usize = 32;
+
+pub type Version = heapless::String<VERSION_SIZE>;
+
+#[derive(Clone, Copy)]
+enum Stage {
+    Header,
+    SegmentHeader,
+    Segment {
+        remaining: u32,
+    },
+    /// zeros up to the checksum, which ends a 16 byte block
+    Padding {
+        remaining: usize,
+    },
+    Checksum,
+    /// sha-256 of everything before it
+    Hash,
+    Done,
+}
+
+/// Checks an esp-idf app image as it is streamed: the header is for this chip, the first
+/// segment holds an app description, and the checksum and appended hash match.
+pub struct ImageCheck {
+    chip_id: u16,
+    stage: Stage,
+    /// bytes of the header or hash being read, the hash is the longer
+    field: heapless::Vec<u8, DIGEST_SIZE>,
+    /// the start of the first segment, holding the app description
+    description: heapless::Vec<u8, { VERSION_OFFSET + VERSION_SIZE }>,
+    segments: u8,
+    segments_left: u8,
+    hash_appended: bool,
+    checksum: u8,
+    position: usize,
+    hasher: Sha256,
+    digest: Digest,
+}
+
+impl ImageCheck {
+    pub fn new(chip_id: u16) -> Self {
+        Self {
+            chip_id,
+            stage: Stage::Header,
+            field: heapless::Vec::new(),
+            description: heapless::Vec::new(),
+            segments: 0,
+            segments_left: 0,
+            hash_appended: false,
+            checksum: CHECKSUM_SEED,
+            position: 0,
+            hasher: Sha256::new(),
+            digest: [0; DIGEST_SIZE],
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), OtaError> {
+        while !data.is_empty() {
+            let len = match self.stage {
+                Stage::Header => HEADER_SIZE - self.field.len(),
+                Stage::SegmentHeader => SEGMENT_HEADER_SIZE - self.field.len(),
+                Stage::Hash => DIGEST_SIZE - self.field.len(),
+                Stage::Segment { remaining } => remaining as usize,
+                Stage::Padding { remaining } => remaining,
+                Stage::Checksum => 1,
+                // anything after the image, such as the partition table of a merged image
+                Stage::Done => return Err(OtaError::InvalidImage),
+            }
+            .min(data.len());
+            let (taken, rest) = data.split_at(len);
+            data = rest;
+
+            if !matches!(self.stage, Stage::Hash) {
+                self.hasher.update(taken);
+            }
+            self.position += len;
+            self.advance(taken)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the whole image has been checked
+    pub fn is_complete(&self) -> bool {
+        matches!(self.stage, Stage::Done)
+    }
+
+    /// The version from the app description
+    pub fn version(&self) -> Version {
+        let version = self
+            .description
+            .get(VERSION_OFFSET..)
+            .unwrap_or_default()
+            .split(|byte| *byte == 0)
+            .next()
+            .unwrap_or_default();
+        core::str::from_utf8(version)
+            .ok()
+            .and_then(|version| Version::try_from(version).ok())
+            .unwrap_or_default()
+    }
+
+    fn advance(&mut self, taken: &[u8]) -> Result<(), OtaError> {
+        match self.stage {
+            Stage::Header | Stage::SegmentHeader | Stage::Hash => {
+                let _ = self.field.extend_from_slice(taken);
+                let size = match self.stage {
+                    Stage::Header => HEADER_SIZE,
+                    Stage::SegmentHeader => SEGMENT_HEADER_SIZE,
+                    _ => DIGEST_SIZE,
+                };
+                if self.field.len() < size {
+                    return Ok(());
+                }
+                let field = core::mem::take(&mut self.field);
+                match self.stage {
+                    Stage::Header => self.header(&field)?,
+                    Stage::SegmentHeader => {
+                        let length = u32::from_le_bytes([field[4], field[5], field[6], field[7]]);
+                        self.stage = Stage::Segment { remaining: length };
+                        if length == 0 {
+                            self.end_segment()?;
+                        }
+                    }
+                    _ => {
+                        if field.as_slice() != self.digest {
+                            return Err(OtaError::HashMismatch);
+                        }
+                        self.stage = Stage::Done;
+                    }
+                }
+            }
+            Stage::Segment { remaining } => {
+                self.checksum = taken.iter().fold(self.checksum, |sum, byte| sum ^ byte);
+                let wanted = self.description.capacity() - self.description.len();
+                if self.is_first_segment() && wanted > 0 {
+                    let _ = self
+                        .description
+                        .extend_from_slice(&taken[..wanted.min(taken.len())]);
+                }
+                let remaining = remaining - taken.len() as u32;
+                self.stage = Stage::Segment { remaining };
+                if remaining == 0 {
+                    self.end_segment()?;
+                }
+            }
+            Stage::Padding { remaining } => {
+                let remaining = remaining - taken.len();
+                self.stage = match remaining {
+                    0 => Stage::Checksum,
+                    _ => Stage::Padding { remaining },
+                };
+            }
+            Stage::Checksum => {
+                if taken[0] != self.checksum {
+                    return Err(OtaError::InvalidImage);
+                }
+                self.stage = if self.hash_appended {
+                    self.digest = self.hasher.clone().finish();
+                    Stage::Hash
+                } else {
+                    Stage::Done
+                };
+            }
+            Stage::Done => {}
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, header: &[u8]) -> Result<(), OtaError> {
+        let segments = header[1];
+        if header[0] != MAGIC || segments == 0 || segments > MAX_SEGMENTS {
+            return Err(OtaError::InvalidImage);
+        }
+        if u16::from_le_bytes([header[12], header[13]]) != self.chip_id {
+            return Err(OtaError::WrongChip);
+        }
+        self.segments = segments;
+        self.segments_left = segments;
+        self.hash_appended = header[23] == 1;
+        self.stage = Stage::SegmentHeader;
+        Ok(())
+    }
+
+    fn is_first_segment(&self) -> bool {
+        self.segments_left == self.segments
+    }
+
+    fn end_segment(&mut self) -> Result<(), OtaError> {
+        if self.is_first_segment() {
+            let description = &self.description;
+            let magic = description
+                .get(..4)
+                .map(|magic| u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]));
+            if magic != Some(APP_DESC_MAGIC) {
+                return Err(OtaError::NotAnApp);
+            }
+        }
+
+        self.segments_left -= 1;
+        self.stage = if self.segments_left > 0 {
+            Stage::SegmentHeader
+        } else {
+            match 15 - self.position % 16 {
+                0 => Stage::Checksum,
+                remaining => Stage::Padding { remaining },
+            }
+        };
+        Ok(())
+    }
+}
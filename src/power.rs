@@ -6,9 +6,10 @@ use embassy_sync::{
 };
 use embassy_time::{Duration, Ticker};
 
-use crate::glue::PowerMonitorADC;
+use crate::{config::config, glue::PowerMonitorADC};
 
-const WATCHER_SIZE: usize = 2;
+/// mqtt, the event publisher and the paper counter each watch the power
+const WATCHER_SIZE: usize = 3;
 pub type PowerMonitorData = u16;
 type PowerMonitorWatcher = Watch<CriticalSectionRawMutex, PowerMonitorData, WATCHER_SIZE>;
 type PowerMonitorSender = Sender<'static, CriticalSectionRawMutex, PowerMonitorData, WATCHER_SIZE>;
@@ -34,10 +35,8 @@ struct ShutdownService {
     shutdown_sender: ShutdownSender,
 }
 
-// magic numbers based on quick manual calibration of adc
-const NORMAL_POWER: u16 = 700;
-const POWER_LOSS: u16 = 1_000;
-const USB_POWER: u16 = 2_200;
+/// readings between reloads of the thresholds, so changes apply within a second
+const THRESHOLD_RELOAD: u32 = 20;
 
 impl ShutdownService {
     fn new(monitor: PowerMonitorADC) -> Self {
@@ -52,25 +51,33 @@ impl ShutdownService {
         let mut status = ShutdownStatus::NormalPower;
 
         let mut ticker = Ticker::every(Duration::from_millis(50));
+        let mut thresholds = config().power;
+        let mut readings = 0u32;
         loop {
             ticker.next().await;
+            readings = readings.wrapping_add(1);
+            if readings.is_multiple_of(THRESHOLD_RELOAD) {
+                thresholds = config().power;
+            }
             let adc_value = self.monitor.read_oneshot();
 
             debug!("Battery ADC: {}", adc_value);
             self.monitor_sender.send(adc_value);
 
-            match (status, adc_value) {
-                (ShutdownStatus::LowPower, 0..=NORMAL_POWER) => {
+            match status {
+                ShutdownStatus::LowPower if adc_value <= thresholds.normal_max => {
                     info!("Power regained, returning to normal power state");
                     status = ShutdownStatus::NormalPower;
                     self.shutdown_sender.send(status);
                 }
-                (ShutdownStatus::NormalPower, POWER_LOSS..=USB_POWER) => {
+                ShutdownStatus::NormalPower
+                    if (thresholds.loss_min..=thresholds.loss_max).contains(&adc_value) =>
+                {
                     warn!("Losing power, sending shutdown signal");
                     status = ShutdownStatus::LowPower;
                     self.shutdown_sender.send(status);
                 }
-                (_, _) => {
+                _ => {
                     continue;
                 }
             }
@@ -83,3 +90,24 @@ pub enum ShutdownStatus {
     LowPower,
     NormalPower,
 }
+
+impl ShutdownStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownStatus::LowPower => "low",
+            ShutdownStatus::NormalPower => "normal",
+        }
+    }
+}
+
+/// The last power monitor reading, `None` before the first
+pub fn power_level() -> Option<PowerMonitorData> {
+    POWER_MONITOR_WATCHER.try_get()
+}
+
+/// Power state derived from the monitor, normal until the first change
+pub fn shutdown_status() -> ShutdownStatus {
+    SHUTDOWN_WATCHER
+        .try_get()
+        .unwrap_or(ShutdownStatus::NormalPower)
+}
@@ -1,8 +1,19 @@
 pub use crate::glue::PowerMonitorADC;
 pub use crate::glue::ThermalPrinter;
+pub use crate::glue::Watchdog;
 pub use crate::glue::Wifi;
+pub use crate::init_storage;
+pub use crate::load_settings;
+pub use crate::start_events;
+pub use crate::start_mdns;
 pub use crate::start_mqtt_client;
+pub use crate::start_ota;
 pub use crate::start_power_monitor;
 pub use crate::start_printer;
+pub use crate::start_raw_server;
+pub use crate::start_restarter;
+pub use crate::start_scheduler;
+pub use crate::start_sntp;
 pub use crate::start_web_host;
 pub use crate::start_wifi;
+pub use crate::watch_new_firmware;
@@ -1,29 +1,140 @@
-use core::str::FromStr as _;
+use core::{
+    fmt::Write as _,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 use defmt::{debug, info, warn};
 use embassy_executor::Spawner;
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     channel::{Channel, Receiver, Sender},
+    mutex::MutexGuard,
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer, with_timeout};
+
+use crate::{
+    config::{config, save_settings, update_config},
+    events::{self, Event},
+    glue::ThermalPrinter,
+    limits::{self, JobUsage},
 };
 
-use crate::glue::ThermalPrinter;
+mod banner;
+mod bitmap_font;
+mod decoration;
+mod escpos;
+mod feed;
+mod font;
+mod format;
+mod history;
+mod image;
+mod job;
+mod layout;
+mod paper;
+mod preview;
+mod queue;
+mod sanitize;
+mod style;
+mod utf8;
+
+pub use banner::{Banner, BannerOptions, BannerText};
+pub use feed::{CutMode, DOTS_PER_MM, Feed};
+pub use format::{TemplateError, TextFormat, render_template};
+pub use history::{JobRecord, JobState, forget_job, job_record, recent_jobs, reprint_of};
+pub use image::{Image, ImageError, ImageFormat, ImageOptions, ImageSource, decode_image};
+pub use job::{
+    JobError, JobOptions, JobSource, MAX_JOB_TIME, PrintJob, is_too_slow, next_job_id,
+    reserve_job_id,
+};
+pub use layout::LINE_HEIGHT;
+pub use paper::{
+    LOW_PAPER_SIGNAL, PaperSensor, PaperStatus, PaperWarning, paper_status, reset_roll,
+    set_low_paper_threshold, set_roll_length,
+};
+pub use preview::render_preview;
+pub use queue::{
+    JobPayload, MAX_COPIES, MAX_QUEUED, MAX_QUEUED_IMAGES, Priority, QueuedJob, cancel, enqueue,
+    queue_length, queue_position, queued_images,
+};
+pub use sanitize::ControlPolicy;
+pub use style::{Align, Style};
 
 const CHANNEL_SIZE: usize = 8;
-pub const DATA_SIZE: usize = 2048;
-pub type MessageData = heapless::String<DATA_SIZE>;
-type PrinterChannel = Channel<CriticalSectionRawMutex, MessageData, CHANNEL_SIZE>;
-type PrinterSender = Sender<'static, CriticalSectionRawMutex, MessageData, CHANNEL_SIZE>;
-type PrinterReceiver = Receiver<'static, CriticalSectionRawMutex, MessageData, CHANNEL_SIZE>;
+pub const CHUNK_SIZE: usize = 256;
+pub type JobChunk = heapless::Vec<u8, CHUNK_SIZE>;
+type PrinterChannel = Channel<CriticalSectionRawMutex, PrinterCommand, CHANNEL_SIZE>;
+type PrinterSender = Sender<'static, CriticalSectionRawMutex, PrinterCommand, CHANNEL_SIZE>;
+type PrinterReceiver = Receiver<'static, CriticalSectionRawMutex, PrinterCommand, CHANNEL_SIZE>;
 
 static PRINTER_CHANNEL: PrinterChannel = Channel::new();
-static MAX_CHARACTERS_PER_LINE: usize = 30;
+/// answers [`PrinterCommand::Idle`]
+static PRINTER_IDLE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// raster rows sent per `GS v 0` command, keeps the buffer small for arbitrarily long images
+const RASTER_BAND_ROWS: usize = 24;
+/// rates the MC206H and similar printers can be configured to, most common first
+pub const COMMON_BAUD_RATES: [u32; 5] = [9600, 19200, 115200, 38400, 57600];
+/// data still owed to a command cut off by an abort that is sent as zeros, a raster image may be
+/// owed too much to send in reasonable time
+const MAX_COMMAND_PADDING: usize = 1024;
+/// lines of a note held back to print it upside down, a note of many short lines is dropped as too
+/// long past this even when it is within [`job::MAX_UPSIDE_DOWN_LENGTH`]
+const MAX_UPSIDE_DOWN_LINES: usize = 256;
+/// how often the printer is asked for its status while idle
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+static PRINTER_ONLINE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the printer answered the last status query and wasn't offline
+pub fn printer_online() -> bool {
+    PRINTER_ONLINE.load(Ordering::Relaxed)
+}
+
+/// Keeps any job from starting while held
+pub type PrinterHold = MutexGuard<'static, CriticalSectionRawMutex, ()>;
+
+/// Waits for the job being printed to finish, then holds the printer idle
+pub async fn hold_printer() -> PrinterHold {
+    let hold = job::lock_jobs().await;
+    PRINTER_IDLE.reset();
+    PRINTER_CHANNEL.send(PrinterCommand::Idle).await;
+    PRINTER_IDLE.wait().await;
+    hold
+}
+
+pub enum PrinterCommand {
+    BeginJob {
+        id: u32,
+        options: JobOptions,
+    },
+    JobData(JobChunk),
+    EndJob,
+    AbortJob(JobError),
+    Banner {
+        banner: Banner,
+        options: JobOptions,
+    },
+    Image {
+        id: u32,
+        image: Image,
+        options: JobOptions,
+    },
+    Feed(Feed),
+    /// reprogram the printer's own serial speed, then switch the uart to match
+    SetBaudRate(u32),
+    /// commands are handled in order, so this is answered once everything sent before it has
+    /// reached the printer
+    Idle,
+}
 
 pub async fn start_printer(printer: ThermalPrinter, spawner: &Spawner) {
+    paper::load_paper_usage().await;
     let printer = ThermalPrinterService::new(printer).await;
 
     spawner.must_spawn(printer_task(printer));
+    spawner.must_spawn(queue::queue_task(PrinterWriter::new()));
+    spawner.must_spawn(paper::save_on_power_loss());
     info!("Printer initialized...");
 }
 
@@ -44,21 +155,68 @@ impl PrinterWriter {
         PrinterWriter { printer_tx }
     }
 
-    pub async fn chunk_print(&self, payload: &str) {
-        let mut offset: usize = 0;
-        while offset < payload.len() {
-            let page = (DATA_SIZE + offset).min(payload.len());
-            let slice = &payload[offset..page];
-            let message: MessageData = heapless::String::from_str(slice).unwrap();
-            self.print(message).await;
-            offset = page;
+    /// Starts a streamed job, waiting for any other job to finish first
+    pub async fn begin_job(&self, options: JobOptions) -> PrintJob {
+        PrintJob::begin(self.printer_tx, None, options).await
+    }
+
+    pub async fn print(&self, payload: &[u8], options: JobOptions) -> Result<(), JobError> {
+        self.print_job(None, payload, options).await
+    }
+
+    /// Prints under an id taken earlier with [`reserve_job_id`]
+    pub async fn print_as(
+        &self,
+        id: u32,
+        payload: &[u8],
+        options: JobOptions,
+    ) -> Result<(), JobError> {
+        self.print_job(Some(id), payload, options).await
+    }
+
+    async fn print_job(
+        &self,
+        id: Option<u32>,
+        payload: &[u8],
+        options: JobOptions,
+    ) -> Result<(), JobError> {
+        info!("Sending {} bytes", payload.len());
+        let mut job = PrintJob::begin(self.printer_tx, id, options.clone()).await;
+        history::keep_text(job.id(), payload, &options);
+        match job.write(payload).await {
+            Ok(()) => {
+                job.finish().await;
+                info!("Data sent");
+                Ok(())
+            }
+            Err(e) => {
+                job.abort(e).await;
+                Err(e)
+            }
         }
     }
 
-    pub async fn print(&self, buf: MessageData) {
-        info!("Sending data: {}", buf);
-        self.printer_tx.send(buf).await;
-        info!("Data sent");
+    pub async fn print_banner(&self, banner: Banner, options: JobOptions) {
+        info!("Sending banner: {}", banner.text());
+        let command = PrinterCommand::Banner { banner, options };
+        PrintJob::exclusive(self.printer_tx, command).await;
+    }
+
+    /// Prints an image under an id taken earlier with [`reserve_job_id`]
+    pub async fn print_image(&self, id: u32, image: Image, options: JobOptions) {
+        info!("Sending image of {} rows", image.rows());
+        let command = PrinterCommand::Image { id, image, options };
+        PrintJob::exclusive(self.printer_tx, command).await;
+    }
+
+    pub async fn feed(&self, feed: Feed) {
+        info!("Requesting paper feed: {}", feed);
+        PrintJob::exclusive(self.printer_tx, PrinterCommand::Feed(feed)).await;
+    }
+
+    pub async fn set_baud_rate(&self, baud_rate: u32) {
+        info!("Requesting printer baud rate change to: {}", baud_rate);
+        PrintJob::exclusive(self.printer_tx, PrinterCommand::SetBaudRate(baud_rate)).await;
     }
 }
 
@@ -68,92 +226,555 @@ impl Default for PrinterWriter {
     }
 }
 
+/// Decoding and layout state of the job currently being received
+struct ActiveJob {
+    id: u32,
+    options: JobOptions,
+    policy: ControlPolicy,
+    decoder: utf8::Utf8Decoder,
+    escpos: escpos::EscPosFilter,
+    formatter: format::Formatter,
+    /// wrapped lines not yet sent to the printer
+    lines: Vec<layout::Line>,
+    received: usize,
+}
+
+impl ActiveJob {
+    fn new(id: u32, options: JobOptions) -> Self {
+        let policy = options.control_policy();
+        let formatter = format::Formatter::new(options.format, options.style);
+        let mut lines = Vec::new();
+        if !policy.bypasses_layout() {
+            decoration::push_header(&options, id, &mut lines);
+        }
+
+        Self {
+            id,
+            options,
+            policy,
+            decoder: utf8::Utf8Decoder::new(),
+            escpos: escpos::EscPosFilter::new(),
+            formatter,
+            lines,
+            received: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.received += data.len();
+        let Self {
+            policy,
+            decoder,
+            formatter,
+            lines,
+            ..
+        } = self;
+        decoder.decode(data, |text| {
+            formatter.push_str(&sanitize::sanitize(text, *policy), lines)
+        });
+    }
+
+    fn finish(&mut self) {
+        let Self {
+            policy,
+            decoder,
+            formatter,
+            lines,
+            ..
+        } = self;
+        decoder.finish(|text| formatter.push_str(&sanitize::sanitize(text, *policy), lines));
+        formatter.finish(lines);
+        if !self.policy.bypasses_layout() {
+            decoration::push_footer(&self.options, self.id, &mut self.lines);
+        }
+    }
+}
+
 struct ThermalPrinterService {
     printer: ThermalPrinter,
     printer_rx: PrinterReceiver,
+    job: Option<ActiveJob>,
+    /// dots of paper fed since the usage was last recorded
+    fed: u32,
+    /// lines printed in the current job
+    lines: u32,
+    /// print modes the printer is currently set to
+    style: Style,
+    /// rotation the printer is currently set to, the whole job is laid out for it
+    upside_down: bool,
 }
 
 impl ThermalPrinterService {
     async fn new(mut printer: ThermalPrinter) -> Self {
-        printer.send_data(&[0x1B, b'@']).await; // ESC @
-        printer.send_data(&[0x1B, b'7', 15, 150, 250]).await; // print density
-        printer.send_data(&[0x1B, b'{', 0x01]).await; // 180° rotation
+        if config().printer.auto_detect_baud {
+            detect_baud_rate(&mut printer).await;
+        }
+
+        let upside_down = config().printer.upside_down;
+        initialize(&mut printer, upside_down).await;
+        check_online(&mut printer).await;
 
         let printer_rx = PRINTER_CHANNEL.receiver();
 
         Self {
             printer,
             printer_rx,
+            job: None,
+            fed: 0,
+            lines: 0,
+            style: Style::default(),
+            upside_down,
         }
     }
-    async fn print(&mut self, text: &[u8]) {
-        debug!("creating lines: {}", text);
 
-        let mut lines = Vec::new();
+    /// Resets the printer, applying the rotation as it is configured now
+    async fn reset(&mut self) {
+        self.upside_down = config().printer.upside_down;
+        initialize(&mut self.printer, self.upside_down).await;
+    }
+
+    /// Turns the printer around if `upside_down` was changed since it was last set, called before
+    /// anything is printed
+    async fn update_rotation(&mut self) {
+        let upside_down = config().printer.upside_down;
+        if upside_down != self.upside_down {
+            info!("Printer rotation changed, upside down: {}", upside_down);
+            self.printer
+                .send_data(&[0x1B, b'{', upside_down as u8])
+                .await; // 180° rotation
+            self.upside_down = upside_down;
+        }
+    }
+
+    async fn begin_job(&mut self, id: u32, options: JobOptions) {
+        if self.job.is_some() {
+            warn!("Previous job was never finished, aborting it");
+            self.abort_job(JobError::Disconnected).await;
+        }
+        info!("Print job {} started from {}", id, options.source);
+        self.lines = 0;
+        self.update_rotation().await;
+        self.feed(options.leading_feed()).await;
+        self.job = Some(ActiveJob::new(id, options));
+    }
+
+    async fn receive_job_data(&mut self, data: &[u8]) {
+        let Some(job) = self.job.as_mut() else {
+            warn!("Received job data outside of a job, discarding it");
+            return;
+        };
+        if job.policy.bypasses_layout() {
+            job.received += data.len();
+            let mut filtered = Vec::new();
+            let data = match job.policy {
+                ControlPolicy::Passthrough => {
+                    job.escpos.filter(data, &mut filtered);
+                    &filtered
+                }
+                _ => data,
+            };
+            // raw jobs lay out their own lines, their line feeds are the best guess of the paper
+            let line_feeds = data.iter().filter(|byte| **byte == b'\n').count() as u32;
+            self.fed += line_feeds * layout::LINE_HEIGHT as u32;
+            self.lines += line_feeds;
+            self.printer.send_data(data).await;
+            return;
+        }
+        job.push(data);
+
+        // the lines have to be printed last to first for the note to read top to bottom when
+        // printing upside down, so the whole note is held back
+        if !self.upside_down {
+            let lines = core::mem::take(&mut job.lines);
+            self.print_lines(lines).await;
+        } else if job.lines.len() > MAX_UPSIDE_DOWN_LINES {
+            warn!(
+                "Note is longer than {} lines, too long to print upside down",
+                MAX_UPSIDE_DOWN_LINES
+            );
+            self.abort_job(JobError::TooLong).await;
+        }
+    }
+
+    async fn end_job(&mut self) {
+        let Some(mut job) = self.job.take() else {
+            warn!("Received the end of a job that was never started");
+            return;
+        };
+        job.finish();
+
+        info!("Printing {} bytes", job.received);
+        self.print_lines(core::mem::take(&mut job.lines)).await;
+        self.set_style(Style::default()).await;
+        if job.policy.bypasses_layout() {
+            // undo whatever settings the job changed
+            self.reset().await;
+        }
+
+        info!("Print complete");
+        self.finish_paper(&job.options).await;
+        history::finished(job.id, job.received, self.lines);
+        let paper_dots = self.record_usage(true).await;
+        self.charge(&job, paper_dots);
+    }
 
-        // First, split by explicit newlines
-        let text = match str::from_utf8(text.strip_suffix(&[0xD]).unwrap_or(text)) {
-            Ok(v) => v,
-            Err(_) => {
-                warn!("Failed to decode utf8 to str");
-                return;
+    async fn abort_job(&mut self, error: JobError) {
+        info!("Print job aborted: {}", error);
+        let job = self.job.take();
+        self.set_style(Style::default()).await;
+        if let Some(job) = &job
+            && job.policy.bypasses_layout()
+        {
+            // the job may have stopped partway through a command, which would take the reset
+            // as its data, and it may have left any mode set
+            let mut pending = job.escpos.pending();
+            if pending <= MAX_COMMAND_PADDING {
+                let zeros = [0u8; 64];
+                while pending > 0 {
+                    let length = pending.min(zeros.len());
+                    self.printer.send_data(&zeros[..length]).await;
+                    pending -= length;
+                }
             }
+            self.reset().await;
+        }
+
+        let paper_dots = self.record_usage(false).await;
+        // whatever was printed before the abort still counts
+        if let Some(job) = job {
+            history::failed(job.id, error, job.received, self.lines);
+            self.charge(&job, paper_dots);
+        }
+    }
+
+    /// Counts the job against the limits of whoever sent it
+    fn charge(&mut self, job: &ActiveJob, paper_dots: u32) {
+        let usage = JobUsage {
+            bytes: job.received as u32,
+            lines: core::mem::take(&mut self.lines),
+            paper_dots,
         };
-        for raw_line in text.lines() {
-            let mut remaining = raw_line.trim();
+        limits::record(&job.options.requesters, usage);
+    }
 
-            while !remaining.is_empty() {
-                let take_len = core::cmp::min(MAX_CHARACTERS_PER_LINE, remaining.len());
-                let slice = &remaining[..take_len];
+    async fn print_lines(&mut self, lines: Vec<layout::Line>) {
+        if self.upside_down {
+            for line in lines.iter().rev() {
+                self.print_line(line).await;
+            }
+        } else {
+            for line in lines.iter() {
+                self.print_line(line).await;
+            }
+        }
+    }
+
+    async fn print_banner(&mut self, banner: &Banner, options: &JobOptions) {
+        info!("Printing banner of {} rows", banner.raster_rows());
+        self.update_rotation().await;
+        self.feed(options.leading_feed()).await;
 
-                // Try to break at the last space within the slice
-                let break_point = slice.rfind(' ').unwrap_or(take_len);
-                let split_idx = if break_point == 0 {
-                    take_len
+        // upside down the banner is printed from its last row, each row turned around
+        let upside_down = self.upside_down;
+        let mut rows = banner.rows();
+        let mut band = [0u8; banner::ROW_BYTES * RASTER_BAND_ROWS];
+        loop {
+            let mut band_rows = 0;
+            for chunk in band.chunks_exact_mut(banner::ROW_BYTES) {
+                let row = if upside_down {
+                    rows.next_back()
                 } else {
-                    break_point
+                    rows.next()
+                };
+                let Some(row) = row else {
+                    break;
                 };
+                chunk.copy_from_slice(&row);
+                if upside_down {
+                    bitmap_font::rotate_raster(chunk);
+                }
+                band_rows += 1;
+            }
+            if band_rows == 0 {
+                break;
+            }
 
-                let (line, rest) = remaining.split_at(split_idx);
-                lines.push(line.trim());
+            self.print_raster(&band[..band_rows * banner::ROW_BYTES], banner::ROW_BYTES)
+                .await;
+        }
 
-                remaining = rest.trim_start();
-            }
+        info!("Banner complete");
+        self.finish_paper(options).await;
+        self.record_usage(true).await;
+    }
+
+    async fn print_image(&mut self, id: u32, mut image: Image, options: &JobOptions) {
+        info!("Printing image of {} rows", image.rows());
+        history::started(id, options);
+        self.update_rotation().await;
+        if self.upside_down {
+            bitmap_font::rotate_raster(image.raster_mut());
         }
+        self.feed(options.leading_feed()).await;
 
-        info!("Printing");
-        for line in lines.into_iter().rev() {
-            self.print_line(line).await;
+        for band in image.raster().chunks(image::ROW_BYTES * RASTER_BAND_ROWS) {
+            self.print_raster(band, image::ROW_BYTES).await;
         }
 
-        info!("Print complete");
-        self.advance_paper(1).await;
+        info!("Image complete");
+        self.finish_paper(options).await;
+        history::finished(id, image.raster().len(), 0);
+        let paper_dots = self.record_usage(true).await;
+        let usage = JobUsage {
+            bytes: image.raster().len() as u32,
+            lines: 0,
+            paper_dots,
+        };
+        limits::record(&options.requesters, usage);
+    }
+
+    /// Prints a 1 bit image, `data` holds whole rows of `row_bytes` each
+    async fn print_raster(&mut self, data: &[u8], row_bytes: usize) {
+        let rows = data.len() / row_bytes;
+        let [width_low, width_high] = (row_bytes as u16).to_le_bytes();
+        let [rows_low, rows_high] = (rows as u16).to_le_bytes();
+
+        self.printer
+            .send_data(&[
+                0x1D, b'v', b'0', 0x00, width_low, width_high, rows_low, rows_high,
+            ])
+            .await; // GS v 0
+        self.printer.send_data(data).await;
+        self.fed += rows as u32;
+    }
+
+    /// Feeds the paper clear of the print head and cuts it, as configured for the job
+    async fn finish_paper(&mut self, options: &JobOptions) {
+        self.feed(options.trailing_feed()).await;
+        self.cut(options.cut()).await;
     }
 
-    async fn advance_paper(&mut self, lines: usize) {
-        debug!("Advancing: {} lines", lines);
-        for _ in 0..lines {
-            self.printer.send_data(&[0x0A]).await; // LF
+    async fn feed(&mut self, feed: Feed) {
+        debug!("Feeding: {}", feed);
+        self.fed += feed.dots(layout::LINE_HEIGHT as u32);
+        match feed {
+            Feed::Lines(0) | Feed::Millimetres(0) => {}
+            Feed::Lines(lines) => {
+                self.printer.send_data(&[0x1B, b'd', lines]).await; // ESC d
+            }
+            Feed::Millimetres(mm) => {
+                let mut dots = mm as u32 * feed::DOTS_PER_MM;
+                while dots > 0 {
+                    let step = dots.min(u8::MAX as u32);
+                    self.printer.send_data(&[0x1B, b'J', step as u8]).await; // ESC J
+                    dots -= step;
+                }
+            }
+        }
+    }
+
+    async fn cut(&mut self, cut: CutMode) {
+        match cut {
+            CutMode::None => {}
+            CutMode::Partial => self.printer.send_data(&[0x1D, b'V', 0x01]).await, // GS V 1
+            CutMode::Full => self.printer.send_data(&[0x1D, b'V', 0x00]).await,    // GS V 0
         }
     }
 
-    async fn print_line(&mut self, line: &str) {
-        debug!("Printing line: {}", line);
+    /// Sends the line as text, or draws it with the bitmap font when the printer's rom lacks
+    /// some of its characters
+    async fn print_line(&mut self, line: &layout::Line) {
+        debug!("Printing line: {}", line.text.as_str());
+        self.lines += 1;
 
-        self.printer.send_data(line.as_bytes()).await;
+        if bitmap_font::needs_raster(&line.text) {
+            let mut raster = bitmap_font::render_line(&line.text);
+            if self.upside_down {
+                bitmap_font::rotate_raster(&mut raster);
+            }
+            for band in raster.chunks(bitmap_font::ROW_BYTES * RASTER_BAND_ROWS) {
+                self.print_raster(band, bitmap_font::ROW_BYTES).await;
+            }
+            return;
+        }
+
+        self.set_style(line.printed_style()).await;
+        let text: String = line.text.chars().map(bitmap_font::substitute).collect();
+        self.printer.send_data(text.as_bytes()).await;
         self.printer.send_data(&[0x0A]).await; // LF
+        self.fed += line.printed_style().line_height() as u32;
+    }
+
+    async fn set_style(&mut self, style: Style) {
+        if style == self.style {
+            return;
+        }
+        self.printer
+            .send_data(&[0x1B, b'!', style.print_mode()])
+            .await; // ESC !
+        self.printer
+            .send_data(&[0x1D, b'B', style.inverse as u8])
+            .await; // GS B
+        self.printer
+            .send_data(&[0x1B, b'a', style.align as u8])
+            .await; // ESC a
+        self.style = style;
+    }
+
+    /// Adds the paper fed since the last call to the usage totals, `job` when it was all fed
+    /// for a single finished job
+    async fn record_usage(&mut self, job: bool) -> u32 {
+        let fed = core::mem::take(&mut self.fed);
+        if job {
+            info!("Job used {} mm of paper", fed / feed::DOTS_PER_MM);
+        }
+        paper::record_usage(fed, job).await;
+        fed
+    }
+
+    /// Uses the epson `GS ( E` user setup commands to persist the new serial speed in the
+    /// printer, which applies it once user setup mode is ended.
+    async fn set_baud_rate(&mut self, baud_rate: u32) {
+        if !COMMON_BAUD_RATES.contains(&baud_rate) {
+            warn!("Refusing to set unsupported baud rate: {}", baud_rate);
+            return;
+        }
+
+        let mut digits = heapless::String::<10>::new();
+        if write!(digits, "{baud_rate}").is_err() {
+            return;
+        }
+        let len = 2 + digits.len() as u8;
+
+        self.printer
+            .send_data(&[0x1D, b'(', b'E', 0x03, 0x00, 0x01, b'I', b'N'])
+            .await; // GS ( E fn=1, enter user setup mode
+        self.printer
+            .send_data(&[0x1D, b'(', b'E', len, 0x00, 0x0B, 0x01])
+            .await; // GS ( E fn=11, set transmission speed
+        self.printer.send_data(digits.as_bytes()).await;
+        self.printer
+            .send_data(&[0x1D, b'(', b'E', 0x04, 0x00, 0x02, b'O', b'U', b'T'])
+            .await; // GS ( E fn=2, end user setup mode
+
+        let previous = self.printer.baud_rate();
+        if self.printer.set_baud_rate(baud_rate).await.is_err() {
+            return;
+        }
+
+        // the printer resets when leaving user setup mode
+        Timer::after(Duration::from_secs(2)).await;
+        if self.printer.query_status().await.is_some() {
+            info!("Printer baud rate changed to: {}", baud_rate);
+            self.reset().await;
+            // the uart has to open at the new rate after a reboot
+            let mut updated = config();
+            updated.printer.baud_rate = baud_rate;
+            if save_settings(&updated).await.is_err() {
+                warn!("Failed to save the new baud rate");
+            }
+            update_config(|config| config.printer.baud_rate = baud_rate);
+        } else {
+            warn!(
+                "Printer did not respond at {} baud, reverting to {}",
+                baud_rate, previous
+            );
+            let _ = self.printer.set_baud_rate(previous).await;
+        }
     }
 
-    // fn set_inverse(&mut self, enable: bool) {
-    //     uart.write_all(&[0x1D, b'B', if enable { 1 } else { 0 }])
-    //         .unwrap();
-    // }
-    //
     async fn run(mut self) {
         loop {
-            let data: MessageData = self.printer_rx.receive().await;
-            info!("Received data: {}", data);
-            self.print(data.as_bytes()).await;
+            let Ok(command) = with_timeout(STATUS_INTERVAL, self.printer_rx.receive()).await else {
+                if self.job.is_none() {
+                    check_online(&mut self.printer).await;
+                }
+                continue;
+            };
+
+            match command {
+                PrinterCommand::BeginJob { id, options } => self.begin_job(id, options).await,
+                PrinterCommand::JobData(data) => self.receive_job_data(&data).await,
+                PrinterCommand::EndJob => {
+                    self.end_job().await;
+                    // the paper most often runs out during a job
+                    check_online(&mut self.printer).await;
+                }
+                PrinterCommand::AbortJob(error) => self.abort_job(error).await,
+                PrinterCommand::Banner { banner, options } => {
+                    self.print_banner(&banner, &options).await
+                }
+                PrinterCommand::Image { id, image, options } => {
+                    self.print_image(id, image, &options).await
+                }
+                PrinterCommand::Feed(feed) => {
+                    self.feed(feed).await;
+                    self.record_usage(false).await;
+                }
+                PrinterCommand::SetBaudRate(baud_rate) => self.set_baud_rate(baud_rate).await,
+                PrinterCommand::Idle => {
+                    self.printer.flush().await;
+                    PRINTER_IDLE.signal(());
+                }
+            }
+        }
+    }
+}
+
+/// Resets the printer and applies the settings every job expects
+async fn initialize(printer: &mut ThermalPrinter, upside_down: bool) {
+    printer.send_data(&[0x1B, b'@']).await; // ESC @
+    printer.send_data(&[0x1B, b'7', 15, 150, 250]).await; // print density
+    printer.send_data(&[0x1B, b'{', upside_down as u8]).await; // 180° rotation
+}
+
+/// Asks for the printer's status, which sets bit 3 while it is offline, and then for its paper
+/// sensor
+async fn check_online(printer: &mut ThermalPrinter) {
+    let online = printer
+        .query_status()
+        .await
+        .is_some_and(|status| status & 0x08 == 0);
+    if PRINTER_ONLINE.swap(online, Ordering::Relaxed) != online {
+        info!("Printer is {}", if online { "online" } else { "offline" });
+        events::publish(Event::Printer { online });
+    }
+
+    let sensor = match printer.query_paper_sensor().await {
+        Some(status) => PaperSensor::from_status(status),
+        None => PaperSensor::Unknown,
+    };
+    paper::set_sensor(sensor).await;
+}
+
+/// Tries the configured baud rate followed by the common rates, keeping the first one the
+/// printer answers a status query on.
+async fn detect_baud_rate(printer: &mut ThermalPrinter) {
+    let configured = config().printer.baud_rate;
+    let candidates = core::iter::once(configured).chain(
+        COMMON_BAUD_RATES
+            .into_iter()
+            .filter(|rate| *rate != configured),
+    );
+
+    for baud_rate in candidates {
+        debug!("Probing printer at {} baud", baud_rate);
+        if printer.set_baud_rate(baud_rate).await.is_err() {
+            continue;
+        }
+        if let Some(status) = printer.query_status().await {
+            info!(
+                "Printer detected at {} baud, status: {:#x}",
+                baud_rate, status
+            );
+            update_config(|config| config.printer.baud_rate = baud_rate);
+            return;
         }
     }
+
+    warn!(
+        "Printer did not respond to any baud rate, falling back to {}",
+        configured
+    );
+    let _ = printer.set_baud_rate(configured).await;
 }
@@ -0,0 +1,120 @@
+use alloc::vec::Vec;
+
+use super::{
+    font::{self, GLYPH_HEIGHT, GLYPH_WIDTH},
+    layout::PAPER_WIDTH,
+};
+
+pub const BANNER_TEXT_SIZE: usize = 64;
+pub type BannerText = heapless::String<BANNER_TEXT_SIZE>;
+
+pub const ROW_BYTES: usize = PAPER_WIDTH / 8;
+/// largest glyph scale that still leaves room for the border across the paper
+pub const MAX_BANNER_SIZE: u8 = 48;
+pub const MAX_BANNER_BORDER: u8 = 16;
+const DEFAULT_BANNER_SIZE: u8 = 32;
+/// blank glyph columns before the first and after the last character
+const MARGIN_COLUMNS: usize = 2;
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct BannerOptions {
+    /// dots per glyph pixel
+    pub size: u8,
+    pub inverse: bool,
+    /// border thickness in dots, 0 disables it
+    pub border: u8,
+}
+
+impl Default for BannerOptions {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_BANNER_SIZE,
+            inverse: false,
+            border: 0,
+        }
+    }
+}
+
+/// Text printed sideways in a large bitmap font, so that it runs along the length of the roll.
+///
+/// The raster is generated a row at a time, only the text is kept in memory.
+pub struct Banner {
+    text: BannerText,
+    options: BannerOptions,
+}
+
+impl Banner {
+    pub fn new(text: BannerText, options: BannerOptions) -> Self {
+        let options = BannerOptions {
+            size: options.size.clamp(1, MAX_BANNER_SIZE),
+            border: options.border.min(MAX_BANNER_BORDER),
+            ..options
+        };
+
+        Self { text, options }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Total number of raster rows the banner occupies along the paper
+    pub fn raster_rows(&self) -> usize {
+        let characters = self.text.chars().count();
+        let columns = 2 * MARGIN_COLUMNS + (characters * (GLYPH_WIDTH + 1)).saturating_sub(1);
+
+        columns * self.options.size as usize
+    }
+
+    /// Yields every raster row of the banner, one dot per bit with the msb on the left. The rows
+    /// can be taken from the end to print the banner upside down.
+    pub fn rows(&self) -> impl DoubleEndedIterator<Item = [u8; ROW_BYTES]> + '_ {
+        let size = self.options.size as usize;
+        let border = self.options.border as usize;
+        let total_rows = self.raster_rows();
+        let columns = self.columns();
+
+        (0..total_rows).map(move |index| {
+            let is_cap = index < border || index + border >= total_rows;
+            self.render_row(columns[index / size], is_cap)
+        })
+    }
+
+    /// Glyph columns along the length of the banner, with the top of the glyph in the lsb
+    fn columns(&self) -> Vec<u8> {
+        let margin = core::iter::repeat_n(0u8, MARGIN_COLUMNS);
+        let glyphs = self.text.chars().enumerate().flat_map(|(index, c)| {
+            let spacing = (index != 0).then_some(0u8);
+            spacing.into_iter().chain(font::glyph(c).iter().copied())
+        });
+
+        margin.clone().chain(glyphs).chain(margin).collect()
+    }
+
+    fn render_row(&self, column: u8, is_cap: bool) -> [u8; ROW_BYTES] {
+        let size = self.options.size as usize;
+        let border = self.options.border as usize;
+        let glyph_width = GLYPH_HEIGHT * size;
+        let offset = (PAPER_WIDTH - glyph_width) / 2;
+
+        let mut row = [0u8; ROW_BYTES];
+        for x in 0..PAPER_WIDTH {
+            let is_border = is_cap || x < border || x >= PAPER_WIDTH - border;
+            let dot = if is_border {
+                !self.options.inverse
+            } else {
+                let set = x
+                    .checked_sub(offset)
+                    .filter(|x| *x < glyph_width)
+                    .is_some_and(|x| column & (1 << (x / size)) != 0);
+                set != self.options.inverse
+            };
+
+            if dot {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+
+        row
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::vec::Vec;
+
+use super::layout::{CHAR_HEIGHT, CHAR_WIDTH, LINE_HEIGHT, PAPER_WIDTH};
+
+pub const ROW_BYTES: usize = PAPER_WIDTH / 8;
+
+/// A glyph of the font compiled in by the build script, see `PRINTER_FONT` in the readme
+pub struct Glyph {
+    c: char,
+    /// advance in dots
+    width: u8,
+    /// start of the glyph in `BITMAPS`, in columns of `FONT_HEIGHT` bytes so a large font still
+    /// fits the index in 16 bits
+    column: u16,
+}
+
+include!(concat!(env!("OUT_DIR"), "/bitmap_font.rs"));
+
+fn glyph(c: char) -> Option<&'static Glyph> {
+    GLYPHS
+        .binary_search_by_key(&c, |glyph| glyph.c)
+        .ok()
+        .map(|index| &GLYPHS[index])
+}
+
+/// Whether the printer's rom can print `c` as text
+pub fn is_native(c: char) -> bool {
+    c.is_ascii()
+}
+
+/// The character actually printed for `c`, anything neither the printer nor the font has
+/// becomes `?`
+pub fn substitute(c: char) -> char {
+    if is_native(c) || glyph(c).is_some() {
+        c
+    } else {
+        '?'
+    }
+}
+
+/// Number of font A character cells `c` takes up, wide glyphs such as cjk take two
+pub fn columns(c: char) -> usize {
+    match glyph(c) {
+        Some(glyph) if !is_native(c) => (glyph.width as usize).div_ceil(CHAR_WIDTH).max(1),
+        _ => 1,
+    }
+}
+
+/// Whether the line has to be drawn with the bitmap font rather than sent as text
+pub fn needs_raster(line: &str) -> bool {
+    line.chars().any(|c| !is_native(c) && glyph(c).is_some())
+}
+
+/// Draws the line as `LINE_HEIGHT` raster rows, keeping every character in the cells the native
+/// font would give it so raster lines line up with text lines
+pub fn render_line(line: &str) -> Vec<u8> {
+    let mut raster = alloc::vec![0u8; ROW_BYTES * LINE_HEIGHT];
+    // sit the glyphs on the bottom of the native character cell
+    let top = CHAR_HEIGHT.saturating_sub(FONT_HEIGHT);
+    let mut cell = 0;
+
+    for c in line.chars() {
+        let cells = columns(c);
+        let Some(glyph) = glyph(substitute(c)) else {
+            cell += cells;
+            continue;
+        };
+
+        let width = glyph.width as usize;
+        let row_bytes = width.div_ceil(8);
+        let left = cell * CHAR_WIDTH + (cells * CHAR_WIDTH).saturating_sub(width) / 2;
+        let offset = glyph.column as usize * FONT_HEIGHT;
+        let bitmap = &BITMAPS[offset..][..row_bytes * FONT_HEIGHT];
+
+        for (y, row) in bitmap.chunks_exact(row_bytes).enumerate() {
+            let y = top + y;
+            if y >= LINE_HEIGHT {
+                break;
+            }
+            for x in (0..width).filter(|x| row[x / 8] & (0x80 >> (x % 8)) != 0) {
+                let x = left + x;
+                if x < PAPER_WIDTH {
+                    raster[y * ROW_BYTES + x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+        cell += cells;
+    }
+
+    raster
+}
+
+/// Turns a raster 180° in place, `ESC {` only rotates text so images are sent pre-rotated
+pub fn rotate_raster(raster: &mut [u8]) {
+    raster.reverse();
+    for byte in raster.iter_mut() {
+        *byte = byte.reverse_bits();
+    }
+}
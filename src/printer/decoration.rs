@@ -0,0 +1,92 @@
+use core::fmt::Write as _;
+
+use alloc::{string::String, vec::Vec};
+use embassy_time::Instant;
+
+use super::{
+    ControlPolicy, JobOptions, JobSource,
+    layout::{Line, LineWrapper, MAX_CHARACTERS_PER_LINE},
+    sanitize::sanitize,
+};
+use crate::{
+    config::{BlockConfig, Divider},
+    time,
+};
+
+/// Appends the job's header block, if enabled, to the lines to print
+pub fn push_header(options: &JobOptions, id: u32, lines: &mut Vec<Line>) {
+    if let Some(block) = options.header() {
+        push_details(&block, &options.source, id, lines);
+        push_divider(block.divider, lines);
+    }
+}
+
+/// Appends the job's footer block, if enabled, to the lines to print
+pub fn push_footer(options: &JobOptions, id: u32, lines: &mut Vec<Line>) {
+    if let Some(block) = options.footer() {
+        push_divider(block.divider, lines);
+        push_details(&block, &options.source, id, lines);
+    }
+}
+
+fn push_details(block: &BlockConfig, source: &JobSource, id: u32, lines: &mut Vec<Line>) {
+    let mut wrapper = LineWrapper::new();
+    let mut text = String::new();
+
+    // writing into a String cannot fail
+    if block.time {
+        let _ = write_timestamp(&mut text);
+    }
+    if block.job_id {
+        let _ = write!(text, "  #{id}");
+    }
+    text.push('\n');
+    if block.source {
+        let _ = match source {
+            JobSource::Web => write!(text, "from web"),
+            JobSource::Mqtt(topic) => write!(
+                text,
+                "from {}",
+                sanitize(topic, ControlPolicy::Strip).replace(['\t', '\n', '\r'], " ")
+            ),
+            JobSource::Schedule(id) => write!(text, "schedule {id}"),
+            JobSource::Socket => write!(text, "from port {}", crate::net::raw::PORT),
+        };
+    }
+
+    wrapper.push_str(&text, lines);
+    wrapper.finish(lines);
+}
+
+/// Local time, or the time since boot while the clock is not synchronized
+fn write_timestamp(text: &mut String) -> core::fmt::Result {
+    if let Some(now) = time::now() {
+        return write!(
+            text,
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            now.year, now.month, now.day, now.hour, now.minute
+        );
+    }
+
+    let seconds = Instant::now().as_secs();
+    write!(
+        text,
+        "up {}d {:02}:{:02}:{:02}",
+        seconds / 86_400,
+        seconds / 3_600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn push_divider(divider: Divider, lines: &mut Vec<Line>) {
+    let c = match divider {
+        Divider::None => return,
+        Divider::Dashes => '-',
+        Divider::Equals => '=',
+        Divider::Dots => '.',
+    };
+    lines.push(Line::new(
+        core::iter::repeat_n(c, MAX_CHARACTERS_PER_LINE).collect(),
+    ));
+}
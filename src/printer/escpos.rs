@@ -0,0 +1,413 @@
+use alloc::vec::Vec;
+
+const ESC: u8 = 0x1B;
+const GS: u8 = 0x1D;
+const FS: u8 = 0x1C;
+const DLE: u8 = 0x10;
+const DC2: u8 = 0x12;
+/// longest fixed part of a command, the prefix and command bytes included
+const MAX_HEADER: usize = 8;
+/// longest argument list ended by a NUL, such as tab stops or barcode data
+const MAX_TERMINATED: usize = 255;
+
+/// What is done with a command
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Pass,
+    Drop,
+}
+
+/// How a command's arguments are laid out
+#[derive(Clone, Copy)]
+enum Args {
+    /// this many bytes after the command byte
+    Fixed(usize),
+    /// bytes up to and including a NUL
+    Terminated,
+    /// a header of this many bytes after the command byte, followed by data whose length
+    /// depends on the header
+    Header(usize),
+}
+
+/// The commands that are understood, `None` for anything else
+fn command(prefix: u8, command: u8) -> Option<(Action, Args)> {
+    use Action::{Drop, Pass};
+    use Args::{Fixed, Header, Terminated};
+
+    let spec = match (prefix, command) {
+        // initialize, default line spacing
+        (ESC, b'@' | b'2') => (Pass, Fixed(0)),
+        // print mode, underline, bold, double strike, font, justification, feeds, line spacing,
+        // upside down, rotation, character spacing, code table, character set, reverse
+        (
+            ESC,
+            b'!' | b'-' | b'E' | b'G' | b'M' | b'a' | b'd' | b'J' | b'3' | b'{' | b'V' | b' '
+            | b't' | b'R' | b'B',
+        ) => (Pass, Fixed(1)),
+        // absolute and relative position
+        (ESC, b'$' | b'\\') => (Pass, Fixed(2)),
+        // tab stops
+        (ESC, b'D') => (Pass, Terminated),
+        // bit image, `m nL nH` and the columns
+        (ESC, b'*') => (Pass, Header(3)),
+        // heating, which can damage the head, is the device's to set
+        (ESC, b'7') => (Drop, Fixed(3)),
+        // turning the printer off or to sleep, panel buttons, cash drawer
+        (ESC, b'=') => (Drop, Fixed(1)),
+        (ESC, b'8') => (Drop, Fixed(2)),
+        (ESC, b'c') => (Drop, Fixed(2)),
+        (ESC, b'p') => (Drop, Fixed(3)),
+
+        // character size, reverse, barcode height, width, text position and font
+        (GS, b'!' | b'B' | b'h' | b'w' | b'H' | b'f') => (Pass, Fixed(1)),
+        // left margin, print area width
+        (GS, b'L' | b'W') => (Pass, Fixed(2)),
+        // barcodes, `m` and data ended by NUL or counted
+        (GS, b'k') => (Pass, Header(1)),
+        // raster images, `m xL xH yL yH` and the rows
+        (GS, b'v') => (Pass, Header(6)),
+        // cut, `m` and a feed for some modes
+        (GS, b'V') => (Pass, Header(1)),
+        // qr codes and other two dimensional codes are passed, the rest of the `(` family holds
+        // the user setup commands that persist settings such as the baud rate
+        (GS, b'(') => (Pass, Header(3)),
+        // automatic status back and status requests would answer on the uart
+        (GS, b'a' | b'r') => (Drop, Fixed(1)),
+
+        // real time requests and power off
+        (DLE, 0x04) => (Drop, Fixed(1)),
+        (DLE, 0x14) => (Drop, Fixed(3)),
+        // density and test page
+        (DC2, b'#') => (Drop, Fixed(1)),
+        (DC2, b'T') => (Drop, Fixed(0)),
+        _ => return None,
+    };
+    Some(spec)
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Text,
+    /// reading a command's fixed bytes into the header, which has this length when complete
+    Header(usize),
+    /// bytes of data after a command's header
+    Data {
+        remaining: usize,
+        action: Action,
+    },
+    /// a byte counting the data after it
+    Counted {
+        action: Action,
+    },
+    /// arguments up to a NUL
+    Terminated {
+        remaining: usize,
+        action: Action,
+    },
+}
+
+/// Filters ESC/POS so trusted producers can format their own jobs without being able to change
+/// the printer's stored settings, damage it or confuse the device reading its status. Known
+/// commands are passed through or dropped whole, including their data; other commands lose
+/// their prefix, so their arguments print as text.
+pub struct EscPosFilter {
+    state: State,
+    header: heapless::Vec<u8, MAX_HEADER>,
+}
+
+impl EscPosFilter {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Text,
+            header: heapless::Vec::new(),
+        }
+    }
+
+    pub fn filter(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        for &byte in data {
+            self.push(byte, output);
+        }
+    }
+
+    /// Bytes the printer still expects for a command that has been passed on, a zero ends
+    /// those that run up to a NUL or count their own data
+    pub fn pending(&self) -> usize {
+        match self.state {
+            State::Data {
+                remaining,
+                action: Action::Pass,
+            } => remaining,
+            State::Counted {
+                action: Action::Pass,
+            }
+            | State::Terminated {
+                action: Action::Pass,
+                ..
+            } => 1,
+            // headers are only passed once they are complete
+            _ => 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8, output: &mut Vec<u8>) {
+        let pass = |action: Action, output: &mut Vec<u8>| {
+            if action == Action::Pass {
+                output.push(byte);
+            }
+        };
+        self.state = match self.state {
+            State::Text => match byte {
+                ESC | GS | FS | DLE | DC2 => {
+                    self.header.clear();
+                    let _ = self.header.push(byte);
+                    State::Header(2)
+                }
+                b'\t' | b'\n' | b'\r' => {
+                    output.push(byte);
+                    State::Text
+                }
+                // other control characters select modes the device doesn't expect
+                0x00..=0x1F | 0x7F => State::Text,
+                _ => {
+                    output.push(byte);
+                    State::Text
+                }
+            },
+            State::Header(len) => {
+                let _ = self.header.push(byte);
+                let len = match self.header.len() {
+                    2 => match command(self.header[0], byte) {
+                        Some((_, Args::Fixed(count) | Args::Header(count))) => 2 + count,
+                        Some((action, Args::Terminated)) => {
+                            self.pass_header(action, output);
+                            self.state = State::Terminated {
+                                remaining: MAX_TERMINATED,
+                                action,
+                            };
+                            return;
+                        }
+                        None => {
+                            self.state = State::Text;
+                            return;
+                        }
+                    },
+                    _ => len,
+                };
+                match self.header.len() < len {
+                    true => State::Header(len),
+                    false => self.end_header(output),
+                }
+            }
+            State::Data { remaining, action } => {
+                pass(action, output);
+                match remaining - 1 {
+                    0 => State::Text,
+                    remaining => State::Data { remaining, action },
+                }
+            }
+            State::Counted { action } => {
+                pass(action, output);
+                match byte {
+                    0 => State::Text,
+                    remaining => State::Data {
+                        remaining: remaining as usize,
+                        action,
+                    },
+                }
+            }
+            State::Terminated { remaining, action } => {
+                pass(action, output);
+                match (byte, remaining - 1) {
+                    (0, _) | (_, 0) => State::Text,
+                    (_, remaining) => State::Terminated { remaining, action },
+                }
+            }
+        };
+    }
+
+    fn pass_header(&self, action: Action, output: &mut Vec<u8>) {
+        if action == Action::Pass {
+            output.extend_from_slice(&self.header);
+        }
+    }
+
+    /// Passes or drops the complete header, and works out the data that follows it
+    fn end_header(&self, output: &mut Vec<u8>) -> State {
+        let header = &self.header;
+        let Some((action, _)) = command(header[0], header[1]) else {
+            return State::Text;
+        };
+        let word = |i: usize| header[i] as usize | (header[i + 1] as usize) << 8;
+        let (action, remaining) = match (header[0], header[1]) {
+            // single density modes have a byte per column, double density three
+            (ESC, b'*') => match header[2] {
+                0 | 1 => (action, word(3)),
+                _ => (action, word(3) * 3),
+            },
+            // `0 m xL xH yL yH`, bytes per row times rows
+            (GS, b'v') => (action, word(4) * word(6)),
+            // only `k`, two dimensional codes, is passed
+            (GS, b'(') if header[2] == b'k' => (action, word(3)),
+            (GS, b'(') => (Action::Drop, word(3)),
+            (GS, b'k') => match header[2] {
+                0..=6 => {
+                    self.pass_header(action, output);
+                    return State::Terminated {
+                        remaining: MAX_TERMINATED,
+                        action,
+                    };
+                }
+                _ => {
+                    self.pass_header(action, output);
+                    return State::Counted { action };
+                }
+            },
+            // cuts that feed first take the amount
+            (GS, b'V') if matches!(header[2], 65 | 66) => (action, 1),
+            _ => (action, 0),
+        };
+        self.pass_header(action, output);
+        match remaining {
+            0 => State::Text,
+            remaining => State::Data { remaining, action },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        EscPosFilter::new().filter(data, &mut output);
+        output
+    }
+
+    /// Filters `data` a byte at a time, so every command is split across chunks
+    fn filter_bytes(data: &[u8]) -> Vec<u8> {
+        let mut filter = EscPosFilter::new();
+        let mut output = Vec::new();
+        for byte in data {
+            filter.filter(core::slice::from_ref(byte), &mut output);
+        }
+        output
+    }
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    #[test]
+    fn passes_text_and_line_breaks() {
+        assert_eq!(filter(b"one\ttwo\r\nthree"), b"one\ttwo\r\nthree");
+    }
+
+    #[test]
+    fn strips_other_controls() {
+        assert_eq!(filter(b"a\x07b\x0Cc\x7Fd"), b"abcd");
+    }
+
+    #[test]
+    fn drops_commands_whole() {
+        let dropped: [&[u8]; 8] = [
+            // user setup, with two bytes of data
+            &[GS, b'(', b'E', 2, 0, 0x0B, 0x01],
+            &[ESC, b'7', 15, 150, 250],
+            &[ESC, b'p', 0, 25, 250],
+            &[ESC, b'=', 2],
+            &[DLE, 0x04, 1],
+            &[DLE, 0x14, 2, 1, 8],
+            &[DC2, b'#', 0xFF],
+            &[DC2, b'T'],
+        ];
+        for command in dropped {
+            let data = concat(&[b"a", command, b"b"]);
+            assert_eq!(filter(&data), b"ab", "{command:?}");
+            assert_eq!(filter_bytes(&data), b"ab", "{command:?}");
+        }
+    }
+
+    #[test]
+    fn passes_known_commands() {
+        let passed: [&[u8]; 6] = [
+            &[ESC, b'@'],
+            &[ESC, b'E', 1],
+            &[ESC, b'$', 10, 0],
+            &[GS, b'!', 0x11],
+            &[GS, b'V', 1],
+            &[GS, b'V', 66, 3],
+        ];
+        for command in passed {
+            let data = concat(&[command, b"x"]);
+            assert_eq!(filter(&data), data, "{command:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_commands_lose_their_prefix() {
+        assert_eq!(filter(&[ESC, b'z', b'a']), b"a");
+        assert_eq!(filter(&[FS, b'&', b'b']), b"b");
+    }
+
+    #[test]
+    fn raster_data_is_passed_as_data() {
+        // two bytes a row, three rows, data that looks like commands
+        let raster = [
+            GS, b'v', b'0', 0, 2, 0, 3, 0, ESC, b'p', DLE, 0x04, DC2, b'T',
+        ];
+        let data = concat(&[&raster, b"x"]);
+        assert_eq!(filter(&data), data);
+        assert_eq!(filter_bytes(&data), data);
+    }
+
+    #[test]
+    fn bit_image_data_length() {
+        // single density, a byte a column
+        let single = [ESC, b'*', 0, 3, 0, ESC, b'7', 1];
+        // double density, three bytes a column
+        let double = [ESC, b'*', 33, 2, 0, 1, 2, 3, DC2, b'#', 6];
+        for image in [&single[..], &double[..]] {
+            let data = concat(&[image, b"x"]);
+            assert_eq!(filter(&data), data);
+        }
+    }
+
+    #[test]
+    fn barcode_data_length() {
+        // data up to a NUL, then data counted by its first byte
+        let terminated = [GS, b'k', 4, b'A', ESC, b'C', 0];
+        let counted = [GS, b'k', 73, 3, 0, ESC, b'p'];
+        for barcode in [&terminated[..], &counted[..]] {
+            let data = concat(&[barcode, b"x"]);
+            assert_eq!(filter(&data), data);
+            assert_eq!(filter_bytes(&data), data);
+        }
+    }
+
+    #[test]
+    fn two_dimensional_codes_are_passed() {
+        // store qr code data `ab` plus a NUL that isn't an end
+        let store = [GS, b'(', b'k', 6, 0, 49, 80, 48, b'a', 0, b'b'];
+        let data = concat(&[&store, b"x"]);
+        assert_eq!(filter(&data), data);
+        assert_eq!(filter_bytes(&data), data);
+    }
+
+    #[test]
+    fn pending_counts_passed_data() {
+        let mut filter = EscPosFilter::new();
+        let mut output = Vec::new();
+        filter.filter(&[GS, b'v', b'0', 0, 2, 0, 3], &mut output);
+        // the header isn't complete, so nothing of it was sent
+        assert_eq!(filter.pending(), 0);
+        assert!(output.is_empty());
+
+        filter.filter(&[0, 1, 2], &mut output);
+        assert_eq!(filter.pending(), 4);
+
+        filter.filter(&[GS, b'(', b'E'], &mut output);
+        filter.filter(&[3, 0, 5, 6, 7], &mut output);
+        assert_eq!(filter.pending(), 0);
+    }
+}
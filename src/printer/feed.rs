@@ -0,0 +1,77 @@
+use core::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// vertical dots per millimetre of the 203dpi print head, the `ESC J` motion unit
+pub const DOTS_PER_MM: u32 = 8;
+
+/// How far to advance the paper, written as `3` (lines) or `10mm`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Feed {
+    Lines(u8),
+    Millimetres(u8),
+}
+
+impl Feed {
+    pub const NONE: Feed = Feed::Lines(0);
+
+    /// Distance in print head dots, used to size the preview
+    pub fn dots(self, line_height: u32) -> u32 {
+        match self {
+            Feed::Lines(lines) => lines as u32 * line_height,
+            Feed::Millimetres(mm) => mm as u32 * DOTS_PER_MM,
+        }
+    }
+}
+
+impl FromStr for Feed {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_suffix("mm") {
+            Some(mm) => mm.trim_end().parse().map(Feed::Millimetres).map_err(|_| ()),
+            None => s.parse().map(Feed::Lines).map_err(|_| ()),
+        }
+    }
+}
+
+/// Cut performed at the end of a job, only for printers fitted with a cutter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum CutMode {
+    None,
+    Partial,
+    Full,
+}
+
+impl fmt::Display for Feed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Feed::Lines(lines) => write!(f, "{lines}"),
+            Feed::Millimetres(mm) => write!(f, "{mm}mm"),
+        }
+    }
+}
+
+impl FromStr for CutMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(CutMode::None),
+            "partial" => Ok(CutMode::Partial),
+            "full" => Ok(CutMode::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for CutMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CutMode::None => "none",
+            CutMode::Partial => "partial",
+            CutMode::Full => "full",
+        })
+    }
+}
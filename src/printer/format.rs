@@ -0,0 +1,201 @@
+use core::fmt::Write as _;
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use serde::Deserialize;
+
+use super::{
+    Style,
+    layout::{Line, LineWrapper},
+};
+use crate::time;
+
+/// How the text of a job is laid out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextFormat {
+    #[default]
+    Plain,
+    /// headings, lists, quotes and rules, markup that can't be printed is removed
+    Markdown,
+}
+
+/// Lays out the text of a job in its format and style. Text can be pushed in arbitrary pieces,
+/// lines are emitted as soon as they are complete.
+pub struct Formatter {
+    wrapper: LineWrapper,
+    style: Style,
+    /// markdown applies to whole lines, this holds the incomplete last one
+    markdown: Option<String>,
+}
+
+impl Formatter {
+    pub fn new(format: TextFormat, style: Style) -> Self {
+        Self {
+            wrapper: LineWrapper::with_style(style),
+            style,
+            markdown: (format == TextFormat::Markdown).then(String::new),
+        }
+    }
+
+    pub fn push_str(&mut self, text: &str, lines: &mut Vec<Line>) {
+        let Self {
+            wrapper,
+            style,
+            markdown,
+        } = self;
+        let Some(pending) = markdown else {
+            wrapper.push_str(text, lines);
+            return;
+        };
+
+        for piece in text.split_inclusive('\n') {
+            pending.push_str(piece);
+            if pending.ends_with('\n') {
+                push_markdown(wrapper, *style, pending, lines);
+                pending.clear();
+            }
+        }
+    }
+
+    /// Emits whatever is left of the last line
+    pub fn finish(&mut self, lines: &mut Vec<Line>) {
+        if let Some(pending) = self.markdown.as_mut() {
+            push_markdown(&mut self.wrapper, self.style, pending, lines);
+            pending.clear();
+        }
+        self.wrapper.finish(lines);
+    }
+}
+
+fn push_markdown(wrapper: &mut LineWrapper, base: Style, line: &str, lines: &mut Vec<Line>) {
+    let (text, style) = markdown_line(line, base);
+    wrapper.set_style(style);
+    wrapper.push_str(&text, lines);
+    wrapper.finish(lines);
+}
+
+/// Converts a line of markdown to the text and style to print it with
+fn markdown_line(line: &str, base: Style) -> (String, Style) {
+    let line = line.trim();
+    let mut style = base;
+
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if let Some(heading) = line[level..]
+        .strip_prefix(' ')
+        .filter(|_| (1..=6).contains(&level))
+    {
+        style.bold = true;
+        match level {
+            1 => style.large = true,
+            2 => style.underline = true,
+            _ => {}
+        }
+        return (inline(heading.trim()), style);
+    }
+
+    if is_rule(line) {
+        return (core::iter::repeat_n('-', style.columns()).collect(), style);
+    }
+    if let Some(quote) = line.strip_prefix('>') {
+        return (format!("| {}", inline(quote.trim_start())), style);
+    }
+    if let Some(item) = ["- ", "* ", "+ "]
+        .iter()
+        .find_map(|bullet| line.strip_prefix(bullet))
+    {
+        return (format!("- {}", inline(item)), style);
+    }
+
+    // a line that is all strong emphasis is printed bold
+    let strong = ["**", "__"].iter().find_map(|marker| {
+        line.strip_prefix(marker)
+            .and_then(|text| text.strip_suffix(marker))
+    });
+    if let Some(text) = strong {
+        style.bold = true;
+        return (inline(text), style);
+    }
+
+    (inline(line), style)
+}
+
+/// `---`, `***` or `___`, optionally spaced out
+fn is_rule(line: &str) -> bool {
+    let mut marks = line.chars().filter(|c| *c != ' ');
+    let Some(mark) = marks.next() else {
+        return false;
+    };
+    let count = 1 + marks.clone().count();
+    matches!(mark, '-' | '*' | '_') && count >= 3 && marks.all(|c| c == mark)
+}
+
+/// Removes the strong emphasis and code markers a whole line style can't show, and the
+/// backslashes escaping markup
+fn inline(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => output.extend(chars.next()),
+            '`' => {}
+            '*' | '_' if chars.peek() == Some(&c) => {
+                chars.next();
+            }
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum TemplateError {
+    UnknownField,
+    Unterminated,
+}
+
+impl TemplateError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateError::UnknownField => "Template uses a field that was not given\n",
+            TemplateError::Unterminated => "Template placeholder is missing its closing }}\n",
+        }
+    }
+}
+
+/// Fills the `{{name}}` placeholders of a template from `fields`, falling back to the built in
+/// `date`, `time` and `job` fields
+pub fn render_template(
+    template: &str,
+    fields: &BTreeMap<String, String>,
+    id: u32,
+) -> Result<String, TemplateError> {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        text.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder.find("}}").ok_or(TemplateError::Unterminated)?;
+        let name = placeholder[..end].trim();
+        match fields.get(name) {
+            Some(value) => text.push_str(value),
+            None => write_builtin(&mut text, name, id)?,
+        }
+        rest = &placeholder[end + 2..];
+    }
+    text.push_str(rest);
+
+    Ok(text)
+}
+
+fn write_builtin(text: &mut String, name: &str, id: u32) -> Result<(), TemplateError> {
+    // writing into a String cannot fail
+    let _ = match (name, time::now()) {
+        ("job", _) => write!(text, "{id}"),
+        ("date", Some(now)) => write!(text, "{:04}-{:02}-{:02}", now.year, now.month, now.day),
+        ("time", Some(now)) => write!(text, "{:02}:{:02}", now.hour, now.minute),
+        // left blank until the clock is synchronized
+        ("date" | "time", None) => Ok(()),
+        _ => return Err(TemplateError::UnknownField),
+    };
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use core::cell::RefCell;
+
+use alloc::{string::String, vec::Vec};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+
+use super::{JobError, JobOptions, JobSource};
+use crate::{
+    events::{self, Event},
+    time,
+};
+
+/// jobs remembered at once, the oldest finished job is forgotten to make room
+pub const MAX_HISTORY: usize = 16;
+/// longest text kept to reprint a job, longer jobs are only listed
+const MAX_REPRINT_LENGTH: usize = 1024;
+
+static HISTORY: Mutex<CriticalSectionRawMutex, RefCell<Vec<JobRecord>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Printing,
+    Printed,
+    Failed,
+    Cancelled,
+}
+
+/// What happened to a recent job, times are unix seconds and `None` until the clock is synced
+#[derive(Clone, Debug)]
+pub struct JobRecord {
+    pub id: u32,
+    pub state: JobState,
+    pub source: JobSource,
+    pub submitted: Option<u64>,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    /// of text received, over all copies
+    pub bytes: usize,
+    /// printed, over all copies
+    pub lines: u32,
+    pub error: Option<JobError>,
+    /// the job as submitted, kept for short jobs whose whole text was known up front
+    reprint: Option<(String, JobOptions)>,
+}
+
+impl JobRecord {
+    fn new(id: u32, state: JobState, options: &JobOptions) -> Self {
+        Self {
+            id,
+            state,
+            source: options.source.clone(),
+            submitted: time::unix_time(),
+            started: None,
+            finished: None,
+            bytes: 0,
+            lines: 0,
+            error: None,
+            reprint: None,
+        }
+    }
+
+    pub fn can_reprint(&self) -> bool {
+        self.reprint.is_some()
+    }
+}
+
+/// Records a queued job, `text` is kept to reprint it if short, images aren't kept
+pub fn queued(id: u32, text: Option<&str>, options: &JobOptions) {
+    let mut record = JobRecord::new(id, JobState::Queued, options);
+    record.reprint = text.and_then(|text| reprint(text, options));
+    insert(record);
+    notify(id, JobState::Queued, None);
+}
+
+/// Marks the job as printing, recording it first if it skipped the queue
+pub fn started(id: u32, options: &JobOptions) {
+    let found = update(id, |record| {
+        record.state = JobState::Printing;
+        if record.started.is_none() {
+            record.started = time::unix_time();
+        }
+    });
+    if !found {
+        let mut record = JobRecord::new(id, JobState::Printing, options);
+        record.started = record.submitted;
+        insert(record);
+    }
+    notify(id, JobState::Printing, None);
+}
+
+/// Keeps the text of a job that skipped the queue so it can be reprinted
+pub fn keep_text(id: u32, text: &[u8], options: &JobOptions) {
+    let Ok(text) = str::from_utf8(text) else {
+        return;
+    };
+    update(id, |record| {
+        if record.reprint.is_none() {
+            record.reprint = reprint(text, options);
+        }
+    });
+}
+
+pub fn finished(id: u32, bytes: usize, lines: u32) {
+    update(id, |record| {
+        record.state = JobState::Printed;
+        record.finished = time::unix_time();
+        record.bytes += bytes;
+        record.lines += lines;
+    });
+    notify(id, JobState::Printed, None);
+}
+
+pub fn failed(id: u32, error: JobError, bytes: usize, lines: u32) {
+    update(id, |record| {
+        record.state = JobState::Failed;
+        record.finished = time::unix_time();
+        record.error = Some(error);
+        record.bytes += bytes;
+        record.lines += lines;
+    });
+    notify(id, JobState::Failed, Some(error));
+}
+
+pub fn cancelled(id: u32) {
+    update(id, |record| {
+        record.state = JobState::Cancelled;
+        record.finished = time::unix_time();
+    });
+    notify(id, JobState::Cancelled, None);
+}
+
+/// Recent jobs, newest first
+pub fn recent_jobs() -> Vec<JobRecord> {
+    HISTORY.lock(|history| history.borrow().iter().rev().cloned().collect())
+}
+
+pub fn job_record(id: u32) -> Option<JobRecord> {
+    HISTORY.lock(|history| {
+        history
+            .borrow()
+            .iter()
+            .find(|record| record.id == id)
+            .cloned()
+    })
+}
+
+/// The text and options to print the job again with
+pub fn reprint_of(id: u32) -> Option<(String, JobOptions)> {
+    job_record(id).and_then(|record| record.reprint)
+}
+
+/// Forgets a job that is no longer queued or printing
+pub fn forget_job(id: u32) {
+    HISTORY.lock(|history| history.borrow_mut().retain(|record| record.id != id));
+}
+
+fn notify(id: u32, state: JobState, error: Option<JobError>) {
+    events::publish(Event::Job {
+        id,
+        state,
+        error: error.map(|error| error.as_str()),
+    });
+}
+
+fn reprint(text: &str, options: &JobOptions) -> Option<(String, JobOptions)> {
+    (text.len() <= MAX_REPRINT_LENGTH).then(|| (String::from(text), options.clone()))
+}
+
+fn insert(record: JobRecord) {
+    HISTORY.lock(|history| {
+        let mut history = history.borrow_mut();
+        if history.len() >= MAX_HISTORY {
+            let oldest = history
+                .iter()
+                .position(|record| !matches!(record.state, JobState::Queued | JobState::Printing))
+                .unwrap_or(0);
+            history.remove(oldest);
+        }
+        history.push(record);
+    });
+}
+
+/// Applies `f` to the job's record, returning whether there was one
+fn update(id: u32, f: impl FnOnce(&mut JobRecord)) -> bool {
+    HISTORY.lock(|history| {
+        let mut history = history.borrow_mut();
+        let record = history.iter_mut().find(|record| record.id == id);
+        record.map(f).is_some()
+    })
+}
@@ -0,0 +1,621 @@
+use core::str::FromStr;
+
+use alloc::vec::Vec;
+use defmt::info;
+use serde::Serialize;
+
+use super::layout::PAPER_WIDTH;
+
+mod bmp;
+mod pipeline;
+mod png;
+mod pnm;
+
+pub const ROW_BYTES: usize = PAPER_WIDTH / 8;
+/// longest image kept in memory, taller images are shrunk to fit. 24KiB of raster, 64mm of paper
+pub const MAX_IMAGE_ROWS: usize = 512;
+/// narrowest image worth printing
+pub const MIN_IMAGE_WIDTH: usize = 8;
+/// rows of the source are buffered while decoding, this bounds their size
+const MAX_SOURCE_WIDTH: usize = 2048;
+const MAX_SOURCE_HEIGHT: usize = 8192;
+const READ_BUFFER_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+    /// binary pbm, pgm or ppm
+    Pnm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ImageError {
+    /// not an image format that is recognised at all
+    UnknownFormat,
+    /// jpeg, or a variant of a known format the decoder doesn't handle
+    Unsupported,
+    Invalid,
+    /// the image ended early
+    Truncated,
+    /// wider or taller than the decoder will buffer
+    TooLarge,
+    OutOfMemory,
+    /// the sender went away
+    Disconnected,
+}
+
+impl ImageError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageError::UnknownFormat => "Not a png, bmp or pnm image",
+            ImageError::Unsupported => "Image uses a format or feature that isn't supported",
+            ImageError::Invalid => "Image is corrupt",
+            ImageError::Truncated => "Image ended early",
+            ImageError::TooLarge => "Image is too large to decode",
+            ImageError::OutOfMemory => "Not enough memory to decode the image",
+            ImageError::Disconnected => "Sender disconnected before the image was complete",
+        }
+    }
+}
+
+/// How grey levels are turned into black and white dots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub enum Dither {
+    /// plain 50% cut off, best for line art and text
+    Threshold,
+    /// 4x4 bayer pattern
+    Ordered,
+    #[default]
+    FloydSteinberg,
+    /// only spreads part of the error, higher contrast than floyd-steinberg
+    Atkinson,
+}
+
+impl FromStr for Dither {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threshold" => Ok(Dither::Threshold),
+            "ordered" | "bayer" => Ok(Dither::Ordered),
+            "floyd-steinberg" | "fs" => Ok(Dither::FloydSteinberg),
+            "atkinson" => Ok(Dither::Atkinson),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Clockwise turn applied before the image is printed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub enum Rotation {
+    #[default]
+    None,
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+impl Rotation {
+    /// whether the width of the source runs along the paper
+    fn is_sideways(self) -> bool {
+        matches!(self, Rotation::Quarter | Rotation::ThreeQuarters)
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Rotation::None),
+            "90" => Ok(Rotation::Quarter),
+            "180" => Ok(Rotation::Half),
+            "270" => Ok(Rotation::ThreeQuarters),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct ImageOptions {
+    /// printed width in dots, narrower images are centred
+    pub width: usize,
+    pub dither: Dither,
+    /// -100 to 100, added to every grey level before dithering
+    pub brightness: i8,
+    pub rotation: Rotation,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            width: PAPER_WIDTH,
+            dither: Dither::default(),
+            brightness: 0,
+            rotation: Rotation::default(),
+        }
+    }
+}
+
+/// Where the encoded image comes from, `read` returns 0 once it ends
+pub trait ImageSource {
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ImageError>;
+}
+
+impl<S: ImageSource> ImageSource for &mut S {
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ImageError> {
+        (**self).read(buffer).await
+    }
+}
+
+/// A decoded image, ready to print. Rows span the whole paper with one dot per bit and the msb
+/// on the left.
+pub struct Image {
+    raster: Vec<u8>,
+    width: usize,
+}
+
+impl Image {
+    /// printed width in dots, not counting the margins that centre it
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn rows(&self) -> usize {
+        self.raster.len() / ROW_BYTES
+    }
+
+    pub fn raster(&self) -> &[u8] {
+        &self.raster
+    }
+
+    pub fn raster_mut(&mut self) -> &mut [u8] {
+        &mut self.raster
+    }
+}
+
+/// Decodes the image as it is read, keeping only the scaled and dithered result
+pub async fn decode_image<S: ImageSource>(
+    source: S,
+    options: &ImageOptions,
+) -> Result<(ImageFormat, Image), ImageError> {
+    let mut reader = Reader::new(source);
+    let format = detect_format(reader.peek(8).await?)?;
+    info!("Decoding {} image", format);
+
+    let image = match format {
+        ImageFormat::Png => png::decode(&mut reader, options).await?,
+        ImageFormat::Bmp => bmp::decode(&mut reader, options).await?,
+        ImageFormat::Pnm => pnm::decode(&mut reader, options).await?,
+    };
+    info!("Decoded image of {}x{} dots", image.width(), image.rows());
+    Ok((format, image))
+}
+
+fn detect_format(magic: &[u8]) -> Result<ImageFormat, ImageError> {
+    match magic {
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Ok(ImageFormat::Png),
+        [b'B', b'M', ..] => Ok(ImageFormat::Bmp),
+        [b'P', b'4' | b'5' | b'6', ..] => Ok(ImageFormat::Pnm),
+        // jpeg, gif and ascii netpbm
+        [0xFF, 0xD8, ..] | [b'G', b'I', b'F', ..] | [b'P', b'1'..=b'3', ..] => {
+            Err(ImageError::Unsupported)
+        }
+        _ => Err(ImageError::UnknownFormat),
+    }
+}
+
+/// A zeroed buffer, or an error instead of the allocator panicking when the heap is short
+fn allocate<T: Clone + Default>(len: usize) -> Result<Vec<T>, ImageError> {
+    let mut buffer = Vec::new();
+    buffer
+        .try_reserve_exact(len)
+        .map_err(|_| ImageError::OutOfMemory)?;
+    buffer.resize(len, T::default());
+    Ok(buffer)
+}
+
+/// Grey level of a colour, rec. 601 weights
+fn luma(red: u8, green: u8, blue: u8) -> u8 {
+    ((red as u32 * 77 + green as u32 * 150 + blue as u32 * 29) >> 8) as u8
+}
+
+/// A grey level drawn over white paper
+fn over_white(grey: u8, alpha: u8) -> u8 {
+    let alpha = alpha as u32;
+    ((grey as u32 * alpha + 255 * (255 - alpha)) / 255) as u8
+}
+
+/// Buffered byte access to an [`ImageSource`], counting the bytes consumed
+struct Reader<S> {
+    source: S,
+    buffer: [u8; READ_BUFFER_SIZE],
+    start: usize,
+    end: usize,
+    position: usize,
+}
+
+impl<S: ImageSource> Reader<S> {
+    fn new(source: S) -> Self {
+        Self {
+            source,
+            buffer: [0; READ_BUFFER_SIZE],
+            start: 0,
+            end: 0,
+            position: 0,
+        }
+    }
+
+    /// Bytes consumed so far
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Reads more into the buffer, `false` once the source has ended
+    async fn fill(&mut self) -> Result<bool, ImageError> {
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        let read = self.source.read(&mut self.buffer[self.end..]).await?;
+        self.end += read;
+        Ok(read > 0)
+    }
+
+    /// Up to `len` bytes without consuming them, fewer only if the source ends first
+    async fn peek(&mut self, len: usize) -> Result<&[u8], ImageError> {
+        while self.end - self.start < len && self.fill().await? {}
+        let end = self.end.min(self.start + len);
+        Ok(&self.buffer[self.start..end])
+    }
+
+    async fn byte(&mut self) -> Result<u8, ImageError> {
+        if self.start == self.end && !self.fill().await? {
+            return Err(ImageError::Truncated);
+        }
+        let byte = self.buffer[self.start];
+        self.start += 1;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    async fn exact(&mut self, out: &mut [u8]) -> Result<(), ImageError> {
+        let mut filled = 0;
+        while filled < out.len() {
+            if self.start == self.end && !self.fill().await? {
+                return Err(ImageError::Truncated);
+            }
+            let len = (self.end - self.start).min(out.len() - filled);
+            out[filled..filled + len].copy_from_slice(&self.buffer[self.start..self.start + len]);
+            self.start += len;
+            self.position += len;
+            filled += len;
+        }
+        Ok(())
+    }
+
+    async fn skip(&mut self, mut len: usize) -> Result<(), ImageError> {
+        while len > 0 {
+            if self.start == self.end && !self.fill().await? {
+                return Err(ImageError::Truncated);
+            }
+            let skipped = (self.end - self.start).min(len);
+            self.start += skipped;
+            self.position += skipped;
+            len -= skipped;
+        }
+        Ok(())
+    }
+
+    async fn u16_le(&mut self) -> Result<u16, ImageError> {
+        let mut bytes = [0; 2];
+        self.exact(&mut bytes).await?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    async fn u32_le(&mut self) -> Result<u32, ImageError> {
+        let mut bytes = [0; 4];
+        self.exact(&mut bytes).await?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    async fn u32_be(&mut self) -> Result<u32, ImageError> {
+        let mut bytes = [0; 4];
+        self.exact(&mut bytes).await?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    /// An image handed over a few bytes at a time, as it arrives off the network
+    struct Chunks<'a> {
+        data: &'a [u8],
+        size: usize,
+    }
+
+    impl ImageSource for Chunks<'_> {
+        async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ImageError> {
+            let len = self.data.len().min(self.size).min(buffer.len());
+            buffer[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    /// Decodes to 8 dots wide without dithering, so a 2x2 source prints as four 4x4 squares
+    fn decode(data: &[u8], size: usize) -> Result<Image, ImageError> {
+        let options = ImageOptions {
+            width: 8,
+            dither: Dither::Threshold,
+            ..ImageOptions::default()
+        };
+        embassy_futures::block_on(decode_image(Chunks { data, size }, &options))
+            .map(|(_, image)| image)
+    }
+
+    fn error(data: &[u8]) -> Option<ImageError> {
+        decode(data, usize::MAX).err()
+    }
+
+    /// The eight dots of each printed row, from the middle of the paper
+    fn rows(image: &Image) -> Vec<u8> {
+        let middle = ROW_BYTES / 2 - 1;
+        image
+            .raster()
+            .chunks(ROW_BYTES)
+            .map(|row| row[middle] << 4 | row[middle + 1] >> 4)
+            .collect()
+    }
+
+    /// black top left and bottom right
+    const CHECKS: [u8; 8] = [0xF0, 0xF0, 0xF0, 0xF0, 0x0F, 0x0F, 0x0F, 0x0F];
+
+    #[test]
+    fn detects_formats() {
+        assert_eq!(detect_format(b"\x89PNG\r\n\x1A\n"), Ok(ImageFormat::Png));
+        assert_eq!(detect_format(b"BM"), Ok(ImageFormat::Bmp));
+        assert_eq!(detect_format(b"P5\n"), Ok(ImageFormat::Pnm));
+        assert_eq!(detect_format(b"\xFF\xD8\xFF"), Err(ImageError::Unsupported));
+        assert_eq!(detect_format(b"P2\n"), Err(ImageError::Unsupported));
+        assert_eq!(detect_format(b"<svg"), Err(ImageError::UnknownFormat));
+        assert_eq!(error(b""), Some(ImageError::UnknownFormat));
+        assert_eq!(error(b"\x89PN"), Some(ImageError::UnknownFormat));
+    }
+
+    #[test]
+    fn decodes_pnm() {
+        let greymap = b"P5 # made by hand\n2 2\n255\n\x00\xFF\xFF\x00";
+        let pixmap = b"P6\n2 2 65535\n\
+            \x00\x00\x00\x00\x00\x00\xFF\xFF\xFF\xFF\xFF\xFF\
+            \xFF\xFF\xFF\xFF\xFF\xFF\x00\x00\x00\x00\x00\x00";
+        let bitmap = b"P4\n2 2\n\x80\x40";
+        for data in [&greymap[..], pixmap, bitmap] {
+            for size in [1, 3, usize::MAX] {
+                let image = decode(data, size).unwrap();
+                assert_eq!((image.width(), image.rows()), (8, 8));
+                assert_eq!(rows(&image), CHECKS);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_pnm_headers() {
+        assert_eq!(
+            error(b"P5\n2 2\n255\n\x00\xFF\xFF"),
+            Some(ImageError::Truncated)
+        );
+        assert_eq!(error(b"P5\n2 2\n"), Some(ImageError::Truncated));
+        assert_eq!(error(b"P5\n2 2\n0\n"), Some(ImageError::Invalid));
+        assert_eq!(error(b"P5\n2 2\n65536\n"), Some(ImageError::Invalid));
+        assert_eq!(error(b"P5\n0 2\n255\n"), Some(ImageError::Invalid));
+        assert_eq!(error(b"P5\n2x2\n255\n"), Some(ImageError::Invalid));
+        assert_eq!(error(b"P5\n4096 2\n255\n"), Some(ImageError::TooLarge));
+        assert_eq!(error(b"P5\n2 100000\n255\n"), Some(ImageError::TooLarge));
+        // digits past what fits in a usize
+        assert_eq!(
+            error(b"P5\n99999999999999999999999 2\n255\n"),
+            Some(ImageError::Invalid)
+        );
+        // a comment running off the end
+        assert_eq!(error(b"P5\n# no end"), Some(ImageError::Truncated));
+    }
+
+    /// A bitmap with an info header and 24 bit pixels, `rows` as stored in the file
+    fn bmp(width: i32, height: i32, rows: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        for row in rows {
+            data.extend_from_slice(row);
+        }
+        data
+    }
+
+    /// 2x2 pixels of blue, green and red, each row padded to 4 bytes
+    const BLACK_WHITE: &[u8] = &[0, 0, 0, 255, 255, 255, 0, 0];
+    const WHITE_BLACK: &[u8] = &[255, 255, 255, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn decodes_bmp() {
+        // stored bottom row first, unless the height is negative
+        let bottom_up = bmp(2, 2, &[WHITE_BLACK, BLACK_WHITE]);
+        let top_down = bmp(2, -2, &[BLACK_WHITE, WHITE_BLACK]);
+        for data in [bottom_up, top_down] {
+            for size in [1, 5, usize::MAX] {
+                assert_eq!(rows(&decode(&data, size).unwrap()), CHECKS);
+            }
+        }
+
+        // 1 bit with an os/2 header and a palette of white then black
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&32u32.to_le_bytes());
+        data.extend_from_slice(&12u32.to_le_bytes());
+        for field in [2u16, 2, 1, 1] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&[255, 255, 255, 0, 0, 0]);
+        data.extend_from_slice(&[0x40, 0, 0, 0, 0x80, 0, 0, 0]);
+        assert_eq!(rows(&decode(&data, 2).unwrap()), CHECKS);
+    }
+
+    #[test]
+    fn rejects_bad_bmp_headers() {
+        let rows = [BLACK_WHITE, WHITE_BLACK];
+        assert_eq!(error(&bmp(2, 2, &rows[..1])), Some(ImageError::Truncated));
+        assert_eq!(error(&bmp(0, 2, &rows)), Some(ImageError::Invalid));
+        assert_eq!(error(&bmp(-2, 2, &rows)), Some(ImageError::Invalid));
+        assert_eq!(error(&bmp(2, 0, &rows)), Some(ImageError::Invalid));
+        assert_eq!(error(&bmp(4096, 2, &rows)), Some(ImageError::TooLarge));
+        // the height can't be made positive
+        assert_eq!(error(&bmp(2, i32::MIN, &rows)), Some(ImageError::TooLarge));
+
+        let mut data = bmp(2, 2, &rows);
+        data[30] = 1; // run length encoded
+        assert_eq!(error(&data), Some(ImageError::Unsupported));
+        let mut data = bmp(2, 2, &rows);
+        data[28] = 16;
+        assert_eq!(error(&data), Some(ImageError::Unsupported));
+        // pixels said to start inside the header
+        let mut data = bmp(2, 2, &rows);
+        data[10] = 20;
+        assert_eq!(error(&data), Some(ImageError::Invalid));
+        // a header too small to hold the size, and one larger than the file
+        let mut data = bmp(2, 2, &rows);
+        data[14] = 8;
+        assert_eq!(error(&data), Some(ImageError::Invalid));
+        let mut data = bmp(2, 2, &rows);
+        data[14..18].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(error(&data), Some(ImageError::Truncated));
+    }
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        png.extend_from_slice(&[0; 4]); // the crc isn't checked
+    }
+
+    /// The signature and header of a 2x2 png
+    fn png_header(depth: u8, colour: u8, interlace: u8) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1A\n".to_vec();
+        let mut header = vec![0, 0, 0, 2, 0, 0, 0, 2];
+        header.extend_from_slice(&[depth, colour, 0, 0, interlace]);
+        chunk(&mut png, b"IHDR", &header);
+        png
+    }
+
+    /// A 2x2 png of the filtered `rows`, each led by its filter type
+    fn png(depth: u8, colour: u8, before: &[(&[u8; 4], &[u8])], rows: &[u8]) -> Vec<u8> {
+        let mut png = png_header(depth, colour, 0);
+        for (kind, data) in before {
+            chunk(&mut png, kind, data);
+        }
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(rows, 6);
+        // split over three chunks, as some encoders do
+        let (first, rest) = compressed.split_at(3);
+        let (second, third) = rest.split_at(rest.len() / 2);
+        for data in [first, second, third] {
+            chunk(&mut png, b"IDAT", data);
+        }
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn decodes_png() {
+        let grey = png(8, 0, &[], &[0, 0, 255, 0, 255, 0]);
+        // the second row is the first one less itself, filtered by the row above
+        let up = png(8, 0, &[], &[0, 0, 255, 2, 255, 1]);
+        let rgba = png(
+            8,
+            6,
+            &[(b"tEXt", b"Comment\0skipped")],
+            // black then clear, clear then black
+            &[0, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255],
+        );
+        // one bit indices into black and red, red being made see through
+        let palette = png(
+            1,
+            3,
+            &[(b"PLTE", &[0, 0, 0, 255, 0, 0]), (b"tRNS", &[255, 0])],
+            &[0, 0x40, 0, 0x80],
+        );
+        for data in [grey, up, rgba, palette] {
+            for size in [1, 7, usize::MAX] {
+                assert_eq!(rows(&decode(&data, size).unwrap()), CHECKS);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_png_headers() {
+        let rows = [0, 0, 255, 0, 255, 0];
+        assert_eq!(error(&png(8, 1, &[], &rows)), Some(ImageError::Invalid));
+        assert_eq!(error(&png(3, 0, &[], &rows)), Some(ImageError::Invalid));
+        assert_eq!(error(&png(16, 3, &[], &rows)), Some(ImageError::Invalid));
+        // palette images need a palette, and one too long to be real is skipped
+        assert_eq!(error(&png(8, 3, &[], &rows)), Some(ImageError::Invalid));
+        let long = [0; 771];
+        assert_eq!(
+            error(&png(8, 3, &[(b"PLTE", &long)], &rows)),
+            Some(ImageError::Invalid)
+        );
+
+        let mut data = png_header(8, 0, 1);
+        chunk(&mut data, b"IEND", &[]);
+        assert_eq!(error(&data), Some(ImageError::Unsupported));
+        let mut data = png_header(8, 0, 0);
+        chunk(&mut data, b"IEND", &[]);
+        assert_eq!(error(&data), Some(ImageError::Truncated));
+        // a header of the wrong length
+        let mut data = png_header(8, 0, 0);
+        data[11] = 12;
+        assert_eq!(error(&data), Some(ImageError::Invalid));
+        let mut data = png_header(8, 0, 0);
+        data[16..20].copy_from_slice(&4096u32.to_be_bytes());
+        assert_eq!(error(&data), Some(ImageError::TooLarge));
+    }
+
+    #[test]
+    fn rejects_bad_png_data() {
+        // an unknown filter type
+        assert_eq!(
+            error(&png(8, 0, &[], &[5, 0, 255, 0, 255, 0])),
+            Some(ImageError::Invalid)
+        );
+        // the stream ends a row early
+        assert_eq!(
+            error(&png(8, 0, &[], &[0, 0, 255])),
+            Some(ImageError::Truncated)
+        );
+        // the chunks end before the stream does, stored uncompressed so half is missing a row
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&[0, 0, 255, 0, 255, 0], 0);
+        let mut data = png_header(8, 0, 0);
+        chunk(&mut data, b"IDAT", &compressed[..compressed.len() / 2]);
+        assert_eq!(error(&data), Some(ImageError::Truncated));
+        chunk(&mut data, b"IEND", &[]);
+        assert_eq!(error(&data), Some(ImageError::Truncated));
+        // not deflate at all
+        let mut data = png_header(8, 0, 0);
+        chunk(&mut data, b"IDAT", &[0xFF; 16]);
+        chunk(&mut data, b"IEND", &[]);
+        assert_eq!(error(&data), Some(ImageError::Invalid));
+    }
+}
@@ -0,0 +1,94 @@
+use super::{
+    Image, ImageError, ImageOptions, ImageSource, MAX_SOURCE_WIDTH, Reader, allocate, luma,
+    pipeline::Pipeline,
+};
+
+const FILE_HEADER_SIZE: usize = 14;
+/// the `BITMAPCOREHEADER` of os/2 bitmaps, with 16 bit sizes and 3 byte palette entries
+const CORE_HEADER_SIZE: u32 = 12;
+const INFO_HEADER_SIZE: u32 = 40;
+/// `BI_RGB`, the only compression handled
+const UNCOMPRESSED: u32 = 0;
+
+/// Decodes uncompressed 1, 4, 8, 24 and 32 bit bitmaps, alpha is ignored as most writers leave
+/// it zeroed
+pub(super) async fn decode<S: ImageSource>(
+    reader: &mut Reader<S>,
+    options: &ImageOptions,
+) -> Result<Image, ImageError> {
+    reader.skip(FILE_HEADER_SIZE - 4).await?; // magic, file size and reserved
+    let data_offset = reader.u32_le().await? as usize;
+
+    let header_size = reader.u32_le().await?;
+    let (width, height, depth, palette_entry) = if header_size == CORE_HEADER_SIZE {
+        let width = reader.u16_le().await? as i32;
+        let height = reader.u16_le().await? as i32;
+        reader.skip(2).await?; // planes
+        let depth = reader.u16_le().await?;
+        (width, height, depth, 3)
+    } else if header_size >= INFO_HEADER_SIZE {
+        let width = reader.u32_le().await? as i32;
+        let height = reader.u32_le().await? as i32;
+        reader.skip(2).await?; // planes
+        let depth = reader.u16_le().await?;
+        if reader.u32_le().await? != UNCOMPRESSED {
+            return Err(ImageError::Unsupported);
+        }
+        // image size, resolution, palette size and important colours
+        reader.skip(header_size as usize - 20).await?;
+        (width, height, depth, 4)
+    } else {
+        return Err(ImageError::Invalid);
+    };
+    if !matches!(depth, 1 | 4 | 8 | 24 | 32) {
+        return Err(ImageError::Unsupported);
+    }
+    if width <= 0 || height == 0 {
+        return Err(ImageError::Invalid);
+    }
+    let width = width as usize;
+    if width > MAX_SOURCE_WIDTH {
+        return Err(ImageError::TooLarge);
+    }
+
+    // whatever sits between the header and the pixels is taken as the palette
+    let mut palette = [0u8; 256];
+    if depth <= 8 {
+        let mut entry = [0u8; 4];
+        for grey in &mut palette[..1 << depth] {
+            if reader.position() + palette_entry > data_offset {
+                break;
+            }
+            reader.exact(&mut entry[..palette_entry]).await?;
+            *grey = luma(entry[2], entry[1], entry[0]);
+        }
+    }
+    let gap = data_offset
+        .checked_sub(reader.position())
+        .ok_or(ImageError::Invalid)?;
+    reader.skip(gap).await?;
+
+    // a negative height marks rows stored top to bottom
+    let bottom_up = height > 0;
+    let mut pipeline = Pipeline::new(width, height.unsigned_abs() as usize, bottom_up, options)?;
+    let stride = (width * depth as usize).div_ceil(32) * 4;
+    let mut row = allocate::<u8>(stride)?;
+    let mut grey = allocate::<u8>(width)?;
+    while !pipeline.is_complete() {
+        reader.exact(&mut row).await?;
+        for (x, out) in grey.iter_mut().enumerate() {
+            *out = match depth {
+                1 => palette[((row[x / 8] >> (7 - x % 8)) & 1) as usize],
+                4 => palette[((row[x / 2] >> (4 - 4 * (x % 2))) & 0x0F) as usize],
+                8 => palette[row[x] as usize],
+                _ => {
+                    let pixel = &row[x * depth as usize / 8..];
+                    luma(pixel[2], pixel[1], pixel[0])
+                }
+            };
+        }
+        pipeline.push_row(&grey);
+    }
+
+    pipeline.finish()
+}
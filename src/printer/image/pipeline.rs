@@ -0,0 +1,222 @@
+use alloc::vec::Vec;
+
+use super::{
+    Dither, Image, ImageError, ImageOptions, MAX_IMAGE_ROWS, MAX_SOURCE_HEIGHT, MAX_SOURCE_WIDTH,
+    MIN_IMAGE_WIDTH, ROW_BYTES, Rotation, allocate,
+};
+use crate::printer::layout::PAPER_WIDTH;
+
+/// 4x4 bayer matrix for ordered dithering
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+/// room either side of a row for the error spread past its edges
+const ERROR_MARGIN: usize = 2;
+
+/// Scales, dithers and places grey rows of the source as they are decoded, so the source is
+/// never held in memory. Rows may arrive bottom to top, error diffusion then runs upwards.
+pub(super) struct Pipeline {
+    source_width: usize,
+    source_height: usize,
+    /// size of the scaled image before it is rotated
+    scaled_width: usize,
+    scaled_height: usize,
+    bottom_up: bool,
+    options: ImageOptions,
+    /// source rows pushed so far
+    received: usize,
+    /// scaled rows dithered so far
+    emitted: usize,
+    /// the current row scaled across, then averaged over the source rows it covers
+    row: Vec<u8>,
+    sums: Vec<u32>,
+    summed: u32,
+    /// dithering error carried to this row and the next two
+    errors: [Vec<i16>; 3],
+    raster: Vec<u8>,
+    printed_width: usize,
+}
+
+impl Pipeline {
+    pub(super) fn new(
+        source_width: usize,
+        source_height: usize,
+        bottom_up: bool,
+        options: &ImageOptions,
+    ) -> Result<Self, ImageError> {
+        if source_width == 0 || source_height == 0 {
+            return Err(ImageError::Invalid);
+        }
+        if source_width > MAX_SOURCE_WIDTH || source_height > MAX_SOURCE_HEIGHT {
+            return Err(ImageError::TooLarge);
+        }
+
+        // source size across and along the paper
+        let (across, along) = if options.rotation.is_sideways() {
+            (source_height, source_width)
+        } else {
+            (source_width, source_height)
+        };
+        let mut printed_width = options.width.clamp(MIN_IMAGE_WIDTH, PAPER_WIDTH);
+        let mut printed_rows = (along * printed_width / across).max(1);
+        if printed_rows > MAX_IMAGE_ROWS {
+            printed_width = (across * MAX_IMAGE_ROWS / along).max(1);
+            printed_rows = MAX_IMAGE_ROWS;
+        }
+        let (scaled_width, scaled_height) = if options.rotation.is_sideways() {
+            (printed_rows, printed_width)
+        } else {
+            (printed_width, printed_rows)
+        };
+
+        let errors = match options.dither {
+            Dither::FloydSteinberg | Dither::Atkinson => [
+                allocate(scaled_width + 2 * ERROR_MARGIN)?,
+                allocate(scaled_width + 2 * ERROR_MARGIN)?,
+                allocate(scaled_width + 2 * ERROR_MARGIN)?,
+            ],
+            Dither::Threshold | Dither::Ordered => [Vec::new(), Vec::new(), Vec::new()],
+        };
+
+        Ok(Self {
+            source_width,
+            source_height,
+            scaled_width,
+            scaled_height,
+            bottom_up,
+            options: *options,
+            received: 0,
+            emitted: 0,
+            row: allocate(scaled_width)?,
+            sums: allocate(scaled_width)?,
+            summed: 0,
+            errors,
+            raster: allocate(printed_rows * ROW_BYTES)?,
+            printed_width,
+        })
+    }
+
+    /// Whether every source row has been pushed
+    pub(super) fn is_complete(&self) -> bool {
+        self.received == self.source_height
+    }
+
+    /// Adds the next source row of grey levels, `row` holds at least the source width
+    pub(super) fn push_row(&mut self, row: &[u8]) {
+        if self.is_complete() {
+            return;
+        }
+
+        // box filter when shrinking, nearest neighbour when enlarging
+        for (x, sum) in self.sums.iter_mut().enumerate() {
+            let start = x * self.source_width / self.scaled_width;
+            let end = ((x + 1) * self.source_width / self.scaled_width).max(start + 1);
+            let total: u32 = row[start..end].iter().map(|&grey| grey as u32).sum();
+            *sum += total / (end - start) as u32;
+        }
+        self.summed += 1;
+        self.received += 1;
+
+        let end = self.received * self.scaled_height / self.source_height;
+        if end <= self.emitted {
+            return;
+        }
+        for (grey, sum) in self.row.iter_mut().zip(self.sums.iter_mut()) {
+            *grey = (*sum / self.summed) as u8;
+            *sum = 0;
+        }
+        self.summed = 0;
+        while self.emitted < end {
+            self.emit_row();
+            self.emitted += 1;
+        }
+    }
+
+    pub(super) fn finish(self) -> Result<Image, ImageError> {
+        if !self.is_complete() {
+            return Err(ImageError::Truncated);
+        }
+
+        Ok(Image {
+            raster: self.raster,
+            width: self.printed_width,
+        })
+    }
+
+    /// Dithers the scaled row and sets its black dots in the raster
+    fn emit_row(&mut self) {
+        let y = if self.bottom_up {
+            self.scaled_height - 1 - self.emitted
+        } else {
+            self.emitted
+        };
+        let brightness = self.options.brightness.clamp(-100, 100) as i16 * 255 / 100;
+
+        for x in 0..self.scaled_width {
+            let grey = (self.row[x] as i16 + brightness).clamp(0, 255);
+            if self.dither(x, grey) {
+                self.set_dot(x, y);
+            }
+        }
+
+        if !self.errors[0].is_empty() {
+            self.errors.rotate_left(1);
+            self.errors[2].fill(0);
+        }
+    }
+
+    /// Whether the dot at `x` of the current row is black
+    fn dither(&mut self, x: usize, grey: i16) -> bool {
+        match self.options.dither {
+            Dither::Threshold => grey < 128,
+            Dither::Ordered => {
+                let threshold = BAYER[self.emitted % 4][x % 4] as i16 * 16 + 8;
+                grey < threshold
+            }
+            Dither::FloydSteinberg => {
+                let (black, error) = self.quantize(x, grey);
+                let i = x + ERROR_MARGIN;
+                let [current, next, _] = &mut self.errors;
+                current[i + 1] += error * 7 / 16;
+                next[i - 1] += error * 3 / 16;
+                next[i] += error * 5 / 16;
+                next[i + 1] += error / 16;
+                black
+            }
+            Dither::Atkinson => {
+                let (black, error) = self.quantize(x, grey);
+                let i = x + ERROR_MARGIN;
+                let share = error / 8;
+                let [current, next, after] = &mut self.errors;
+                current[i + 1] += share;
+                current[i + 2] += share;
+                next[i - 1] += share;
+                next[i] += share;
+                next[i + 1] += share;
+                after[i] += share;
+                black
+            }
+        }
+    }
+
+    /// Adds the error carried to `x`, returning whether it is black and what is left over
+    fn quantize(&self, x: usize, grey: i16) -> (bool, i16) {
+        let value = grey + self.errors[0][x + ERROR_MARGIN];
+        if value < 128 {
+            (true, value)
+        } else {
+            (false, value - 255)
+        }
+    }
+
+    /// Sets a dot given in scaled coordinates, turning it to its printed place
+    fn set_dot(&mut self, x: usize, y: usize) {
+        let (width, height) = (self.scaled_width, self.scaled_height);
+        let (column, row) = match self.options.rotation {
+            Rotation::None => (x, y),
+            Rotation::Quarter => (height - 1 - y, x),
+            Rotation::Half => (width - 1 - x, height - 1 - y),
+            Rotation::ThreeQuarters => (y, width - 1 - x),
+        };
+        let column = column + (PAPER_WIDTH - self.printed_width) / 2;
+        self.raster[row * ROW_BYTES + column / 8] |= 0x80 >> (column % 8);
+    }
+}
@@ -0,0 +1,282 @@
+use alloc::boxed::Box;
+
+use miniz_oxide::{
+    DataFormat, MZError, MZFlush, MZStatus,
+    inflate::stream::{InflateState, inflate},
+};
+
+use super::{
+    Image, ImageError, ImageOptions, ImageSource, MAX_SOURCE_WIDTH, Reader, allocate, luma,
+    over_white, pipeline::Pipeline,
+};
+
+const SIGNATURE_SIZE: usize = 8;
+const IHDR: [u8; 4] = *b"IHDR";
+const PLTE: [u8; 4] = *b"PLTE";
+const TRNS: [u8; 4] = *b"tRNS";
+const IDAT: [u8; 4] = *b"IDAT";
+const IEND: [u8; 4] = *b"IEND";
+/// compressed bytes read from the image at a time
+const INPUT_SIZE: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ColourType {
+    Grey = 0,
+    Rgb = 2,
+    Palette = 3,
+    GreyAlpha = 4,
+    Rgba = 6,
+}
+
+impl ColourType {
+    fn channels(self) -> usize {
+        match self {
+            ColourType::Grey | ColourType::Palette => 1,
+            ColourType::GreyAlpha => 2,
+            ColourType::Rgb => 3,
+            ColourType::Rgba => 4,
+        }
+    }
+}
+
+/// Decodes non interlaced png images of any colour type and bit depth
+pub(super) async fn decode<S: ImageSource>(
+    reader: &mut Reader<S>,
+    options: &ImageOptions,
+) -> Result<Image, ImageError> {
+    reader.skip(SIGNATURE_SIZE).await?;
+
+    let (length, kind) = chunk_header(reader).await?;
+    if kind != IHDR || length != 13 {
+        return Err(ImageError::Invalid);
+    }
+    let width = reader.u32_be().await? as usize;
+    let height = reader.u32_be().await? as usize;
+    let mut fields = [0u8; 5];
+    reader.exact(&mut fields).await?;
+    reader.skip(4).await?; // crc
+    let [depth, colour, compression, filter, interlace] = fields;
+    let colour = match (colour, depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => ColourType::Grey,
+        (2, 8 | 16) => ColourType::Rgb,
+        (3, 1 | 2 | 4 | 8) => ColourType::Palette,
+        (4, 8 | 16) => ColourType::GreyAlpha,
+        (6, 8 | 16) => ColourType::Rgba,
+        _ => return Err(ImageError::Invalid),
+    };
+    if compression != 0 || filter != 0 {
+        return Err(ImageError::Invalid);
+    }
+    if interlace != 0 {
+        return Err(ImageError::Unsupported);
+    }
+    if width > MAX_SOURCE_WIDTH {
+        return Err(ImageError::TooLarge);
+    }
+
+    // palette entries as grey levels over white, missing ones print black
+    let mut palette = [0u8; 256];
+    let mut palette_rgb = [0u8; 256 * 3];
+    let mut palette_size = 0;
+    let length = loop {
+        let (length, kind) = chunk_header(reader).await?;
+        match kind {
+            IDAT => break length,
+            PLTE if length % 3 == 0 && length <= palette_rgb.len() => {
+                reader.exact(&mut palette_rgb[..length]).await?;
+                palette_size = length / 3;
+                for (grey, rgb) in palette.iter_mut().zip(palette_rgb.chunks_exact(3)) {
+                    *grey = luma(rgb[0], rgb[1], rgb[2]);
+                }
+            }
+            TRNS if colour == ColourType::Palette && length <= palette_size => {
+                for grey in &mut palette[..length] {
+                    *grey = over_white(*grey, reader.byte().await?);
+                }
+            }
+            IEND => return Err(ImageError::Truncated),
+            _ => reader.skip(length).await?,
+        }
+        reader.skip(4).await?; // crc
+    };
+    if colour == ColourType::Palette && palette_size == 0 {
+        return Err(ImageError::Invalid);
+    }
+
+    let mut pipeline = Pipeline::new(width, height, false, options)?;
+    let bits_per_pixel = colour.channels() * depth as usize;
+    let row_bytes = (width * bits_per_pixel).div_ceil(8);
+    // filters work on whole bytes, this is how far back the matching byte of the last pixel is
+    let pixel_bytes = bits_per_pixel.div_ceil(8);
+    let mut previous = allocate::<u8>(row_bytes)?;
+    let mut current = allocate::<u8>(row_bytes)?;
+    let mut grey = allocate::<u8>(width)?;
+
+    let mut data = ImageData {
+        reader,
+        remaining: length,
+        inflater: InflateState::new_boxed(DataFormat::Zlib),
+        input: [0; INPUT_SIZE],
+        start: 0,
+        end: 0,
+    };
+    while !pipeline.is_complete() {
+        let mut filter = [0u8];
+        data.read(&mut filter).await?;
+        data.read(&mut current).await?;
+        unfilter(filter[0], &mut current, &previous, pixel_bytes)?;
+        to_grey(&current, &mut grey, colour, depth, &palette);
+        pipeline.push_row(&grey);
+        core::mem::swap(&mut previous, &mut current);
+    }
+
+    pipeline.finish()
+}
+
+async fn chunk_header<S: ImageSource>(
+    reader: &mut Reader<S>,
+) -> Result<(usize, [u8; 4]), ImageError> {
+    let length = reader.u32_be().await? as usize;
+    let mut kind = [0; 4];
+    reader.exact(&mut kind).await?;
+    Ok((length, kind))
+}
+
+/// The compressed data, which may be split over consecutive `IDAT` chunks, inflated as the rows
+/// are needed
+struct ImageData<'a, S> {
+    reader: &'a mut Reader<S>,
+    /// bytes left in the current chunk
+    remaining: usize,
+    /// holds the 32 KiB window deflate refers back into, the one large allocation
+    inflater: Box<InflateState>,
+    input: [u8; INPUT_SIZE],
+    /// compressed bytes in `input` not yet inflated
+    start: usize,
+    end: usize,
+}
+
+impl<S: ImageSource> ImageData<'_, S> {
+    /// Fills `out` with the next inflated bytes
+    async fn read(&mut self, out: &mut [u8]) -> Result<(), ImageError> {
+        let mut filled = 0;
+        while filled < out.len() {
+            let result = inflate(
+                &mut self.inflater,
+                &self.input[self.start..self.end],
+                &mut out[filled..],
+                MZFlush::None,
+            );
+            self.start += result.bytes_consumed;
+            filled += result.bytes_written;
+            match result.status {
+                // the stream ended before the last row
+                Ok(MZStatus::StreamEnd) if filled < out.len() => {
+                    return Err(ImageError::Truncated);
+                }
+                Ok(MZStatus::Ok | MZStatus::StreamEnd) => {}
+                // nothing more comes out until there is more input
+                Err(MZError::Buf) if self.start == self.end => {}
+                Ok(MZStatus::NeedDict) | Err(_) => return Err(ImageError::Invalid),
+            }
+            if filled < out.len() && self.start == self.end {
+                self.refill().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next compressed bytes, from the following `IDAT` chunk once this one is used up
+    async fn refill(&mut self) -> Result<(), ImageError> {
+        while self.remaining == 0 {
+            self.reader.skip(4).await?; // crc
+            let (length, kind) = chunk_header(self.reader).await?;
+            if kind != IDAT {
+                return Err(ImageError::Truncated);
+            }
+            self.remaining = length;
+        }
+        let length = self.remaining.min(INPUT_SIZE);
+        self.reader.exact(&mut self.input[..length]).await?;
+        self.remaining -= length;
+        self.start = 0;
+        self.end = length;
+        Ok(())
+    }
+}
+
+/// Undoes the filter the row was sent with, `previous` is the unfiltered row above
+fn unfilter(
+    filter: u8,
+    row: &mut [u8],
+    previous: &[u8],
+    pixel_bytes: usize,
+) -> Result<(), ImageError> {
+    for i in 0..row.len() {
+        let left = if i >= pixel_bytes {
+            row[i - pixel_bytes]
+        } else {
+            0
+        };
+        let up = previous[i];
+        let up_left = if i >= pixel_bytes {
+            previous[i - pixel_bytes]
+        } else {
+            0
+        };
+        let prediction = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(ImageError::Invalid),
+        };
+        row[i] = row[i].wrapping_add(prediction);
+    }
+    Ok(())
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let to_left = (estimate - left as i16).abs();
+    let to_up = (estimate - up as i16).abs();
+    let to_up_left = (estimate - up_left as i16).abs();
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Converts an unfiltered row to grey levels, 16 bit samples only use their high byte
+fn to_grey(row: &[u8], grey: &mut [u8], colour: ColourType, depth: u8, palette: &[u8; 256]) {
+    let sample_bytes = if depth == 16 { 2 } else { 1 };
+    let pixel = |x: usize, channel: usize| row[(x * colour.channels() + channel) * sample_bytes];
+
+    for (x, out) in grey.iter_mut().enumerate() {
+        *out = match colour {
+            ColourType::Grey if depth < 8 => {
+                packed_sample(row, x, depth) * (255 / ((1u8 << depth) - 1))
+            }
+            ColourType::Grey => pixel(x, 0),
+            ColourType::Palette if depth < 8 => palette[packed_sample(row, x, depth) as usize],
+            ColourType::Palette => palette[row[x] as usize],
+            ColourType::GreyAlpha => over_white(pixel(x, 0), pixel(x, 1)),
+            ColourType::Rgb => luma(pixel(x, 0), pixel(x, 1), pixel(x, 2)),
+            ColourType::Rgba => {
+                over_white(luma(pixel(x, 0), pixel(x, 1), pixel(x, 2)), pixel(x, 3))
+            }
+        };
+    }
+}
+
+/// A sample of less than a byte, packed msb first
+fn packed_sample(row: &[u8], x: usize, depth: u8) -> u8 {
+    let depth = depth as usize;
+    let bit = x * depth;
+    let shift = 8 - depth - bit % 8;
+    (row[bit / 8] >> shift) & ((1 << depth) - 1) as u8
+}
@@ -0,0 +1,95 @@
+use super::{
+    Image, ImageError, ImageOptions, ImageSource, MAX_SOURCE_WIDTH, Reader, allocate, luma,
+    pipeline::Pipeline,
+};
+
+/// Decodes binary netpbm images: `P4` bitmaps, `P5` greymaps and `P6` pixmaps
+pub(super) async fn decode<S: ImageSource>(
+    reader: &mut Reader<S>,
+    options: &ImageOptions,
+) -> Result<Image, ImageError> {
+    reader.skip(1).await?;
+    let kind = reader.byte().await?;
+    let width = header_number(reader).await?;
+    let height = header_number(reader).await?;
+    let max = if kind == b'4' {
+        1
+    } else {
+        header_number(reader).await?
+    };
+    if max == 0 || max > u16::MAX as usize {
+        return Err(ImageError::Invalid);
+    }
+    if width > MAX_SOURCE_WIDTH {
+        return Err(ImageError::TooLarge);
+    }
+
+    let channels = if kind == b'6' { 3 } else { 1 };
+    let sample_bytes = if max > 255 { 2 } else { 1 };
+    let row_bytes = match kind {
+        b'4' => width.div_ceil(8),
+        _ => width * channels * sample_bytes,
+    };
+
+    let mut pipeline = Pipeline::new(width, height, false, options)?;
+    let mut row = allocate::<u8>(row_bytes)?;
+    let mut grey = allocate::<u8>(width)?;
+    while !pipeline.is_complete() {
+        reader.exact(&mut row).await?;
+        for (x, out) in grey.iter_mut().enumerate() {
+            *out = match kind {
+                // set bits are black
+                b'4' => ((row[x / 8] >> (7 - x % 8)) & 1 == 0) as u8 * 255,
+                b'5' => sample(&row, x, max),
+                _ => luma(
+                    sample(&row, 3 * x, max),
+                    sample(&row, 3 * x + 1, max),
+                    sample(&row, 3 * x + 2, max),
+                ),
+            };
+        }
+        pipeline.push_row(&grey);
+    }
+
+    pipeline.finish()
+}
+
+/// The `index`th sample of a row scaled to 0-255, samples are two bytes when `max` needs them
+fn sample(row: &[u8], index: usize, max: usize) -> u8 {
+    let value = if max > 255 {
+        u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]) as usize
+    } else {
+        row[index] as usize
+    };
+    (value.min(max) * 255 / max) as u8
+}
+
+/// A decimal header field, skipping whitespace and comments before it and eating the single
+/// whitespace character after it
+async fn header_number<S: ImageSource>(reader: &mut Reader<S>) -> Result<usize, ImageError> {
+    let mut byte = reader.byte().await?;
+    loop {
+        match byte {
+            b'#' => {
+                while !matches!(byte, b'\n' | b'\r') {
+                    byte = reader.byte().await?;
+                }
+            }
+            b' ' | b'\t' | b'\n' | b'\r' => byte = reader.byte().await?,
+            _ => break,
+        }
+    }
+
+    let mut value: usize = 0;
+    while byte.is_ascii_digit() {
+        value = value
+            .checked_mul(10)
+            .and_then(|value| value.checked_add((byte - b'0') as usize))
+            .ok_or(ImageError::Invalid)?;
+        byte = reader.byte().await?;
+    }
+    if !byte.is_ascii_whitespace() {
+        return Err(ImageError::Invalid);
+    }
+    Ok(value)
+}
@@ -0,0 +1,303 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::string::String;
+use defmt::{debug, warn};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::{Mutex, MutexGuard},
+};
+use embassy_time::{Duration, Instant};
+
+use super::{
+    CHUNK_SIZE, ControlPolicy, CutMode, Feed, JobChunk, PrinterCommand, PrinterSender, Style,
+    TextFormat, history,
+};
+use crate::{
+    config::{BlockConfig, config},
+    limits::Requesters,
+};
+
+/// Held by a producer for the whole duration of its job so chunks from different jobs can't
+/// interleave in the printer channel.
+static JOB_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+static NEXT_JOB_ID: AtomicU32 = AtomicU32::new(1);
+
+/// longest a job streamed over the network may hold the printer for
+pub const MAX_JOB_TIME: Duration = Duration::from_secs(5 * 60);
+/// slowest a streamed job may arrive once it has had time to get going, a trickle of bytes would
+/// hold the printer for hours
+const MIN_BYTES_PER_SECOND: u64 = 64;
+const RATE_GRACE: Duration = Duration::from_secs(30);
+/// longest note printed upside down, which is held back whole to print it last line first
+pub const MAX_UPSIDE_DOWN_LENGTH: usize = 4 * 1024;
+
+/// Waits for the job in progress to finish, no other job can start while the guard is held
+pub(super) async fn lock_jobs() -> MutexGuard<'static, CriticalSectionRawMutex, ()> {
+    JOB_LOCK.lock().await
+}
+
+/// The id the next job will be given
+pub fn next_job_id() -> u32 {
+    NEXT_JOB_ID.load(Ordering::Relaxed)
+}
+
+/// Takes the next job id, for jobs that need it before they are printed
+pub fn reserve_job_id() -> u32 {
+    NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Whether a streamed job begun at `started` arrives too slowly, `received` bytes in
+pub fn is_too_slow(started: Instant, received: u64) -> bool {
+    let elapsed = started.elapsed();
+    elapsed > RATE_GRACE && received < MIN_BYTES_PER_SECOND * elapsed.as_secs()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum JobError {
+    /// the job is longer than `PrinterConfig::max_job_length`, its own maximum, or than a note
+    /// can be to print upside down
+    TooLong,
+    /// the sender went away before the job was complete
+    Disconnected,
+    /// the sender took too long, or sent too slowly, while holding the printer
+    TooSlow,
+}
+
+impl JobError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobError::TooLong => "Print job is too long",
+            JobError::Disconnected => "Sender disconnected before the job was complete",
+            JobError::TooSlow => "Sender was too slow to send the job",
+        }
+    }
+}
+
+/// Where a job was submitted from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobSource {
+    Web,
+    /// the part of the topic after the producer prefix
+    Mqtt(String),
+    /// fired by the schedule with this id
+    Schedule(u32),
+    /// the raw print port
+    Socket,
+}
+
+impl fmt::Display for JobSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobSource::Web => write!(f, "web"),
+            JobSource::Mqtt(topic) => write!(f, "mqtt {topic}"),
+            JobSource::Schedule(id) => write!(f, "schedule {id}"),
+            JobSource::Socket => write!(f, "port {}", crate::net::raw::PORT),
+        }
+    }
+}
+
+impl defmt::Format for JobSource {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            JobSource::Web => defmt::write!(f, "web"),
+            JobSource::Mqtt(topic) => defmt::write!(f, "mqtt {}", topic.as_str()),
+            JobSource::Schedule(id) => defmt::write!(f, "schedule {}", id),
+            JobSource::Socket => defmt::write!(f, "port {}", crate::net::raw::PORT),
+        }
+    }
+}
+
+/// Per job overrides of the printer defaults, `None` falls back to the configured value
+#[derive(Clone, Debug, defmt::Format)]
+pub struct JobOptions {
+    pub source: JobSource,
+    /// paper fed before the job is printed
+    pub leading_feed: Option<Feed>,
+    /// paper fed after the job so it clears the tear bar or cutter
+    pub trailing_feed: Option<Feed>,
+    pub cut: Option<CutMode>,
+    pub header: Option<bool>,
+    pub footer: Option<bool>,
+    /// bytes the job may have, for sources that stream more than a note
+    pub max_length: Option<usize>,
+    pub format: TextFormat,
+    /// applied to every line of the job's text
+    pub style: Style,
+    /// charged for the job against their limits, empty for trusted sources
+    pub requesters: Requesters,
+}
+
+impl JobOptions {
+    pub fn new(source: JobSource) -> Self {
+        Self {
+            source,
+            leading_feed: None,
+            trailing_feed: None,
+            cut: None,
+            header: None,
+            footer: None,
+            max_length: None,
+            format: TextFormat::Plain,
+            style: Style::default(),
+            requesters: Requesters::new(),
+        }
+    }
+
+    pub fn leading_feed(&self) -> Feed {
+        self.leading_feed
+            .unwrap_or_else(|| config().printer.leading_feed)
+    }
+
+    pub fn trailing_feed(&self) -> Feed {
+        self.trailing_feed
+            .unwrap_or_else(|| config().printer.trailing_feed)
+    }
+
+    /// Bytes the job may have, a note printed upside down is held in memory until it is complete
+    pub fn max_length(&self) -> usize {
+        let printer = config().printer;
+        let max_length = self.max_length.unwrap_or(printer.max_job_length);
+        if printer.upside_down && !self.control_policy().bypasses_layout() {
+            max_length.min(MAX_UPSIDE_DOWN_LENGTH)
+        } else {
+            max_length
+        }
+    }
+
+    pub fn cut(&self) -> CutMode {
+        self.cut.unwrap_or_else(|| config().printer.cut)
+    }
+
+    /// The header block to print, if enabled for this job
+    pub fn header(&self) -> Option<BlockConfig> {
+        self.block(self.header, config().header)
+    }
+
+    /// The footer block to print, if enabled for this job
+    pub fn footer(&self) -> Option<BlockConfig> {
+        self.block(self.footer, config().footer)
+    }
+
+    /// How control characters in the job's text are handled, only configurable per source so
+    /// producers can't opt themselves into raw printing
+    pub fn control_policy(&self) -> ControlPolicy {
+        let controls = config().controls;
+        match self.source {
+            JobSource::Web => controls.web,
+            JobSource::Mqtt(_) => controls.mqtt,
+            JobSource::Schedule(_) => controls.schedule,
+            JobSource::Socket => controls.socket,
+        }
+    }
+
+    fn block(&self, enabled: Option<bool>, block: BlockConfig) -> Option<BlockConfig> {
+        let enabled = enabled.unwrap_or(match self.source {
+            JobSource::Web => block.web,
+            JobSource::Mqtt(_) => block.mqtt,
+            JobSource::Schedule(_) => block.schedule,
+            JobSource::Socket => block.socket,
+        });
+        enabled.then_some(block)
+    }
+}
+
+/// A print job being streamed to the printer.
+///
+/// Data is pushed in chunks of any size, the printer decodes and lays it out as it arrives.
+/// Dropping the job without calling [`PrintJob::finish`], e.g. when the request sending it is
+/// cancelled, aborts it as [`JobError::Disconnected`].
+pub struct PrintJob {
+    id: u32,
+    printer_tx: PrinterSender,
+    written: usize,
+    max_length: usize,
+    /// the printer was told the job finished or was aborted
+    ended: bool,
+    _lock: MutexGuard<'static, CriticalSectionRawMutex, ()>,
+}
+
+impl PrintJob {
+    /// Waits for any other job to finish, then starts this one under `id`, or the next free
+    /// id if none was reserved
+    pub(super) async fn begin(
+        printer_tx: PrinterSender,
+        id: Option<u32>,
+        options: JobOptions,
+    ) -> Self {
+        let lock = JOB_LOCK.lock().await;
+        let id = id.unwrap_or_else(reserve_job_id);
+        let max_length = options.max_length();
+        history::started(id, &options);
+        printer_tx
+            .send(PrinterCommand::BeginJob { id, options })
+            .await;
+
+        Self {
+            id,
+            printer_tx,
+            written: 0,
+            max_length,
+            ended: false,
+            _lock: lock,
+        }
+    }
+
+    /// Waits for the printer to be free of other jobs before running `command`
+    pub(super) async fn exclusive(printer_tx: PrinterSender, command: PrinterCommand) {
+        let _lock = JOB_LOCK.lock().await;
+        printer_tx.send(command).await;
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), JobError> {
+        if self.written + data.len() > self.max_length {
+            warn!(
+                "Print job exceeds the maximum length of {} bytes",
+                self.max_length
+            );
+            return Err(JobError::TooLong);
+        }
+
+        for chunk in data.chunks(CHUNK_SIZE) {
+            // chunks are never longer than the capacity
+            let chunk = JobChunk::from_slice(chunk).unwrap_or_default();
+            self.printer_tx.send(PrinterCommand::JobData(chunk)).await;
+        }
+        self.written += data.len();
+        debug!("{} bytes queued for printing", self.written);
+
+        Ok(())
+    }
+
+    pub async fn finish(mut self) {
+        self.printer_tx.send(PrinterCommand::EndJob).await;
+        self.ended = true;
+    }
+
+    /// Discards the job, anything the printer has not printed yet is dropped
+    pub async fn abort(mut self, error: JobError) {
+        self.printer_tx.send(PrinterCommand::AbortJob(error)).await;
+        self.ended = true;
+    }
+}
+
+impl Drop for PrintJob {
+    fn drop(&mut self) {
+        if self.ended {
+            return;
+        }
+        warn!("Print job {} was dropped unfinished, aborting it", self.id);
+        // the abort is sent before the lock is released, so it reaches the printer ahead of the
+        // next job. With the channel full, the printer aborts the job when the next one begins.
+        let abort = PrinterCommand::AbortJob(JobError::Disconnected);
+        if self.printer_tx.try_send(abort).is_err() {
+            warn!("Printer channel is full, job {} is aborted later", self.id);
+        }
+    }
+}
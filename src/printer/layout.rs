@@ -0,0 +1,149 @@
+use alloc::{string::String, vec::Vec};
+
+use super::{Style, bitmap_font};
+
+pub const MAX_CHARACTERS_PER_LINE: usize = 30;
+/// dots across the 58mm print head
+pub const PAPER_WIDTH: usize = 384;
+// font A
+pub const CHAR_WIDTH: usize = 12;
+pub const CHAR_HEIGHT: usize = 24;
+pub const LINE_HEIGHT: usize = 30;
+/// columns between the printer's default tab stops
+const TAB_WIDTH: usize = 8;
+
+/// A wrapped line and the style to print it in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    pub style: Style,
+}
+
+impl Line {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            style: Style::default(),
+        }
+    }
+
+    /// The style the line really gets, lines drawn with the bitmap font are always plain
+    pub fn printed_style(&self) -> Style {
+        if bitmap_font::needs_raster(&self.text) {
+            Style::default()
+        } else {
+            self.style
+        }
+    }
+}
+
+/// Splits text into printable lines, wrapping at the last space that fits on the line. Text can
+/// be pushed in arbitrary pieces and complete lines are emitted as soon as they are known.
+///
+/// Widths are counted in character cells, as wide glyphs from the bitmap font take two.
+///
+/// Shared between the printer and the web preview so both lay out text identically.
+pub struct LineWrapper {
+    line: String,
+    /// character cells taken up by `line`
+    characters: usize,
+    style: Style,
+}
+
+impl LineWrapper {
+    pub fn new() -> Self {
+        Self::with_style(Style::default())
+    }
+
+    pub fn with_style(style: Style) -> Self {
+        Self {
+            line: String::new(),
+            characters: 0,
+            style,
+        }
+    }
+
+    /// Changes the style of the lines that follow, text already pushed onto the current line
+    /// takes the new style too
+    pub fn set_style(&mut self, style: Style) {
+        self.style = style;
+    }
+
+    pub fn push_str(&mut self, text: &str, lines: &mut Vec<Line>) {
+        for c in text.chars() {
+            match c {
+                '\n' => self.end_line(lines),
+                '\r' => {}
+                c if self.line.is_empty() && c.is_whitespace() => {}
+                // expanded here so the line's width is known
+                '\t' => {
+                    for _ in 0..TAB_WIDTH - self.characters % TAB_WIDTH {
+                        self.push_str(" ", lines);
+                    }
+                }
+                c => {
+                    self.line.push(c);
+                    self.characters += bitmap_font::columns(c);
+
+                    if self.characters > self.style.columns() {
+                        self.wrap(lines);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emits whatever is left of the last line
+    pub fn finish(&mut self, lines: &mut Vec<Line>) {
+        self.end_line(lines);
+    }
+
+    fn end_line(&mut self, lines: &mut Vec<Line>) {
+        while self.characters > self.style.columns() {
+            self.wrap(lines);
+        }
+
+        let line = self.line.trim();
+        if !line.is_empty() {
+            lines.push(Line {
+                text: String::from(line),
+                style: self.style,
+            });
+        }
+        self.line.clear();
+        self.characters = 0;
+    }
+
+    /// Moves the first full line out of the buffer
+    fn wrap(&mut self, lines: &mut Vec<Line>) {
+        let max = self.style.columns();
+        let mut columns = 0;
+        let take_len = self
+            .line
+            .char_indices()
+            .find(|(_, c)| {
+                columns += bitmap_font::columns(*c);
+                columns > max
+            })
+            .map_or(self.line.len(), |(index, _)| index);
+        let slice = &self.line[..take_len];
+
+        // Try to break at the last space within the slice
+        let break_point = slice.rfind(' ').unwrap_or(take_len);
+        let split_idx = if break_point == 0 {
+            take_len
+        } else {
+            break_point
+        };
+
+        let (line, rest) = self.line.split_at(split_idx);
+        lines.push(Line {
+            text: String::from(line.trim()),
+            style: self.style,
+        });
+
+        let rest = String::from(rest.trim_start());
+        self.characters = rest.chars().map(bitmap_font::columns).sum();
+        self.line = rest;
+    }
+}
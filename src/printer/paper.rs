@@ -0,0 +1,234 @@
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use serde::{Deserialize, Serialize};
+
+use super::feed::DOTS_PER_MM;
+use crate::{
+    events::{self, Event},
+    power::{SHUTDOWN_WATCHER, ShutdownStatus},
+    storage::{self, Record},
+};
+
+/// usage is written to flash every 2m of paper, when the paper runs low and when the power is
+/// failing, so a power cut loses little even when the warning comes too late
+const SAVE_INTERVAL_DOTS: u64 = 2_000 * DOTS_PER_MM as u64;
+const DEFAULT_LOW_PAPER_MM: u32 = 1_000;
+
+static PAPER: Mutex<CriticalSectionRawMutex, PaperState> = Mutex::new(PaperState {
+    usage: PaperUsage {
+        roll_dots: 0,
+        total_dots: 0,
+        last_job_dots: 0,
+        roll_length_mm: 0,
+        low_paper_mm: DEFAULT_LOW_PAPER_MM,
+    },
+    unsaved_dots: 0,
+    sensor: PaperSensor::Unknown,
+});
+
+/// Signalled when the roll runs below the low paper threshold or the printer's sensor says it is
+/// near its end or out
+pub static LOW_PAPER_SIGNAL: Signal<CriticalSectionRawMutex, PaperWarning> = Signal::new();
+
+struct PaperState {
+    usage: PaperUsage,
+    unsaved_dots: u64,
+    sensor: PaperSensor,
+}
+
+impl PaperState {
+    fn status(&self) -> PaperStatus {
+        self.usage.status(self.sensor)
+    }
+}
+
+/// What the printer's paper roll sensors report
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaperSensor {
+    /// the printer hasn't answered yet
+    Unknown,
+    Present,
+    NearEnd,
+    Out,
+}
+
+impl PaperSensor {
+    /// Reads the `DLE EOT 4` status byte
+    pub fn from_status(status: u8) -> Self {
+        if status & 0x60 != 0 {
+            PaperSensor::Out
+        } else if status & 0x0C != 0 {
+            PaperSensor::NearEnd
+        } else {
+            PaperSensor::Present
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum PaperWarning {
+    /// the estimate of the paper left in mm is below the threshold
+    Low(u32),
+    NearEnd,
+    Out,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct PaperUsage {
+    /// fed since the roll was last reset
+    roll_dots: u64,
+    /// fed over the life of the device
+    total_dots: u64,
+    last_job_dots: u64,
+    /// 0 while the roll length is unknown
+    roll_length_mm: u32,
+    low_paper_mm: u32,
+}
+
+/// Paper usage in millimetres
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct PaperStatus {
+    pub used_mm: u32,
+    pub total_mm: u32,
+    pub last_job_mm: u32,
+    pub roll_length_mm: Option<u32>,
+    /// estimate, only known once the roll length is set
+    pub remaining_mm: Option<u32>,
+    pub low_paper_mm: u32,
+    /// the estimate is below `low_paper_mm`
+    pub low: bool,
+    /// the printer's own sensor, independent of the estimate
+    pub sensor: PaperSensor,
+}
+
+impl PaperUsage {
+    fn status(&self, sensor: PaperSensor) -> PaperStatus {
+        let mm = |dots: u64| (dots / DOTS_PER_MM as u64).min(u32::MAX as u64) as u32;
+        let used_mm = mm(self.roll_dots);
+        let roll_length_mm = (self.roll_length_mm > 0).then_some(self.roll_length_mm);
+        let remaining_mm = roll_length_mm.map(|length| length.saturating_sub(used_mm));
+
+        PaperStatus {
+            used_mm,
+            total_mm: mm(self.total_dots),
+            last_job_mm: mm(self.last_job_dots),
+            roll_length_mm,
+            remaining_mm,
+            low_paper_mm: self.low_paper_mm,
+            low: remaining_mm.is_some_and(|remaining| remaining <= self.low_paper_mm),
+            sensor,
+        }
+    }
+}
+
+pub(super) async fn load_paper_usage() {
+    if let Some(usage) = storage::load::<PaperUsage>(Record::Paper).await {
+        let status = usage.status(PaperSensor::Unknown);
+        info!("Loaded paper usage, {} mm used of the roll", status.used_mm);
+        PAPER.lock().await.usage = usage;
+    }
+}
+
+pub async fn paper_status() -> PaperStatus {
+    PAPER.lock().await.status()
+}
+
+/// Adds paper fed by the printer, `job` marks the end of a job and keeps its length
+pub(super) async fn record_usage(dots: u32, job: bool) {
+    let mut paper = PAPER.lock().await;
+    let was_low = paper.status().low;
+
+    let dots = dots as u64;
+    paper.usage.roll_dots += dots;
+    paper.usage.total_dots += dots;
+    if job {
+        paper.usage.last_job_dots = dots;
+    }
+    paper.unsaved_dots += dots;
+
+    let status = paper.status();
+    if status.low && !was_low {
+        let remaining = status.remaining_mm.unwrap_or_default();
+        warn!("Paper is running low, about {} mm left", remaining);
+        LOW_PAPER_SIGNAL.signal(PaperWarning::Low(remaining));
+    }
+
+    if status.low != was_low {
+        events::publish(Event::Paper(status));
+    }
+    if paper.unsaved_dots >= SAVE_INTERVAL_DOTS || status.low != was_low {
+        let _ = save(&mut paper).await;
+    }
+}
+
+/// Records what the printer's paper sensor reports, warning when the roll nears its end or runs
+/// out
+pub(super) async fn set_sensor(sensor: PaperSensor) {
+    let mut paper = PAPER.lock().await;
+    if paper.sensor == sensor {
+        return;
+    }
+    paper.sensor = sensor;
+    info!("Paper sensor: {}", sensor);
+
+    match sensor {
+        PaperSensor::NearEnd => LOW_PAPER_SIGNAL.signal(PaperWarning::NearEnd),
+        PaperSensor::Out => LOW_PAPER_SIGNAL.signal(PaperWarning::Out),
+        PaperSensor::Unknown | PaperSensor::Present => {}
+    }
+    events::publish(Event::Paper(paper.status()));
+}
+
+/// Sets the length of the loaded roll, 0 disables the estimate
+pub async fn set_roll_length(mm: u32) -> Result<PaperStatus, ()> {
+    update(|usage| usage.roll_length_mm = mm).await
+}
+
+pub async fn set_low_paper_threshold(mm: u32) -> Result<PaperStatus, ()> {
+    update(|usage| usage.low_paper_mm = mm).await
+}
+
+/// Starts counting a freshly loaded roll
+pub async fn reset_roll() -> Result<PaperStatus, ()> {
+    info!("Paper roll reset");
+    update(|usage| usage.roll_dots = 0).await
+}
+
+async fn update(change: impl FnOnce(&mut PaperUsage)) -> Result<PaperStatus, ()> {
+    let mut paper = PAPER.lock().await;
+    let previous = paper.usage;
+    change(&mut paper.usage);
+
+    if save(&mut paper).await.is_err() {
+        paper.usage = previous;
+        return Err(());
+    }
+    let status = paper.status();
+    events::publish(Event::Paper(status));
+    Ok(status)
+}
+
+/// Saves the usage as soon as the power starts failing, before the device goes down
+#[embassy_executor::task]
+pub(super) async fn save_on_power_loss() {
+    let Some(mut shutdown_recv) = SHUTDOWN_WATCHER.receiver() else {
+        warn!("No shutdown receiver left, paper usage is not saved on power loss");
+        return;
+    };
+
+    loop {
+        if matches!(shutdown_recv.changed().await, ShutdownStatus::LowPower) {
+            let mut paper = PAPER.lock().await;
+            if paper.unsaved_dots > 0 && save(&mut paper).await.is_ok() {
+                info!("Saved paper usage on power loss");
+            }
+        }
+    }
+}
+
+async fn save(paper: &mut PaperState) -> Result<(), ()> {
+    storage::save(Record::Paper, &paper.usage).await?;
+    paper.unsaved_dots = 0;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use core::fmt::Write as _;
+
+use alloc::{string::String, vec::Vec};
+
+use super::{
+    Align, CutMode, JobOptions, bitmap_font, decoration,
+    format::Formatter,
+    job::next_job_id,
+    layout::{CHAR_HEIGHT, CHAR_WIDTH, LINE_HEIGHT, Line, PAPER_WIDTH},
+    sanitize::sanitize,
+};
+
+/// Renders the text as a black and white svg of the paper, using the printer's own line layout
+/// and the job's header, footer and paper feeds
+pub fn render_preview(text: &str, options: &JobOptions) -> String {
+    let id = next_job_id();
+    let mut lines = Vec::new();
+    let mut formatter = Formatter::new(options.format, options.style);
+    decoration::push_header(options, id, &mut lines);
+    let text = sanitize(
+        text.strip_suffix('\r').unwrap_or(text),
+        options.control_policy(),
+    );
+    formatter.push_str(&text, &mut lines);
+    formatter.finish(&mut lines);
+    decoration::push_footer(options, id, &mut lines);
+    let top = options.leading_feed().dots(LINE_HEIGHT as u32) as usize;
+    let bottom = options.trailing_feed().dots(LINE_HEIGHT as u32) as usize;
+    let text_height: usize = lines
+        .iter()
+        .map(|line| line.printed_style().line_height())
+        .sum();
+    let height = top + text_height + bottom;
+
+    let mut svg = String::new();
+    // writing into a String cannot fail
+    let _ = write!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{PAPER_WIDTH}\" height=\"{height}\" \
+        viewBox=\"0 0 {PAPER_WIDTH} {height}\">\
+        <rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\
+        <g font-family=\"monospace\" font-size=\"{CHAR_HEIGHT}\" fill=\"#000\">"
+    );
+
+    let mut y = top;
+    for line in lines.iter() {
+        push_line(&mut svg, line, y);
+        y += line.printed_style().line_height();
+    }
+
+    svg.push_str("</g>");
+    if options.cut() != CutMode::None {
+        let cut = height.saturating_sub(1);
+        let _ = write!(
+            svg,
+            "<line x1=\"0\" y1=\"{cut}\" x2=\"{PAPER_WIDTH}\" y2=\"{cut}\" \
+            stroke=\"#000\" stroke-width=\"2\" stroke-dasharray=\"8 4\"/>"
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Draws a line whose top is `y` dots down the paper
+fn push_line(svg: &mut String, line: &Line, y: usize) {
+    let style = line.printed_style();
+    let scale = if style.large { 2 } else { 1 };
+    let columns: usize = line.text.chars().map(bitmap_font::columns).sum();
+    let width = columns * CHAR_WIDTH * scale;
+    let x = match style.align {
+        Align::Left => 0,
+        Align::Center => PAPER_WIDTH.saturating_sub(width) / 2,
+        Align::Right => PAPER_WIDTH.saturating_sub(width),
+    };
+    let fill = if style.inverse {
+        let _ = write!(
+            svg,
+            "<rect x=\"{x}\" y=\"{y}\" width=\"{width}\" height=\"{}\"/>",
+            CHAR_HEIGHT * scale
+        );
+        "#fff"
+    } else {
+        "#000"
+    };
+
+    let _ = write!(
+        svg,
+        "<text x=\"{x}\" y=\"{}\" textLength=\"{width}\" fill=\"{fill}\" \
+        font-size=\"{}\" lengthAdjust=\"spacingAndGlyphs\" xml:space=\"preserve\"",
+        y + CHAR_HEIGHT * scale,
+        CHAR_HEIGHT * scale
+    );
+    if style.bold {
+        svg.push_str(" font-weight=\"bold\"");
+    }
+    if style.underline {
+        svg.push_str(" text-decoration=\"underline\"");
+    }
+    svg.push('>');
+    push_escaped(svg, &line.text);
+    svg.push_str("</text>");
+}
+
+fn push_escaped(svg: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => svg.push_str("&amp;"),
+            '<' => svg.push_str("&lt;"),
+            '>' => svg.push_str("&gt;"),
+            c if c.is_control() => svg.push('\u{FFFD}'),
+            c => svg.push(bitmap_font::substitute(c)),
+        }
+    }
+}
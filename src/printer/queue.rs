@@ -0,0 +1,171 @@
+use core::cell::RefCell;
+
+use alloc::{string::String, vec::Vec};
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Instant, Timer};
+use serde::Deserialize;
+
+use super::{Image, JobOptions, PrinterWriter, history};
+use crate::{dedup, limits};
+
+/// jobs waiting at once, further jobs are refused until the printer catches up
+pub const MAX_QUEUED: usize = 8;
+pub const MAX_COPIES: u8 = 5;
+/// images waiting at once, each holds its whole raster
+pub const MAX_QUEUED_IMAGES: usize = 2;
+
+static QUEUE: Mutex<CriticalSectionRawMutex, RefCell<Vec<QueuedJob>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+static QUEUE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, defmt::Format, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A job waiting for the printer, printed after any of higher priority. Only queued jobs are
+/// ordered by priority, jobs from other sources print as they arrive, in between them.
+pub struct QueuedJob {
+    /// reserved with [`super::reserve_job_id`]
+    pub id: u32,
+    pub payload: JobPayload,
+    pub options: JobOptions,
+    pub priority: Priority,
+    pub copies: u8,
+    /// held back until then by its sender's rate limit, later jobs print in the meantime
+    pub not_before: Instant,
+}
+
+/// What a queued job prints
+pub enum JobPayload {
+    Text(String),
+    /// decoded and dithered, printed once whatever the copies
+    Image(Image),
+}
+
+/// Adds the job behind the others of the same or higher priority, returning how many jobs
+/// will print before it
+pub fn enqueue(job: QueuedJob) -> Result<usize, ()> {
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        let is_image = matches!(job.payload, JobPayload::Image(_));
+        if queue.len() >= MAX_QUEUED || (is_image && count_images(&queue) >= MAX_QUEUED_IMAGES) {
+            warn!("Print queue is full, refusing job {}", job.id);
+            return Err(());
+        }
+
+        let position = queue
+            .iter()
+            .position(|queued| queued.priority < job.priority)
+            .unwrap_or(queue.len());
+        info!("Job {} queued at position {}", job.id, position);
+        let text = match &job.payload {
+            JobPayload::Text(text) => Some(text.as_str()),
+            JobPayload::Image(_) => None,
+        };
+        history::queued(job.id, text, &job.options);
+        queue.insert(position, job);
+        QUEUE_SIGNAL.signal(());
+        Ok(position)
+    })
+}
+
+/// Jobs waiting to be printed
+pub fn queue_length() -> usize {
+    QUEUE.lock(|queue| queue.borrow().len())
+}
+
+/// How many images are waiting, see [`MAX_QUEUED_IMAGES`]
+pub fn queued_images() -> usize {
+    QUEUE.lock(|queue| count_images(&queue.borrow()))
+}
+
+fn count_images(queue: &[QueuedJob]) -> usize {
+    queue
+        .iter()
+        .filter(|job| matches!(job.payload, JobPayload::Image(_)))
+        .count()
+}
+
+/// How many jobs will print before the job, `None` once it has left the queue
+pub fn queue_position(id: u32) -> Option<usize> {
+    QUEUE.lock(|queue| queue.borrow().iter().position(|job| job.id == id))
+}
+
+/// Takes the job out of the queue before it prints, returning whether it was still queued
+pub fn cancel(id: u32) -> bool {
+    let cancelled = QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        let position = queue.iter().position(|job| job.id == id);
+        position.map(|position| queue.remove(position))
+    });
+    let Some(job) = cancelled else {
+        return false;
+    };
+    info!("Job {} cancelled", id);
+    history::cancelled(id);
+    // nothing was printed, so the job neither counts as sent nor against the limits
+    dedup::forget_job(id);
+    limits::refund(&job.options.requesters);
+    true
+}
+
+/// The first job that may print now, or when the next deferred one may
+fn next_job() -> Result<QueuedJob, Option<Instant>> {
+    let now = Instant::now();
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        match queue.iter().position(|job| job.not_before <= now) {
+            Some(position) => Ok(queue.remove(position)),
+            None => Err(queue.iter().map(|job| job.not_before).min()),
+        }
+    })
+}
+
+#[embassy_executor::task]
+pub async fn queue_task(printer: PrinterWriter) {
+    loop {
+        let job = match next_job() {
+            Ok(job) => job,
+            Err(Some(deferred)) => {
+                select(QUEUE_SIGNAL.wait(), Timer::at(deferred)).await;
+                continue;
+            }
+            Err(None) => {
+                QUEUE_SIGNAL.wait().await;
+                continue;
+            }
+        };
+
+        let text = match job.payload {
+            JobPayload::Text(text) => text,
+            JobPayload::Image(image) => {
+                printer.print_image(job.id, image, job.options).await;
+                continue;
+            }
+        };
+        for copy in 1..=job.copies {
+            info!("Printing copy {} of {} of job {}", copy, job.copies, job.id);
+            let result = printer
+                .print_as(job.id, text.as_bytes(), job.options.clone())
+                .await;
+            if let Err(e) = result {
+                warn!("Failed to print queued job {}: {:?}", job.id, e);
+                // the sender may retry it
+                dedup::forget_job(job.id);
+                break;
+            }
+        }
+    }
+}
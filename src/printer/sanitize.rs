@@ -0,0 +1,120 @@
+use core::str::FromStr;
+
+use alloc::{borrow::Cow, string::String};
+use serde::{Deserialize, Serialize};
+
+/// What happens to control characters in a job's text, so untrusted producers can't slip
+/// ESC/POS commands in with their text
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum ControlPolicy {
+    /// drop them
+    Strip,
+    /// print them visibly in caret notation, e.g. `^[` for ESC
+    Escape,
+    /// send the job's bytes to the printer untouched, without layout, header or footer, for
+    /// trusted producers that format their own ESC/POS
+    Raw,
+    /// like `Raw`, but ESC/POS commands that could change the printer's stored settings or
+    /// answer on its uart are dropped
+    Passthrough,
+}
+
+impl ControlPolicy {
+    /// Whether the job's bytes go to the printer as they are rather than through the layout
+    pub fn bypasses_layout(self) -> bool {
+        matches!(self, ControlPolicy::Raw | ControlPolicy::Passthrough)
+    }
+}
+
+impl FromStr for ControlPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "strip" => Ok(ControlPolicy::Strip),
+            "escape" => Ok(ControlPolicy::Escape),
+            "raw" => Ok(ControlPolicy::Raw),
+            "passthrough" => Ok(ControlPolicy::Passthrough),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Removes or escapes every control character but tab and line breaks, which the layout
+/// handles itself
+pub fn sanitize(text: &str, policy: ControlPolicy) -> Cow<'_, str> {
+    let is_allowed = |c: char| !c.is_control() || matches!(c, '\t' | '\n' | '\r');
+    if policy.bypasses_layout() || text.chars().all(is_allowed) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut clean = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            c if is_allowed(c) => clean.push(c),
+            _ if policy == ControlPolicy::Strip => {}
+            // C0 and DEL
+            c if c.is_ascii() => {
+                clean.push('^');
+                clean.push((c as u8 ^ 0x40) as char);
+            }
+            // C1 has no caret notation
+            _ => clean.push('?'),
+        }
+    }
+    Cow::Owned(clean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_clean_text_borrowed() {
+        let text = "Milk\teggs\r\nbread \u{e9}\u{1f35e}";
+        for policy in [ControlPolicy::Strip, ControlPolicy::Escape] {
+            assert!(matches!(sanitize(text, policy), Cow::Borrowed(t) if t == text));
+        }
+    }
+
+    #[test]
+    fn strips_escpos_commands() {
+        // ESC @ initialize, GS V 0 cut, ESC p pulse the cash drawer
+        let text = "a\x1B@b\x1DV\x00c\x1Bp\x00\x19\x19d";
+        assert_eq!(sanitize(text, ControlPolicy::Strip), "a@bVcpd");
+        // DEL and the C1 controls too
+        assert_eq!(sanitize("x\x7Fy\u{9B}z", ControlPolicy::Strip), "xyz");
+    }
+
+    #[test]
+    fn escapes_in_caret_notation() {
+        assert_eq!(sanitize("a\x1B@b", ControlPolicy::Escape), "a^[@b");
+        assert_eq!(sanitize("\x00\x07\x1D", ControlPolicy::Escape), "^@^G^]");
+        assert_eq!(sanitize("\x7F", ControlPolicy::Escape), "^?");
+        assert_eq!(sanitize("\u{85}", ControlPolicy::Escape), "?");
+        assert_eq!(
+            sanitize("tab\tline\n", ControlPolicy::Escape),
+            "tab\tline\n"
+        );
+    }
+
+    #[test]
+    fn raw_policies_pass_everything() {
+        let text = "\x1B@\x1DV\x00";
+        for policy in [ControlPolicy::Raw, ControlPolicy::Passthrough] {
+            assert!(policy.bypasses_layout());
+            assert_eq!(sanitize(text, policy), text);
+        }
+        assert!(!ControlPolicy::Strip.bypasses_layout());
+        assert!(!ControlPolicy::Escape.bypasses_layout());
+    }
+
+    #[test]
+    fn parses_policy_names() {
+        assert_eq!("strip".parse(), Ok(ControlPolicy::Strip));
+        assert_eq!(" escape ".parse(), Ok(ControlPolicy::Escape));
+        assert_eq!("raw".parse(), Ok(ControlPolicy::Raw));
+        assert_eq!("passthrough".parse(), Ok(ControlPolicy::Passthrough));
+        assert_eq!("Raw".parse::<ControlPolicy>(), Err(()));
+    }
+}
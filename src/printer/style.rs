@@ -0,0 +1,61 @@
+use serde::Deserialize;
+
+use super::layout::{CHAR_HEIGHT, LINE_HEIGHT, MAX_CHARACTERS_PER_LINE};
+
+/// Where a line sits across the paper
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// Print modes applied to whole lines
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format, Deserialize)]
+#[serde(default)]
+pub struct Style {
+    pub bold: bool,
+    pub underline: bool,
+    /// white text on black
+    pub inverse: bool,
+    /// double width and height
+    pub large: bool,
+    pub align: Align,
+}
+
+impl Style {
+    /// Character cells that fit across the paper
+    pub fn columns(&self) -> usize {
+        if self.large {
+            MAX_CHARACTERS_PER_LINE / 2
+        } else {
+            MAX_CHARACTERS_PER_LINE
+        }
+    }
+
+    /// Dots of paper a line takes up
+    pub fn line_height(&self) -> usize {
+        if self.large {
+            LINE_HEIGHT + CHAR_HEIGHT
+        } else {
+            LINE_HEIGHT
+        }
+    }
+
+    /// The `ESC !` print mode byte
+    pub fn print_mode(&self) -> u8 {
+        let mut mode = 0;
+        if self.bold {
+            mode |= 0x08;
+        }
+        if self.large {
+            mode |= 0x30; // double height and width
+        }
+        if self.underline {
+            mode |= 0x80;
+        }
+        mode
+    }
+}
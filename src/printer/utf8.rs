@@ -0,0 +1,168 @@
+/// Decodes utf8 split across arbitrary chunk boundaries, carrying incomplete characters over to
+/// the next chunk and replacing invalid sequences with U+FFFD.
+pub struct Utf8Decoder {
+    pending: [u8; 4],
+    pending_len: usize,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            pending: [0; 4],
+            pending_len: 0,
+        }
+    }
+
+    pub fn decode(&mut self, mut chunk: &[u8], mut output: impl FnMut(&str)) {
+        if self.pending_len > 0 {
+            let expected = utf8_width(self.pending[0]);
+            while self.pending_len < expected {
+                match chunk.split_first() {
+                    // continuation byte
+                    Some((&byte, rest)) if byte & 0xC0 == 0x80 => {
+                        self.pending[self.pending_len] = byte;
+                        self.pending_len += 1;
+                        chunk = rest;
+                    }
+                    Some(_) => break,
+                    None => return,
+                }
+            }
+
+            match str::from_utf8(&self.pending[..self.pending_len]) {
+                Ok(c) => output(c),
+                Err(_) => output("\u{FFFD}"),
+            }
+            self.pending_len = 0;
+        }
+
+        loop {
+            match str::from_utf8(chunk) {
+                Ok(text) => {
+                    output(text);
+                    return;
+                }
+                Err(e) => {
+                    let (valid, rest) = chunk.split_at(e.valid_up_to());
+                    // valid_up_to guarantees this prefix is valid
+                    output(str::from_utf8(valid).unwrap_or_default());
+
+                    match e.error_len() {
+                        Some(len) => {
+                            output("\u{FFFD}");
+                            chunk = &rest[len..];
+                        }
+                        None => {
+                            // the character continues in the next chunk
+                            self.pending[..rest.len()].copy_from_slice(rest);
+                            self.pending_len = rest.len();
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flushes a character left incomplete by the end of the job
+    pub fn finish(&mut self, mut output: impl FnMut(&str)) {
+        if self.pending_len > 0 {
+            output("\u{FFFD}");
+            self.pending_len = 0;
+        }
+    }
+}
+
+fn utf8_width(lead: u8) -> usize {
+    match lead {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+
+    /// Decodes the chunks one after another as a job would, finishing after the last
+    fn decode(chunks: &[&[u8]]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let mut text = String::new();
+        for chunk in chunks {
+            decoder.decode(chunk, |part| text.push_str(part));
+        }
+        decoder.finish(|part| text.push_str(part));
+        text
+    }
+
+    #[test]
+    fn decodes_whole_text() {
+        assert_eq!(decode(&[b"plain ascii"]), "plain ascii");
+        assert_eq!(
+            decode(&["caf\u{e9} \u{20ac}5 \u{1f600}".as_bytes()]),
+            "caf\u{e9} \u{20ac}5 \u{1f600}"
+        );
+        assert_eq!(decode(&[]), "");
+    }
+
+    #[test]
+    fn carries_characters_split_across_chunks() {
+        // two, three and four byte characters
+        let text = "a\u{e9}\u{20ac}\u{1f600}z";
+        let bytes = text.as_bytes();
+        for first in 0..=bytes.len() {
+            for second in first..=bytes.len() {
+                let chunks = [&bytes[..first], &bytes[first..second], &bytes[second..]];
+                assert_eq!(decode(&chunks), text, "split at {first} and {second}");
+            }
+        }
+    }
+
+    #[test]
+    fn carries_a_character_over_several_chunks() {
+        assert_eq!(
+            decode(&[b"\xF0", b"\x9F", b"", b"\x98", b"\x80!"]),
+            "\u{1f600}!"
+        );
+    }
+
+    #[test]
+    fn replaces_invalid_bytes() {
+        assert_eq!(decode(&[b"a\xFFb"]), "a\u{FFFD}b");
+        // a continuation byte without a lead byte
+        assert_eq!(decode(&[b"\x80a"]), "\u{FFFD}a");
+        // an overlong encoding of '/'
+        assert_eq!(decode(&[b"\xC0\xAF"]), "\u{FFFD}\u{FFFD}");
+        // a surrogate, which utf8 can't encode
+        assert_eq!(decode(&[b"\xED\xA0\x80"]), "\u{FFFD}\u{FFFD}\u{FFFD}");
+    }
+
+    #[test]
+    fn replaces_a_character_cut_short_by_the_next_chunk() {
+        assert_eq!(decode(&[b"\xE2\x82", b"A"]), "\u{FFFD}A");
+        assert_eq!(
+            decode(&[b"x\xF0", b"\x9F", b"\xE2\x82\xAC"]),
+            "x\u{FFFD}\u{20ac}"
+        );
+    }
+
+    #[test]
+    fn replaces_a_truncated_character_at_the_end() {
+        assert_eq!(decode(&[b"ab\xF0\x9F\x98"]), "ab\u{FFFD}");
+        assert_eq!(decode(&[b"ab", b"\xC3"]), "ab\u{FFFD}");
+    }
+
+    #[test]
+    fn finishing_without_a_pending_character_adds_nothing() {
+        let mut decoder = Utf8Decoder::new();
+        let mut text = String::new();
+        decoder.decode(b"done\xC3\xA9", |part| text.push_str(part));
+        decoder.finish(|part| text.push_str(part));
+        decoder.finish(|part| text.push_str(part));
+        assert_eq!(text, "done\u{e9}");
+    }
+}
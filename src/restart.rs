@@ -0,0 +1,46 @@
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer, with_timeout};
+
+use crate::printer;
+
+/// time for the reply to the request that asked for the restart to be sent
+const REPLY_GRACE: Duration = Duration::from_secs(2);
+/// longest wait for the queued jobs to print, any still queued after it are lost
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(60);
+
+static RESTART_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn start_restarter(spawner: &Spawner) {
+    spawner.must_spawn(restart_task());
+}
+
+/// Restarts the device once the print queue is empty, without cutting off the job printing
+pub fn request_restart() {
+    RESTART_SIGNAL.signal(());
+}
+
+#[embassy_executor::task]
+async fn restart_task() {
+    RESTART_SIGNAL.wait().await;
+    info!("Restart requested, waiting for the printer");
+    Timer::after(REPLY_GRACE).await;
+
+    let drained = with_timeout(QUEUE_TIMEOUT, async {
+        while printer::queue_length() > 0 {
+            Timer::after(Duration::from_millis(500)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Restarting with {} jobs still queued",
+            printer::queue_length()
+        );
+    }
+
+    let _hold = printer::hold_printer().await;
+    info!("Restarting");
+    esp_hal::system::software_reset()
+}
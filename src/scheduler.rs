@@ -0,0 +1,262 @@
+use core::{fmt, str::FromStr};
+
+use alloc::{string::String, vec::Vec};
+use defmt::{error, info, warn};
+use embassy_executor::Spawner;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Timer};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    printer::{JobOptions, JobSource, PrinterWriter},
+    storage::{self, Record},
+    time::{self, DateTime},
+};
+
+mod cron;
+
+pub use cron::Cron;
+
+/// limited by the size of a storage record
+pub const MAX_SCHEDULES: usize = 12;
+pub const MAX_MESSAGE_LENGTH: usize = 512;
+/// how often to check for the clock while it is not synchronized
+const CLOCK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// minutes that passed while schedules printed are checked afterwards, a longer gap is taken as
+/// the clock being set rather than time passing
+const MAX_CATCH_UP_MINUTES: u64 = 60;
+
+static SCHEDULES: Mutex<CriticalSectionRawMutex, ScheduleTable> = Mutex::new(ScheduleTable {
+    next_id: 1,
+    schedules: Vec::new(),
+});
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum ScheduleError {
+    InvalidCron,
+    InvalidTime,
+    /// a one-shot time that has already passed
+    InPast,
+    /// the clock is not synchronized yet, so one-shot times can't be checked
+    NoClock,
+    TooMany,
+    TooLong,
+    NotFound,
+    /// the change could not be persisted, so it was not applied
+    Storage,
+}
+
+impl ScheduleError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScheduleError::InvalidCron => "invalid cron expression",
+            ScheduleError::InvalidTime => "invalid time, expected YYYY-MM-DD HH:MM",
+            ScheduleError::InPast => "time is in the past",
+            ScheduleError::NoClock => "clock is not synchronized yet",
+            ScheduleError::TooMany => "too many schedules",
+            ScheduleError::TooLong => "message is too long",
+            ScheduleError::NotFound => "schedule not found",
+            ScheduleError::Storage => "failed to save schedules",
+        }
+    }
+}
+
+/// When a schedule fires, times are local
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Trigger {
+    /// recurring, see [`Cron`]
+    Cron(String),
+    /// once at the given unix time, then removed
+    At(i64),
+}
+
+impl Trigger {
+    pub fn cron(expression: &str) -> Result<Self, ScheduleError> {
+        expression
+            .parse::<Cron>()
+            .map_err(|()| ScheduleError::InvalidCron)?;
+        Ok(Trigger::Cron(String::from(expression.trim())))
+    }
+
+    /// Parses a local `YYYY-MM-DD HH:MM` time
+    pub fn at(local_time: &str) -> Result<Self, ScheduleError> {
+        let local = local_time
+            .parse::<DateTime>()
+            .map_err(|()| ScheduleError::InvalidTime)?;
+        let now = time::unix_time().ok_or(ScheduleError::NoClock)? as i64;
+
+        let at = time::to_unix(&local);
+        if at <= now {
+            return Err(ScheduleError::InPast);
+        }
+        Ok(Trigger::At(at))
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = ScheduleError;
+
+    /// Parses `cron <expression>` or `at <YYYY-MM-DD HH:MM>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(' ') {
+            Some(("cron", expression)) => Trigger::cron(expression),
+            Some(("at", local_time)) => Trigger::at(local_time),
+            _ => Err(ScheduleError::InvalidCron),
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Cron(expression) => write!(f, "cron {expression}"),
+            Trigger::At(at) => write!(f, "at {}", time::to_local(*at)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: u32,
+    pub trigger: Trigger,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduleTable {
+    next_id: u32,
+    schedules: Vec<Schedule>,
+}
+
+pub async fn start_scheduler(spawner: &Spawner) {
+    if let Some(table) = storage::load::<ScheduleTable>(Record::Schedules).await {
+        info!("Loaded {} schedules", table.schedules.len());
+        *SCHEDULES.lock().await = table;
+    }
+
+    spawner.must_spawn(scheduler_task(PrinterWriter::new()));
+    info!("Scheduler initialized...");
+}
+
+pub async fn schedules() -> Vec<Schedule> {
+    SCHEDULES.lock().await.schedules.clone()
+}
+
+pub async fn add_schedule(trigger: Trigger, message: String) -> Result<u32, ScheduleError> {
+    if message.len() > MAX_MESSAGE_LENGTH {
+        return Err(ScheduleError::TooLong);
+    }
+
+    let mut table = SCHEDULES.lock().await;
+    if table.schedules.len() >= MAX_SCHEDULES {
+        return Err(ScheduleError::TooMany);
+    }
+
+    let id = table.next_id;
+    table.next_id += 1;
+    table.schedules.push(Schedule {
+        id,
+        trigger,
+        message,
+    });
+
+    if storage::save(Record::Schedules, &*table).await.is_err() {
+        table.schedules.pop();
+        table.next_id -= 1;
+        return Err(ScheduleError::Storage);
+    }
+
+    info!("Added schedule {}", id);
+    Ok(id)
+}
+
+pub async fn remove_schedule(id: u32) -> Result<(), ScheduleError> {
+    let mut table = SCHEDULES.lock().await;
+    let index = table
+        .schedules
+        .iter()
+        .position(|schedule| schedule.id == id)
+        .ok_or(ScheduleError::NotFound)?;
+
+    let schedule = table.schedules.remove(index);
+    if storage::save(Record::Schedules, &*table).await.is_err() {
+        table.schedules.insert(index, schedule);
+        return Err(ScheduleError::Storage);
+    }
+
+    info!("Removed schedule {}", id);
+    Ok(())
+}
+
+#[embassy_executor::task]
+async fn scheduler_task(printer: PrinterWriter) {
+    let mut last_minute = None;
+    loop {
+        let Some(now) = time::unix_time() else {
+            Timer::after(CLOCK_POLL_INTERVAL).await;
+            continue;
+        };
+
+        let minute = now / 60;
+        let first = match last_minute {
+            Some(last) if last == minute => None,
+            Some(last) if last < minute && minute - last <= MAX_CATCH_UP_MINUTES => Some(last + 1),
+            _ => Some(minute),
+        };
+        if let Some(first) = first {
+            last_minute = Some(minute);
+            for due in first..=minute {
+                if due != minute {
+                    info!("Catching up on schedules due at minute {}", due);
+                }
+                let at = if due == minute { now } else { due * 60 };
+                run_due(&printer, at as i64).await;
+            }
+        }
+
+        // wake at the start of the next minute, printing may have taken a while
+        let now = time::unix_time().unwrap_or(now);
+        Timer::after(Duration::from_secs(60 - now % 60)).await;
+    }
+}
+
+/// Prints every schedule due in the minute of `now`, one-shot schedules are removed once printed
+async fn run_due(printer: &PrinterWriter, now: i64) {
+    let local = time::to_local(now);
+    let mut due = Vec::new();
+
+    {
+        let mut table = SCHEDULES.lock().await;
+        let count = table.schedules.len();
+        table.schedules.retain(|schedule| match &schedule.trigger {
+            Trigger::Cron(expression) => {
+                if expression
+                    .parse::<Cron>()
+                    .is_ok_and(|cron| cron.matches(&local))
+                {
+                    due.push((schedule.id, schedule.message.clone()));
+                }
+                true
+            }
+            Trigger::At(at) if *at <= now => {
+                due.push((schedule.id, schedule.message.clone()));
+                false
+            }
+            Trigger::At(_) => true,
+        });
+
+        if table.schedules.len() != count
+            && storage::save(Record::Schedules, &*table).await.is_err()
+        {
+            warn!("Failed to persist the removal of finished schedules");
+        }
+    }
+
+    for (id, message) in due {
+        info!("Schedule {} is due", id);
+        let options = JobOptions::new(JobSource::Schedule(id));
+        if let Err(e) = printer.print(message.as_bytes(), options).await {
+            error!("Failed to print schedule {}: {:?}", id, e);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use core::str::FromStr;
+
+use crate::time::DateTime;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A five field cron expression, `minute hour day-of-month month day-of-week`.
+///
+/// Fields accept `*`, numbers, `a-b` ranges, `a,b` lists and `/n` steps. Months and weekdays can
+/// also be given by their three letter names, and Sunday is both 0 and 7. As in other crons,
+/// when both day fields are restricted a day matching either one fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Cron {
+    pub fn matches(&self, time: &DateTime) -> bool {
+        let is_set = |mask: u64, value: u8| mask & (1 << value) != 0;
+
+        let day = is_set(self.days, time.day);
+        let weekday = is_set(self.weekdays, time.weekday());
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+
+        is_set(self.minutes, time.minute)
+            && is_set(self.hours, time.hour)
+            && is_set(self.months, time.month)
+            && day_matches
+    }
+}
+
+impl FromStr for Cron {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let mut next = || fields.next().ok_or(());
+        let (minutes, hours, days, months, weekdays) =
+            (next()?, next()?, next()?, next()?, next()?);
+        if fields.next().is_some() {
+            return Err(());
+        }
+
+        let weekday_mask = parse_field(weekdays, 0, 7, &WEEKDAY_NAMES)?;
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59, &[])?,
+            hours: parse_field(hours, 0, 23, &[])?,
+            days: parse_field(days, 1, 31, &[])?,
+            months: parse_field(months, 1, 12, &MONTH_NAMES)?,
+            // fold 7 into Sunday
+            weekdays: (weekday_mask | weekday_mask >> 7) & 0x7F,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+}
+
+/// Parses one field into a bit mask of the values it matches
+fn parse_field(field: &str, min: u8, max: u8, names: &[&str]) -> Result<u64, ()> {
+    let value = |s: &str| -> Result<u8, ()> {
+        let value = match names.iter().position(|name| name.eq_ignore_ascii_case(s)) {
+            Some(index) => index as u8 + min,
+            None => s.parse().map_err(|_| ())?,
+        };
+        (min..=max).contains(&value).then_some(value).ok_or(())
+    };
+
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u8>().map_err(|_| ())?)),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/15` runs from 5 to the end of the range
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end || step == Some(0) {
+            return Err(());
+        }
+
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cron(expression: &str) -> Cron {
+        expression.parse().unwrap()
+    }
+
+    fn at(text: &str) -> DateTime {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn every_minute_matches_anything() {
+        let every = cron("* * * * *");
+        assert!(every.matches(&at("2026-10-19 00:00")));
+        assert!(every.matches(&at("2028-02-29 23:59")));
+    }
+
+    #[test]
+    fn matches_fixed_times() {
+        let morning = cron("30 8 * * *");
+        assert!(morning.matches(&at("2026-10-19 08:30")));
+        assert!(!morning.matches(&at("2026-10-19 08:31")));
+        assert!(!morning.matches(&at("2026-10-19 20:30")));
+    }
+
+    #[test]
+    fn expands_ranges_lists_and_steps() {
+        let quarters = cron("*/15 9-17 * * *");
+        for minute in 0..60 {
+            let time = DateTime {
+                minute,
+                ..at("2026-10-19 12:00")
+            };
+            assert_eq!(quarters.matches(&time), minute % 15 == 0, "minute {minute}");
+        }
+        assert!(!quarters.matches(&at("2026-10-19 08:45")));
+        assert!(quarters.matches(&at("2026-10-19 17:45")));
+        assert!(!quarters.matches(&at("2026-10-19 18:00")));
+
+        // a step from a start runs to the end of the range
+        let odd = cron("5/20 1,3,22 * * *");
+        assert!(odd.matches(&at("2026-10-19 03:25")));
+        assert!(odd.matches(&at("2026-10-19 22:45")));
+        assert!(!odd.matches(&at("2026-10-19 22:00")));
+        assert!(!odd.matches(&at("2026-10-19 02:05")));
+
+        let stepped_range = cron("0 0-12/6 * * *");
+        assert!(stepped_range.matches(&at("2026-10-19 12:00")));
+        assert!(!stepped_range.matches(&at("2026-10-19 18:00")));
+    }
+
+    #[test]
+    fn names_months_and_weekdays() {
+        let weekdays = cron("0 8 * * mon-FRI");
+        // 2026-10-19 is a Monday
+        assert!(weekdays.matches(&at("2026-10-19 08:00")));
+        assert!(weekdays.matches(&at("2026-10-23 08:00")));
+        assert!(!weekdays.matches(&at("2026-10-24 08:00")));
+        assert!(!weekdays.matches(&at("2026-10-25 08:00")));
+
+        let december = cron("0 0 1 dec *");
+        assert!(december.matches(&at("2026-12-01 00:00")));
+        assert!(!december.matches(&at("2026-11-01 00:00")));
+    }
+
+    #[test]
+    fn sunday_is_both_0_and_7() {
+        assert_eq!(cron("0 0 * * 0"), cron("0 0 * * 7"));
+        assert!(cron("0 0 * * 7").matches(&at("2026-10-25 00:00")));
+        assert!(cron("0 0 * * 5-7").matches(&at("2026-10-25 00:00")));
+    }
+
+    #[test]
+    fn either_restricted_day_field_fires() {
+        // the 13th, and every Friday
+        let unlucky = cron("0 12 13 * fri");
+        assert!(unlucky.matches(&at("2026-10-13 12:00")));
+        assert!(unlucky.matches(&at("2026-10-23 12:00")));
+        assert!(!unlucky.matches(&at("2026-10-22 12:00")));
+
+        // with one day field left open, the other must match
+        let thirteenth = cron("0 12 13 * *");
+        assert!(!thirteenth.matches(&at("2026-10-23 12:00")));
+    }
+
+    #[test]
+    fn leap_days_only_match_in_leap_years() {
+        let leap_day = cron("0 0 29 2 *");
+        assert!(leap_day.matches(&at("2028-02-29 00:00")));
+        assert!(!leap_day.matches(&at("2027-03-01 00:00")));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * 32 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "10-5 * * * *",
+            "*/0 * * * *",
+            "1,,2 * * * *",
+            "* * * foo *",
+            "-1 * * * *",
+        ] {
+            assert!(expression.parse::<Cron>().is_err(), "{expression:?}");
+        }
+    }
+}
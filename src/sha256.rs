@@ -0,0 +1,114 @@
+//! SHA-256 from the `sha2` crate, with the PBKDF2 needed to store credentials. Firmware updates
+//! are checked against it too.
+
+use pbkdf2::hmac::{Hmac, Mac};
+use sha2::Digest as _;
+
+pub const DIGEST_SIZE: usize = 32;
+/// PBKDF2 iterations run between letting other tasks in, each is two SHA-256 blocks
+const PBKDF2_ITERATIONS_PER_YIELD: u32 = 256;
+
+pub type Digest = [u8; DIGEST_SIZE];
+
+#[derive(Clone)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self(sha2::Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> Digest {
+        self.0.finalize().into()
+    }
+}
+
+pub fn sha256(data: &[u8]) -> Digest {
+    sha2::Sha256::digest(data).into()
+}
+
+/// PBKDF2-HMAC-SHA256 with a single block of output. Thousands of iterations take a large part
+/// of a second, so other tasks are let in every few hundred.
+pub async fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> Digest {
+    let key = Hmac::<sha2::Sha256>::new_from_slice(password).expect("hmac takes any key length");
+    let mut mac = key.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut block: Digest = mac.finalize().into_bytes().into();
+    let mut hash = block;
+    for iteration in 1..iterations {
+        let mut mac = key.clone();
+        mac.update(&block);
+        block = mac.finalize().into_bytes().into();
+        for (hash, block) in hash.iter_mut().zip(&block) {
+            *hash ^= block;
+        }
+        if iteration % PBKDF2_ITERATIONS_PER_YIELD == 0 {
+            embassy_futures::yield_now().await;
+        }
+    }
+    hash
+}
+
+/// Compares digests in the same time however many bytes match
+pub fn digests_match(a: &Digest, b: &Digest) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Digest {
+        let mut digest = [0; DIGEST_SIZE];
+        for (byte, pair) in digest.iter_mut().zip(text.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap();
+        }
+        digest
+    }
+
+    #[test]
+    fn hashes_known_vectors() {
+        assert_eq!(
+            sha256(b"abc"),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        let mut hasher = Sha256::new();
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(hasher.finish(), sha256(b"abc"));
+    }
+
+    #[test]
+    fn derives_keys_across_yields() {
+        // rfc 7914's PBKDF2-HMAC-SHA256 vector
+        let hash = embassy_futures::block_on(pbkdf2(b"passwd", b"salt", 1));
+        assert_eq!(
+            hash,
+            hex("55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc")
+        );
+        // and the crate's own, with enough iterations to yield a few times
+        for iterations in [2, 256, 257, 10_000] {
+            let hash = embassy_futures::block_on(pbkdf2(b"correct horse", b"pepper", iterations));
+            let expected = pbkdf2::pbkdf2_hmac_array::<sha2::Sha256, DIGEST_SIZE>(
+                b"correct horse",
+                b"pepper",
+                iterations,
+            );
+            assert_eq!(hash, expected);
+        }
+    }
+
+    #[test]
+    fn compares_digests() {
+        let digest = sha256(b"token");
+        let mut other = digest;
+        assert!(digests_match(&digest, &other));
+        other[31] ^= 1;
+        assert!(!digests_match(&digest, &other));
+    }
+}
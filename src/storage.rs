@@ -0,0 +1,220 @@
+use alloc::vec::Vec;
+use defmt::{error, info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{self, PARTITION_TABLE_MAX_LEN};
+use esp_hal::rom::crc::crc32_le;
+use esp_storage::FlashStorage;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// label of the data partition in `partitions.csv`
+const PARTITION_LABEL: &str = "storage";
+const RECORDS: usize = 5;
+/// magic, crc, sequence number and length
+const HEADER_SIZE: usize = 16;
+/// changed with the layout of the partition, so copies in another layout are ignored
+const MAGIC: u32 = u32::from_le_bytes(*b"SCR3");
+
+static STORAGE: Mutex<CriticalSectionRawMutex, Option<PersistentStorage>> = Mutex::new(None);
+
+/// Each kind of persisted data has two fixed slots in the storage partition. Saves go to the slot
+/// not holding the newest copy, so one cut off by a power loss leaves the previous copy intact.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum Record {
+    Schedules = 0,
+    Paper = 1,
+    Auth = 2,
+    Config = 3,
+    Update = 4,
+}
+
+impl Record {
+    const ALL: [Record; RECORDS] = [
+        Record::Schedules,
+        Record::Paper,
+        Record::Auth,
+        Record::Config,
+        Record::Update,
+    ];
+
+    /// bytes of each of the record's slots, only the schedules need more than a sector
+    fn slot_size(self) -> u32 {
+        match self {
+            Record::Schedules => 8 * 1024,
+            _ => 4 * 1024,
+        }
+    }
+
+    /// offsets of the two slots in the partition, after those of the records before it
+    fn slots(self) -> [u32; 2] {
+        let first = Self::ALL[..self as usize]
+            .iter()
+            .map(|record| 2 * record.slot_size())
+            .sum::<u32>();
+        [first, first + self.slot_size()]
+    }
+}
+
+/// A copy of a record read from one of its slots
+struct StoredCopy {
+    slot: usize,
+    sequence: u32,
+    data: Vec<u8>,
+}
+
+struct PersistentStorage {
+    flash: FlashStorage<'static>,
+    /// offset and size of the storage partition
+    partition: Option<(u32, u32)>,
+}
+
+/// Finds the storage partition, without one nothing is persisted across reboots
+pub async fn init_storage(mut flash: FlashStorage<'static>) {
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let partition = match partitions::read_partition_table(&mut flash, &mut buffer) {
+        Ok(table) => table
+            .iter()
+            .find(|partition| partition.label_as_str() == PARTITION_LABEL)
+            .map(|partition| (partition.offset(), partition.len())),
+        Err(e) => {
+            error!("Failed to read the partition table: {:?}", e);
+            None
+        }
+    };
+
+    match partition {
+        Some((offset, size)) => info!("Storage partition at {:#x}, {} bytes", offset, size),
+        None => warn!("No storage partition, settings will not persist"),
+    }
+    *STORAGE.lock().await = Some(PersistentStorage { flash, partition });
+}
+
+/// Runs `f` with the whole flash, `None` before storage is initialized. Records are not
+/// written meanwhile, so `f` must leave the storage partition alone.
+pub async fn with_flash<R>(f: impl FnOnce(&mut FlashStorage<'static>) -> R) -> Option<R> {
+    let mut storage = STORAGE.lock().await;
+    storage.as_mut().map(|storage| f(&mut storage.flash))
+}
+
+/// Reads a record, `None` if it was never saved, both copies are corrupt or it no longer
+/// deserializes
+pub async fn load<T: DeserializeOwned>(record: Record) -> Option<T> {
+    let mut storage = STORAGE.lock().await;
+    let storage = storage.as_mut()?;
+    let copy = storage.newest(record)?;
+
+    match postcard::from_bytes(&copy.data) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Stored {} record is from an incompatible version", record);
+            None
+        }
+    }
+}
+
+pub async fn save<T: Serialize>(record: Record, value: &T) -> Result<(), ()> {
+    let mut storage = STORAGE.lock().await;
+    let Some(storage) = storage
+        .as_mut()
+        .filter(|storage| storage.partition.is_some())
+    else {
+        return Err(());
+    };
+    let Some(addresses) = storage.addresses(record) else {
+        error!("Storage partition is too small for the {} record", record);
+        return Err(());
+    };
+
+    let data = postcard::to_allocvec(value).map_err(|_| ())?;
+    if data.len() > record.slot_size() as usize - HEADER_SIZE {
+        warn!(
+            "{} record of {} bytes is too large to store",
+            record,
+            data.len()
+        );
+        return Err(());
+    }
+
+    // the newest copy is left alone until this one is complete
+    let (slot, sequence) = match storage.newest(record) {
+        Some(newest) => (1 - newest.slot, newest.sequence.wrapping_add(1)),
+        None => (0, 0),
+    };
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + data.len());
+    bytes.extend_from_slice(&MAGIC.to_le_bytes());
+    bytes.extend_from_slice(&[0; 4]);
+    bytes.extend_from_slice(&sequence.to_le_bytes());
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&data);
+    let crc = crc32_le(0, &bytes[8..]);
+    bytes[4..8].copy_from_slice(&crc.to_le_bytes());
+
+    storage.flash.write(addresses[slot], &bytes).map_err(|e| {
+        error!("Failed to write the {} record: {:?}", record, e);
+    })
+}
+
+impl PersistentStorage {
+    fn addresses(&self, record: Record) -> Option<[u32; 2]> {
+        let (offset, size) = self.partition?;
+        let slots = record.slots();
+        let fits = slots.iter().all(|slot| slot + record.slot_size() <= size);
+        fits.then(|| slots.map(|slot| offset + slot))
+    }
+
+    /// The copy with the highest sequence number of those that are intact
+    fn newest(&mut self, record: Record) -> Option<StoredCopy> {
+        let addresses = self.addresses(record)?;
+        let copies = [0, 1].map(|slot| self.read(record, slot, addresses[slot]));
+        if copies.iter().any(|copy| copy.is_err()) {
+            warn!("A stored copy of the {} record is corrupt", record);
+        }
+
+        let [first, second] = copies.map(|copy| copy.ok().flatten());
+        match (first, second) {
+            // sequence numbers are compared as a wrapping difference
+            (Some(first), Some(second))
+                if (second.sequence.wrapping_sub(first.sequence) as i32) > 0 =>
+            {
+                Some(second)
+            }
+            (Some(first), _) => Some(first),
+            (None, second) => second,
+        }
+    }
+
+    /// Reads the copy in a slot, `Ok(None)` if nothing was ever written there and `Err` if the
+    /// copy is incomplete or corrupt
+    fn read(
+        &mut self,
+        record: Record,
+        slot: usize,
+        address: u32,
+    ) -> Result<Option<StoredCopy>, ()> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.flash.read(address, &mut header).map_err(|_| ())?;
+        let [magic, crc, sequence, length] = [0, 4, 8, 12]
+            .map(|i| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]));
+
+        if magic != MAGIC {
+            return Ok(None);
+        }
+        if length > record.slot_size() - HEADER_SIZE as u32 {
+            return Err(());
+        }
+
+        // the crc covers the sequence number and length as well as the data
+        let mut bytes = alloc::vec![0u8; HEADER_SIZE + length as usize];
+        self.flash.read(address, &mut bytes).map_err(|_| ())?;
+        if crc32_le(0, &bytes[8..]) != crc {
+            return Err(());
+        }
+        let data = bytes.split_off(HEADER_SIZE);
+        Ok(Some(StoredCopy {
+            slot,
+            sequence,
+            data,
+        }))
+    }
+}
@@ -0,0 +1,50 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+
+use crate::config::config;
+
+mod calendar;
+
+pub use calendar::{DateTime, DstRule, TimeZone};
+
+/// Unix time in microseconds at the uptime instant of the last sync
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<Option<(Instant, u64)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Anchors the wall clock to the given unix time, as measured at `instant`
+pub fn set_unix_time(instant: Instant, unix_micros: u64) {
+    CLOCK.lock(|clock| clock.set(Some((instant, unix_micros))));
+}
+
+/// Seconds since the unix epoch in UTC, `None` until the clock has been synchronized
+pub fn unix_time() -> Option<u64> {
+    let (instant, unix_micros) = CLOCK.lock(|clock| clock.get())?;
+    let elapsed = Instant::now().saturating_duration_since(instant);
+    Some((unix_micros + elapsed.as_micros()) / 1_000_000)
+}
+
+/// The current local time, `None` until the clock has been synchronized
+pub fn now() -> Option<DateTime> {
+    Some(to_local(unix_time()? as i64))
+}
+
+/// Converts unix time to local time using the configured offset and dst rule
+pub fn to_local(utc: i64) -> DateTime {
+    time_zone().to_local(utc)
+}
+
+/// Converts local time back to unix time, a time repeated when dst ends resolves to its first
+/// occurrence
+pub fn to_unix(local: &DateTime) -> i64 {
+    time_zone().to_unix(local)
+}
+
+fn time_zone() -> TimeZone {
+    let time = config().time;
+    TimeZone {
+        utc_offset_minutes: time.utc_offset_minutes,
+        dst: time.dst,
+    }
+}
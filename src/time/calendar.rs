@@ -0,0 +1,350 @@
+use core::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// When daylight saving time adds an hour to the local time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum DstRule {
+    None,
+    /// last Sunday of March to the last Sunday of October
+    Eu,
+    /// second Sunday of March to the first Sunday of November
+    Us,
+}
+
+impl FromStr for DstRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(DstRule::None),
+            "eu" => Ok(DstRule::Eu),
+            "us" => Ok(DstRule::Us),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A standard time offset from UTC and the daylight saving time rule on top of it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeZone {
+    pub utc_offset_minutes: i16,
+    pub dst: DstRule,
+}
+
+impl TimeZone {
+    /// Converts unix time to local time
+    pub fn to_local(self, utc: i64) -> DateTime {
+        DateTime::from_unix(utc + self.offset(utc))
+    }
+
+    /// Converts local time back to unix time, a time repeated when dst ends resolves to its first
+    /// occurrence
+    pub fn to_unix(self, local: &DateTime) -> i64 {
+        let standard = local.to_unix() - self.utc_offset_minutes as i64 * 60;
+        let daylight = standard - 3_600;
+        if self.to_local(daylight) == *local {
+            daylight
+        } else {
+            standard
+        }
+    }
+
+    /// Seconds local time is ahead of UTC at the unix time `utc`
+    fn offset(self, utc: i64) -> i64 {
+        let offset = self.utc_offset_minutes as i64 * 60;
+        let dst = if self.is_dst(utc, offset) { 3_600 } else { 0 };
+
+        offset + dst
+    }
+
+    /// Whether daylight saving time is in effect at the unix time `utc`, `offset` seconds from UTC
+    fn is_dst(self, utc: i64, offset: i64) -> bool {
+        let year = DateTime::from_unix(utc + offset).year;
+        let (start, end) = match self.dst {
+            DstRule::None => return false,
+            // last Sunday of March to the last Sunday of October, at 01:00 UTC
+            DstRule::Eu => (
+                last_sunday(year, 3) * SECONDS_PER_DAY + 3_600,
+                last_sunday(year, 10) * SECONDS_PER_DAY + 3_600,
+            ),
+            // second Sunday of March to the first Sunday of November, at 02:00 local time
+            DstRule::Us => (
+                (nth_sunday(year, 3, 2) * SECONDS_PER_DAY + 7_200) - offset,
+                (nth_sunday(year, 11, 1) * SECONDS_PER_DAY + 3_600) - offset,
+            ),
+        };
+
+        (start..end).contains(&utc)
+    }
+}
+
+/// A civil date and time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn from_unix(seconds: i64) -> Self {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let seconds = seconds.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds / 3_600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// Seconds since the epoch, treating this as a UTC time
+    pub fn to_unix(self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + self.hour as i64 * 3_600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// 0 for Sunday through 6 for Saturday
+    pub fn weekday(self) -> u8 {
+        weekday(days_from_civil(self.year, self.month, self.day)) as u8
+    }
+}
+
+impl FromStr for DateTime {
+    type Err = ();
+
+    /// Parses `YYYY-MM-DD HH:MM`, optionally with seconds and a `T` separator
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (date, time) = s.trim().split_once([' ', 'T']).ok_or(())?;
+        let (year, date) = date.split_once('-').ok_or(())?;
+        let (month, day) = date.split_once('-').ok_or(())?;
+        let (hour, time) = time.split_once(':').ok_or(())?;
+        let (minute, second) = time.split_once(':').unwrap_or((time, "0"));
+
+        let date_time = Self {
+            year: year.parse().map_err(|_| ())?,
+            month: month.parse().map_err(|_| ())?,
+            day: day.parse().map_err(|_| ())?,
+            hour: hour.parse().map_err(|_| ())?,
+            minute: minute.parse().map_err(|_| ())?,
+            second: second.parse().map_err(|_| ())?,
+        };
+        // a round trip rejects out of range fields such as the 31st of April
+        if Self::from_unix(date_time.to_unix()) != date_time {
+            return Err(());
+        }
+
+        Ok(date_time)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl defmt::Format for DateTime {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "{=i32:04}-{=u8:02}-{=u8:02} {=u8:02}:{=u8:02}:{=u8:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+// date conversions from http://howardhinnant.github.io/date_algorithms.html
+
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year as i32, month, day)
+}
+
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// 0 for Sunday through 6 for Saturday
+fn weekday(days: i64) -> i64 {
+    // the epoch was a Thursday
+    (days + 4).rem_euclid(7)
+}
+
+/// Day number of the last Sunday of the month
+fn last_sunday(year: i32, month: u8) -> i64 {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let last_day = days_from_civil(next_year, next_month, 1) - 1;
+    last_day - weekday(last_day)
+}
+
+/// Day number of the `n`th Sunday of the month
+fn nth_sunday(year: i32, month: u8, n: i64) -> i64 {
+    let first_day = days_from_civil(year, month, 1);
+    let first_sunday = first_day + (7 - weekday(first_day)) % 7;
+    first_sunday + 7 * (n - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    const CET: TimeZone = TimeZone {
+        utc_offset_minutes: 60,
+        dst: DstRule::Eu,
+    };
+    const EST: TimeZone = TimeZone {
+        utc_offset_minutes: -300,
+        dst: DstRule::Us,
+    };
+
+    fn at(text: &str) -> DateTime {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn converts_unix_time() {
+        assert_eq!(DateTime::from_unix(0), at("1970-01-01 00:00"));
+        assert_eq!(DateTime::from_unix(-1), at("1969-12-31 23:59:59"));
+        assert_eq!(DateTime::from_unix(951_782_400), at("2000-02-29 00:00"));
+        assert_eq!(DateTime::from_unix(4_107_542_400), at("2100-03-01 00:00"));
+        for seconds in [-86_401, 0, 951_782_399, 1_792_368_000, 4_107_542_400] {
+            assert_eq!(DateTime::from_unix(seconds).to_unix(), seconds);
+        }
+    }
+
+    #[test]
+    fn counts_weekdays_from_sunday() {
+        assert_eq!(at("1970-01-01 00:00").weekday(), 4);
+        assert_eq!(at("2026-10-18 23:59").weekday(), 0);
+        assert_eq!(at("2026-10-19 00:00").weekday(), 1);
+        assert_eq!(at("2000-02-29 12:00").weekday(), 2);
+    }
+
+    #[test]
+    fn parses_only_real_dates() {
+        assert_eq!(at("2026-10-19T17:30:15").second, 15);
+        assert_eq!(at(" 2026-10-19 17:30 ").minute, 30);
+        assert!("2024-02-29 00:00".parse::<DateTime>().is_ok());
+        assert!("2000-02-29 00:00".parse::<DateTime>().is_ok());
+        // not leap years
+        assert!("2023-02-29 00:00".parse::<DateTime>().is_err());
+        assert!("1900-02-29 00:00".parse::<DateTime>().is_err());
+        assert!("2026-04-31 00:00".parse::<DateTime>().is_err());
+        assert!("2026-13-01 00:00".parse::<DateTime>().is_err());
+        assert!("2026-10-19 24:00".parse::<DateTime>().is_err());
+        assert!("2026-10-19".parse::<DateTime>().is_err());
+        assert_eq!(
+            format!("{}", at("2026-01-02 03:04:05")),
+            "2026-01-02 03:04:05"
+        );
+    }
+
+    #[test]
+    fn applies_the_fixed_offset_without_dst() {
+        let zone = TimeZone {
+            utc_offset_minutes: 330,
+            dst: DstRule::None,
+        };
+        assert_eq!(zone.to_local(1_774_746_000), at("2026-03-29 06:30"));
+        assert_eq!(zone.to_unix(&at("2026-03-29 06:30")), 1_774_746_000);
+    }
+
+    #[test]
+    fn changes_eu_time_at_one_utc() {
+        // 2026-03-29 01:00 UTC
+        let start = 1_774_746_000;
+        assert_eq!(CET.to_local(start - 1), at("2026-03-29 01:59:59"));
+        assert_eq!(CET.to_local(start), at("2026-03-29 03:00"));
+        // 2026-10-25 01:00 UTC
+        let end = 1_792_890_000;
+        assert_eq!(CET.to_local(end - 1), at("2026-10-25 02:59:59"));
+        assert_eq!(CET.to_local(end), at("2026-10-25 02:00"));
+        assert_eq!(CET.to_local(0), at("1970-01-01 01:00"));
+    }
+
+    #[test]
+    fn changes_us_time_at_two_local() {
+        // 2026-03-08 07:00 UTC
+        let start = 1_772_953_200;
+        assert_eq!(EST.to_local(start - 1), at("2026-03-08 01:59:59"));
+        assert_eq!(EST.to_local(start), at("2026-03-08 03:00"));
+        // 2026-11-01 06:00 UTC
+        let end = 1_793_512_800;
+        assert_eq!(EST.to_local(end - 1), at("2026-11-01 01:59:59"));
+        assert_eq!(EST.to_local(end), at("2026-11-01 01:00"));
+    }
+
+    #[test]
+    fn resolves_local_times_around_the_changes() {
+        // the repeated hour resolves to its first occurrence, still in summer time
+        assert_eq!(
+            CET.to_unix(&at("2026-10-25 02:30")),
+            1_792_890_000 - 30 * 60
+        );
+        assert_eq!(
+            EST.to_unix(&at("2026-11-01 01:30")),
+            1_793_512_800 - 30 * 60
+        );
+        // the skipped hour lands an hour later
+        let skipped = CET.to_unix(&at("2026-03-29 02:30"));
+        assert_eq!(CET.to_local(skipped), at("2026-03-29 03:30"));
+        for local in ["2026-01-15 08:00", "2026-07-15 08:00", "2028-02-29 23:59"] {
+            assert_eq!(CET.to_local(CET.to_unix(&at(local))), at(local));
+            assert_eq!(EST.to_local(EST.to_unix(&at(local))), at(local));
+        }
+    }
+
+    #[test]
+    fn parses_dst_rules() {
+        assert_eq!(" eu\n".parse(), Ok(DstRule::Eu));
+        assert_eq!("us".parse(), Ok(DstRule::Us));
+        assert_eq!("none".parse(), Ok(DstRule::None));
+        assert_eq!("EU".parse::<DstRule>(), Err(()));
+    }
+}